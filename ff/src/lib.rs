@@ -19,7 +19,7 @@ mod ff_extern;
 mod wrapper;
 use wrapper::{WrapperFatFs, WrapperRead, WrapperReadWrite};
 
-pub use ff_c::{FM_EXFAT, FM_FAT32};
+pub use ff_c::{FM_EXFAT, FM_FAT32, FM_SFD};
 
 const DRIVE: &str = "0:";
 
@@ -119,8 +119,13 @@ impl<T: Read + Seek> FatFs<T> {
         })
     }
 
-    /// Read directory from path
-    pub fn read_dir(&self, path: &str) -> Result<Vec<FileInfo>, io::Error> {
+    /// Read directory from path, fails as soon as it has more than
+    /// `max_entries` entries
+    pub fn read_dir(
+        &self,
+        path: &str,
+        max_entries: Option<u64>,
+    ) -> Result<Vec<FileInfo>, io::Error> {
         let mut dir = ff_c::DIR::new();
         let mut files_info = Vec::new();
         let path_u16 = str_to_utf16(path);
@@ -140,6 +145,16 @@ impl<T: Read + Seek> FatFs<T> {
                 break;
             }
 
+            if let Some(max) = max_entries {
+                if files_info.len() as u64 >= max {
+                    unsafe { ff_c::f_closedir(&mut dir) };
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        format!("more than {max} entries"),
+                    ));
+                }
+            }
+
             let idx_end = fno
                 .fname
                 .as_slice()
@@ -21,6 +21,26 @@ pub struct Ntfs3g<T> {
     inner_type: PhantomData<T>,
 }
 
+/// File times in nanoseconds since the Unix epoch
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NtfsTimes {
+    pub creation: i64,
+    pub modification: i64,
+    pub change: i64,
+    pub access: i64,
+}
+
+/// Set times of an inode.
+/// Ntfs timestamp is a 64-bit value representing the number of 100-nanosecond intervals
+/// since January 1, 1601 (UTC)
+unsafe fn set_inode_times(ni: *mut n3g_c::ntfs_inode, times: &NtfsTimes) {
+    let ntfs_ts = |nanos: i64| (nanos / 100 + 11644473600 * 10 * 1000 * 1000).max(0) as u64;
+    (*ni).creation_time = ntfs_ts(times.creation);
+    (*ni).last_data_change_time = ntfs_ts(times.modification);
+    (*ni).last_mft_change_time = ntfs_ts(times.change);
+    (*ni).last_access_time = ntfs_ts(times.access);
+}
+
 fn split_path_parent(path: &str) -> Result<(String, String)> {
     let (mut parent_dir, filename) = match path.rsplit_once('/') {
         Some((parent, filename)) => (parent.to_owned(), filename.to_owned()),
@@ -133,7 +153,7 @@ impl<T: Read + Write + Seek> Ntfs3g<T> {
     }
 
     /// Create a new file and returns an NtfsAttr (that impl Write and Seek)
-    pub fn new_file<'a>(&'a mut self, path: &str, timestamp: i64) -> Result<NtfsAttr<'a, T>> {
+    pub fn new_file<'a>(&'a mut self, path: &str, times: &NtfsTimes) -> Result<NtfsAttr<'a, T>> {
         let (parent_dir, filename) = split_path_parent(path)?;
         let p_ni = self.inode_from_path(&parent_dir)?;
         let path_u16 = str2ntfsunicode(&filename)?;
@@ -165,16 +185,7 @@ impl<T: Read + Write + Seek> Ntfs3g<T> {
             ));
         }
 
-        // Set timestamp.
-        // Ntfs timestamp is a 64-bit value representing the number of 100-nanosecond intervals
-        // since January 1, 1601 (UTC)
-        let ntfs_ts: u64 = ((timestamp + 11644473600) * 10 * 1000 * 1000) as u64;
-        unsafe {
-            (*file_ni).creation_time = ntfs_ts;
-            (*file_ni).last_data_change_time = ntfs_ts;
-            (*file_ni).last_mft_change_time = ntfs_ts;
-            (*file_ni).last_access_time = ntfs_ts;
-        }
+        unsafe { set_inode_times(file_ni, times) };
 
         Ok(NtfsAttr {
             attr: file_na,
@@ -183,7 +194,7 @@ impl<T: Read + Write + Seek> Ntfs3g<T> {
         })
     }
 
-    pub fn new_dir(&mut self, path: &str, timestamp: i64) -> Result<()> {
+    pub fn new_dir(&mut self, path: &str, times: &NtfsTimes) -> Result<()> {
         let (parent_dir, filename) = split_path_parent(path)?;
         let p_ni = self.inode_from_path(&parent_dir)?;
         let path_u16 = str2ntfsunicode(&filename)?;
@@ -209,14 +220,7 @@ impl<T: Read + Write + Seek> Ntfs3g<T> {
             }
         }
 
-        // Set timestamp
-        let ntfs_ts: u64 = ((timestamp + 11644473600) * 10 * 1000 * 1000) as u64;
-        unsafe {
-            (*dir_ni).creation_time = ntfs_ts;
-            (*dir_ni).last_data_change_time = ntfs_ts;
-            (*dir_ni).last_mft_change_time = ntfs_ts;
-            (*dir_ni).last_access_time = ntfs_ts;
-        }
+        unsafe { set_inode_times(dir_ni, times) };
 
         unsafe { n3g_c::ntfs_inode_close(dir_ni) };
 
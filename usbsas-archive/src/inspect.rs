@@ -0,0 +1,1048 @@
+//! Parsing of zip (including zip64), tar and gzip archives, and of the
+//! archives they contain, listing their members and detecting encryption and
+//! decompression bombs. Malformed archives are reported as corrupted.
+
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// Bytes needed to identify an archive (tar magic is at offset 257)
+const FORMAT_HEADER_SIZE: usize = 512;
+// First bytes of members given to the filter
+pub const MEMBER_HEADER_SIZE: usize = 1024;
+const MAX_CENTRAL_DIRECTORY_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_TAR_EXTENDED_HEADER_SIZE: u64 = 64 * 1024;
+const MAX_GZIP_FIELD_SIZE: u64 = 4096;
+// Small members can legitimately have huge ratios (blank files etc.)
+const RATIO_MIN_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+    Gzip,
+    SevenZip,
+}
+
+impl Format {
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(Format::Zip)
+        } else if header.starts_with(b"\x1f\x8b\x08") {
+            Some(Format::Gzip)
+        } else if header.starts_with(b"7z\xbc\xaf\x27\x1c") {
+            Some(Format::SevenZip)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(Format::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::Tar => "tar",
+            Format::Gzip => "gzip",
+            Format::SevenZip => "7z",
+        }
+    }
+}
+
+pub struct Limits {
+    pub max_depth: u64,
+    pub max_entries: u64,
+    pub max_ratio: u64,
+    pub max_size: u64,
+    pub max_nested_size: u64,
+}
+
+pub struct Member {
+    pub path: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub header: Vec<u8>,
+}
+
+pub struct Issue {
+    pub kind: &'static str,
+    pub path: String,
+}
+
+#[derive(Default)]
+pub struct Inspection {
+    pub format: Option<Format>,
+    pub members: Vec<Member>,
+    pub issues: Vec<Issue>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Stored,
+    Deflate,
+    Unsupported,
+}
+
+/// Member as described by its archive
+struct Entry {
+    path: String,
+    method: Method,
+    /// Unknown for gzip, read until the end of the deflate stream
+    compressed_size: Option<u64>,
+    size: u64,
+    encrypted: bool,
+}
+
+#[derive(Default)]
+struct Extracted {
+    size: u64,
+    consumed: u64,
+    /// Bytes decompressed or kept in memory
+    charged: u64,
+    complete: bool,
+    exceeded: bool,
+}
+
+/// Keeps the first bytes of a member, or all of it if it is an archive to
+/// inspect
+struct Sink {
+    data: Vec<u8>,
+    keep_all: Option<bool>,
+    nested: bool,
+    max_kept: usize,
+    too_large: bool,
+}
+
+impl Sink {
+    fn new(nested: bool, max_kept: u64) -> Self {
+        Sink {
+            data: Vec::new(),
+            keep_all: None,
+            nested,
+            max_kept: usize::try_from(max_kept).unwrap_or(usize::MAX),
+            too_large: false,
+        }
+    }
+
+    fn push(&mut self, buf: &[u8]) {
+        match self.keep_all {
+            Some(true) if self.data.len().saturating_add(buf.len()) > self.max_kept => {
+                self.drop_nested()
+            }
+            Some(true) => self.data.extend_from_slice(buf),
+            Some(false) => {
+                let len = MEMBER_HEADER_SIZE
+                    .saturating_sub(self.data.len())
+                    .min(buf.len());
+                self.data.extend_from_slice(&buf[..len]);
+            }
+            None => {
+                self.data.extend_from_slice(buf);
+                if self.data.len() >= FORMAT_HEADER_SIZE {
+                    self.decide();
+                }
+            }
+        }
+    }
+
+    fn decide(&mut self) {
+        let keep_all = self.nested && Format::detect(&self.data).is_some();
+        if !keep_all {
+            self.data.truncate(MEMBER_HEADER_SIZE);
+        }
+        self.keep_all = Some(keep_all);
+        if keep_all && self.data.len() > self.max_kept {
+            self.drop_nested();
+        }
+    }
+
+    // Only the header of a nested archive too large to be kept is kept, it
+    // won't be inspected
+    fn drop_nested(&mut self) {
+        self.data.truncate(MEMBER_HEADER_SIZE);
+        self.keep_all = Some(false);
+        self.too_large = true;
+    }
+
+    fn finish(&mut self) {
+        if self.keep_all.is_none() {
+            self.decide();
+        }
+    }
+
+    fn wants_all(&self) -> bool {
+        self.keep_all != Some(false)
+    }
+
+    fn kept(&self) -> u64 {
+        match self.keep_all {
+            Some(true) => self.data.len() as u64,
+            _ => 0,
+        }
+    }
+
+    fn header(&self) -> Vec<u8> {
+        self.data[..self.data.len().min(MEMBER_HEADER_SIZE)].to_vec()
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidData)
+}
+
+fn u16_at(buf: &[u8], off: usize) -> io::Result<u16> {
+    buf.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(invalid)
+}
+
+fn u32_at(buf: &[u8], off: usize) -> io::Result<u32> {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(invalid)
+}
+
+fn u64_at(buf: &[u8], off: usize) -> io::Result<u64> {
+    buf.get(off..off + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(invalid)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Decompress a member, keeping what the sink wants, until its end or until
+/// more than `budget` bytes are produced
+fn extract<R: Read>(
+    input: &mut R,
+    method: Method,
+    compressed_size: Option<u64>,
+    sink: &mut Sink,
+    budget: u64,
+) -> io::Result<Extracted> {
+    let mut ext = Extracted::default();
+    let mut inbuf = vec![0; CHUNK_SIZE];
+    match method {
+        Method::Stored => {
+            let total = compressed_size.unwrap_or(0);
+            while ext.consumed < total {
+                let len = (total - ext.consumed).min(CHUNK_SIZE as u64) as usize;
+                input.read_exact(&mut inbuf[..len])?;
+                ext.consumed += len as u64;
+                ext.size += len as u64;
+                sink.push(&inbuf[..len]);
+                // Only archives to inspect are read entirely
+                if !sink.wants_all() {
+                    break;
+                }
+                if sink.kept() > budget {
+                    ext.exceeded = true;
+                    break;
+                }
+            }
+            sink.finish();
+            ext.complete = ext.consumed == total;
+            ext.charged = sink.kept();
+        }
+        Method::Deflate => {
+            let mut state = InflateState::new_boxed(DataFormat::Raw);
+            let mut outbuf = vec![0; CHUNK_SIZE];
+            let mut remaining = compressed_size.unwrap_or(u64::MAX);
+            let (mut start, mut end) = (0, 0);
+            loop {
+                if start == end && remaining > 0 {
+                    let len = remaining.min(CHUNK_SIZE as u64) as usize;
+                    end = input.read(&mut inbuf[..len])?;
+                    start = 0;
+                    remaining = match end {
+                        0 => 0,
+                        _ => remaining.saturating_sub(end as u64),
+                    };
+                }
+                let res = inflate(&mut state, &inbuf[start..end], &mut outbuf, MZFlush::None);
+                start += res.bytes_consumed;
+                ext.consumed += res.bytes_consumed as u64;
+                ext.size += res.bytes_written as u64;
+                sink.push(&outbuf[..res.bytes_written]);
+                if ext.size > budget {
+                    ext.exceeded = true;
+                    break;
+                }
+                match res.status {
+                    Ok(MZStatus::StreamEnd) => {
+                        ext.complete = true;
+                        break;
+                    }
+                    // Buf errors when more input is needed
+                    Ok(_) | Err(MZError::Buf)
+                        if res.bytes_consumed > 0
+                            || res.bytes_written > 0
+                            || (start == end && remaining > 0) => {}
+                    _ => return Err(invalid()),
+                }
+            }
+            sink.finish();
+            ext.charged = ext.size;
+        }
+        Method::Unsupported => (),
+    }
+    Ok(ext)
+}
+
+struct Inspector<'a> {
+    limits: &'a Limits,
+    out: Inspection,
+    entries: u64,
+    decompressed: u64,
+    stopped: bool,
+}
+
+/// Inspect the archive `name` of `size` bytes, nothing is reported if it
+/// isn't an archive. Errors are those of the reader, not of the archive.
+pub fn inspect<R: Read + Seek>(
+    reader: &mut R,
+    size: u64,
+    name: &str,
+    limits: &Limits,
+) -> io::Result<Inspection> {
+    let header = read_at(reader, 0, size.min(FORMAT_HEADER_SIZE as u64) as usize)?;
+    let mut inspector = Inspector {
+        limits,
+        out: Inspection::default(),
+        entries: 0,
+        decompressed: 0,
+        stopped: false,
+    };
+    inspector.out.format = Format::detect(&header);
+    if let Some(format) = inspector.out.format {
+        inspector.archive(reader, size, format, name, "", 1)?;
+    }
+    Ok(inspector.out)
+}
+
+impl Inspector<'_> {
+    fn issue(&mut self, kind: &'static str, path: &str) {
+        self.out.issues.push(Issue {
+            kind,
+            path: path.into(),
+        });
+    }
+
+    /// Report an exceeded limit and stop the inspection
+    fn stop(&mut self, kind: &'static str, path: &str) {
+        self.issue(kind, path);
+        self.stopped = true;
+    }
+
+    /// Inspect an archive, `name` is its file name and `prefix` the path of
+    /// its members
+    fn archive<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        size: u64,
+        format: Format,
+        name: &str,
+        prefix: &str,
+        depth: u64,
+    ) -> io::Result<()> {
+        let res = match format {
+            Format::Zip => self.zip(reader, size, prefix, depth),
+            Format::Tar => self.tar(reader, size, prefix, depth),
+            Format::Gzip => self.gzip(reader, size, name, prefix, depth),
+            Format::SevenZip => {
+                self.issue("not_inspected", prefix.trim_end_matches('/'));
+                Ok(())
+            }
+        };
+        match res {
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                self.issue("corrupted", prefix.trim_end_matches('/'));
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Record a member, the reader is positioned at its data
+    fn member<R: Read>(&mut self, data: &mut R, entry: Entry, depth: u64) -> io::Result<()> {
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            self.stop("too_many_entries", "");
+            return Ok(());
+        }
+        if entry.encrypted || entry.method == Method::Unsupported {
+            let kind = if entry.encrypted {
+                "encrypted"
+            } else {
+                "not_inspected"
+            };
+            self.issue(kind, &entry.path);
+            self.out.members.push(Member {
+                path: entry.path,
+                size: entry.size,
+                compressed_size: entry.compressed_size.unwrap_or_default(),
+                header: Vec::new(),
+            });
+            return Ok(());
+        }
+
+        let budget = self.limits.max_size.saturating_sub(self.decompressed);
+        let mut sink = Sink::new(depth < self.limits.max_depth, self.limits.max_nested_size);
+        let ext = extract(data, entry.method, entry.compressed_size, &mut sink, budget)?;
+        self.decompressed += ext.charged;
+        if ext.exceeded {
+            self.stop("too_large", &entry.path);
+        }
+        if entry.method == Method::Deflate
+            && ext.size >= RATIO_MIN_SIZE
+            && ext.size / ext.consumed.max(1) > self.limits.max_ratio
+        {
+            self.issue("compression_ratio", &entry.path);
+        }
+        let size = match entry.method {
+            Method::Deflate if ext.complete => ext.size,
+            _ => entry.size.max(ext.size),
+        };
+        self.out.members.push(Member {
+            path: entry.path.clone(),
+            size,
+            compressed_size: entry.compressed_size.unwrap_or(ext.consumed),
+            header: sink.header(),
+        });
+
+        if let Some(format) = Format::detect(&sink.data) {
+            if depth >= self.limits.max_depth {
+                self.issue("too_deep", &entry.path);
+            } else if sink.too_large {
+                self.issue("too_large", &entry.path);
+            } else if ext.complete && !self.stopped {
+                let len = sink.data.len() as u64;
+                let name = entry.path.rsplit('/').next().unwrap_or_default();
+                self.archive(
+                    &mut Cursor::new(sink.data),
+                    len,
+                    format,
+                    name,
+                    &format!("{}/", entry.path),
+                    depth + 1,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn zip<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        size: u64,
+        prefix: &str,
+        depth: u64,
+    ) -> io::Result<()> {
+        // End of central directory record, followed by a comment of up to
+        // 64 KiB
+        let tail_len = size.min(22 + 0xffff);
+        let tail = read_at(reader, size - tail_len, tail_len as usize)?;
+        let eocd = (0..=tail.len().checked_sub(22).ok_or_else(invalid)?)
+            .rev()
+            .find(|&i| tail[i..].starts_with(b"PK\x05\x06"))
+            .ok_or_else(invalid)?;
+        let record = &tail[eocd..];
+        let mut entries = u64::from(u16_at(record, 10)?);
+        let mut cd_size = u64::from(u32_at(record, 12)?);
+        let mut cd_offset = u64::from(u32_at(record, 16)?);
+        if entries == 0xffff || cd_size == 0xffff_ffff || cd_offset == 0xffff_ffff {
+            // Zip64 end of central directory locator, just before
+            let locator_offset = (size - tail_len + eocd as u64)
+                .checked_sub(20)
+                .ok_or_else(invalid)?;
+            let locator = read_at(reader, locator_offset, 20)?;
+            if !locator.starts_with(b"PK\x06\x07") {
+                return Err(invalid());
+            }
+            let record = read_at(reader, u64_at(&locator, 8)?, 56)?;
+            if !record.starts_with(b"PK\x06\x06") {
+                return Err(invalid());
+            }
+            entries = u64_at(&record, 32)?;
+            cd_size = u64_at(&record, 40)?;
+            cd_offset = u64_at(&record, 48)?;
+        }
+        if self.entries.saturating_add(entries) > self.limits.max_entries {
+            self.stop("too_many_entries", prefix.trim_end_matches('/'));
+            return Ok(());
+        }
+        if cd_size > MAX_CENTRAL_DIRECTORY_SIZE
+            || cd_offset.checked_add(cd_size).is_none_or(|end| end > size)
+        {
+            return Err(invalid());
+        }
+        let cd = read_at(reader, cd_offset, cd_size as usize)?;
+
+        let mut offset = 0;
+        for _ in 0..entries {
+            if self.stopped {
+                break;
+            }
+            if !cd
+                .get(offset..)
+                .is_some_and(|record| record.starts_with(b"PK\x01\x02"))
+            {
+                return Err(invalid());
+            }
+            let flags = u16_at(&cd, offset + 8)?;
+            let method = u16_at(&cd, offset + 10)?;
+            let mut compressed_size = u64::from(u32_at(&cd, offset + 20)?);
+            let mut member_size = u64::from(u32_at(&cd, offset + 24)?);
+            let name_len = usize::from(u16_at(&cd, offset + 28)?);
+            let extra_len = usize::from(u16_at(&cd, offset + 30)?);
+            let comment_len = usize::from(u16_at(&cd, offset + 32)?);
+            let mut local_offset = u64::from(u32_at(&cd, offset + 42)?);
+            let name_start = offset + 46;
+            let name = cd
+                .get(name_start..name_start + name_len)
+                .ok_or_else(invalid)?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            let extra = cd
+                .get(name_start + name_len..name_start + name_len + extra_len)
+                .ok_or_else(invalid)?;
+            offset = name_start + name_len + extra_len + comment_len;
+
+            // Zip64 extended information, only the fields set to the max in
+            // the record are present
+            let mut pos = 0;
+            while pos + 4 <= extra.len() {
+                let id = u16_at(extra, pos)?;
+                let len = usize::from(u16_at(extra, pos + 2)?);
+                let field = extra.get(pos + 4..pos + 4 + len).ok_or_else(invalid)?;
+                if id == 1 {
+                    let mut field_pos = 0;
+                    for value in [&mut member_size, &mut compressed_size, &mut local_offset] {
+                        if *value == 0xffff_ffff {
+                            *value = u64_at(field, field_pos)?;
+                            field_pos += 8;
+                        }
+                    }
+                }
+                pos += 4 + len;
+            }
+
+            if name.ends_with('/') {
+                continue;
+            }
+            let local = read_at(reader, local_offset, 30)?;
+            if !local.starts_with(b"PK\x03\x04") {
+                return Err(invalid());
+            }
+            let data_offset =
+                local_offset + 30 + u64::from(u16_at(&local, 26)?) + u64::from(u16_at(&local, 28)?);
+            if data_offset
+                .checked_add(compressed_size)
+                .is_none_or(|end| end > size)
+            {
+                return Err(invalid());
+            }
+            reader.seek(SeekFrom::Start(data_offset))?;
+            let entry = Entry {
+                path: format!("{prefix}{name}"),
+                method: match method {
+                    0 => Method::Stored,
+                    8 => Method::Deflate,
+                    _ => Method::Unsupported,
+                },
+                compressed_size: Some(compressed_size),
+                size: member_size,
+                // Traditional or strong encryption, or AES (method 99)
+                encrypted: flags & 0x1 != 0 || method == 99,
+            };
+            self.member(&mut (&mut *reader).take(compressed_size), entry, depth)?;
+        }
+        Ok(())
+    }
+
+    fn tar<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        size: u64,
+        prefix: &str,
+        depth: u64,
+    ) -> io::Result<()> {
+        let mut pos = 0;
+        let mut long_name = None;
+        while pos + 512 <= size && !self.stopped {
+            let block = read_at(reader, pos, 512)?;
+            pos += 512;
+            if block.iter().all(|b| *b == 0) {
+                break;
+            }
+            let checksum = tar_number(&block[148..156])?;
+            let sum: u64 = block
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        u64::from(*b)
+                    }
+                })
+                .sum();
+            if checksum != sum {
+                return Err(invalid());
+            }
+            let entry_size = tar_number(&block[124..136])?;
+            let data_end = pos
+                .checked_add(entry_size)
+                .filter(|end| *end <= size)
+                .ok_or_else(invalid)?;
+            match block[156] {
+                // GNU long name and pax extended header
+                b'L' | b'x' => {
+                    if entry_size > MAX_TAR_EXTENDED_HEADER_SIZE {
+                        return Err(invalid());
+                    }
+                    let data = read_at(reader, pos, entry_size as usize)?;
+                    long_name = match block[156] {
+                        b'L' => Some(cstr(&data)),
+                        _ => pax_path(&data)?,
+                    };
+                }
+                b'0' | b'\0' | b'7' => {
+                    let name = long_name.take().unwrap_or_else(|| tar_name(&block));
+                    let entry = Entry {
+                        path: format!("{prefix}{name}"),
+                        method: Method::Stored,
+                        compressed_size: Some(entry_size),
+                        size: entry_size,
+                        encrypted: false,
+                    };
+                    self.member(&mut (&mut *reader).take(entry_size), entry, depth)?;
+                }
+                _ => long_name = None,
+            }
+            pos = data_end.div_ceil(512) * 512;
+        }
+        Ok(())
+    }
+
+    fn gzip<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        size: u64,
+        name: &str,
+        prefix: &str,
+        depth: u64,
+    ) -> io::Result<()> {
+        let header = read_at(reader, 0, 10)?;
+        let flags = header[3];
+        let mut offset = 10;
+        // Extra field
+        if flags & 0x4 != 0 {
+            let len = read_at(reader, offset, 2)?;
+            offset += 2 + u64::from(u16_at(&len, 0)?);
+        }
+        let mut member_name = None;
+        // Original file name then comment, zero-terminated
+        for flag in [0x8, 0x10] {
+            if flags & flag != 0 {
+                let len = size.saturating_sub(offset).min(MAX_GZIP_FIELD_SIZE);
+                let field = read_at(reader, offset, len as usize)?;
+                let end = field.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+                offset += end as u64 + 1;
+                if flag == 0x8 {
+                    member_name = Some(cstr(&field));
+                }
+            }
+        }
+        // Header CRC
+        if flags & 0x2 != 0 {
+            offset += 2;
+        }
+        if offset > size {
+            return Err(invalid());
+        }
+        // Only keep the file name of the original path
+        let member_name = member_name
+            .and_then(|n| {
+                n.rsplit('/')
+                    .next()
+                    .filter(|n| !n.is_empty())
+                    .map(String::from)
+            })
+            .unwrap_or_else(|| gunzipped_name(name));
+        reader.seek(SeekFrom::Start(offset))?;
+        let entry = Entry {
+            path: format!("{prefix}{member_name}"),
+            method: Method::Deflate,
+            compressed_size: None,
+            size: 0,
+            encrypted: false,
+        };
+        self.member(&mut (&mut *reader).take(size - offset), entry, depth)
+    }
+}
+
+fn cstr(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn tar_name(block: &[u8]) -> String {
+    let name = cstr(&block[..100]);
+    if &block[257..262] == b"ustar" {
+        let prefix = cstr(&block[345..500]);
+        if !prefix.is_empty() {
+            return format!("{prefix}/{name}");
+        }
+    }
+    name
+}
+
+/// Octal, or base-256 for large values
+fn tar_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7f), |acc, b| {
+                acc.checked_mul(256)
+                    .map(|acc| acc + u64::from(*b))
+                    .ok_or_else(invalid)
+            });
+    }
+    field
+        .iter()
+        .filter(|b| !matches!(b, b' ' | b'\0'))
+        .try_fold(0u64, |acc, b| match b {
+            b'0'..=b'7' => acc
+                .checked_mul(8)
+                .map(|acc| acc + u64::from(b - b'0'))
+                .ok_or_else(invalid),
+            _ => Err(invalid()),
+        })
+}
+
+/// Records of pax extended headers are "<len> <key>=<value>\n"
+fn pax_path(data: &[u8]) -> io::Result<Option<String>> {
+    let mut path = None;
+    let mut pos = 0;
+    while pos < data.len() && data[pos] != 0 {
+        let space = data[pos..]
+            .iter()
+            .position(|b| *b == b' ')
+            .ok_or_else(invalid)?;
+        let len: usize = std::str::from_utf8(&data[pos..pos + space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|len| *len > space + 1)
+            .ok_or_else(invalid)?;
+        let end = pos.checked_add(len).ok_or_else(invalid)?;
+        let record = data.get(pos + space + 1..end).ok_or_else(invalid)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        pos = end;
+    }
+    Ok(path)
+}
+
+/// Name of the decompressed file when the gzip header doesn't have it
+fn gunzipped_name(name: &str) -> String {
+    let lower = name.to_ascii_lowercase();
+    if let Some(stem) = lower.strip_suffix(".tgz").map(|s| &name[..s.len()]) {
+        format!("{stem}.tar")
+    } else if let Some(stem) = lower.strip_suffix(".gz").map(|s| &name[..s.len()]) {
+        stem.into()
+    } else {
+        format!("{name}.out")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+
+    const LIMITS: Limits = Limits {
+        max_depth: 3,
+        max_entries: 100,
+        max_ratio: 100,
+        max_size: 1 << 30,
+        max_nested_size: 1 << 26,
+    };
+
+    // (name, data, method, flags) members of a zip archive
+    fn zip(entries: &[(&str, &[u8], u16, u16)]) -> Vec<u8> {
+        let (mut zip, mut cd) = (Vec::new(), Vec::new());
+        for (name, data, method, flags) in entries {
+            let compressed = match method {
+                8 => compress_to_vec(data, 6),
+                _ => data.to_vec(),
+            };
+            let sizes = [compressed.len() as u32, data.len() as u32];
+            let common: Vec<u8> = [
+                &flags.to_le_bytes()[..],
+                &method.to_le_bytes(),
+                &[0; 8],
+                &sizes[0].to_le_bytes(),
+                &sizes[1].to_le_bytes(),
+                &(name.len() as u16).to_le_bytes(),
+                &[0; 2],
+            ]
+            .concat();
+            cd.extend([&b"PK\x01\x02\x14\0\x14\0"[..], &common, &[0; 10]].concat());
+            cd.extend((zip.len() as u32).to_le_bytes());
+            cd.extend(name.as_bytes());
+            zip.extend(
+                [
+                    &b"PK\x03\x04\x14\0"[..],
+                    &common,
+                    name.as_bytes(),
+                    &compressed,
+                ]
+                .concat(),
+            );
+        }
+        let count = (entries.len() as u16).to_le_bytes();
+        let eocd = [
+            &b"PK\x05\x06\0\0\0\0"[..],
+            &count,
+            &count,
+            &(cd.len() as u32).to_le_bytes(),
+            &(zip.len() as u32).to_le_bytes(),
+            &[0; 2],
+        ]
+        .concat();
+        [zip, cd, eocd].concat()
+    }
+
+    fn tar_header(name: &str, size: usize, kind: u8) -> Vec<u8> {
+        let mut block = vec![0; 512];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        block[156] = kind;
+        block[257..262].copy_from_slice(b"ustar");
+        block[148..156].fill(b' ');
+        let sum: u64 = block.iter().map(|b| u64::from(*b)).sum();
+        block[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        block
+    }
+
+    // (name, type, data) entries of a tar archive
+    fn tar(entries: &[(&str, u8, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, kind, data) in entries {
+            tar.extend(tar_header(name, data.len(), *kind));
+            tar.extend(*data);
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        }
+        tar.extend([0; 1024]);
+        tar
+    }
+
+    fn gzip(name: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let flags = if name.is_some() { 0x8 } else { 0 };
+        let mut gzip = vec![0x1f, 0x8b, 0x08, flags, 0, 0, 0, 0, 0, 3];
+        if let Some(name) = name {
+            gzip.extend(name.as_bytes());
+            gzip.push(0);
+        }
+        gzip.extend(compress_to_vec(data, 6));
+        // CRC and size, not checked
+        gzip.extend([0; 8]);
+        gzip
+    }
+
+    fn run(data: &[u8], name: &str, limits: &Limits) -> Inspection {
+        inspect(&mut Cursor::new(data), data.len() as u64, name, limits).unwrap()
+    }
+
+    fn members(inspection: &Inspection) -> Vec<(&str, u64)> {
+        inspection
+            .members
+            .iter()
+            .map(|member| (member.path.as_str(), member.size))
+            .collect()
+    }
+
+    fn issues(inspection: &Inspection) -> Vec<(&str, &str)> {
+        inspection
+            .issues
+            .iter()
+            .map(|issue| (issue.kind, issue.path.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn zip_members() {
+        let text = b"some text ".repeat(100);
+        let data = zip(&[
+            ("dir/", b"", 0, 0),
+            ("dir/a.txt", b"hello", 0, 0),
+            ("dir/b.txt", &text, 8, 0),
+            ("c.bz2", b"BZh9", 12, 0),
+            ("secret.txt", b"xxxx", 0, 1),
+        ]);
+        let inspection = run(&data, "a.zip", &LIMITS);
+        assert_eq!(inspection.format, Some(Format::Zip));
+        assert_eq!(
+            members(&inspection),
+            vec![
+                ("dir/a.txt", 5),
+                ("dir/b.txt", 1000),
+                ("c.bz2", 4),
+                ("secret.txt", 4)
+            ]
+        );
+        assert_eq!(inspection.members[0].header, b"hello");
+        assert_eq!(inspection.members[1].header, text);
+        assert_eq!(
+            issues(&inspection),
+            vec![("not_inspected", "c.bz2"), ("encrypted", "secret.txt")]
+        );
+    }
+
+    #[test]
+    fn nested_archives() {
+        let inner = zip(&[("a.txt", b"hello", 8, 0)]);
+        let tar = tar(&[
+            ("docs/inner.zip", b'0', &inner),
+            ("docs/b.txt", b'0', b"world"),
+        ]);
+        let inspection = run(&gzip(None, &tar), "x.tgz", &LIMITS);
+        assert_eq!(inspection.format, Some(Format::Gzip));
+        assert_eq!(
+            members(&inspection),
+            vec![
+                ("x.tar", tar.len() as u64),
+                ("x.tar/docs/inner.zip", inner.len() as u64),
+                ("x.tar/docs/inner.zip/a.txt", 5),
+                ("x.tar/docs/b.txt", 5),
+            ]
+        );
+        assert!(inspection.issues.is_empty());
+
+        let limits = Limits {
+            max_depth: 2,
+            ..LIMITS
+        };
+        let inspection = run(&gzip(Some("y.tar"), &tar), "x.tgz", &limits);
+        assert_eq!(members(&inspection).len(), 3);
+        assert_eq!(
+            issues(&inspection),
+            vec![("too_deep", "y.tar/docs/inner.zip")]
+        );
+
+        // Nested archives are kept in memory up to max_nested_size bytes
+        let limits = Limits {
+            max_nested_size: 1024,
+            ..LIMITS
+        };
+        let inspection = run(&gzip(None, &tar), "x.tgz", &limits);
+        assert_eq!(members(&inspection), vec![("x.tar", tar.len() as u64)]);
+        assert_eq!(issues(&inspection), vec![("too_large", "x.tar")]);
+    }
+
+    #[test]
+    fn pax_names() {
+        let tar = tar(&[
+            ("PaxHeader", b'x', b"29 path=a/very/long/name.txt\n"),
+            ("a/very/lo", b'0', b"hello"),
+            ("other.txt", b'0', b"world"),
+        ]);
+        let inspection = run(&tar, "a.tar", &LIMITS);
+        assert_eq!(
+            members(&inspection),
+            vec![("a/very/long/name.txt", 5), ("other.txt", 5)]
+        );
+        assert_eq!(
+            pax_path(b"18446744073709551615 path=x\n")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let zip = zip(&[("a.txt", b"hello", 0, 0)]);
+        let tar = tar(&[("a.txt", b'0', &[1; 1000])]);
+        let gzip = gzip(Some("a.txt"), &[1; 1000]);
+        for (data, name) in [(&zip, "a.zip"), (&tar, "a.tar"), (&gzip, "a.gz")] {
+            let inspection = run(&data[..data.len() / 2], name, &LIMITS);
+            assert_eq!(issues(&inspection), vec![("corrupted", "")]);
+        }
+        // Corrupted nested archives don't stop the inspection of their parent
+        let outer = self::tar(&[("a.zip", b'0', &zip[..zip.len() - 1]), ("b", b'0', b"")]);
+        let inspection = run(&outer, "a.tar", &LIMITS);
+        assert_eq!(issues(&inspection), vec![("corrupted", "a.zip")]);
+        assert_eq!(
+            members(&inspection),
+            vec![("a.zip", zip.len() as u64 - 1), ("b", 0)]
+        );
+    }
+
+    #[test]
+    fn malicious() {
+        // Central directory beyond the end of the archive
+        let mut data = zip(&[("a.txt", b"hello", 0, 0)]);
+        let len = data.len();
+        data[len - 6..len - 2].copy_from_slice(&u32::MAX.to_le_bytes());
+        let inspection = run(&data, "a.zip", &LIMITS);
+        assert_eq!(issues(&inspection), vec![("corrupted", "")]);
+
+        // Bad tar checksum
+        let mut data = tar(&[("a.txt", b'0', b"hello")]);
+        data[0] = b'b';
+        assert_eq!(
+            issues(&run(&data, "a.tar", &LIMITS)),
+            vec![("corrupted", "")]
+        );
+
+        // Overflowing pax record length
+        let data = tar(&[
+            ("PaxHeader", b'x', b"18446744073709551615 path=x\n"),
+            ("a.txt", b'0', b"hello"),
+        ]);
+        assert_eq!(
+            issues(&run(&data, "a.tar", &LIMITS)),
+            vec![("corrupted", "")]
+        );
+
+        // Decompression bomb
+        let zeroes = vec![0; 2 << 20];
+        let data = zip(&[("zeroes", &zeroes, 8, 0)]);
+        let inspection = run(&data, "a.zip", &LIMITS);
+        assert_eq!(members(&inspection), vec![("zeroes", 2 << 20)]);
+        assert_eq!(issues(&inspection), vec![("compression_ratio", "zeroes")]);
+        let limits = Limits {
+            max_size: 1 << 20,
+            ..LIMITS
+        };
+        let inspection = run(&gzip(None, &zeroes), "zeroes.gz", &limits);
+        assert_eq!(
+            issues(&inspection),
+            vec![("too_large", "zeroes"), ("compression_ratio", "zeroes")]
+        );
+
+        let limits = Limits {
+            max_entries: 1,
+            ..LIMITS
+        };
+        let data = zip(&[("a", b"", 0, 0), ("b", b"", 0, 0)]);
+        let inspection = run(&data, "a.zip", &limits);
+        assert!(inspection.members.is_empty());
+        assert_eq!(issues(&inspection), vec![("too_many_entries", "")]);
+    }
+}
@@ -0,0 +1,247 @@
+//! usbsas's archive inspection process. Archives found in transfers (zip, tar,
+//! gzip) are parsed, recursively, to list their members for the report and
+//! the filter, and to detect encrypted archives and decompression bombs. The
+//! data of archives is requested from usbsas as needed.
+
+mod inspect;
+
+use inspect::Limits;
+use log::{debug, warn};
+use std::io::{self, Read, Seek, SeekFrom};
+use thiserror::Error;
+use usbsas_comm::{protoresponse, Comm};
+use usbsas_config::{conf_parse, conf_read};
+use usbsas_proto as proto;
+use usbsas_proto::archive::request::Msg;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("sandbox: {0}")]
+    Sandbox(#[from] usbsas_sandbox::Error),
+    #[error("Bad Request")]
+    BadRequest,
+    #[error("State error")]
+    State,
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Size of the blocks of archives requested from usbsas
+const READ_SIZE: u64 = 1024 * 1024;
+
+const DEFAULT_MAX_DEPTH: u64 = 3;
+const DEFAULT_MAX_ENTRIES: u64 = 10_000;
+const DEFAULT_MAX_RATIO: u64 = 100;
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+const DEFAULT_MAX_NESTED_SIZE: u64 = 64 * 1024 * 1024;
+
+protoresponse!(
+    CommArchive,
+    archive,
+    readdata = ReadData[ResponseReadData],
+    inspect = Inspect[ResponseInspect],
+    error = Error[ResponseError],
+    end = End[ResponseEnd]
+);
+
+/// Archive being inspected, read by blocks from usbsas
+struct Remote<'a> {
+    comm: &'a mut Comm<proto::archive::Request>,
+    size: u64,
+    pos: u64,
+    block: Vec<u8>,
+    block_offset: u64,
+}
+
+impl<'a> Remote<'a> {
+    fn new(comm: &'a mut Comm<proto::archive::Request>, size: u64) -> Self {
+        Remote {
+            comm,
+            size,
+            pos: 0,
+            block: Vec::new(),
+            block_offset: 0,
+        }
+    }
+
+    fn fetch(&mut self) -> io::Result<()> {
+        let size = (self.size - self.pos).min(READ_SIZE);
+        self.comm.readdata(proto::archive::ResponseReadData {
+            offset: self.pos,
+            size,
+        })?;
+        let req: proto::archive::Request = self.comm.recv()?;
+        match req.msg {
+            Some(Msg::Data(data)) if data.data.len() as u64 == size => {
+                self.block = data.data;
+                self.block_offset = self.pos;
+                Ok(())
+            }
+            _ => Err(io::Error::other("couldn't read archive")),
+        }
+    }
+}
+
+impl Read for Remote<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos < self.block_offset || self.pos >= self.block_offset + self.block.len() as u64 {
+            self.fetch()?;
+        }
+        let start = (self.pos - self.block_offset) as usize;
+        let len = buf.len().min(self.block.len() - start);
+        buf[..len].copy_from_slice(&self.block[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for Remote<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(off) => self.size.checked_add_signed(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+enum State {
+    Init(InitState),
+    Running(RunningState),
+    End,
+}
+
+impl State {
+    fn run(self, comm: &mut Comm<proto::archive::Request>) -> Result<Self> {
+        match self {
+            State::Init(s) => s.run(comm),
+            State::Running(s) => s.run(comm),
+            State::End => Err(Error::State),
+        }
+    }
+}
+
+struct InitState {
+    config_path: String,
+}
+
+struct RunningState {
+    limits: Limits,
+}
+
+impl InitState {
+    fn run(self, comm: &mut Comm<proto::archive::Request>) -> Result<State> {
+        let config_str = conf_read(&self.config_path)?;
+
+        usbsas_sandbox::archive::seccomp(comm.input_fd(), comm.output_fd())?;
+
+        let archives = conf_parse(&config_str)?.archives.unwrap_or_default();
+        Ok(State::Running(RunningState {
+            limits: Limits {
+                max_depth: archives.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+                max_entries: archives.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+                max_ratio: archives.max_ratio.unwrap_or(DEFAULT_MAX_RATIO),
+                max_size: archives.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                max_nested_size: archives.max_nested_size.unwrap_or(DEFAULT_MAX_NESTED_SIZE),
+            },
+        }))
+    }
+}
+
+impl RunningState {
+    fn run(self, comm: &mut Comm<proto::archive::Request>) -> Result<State> {
+        loop {
+            let req: proto::archive::Request = comm.recv()?;
+            match req.msg.ok_or(Error::BadRequest)? {
+                Msg::Inspect(req) => self.inspect(comm, req)?,
+                Msg::Data(_) => return Err(Error::BadRequest),
+                Msg::End(_) => {
+                    comm.end(proto::archive::ResponseEnd {})?;
+                    break;
+                }
+            }
+        }
+        Ok(State::End)
+    }
+
+    fn inspect(
+        &self,
+        comm: &mut Comm<proto::archive::Request>,
+        req: proto::archive::RequestInspect,
+    ) -> Result<()> {
+        debug!("inspect {}", req.path);
+        let name = req.path.rsplit('/').next().unwrap_or_default();
+        let inspection = match inspect::inspect(
+            &mut Remote::new(comm, req.size),
+            req.size,
+            name,
+            &self.limits,
+        ) {
+            Ok(inspection) => inspection,
+            Err(err) => {
+                warn!("couldn't inspect {}: {}", req.path, err);
+                comm.error(proto::archive::ResponseError {
+                    err: format!("{err}"),
+                })?;
+                return Ok(());
+            }
+        };
+        comm.inspect(proto::archive::ResponseInspect {
+            format: inspection
+                .format
+                .map(|format| format.name().into())
+                .unwrap_or_default(),
+            members: inspection
+                .members
+                .into_iter()
+                .map(|member| proto::archive::ArchiveMember {
+                    path: member.path,
+                    size: member.size,
+                    compressed_size: member.compressed_size,
+                    header: member.header,
+                })
+                .collect(),
+            issues: inspection
+                .issues
+                .into_iter()
+                .map(|issue| proto::archive::ArchiveIssue {
+                    kind: issue.kind.into(),
+                    path: issue.path,
+                })
+                .collect(),
+        })?;
+        Ok(())
+    }
+}
+
+pub struct Archive {
+    comm: Comm<proto::archive::Request>,
+    state: State,
+}
+
+impl Archive {
+    pub fn new(comm: Comm<proto::archive::Request>, config_path: String) -> Result<Self> {
+        Ok(Archive {
+            comm,
+            state: State::Init(InitState { config_path }),
+        })
+    }
+
+    pub fn main_loop(self) -> Result<()> {
+        let (mut comm, mut state) = (self.comm, self.state);
+        loop {
+            state = match state.run(&mut comm)? {
+                State::End => break,
+                state => state,
+            }
+        }
+        Ok(())
+    }
+}
@@ -16,6 +16,7 @@ var lang_en = {
     "cpsrverror": "Server error while transferring",
     "date": "Date",
     "destfsfmt": "Output device filesystem &nbsp;",
+    "destparttable": "Output device partition table &nbsp;",
     "devicetoosmall": "Error: destination device is too small",
     "erasewarn": "Device will be wiped, the operation is irreversible",
     "err-fetch-url": "Error fetching url",
@@ -40,6 +41,7 @@ var lang_en = {
     "filechoice": "Files choice",
     "filterav": "File filtered by antivirus : ",
     "filterf": "Filtered file : ",
+    "filterrule": "filtered by rule ",
     "fmt1desc": "Write a new filesystem on the device",
     "fmt1time": "duration: less than a minute",
     "fmt1title": "Quick format",
@@ -16,6 +16,7 @@ var lang_fr = {
     "cpsrverror": "Erreur serveur lors de la copie",
     "date": "Date",
     "destfsfmt": "Système de fichiers du périphérique destination &nbsp;",
+    "destparttable": "Table des partitions du périphérique destination &nbsp;",
     "devicetoosmall": "Erreur: le périphérique de destination est trop petit",
     "erasewarn": "Le périphérique sera effacé, l'opération est irréversible.",
     "err-fetch-url": "Erreur lors de la récupération de l'URL",
@@ -40,6 +41,7 @@ var lang_fr = {
     "filechoice": "Choix des fichiers à envoyer",
     "filterav": "Fichier filtré par l'antivirus : ",
     "filterf": "Fichier filtré : ",
+    "filterrule": "filtré par la règle ",
     "fmt1desc": "Écriture d'un nouveau système de fichier sur le périphérique",
     "fmt1time": "durée: moins d'une minute",
     "fmt1title": "Formatage rapide",
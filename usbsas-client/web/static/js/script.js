@@ -98,7 +98,7 @@ class Path {
 }
 
 class File {
-  constructor(path, size, ftype, timestamp) {
+  constructor(path, size, ftype, timestamp, symlink_target) {
     // Path instance
     this.path = path;
     // Size in bytes
@@ -106,9 +106,12 @@ class File {
     /* ftype:
      * 1: regular file
      * 2: directory
+     * 3: symlink
+     * 4-7: block / char device, fifo, socket
      */
     this.ftype = ftype;
     this.timestamp = timestamp;
+    this.symlink_target = symlink_target;
   }
 
   isDir() {
@@ -119,6 +122,10 @@ class File {
     return this.ftype == 1;
   }
 
+  isSymlink() {
+    return this.ftype == 3;
+  }
+
   static humanFileSize(bytes) {
     // Inspired from http://stackoverflow.com/questions/10420352/converting-file-size-in-bytes-to-human-readable
     var thresh = 1024;
@@ -335,6 +342,32 @@ function clear_error() {
   error.innerText = "";
 }
 
+function filtered_text(filtered) {
+  if (filtered.rule) {
+    let text = filtered.path + " (" + langDocument["filterrule"] + filtered.rule;
+    if (filtered.description) {
+      text += ": " + filtered.description;
+    }
+    return text + ")";
+  }
+  return filtered.path;
+}
+
+// Paths of files reported dirty by the remote analyzer or the yara rules
+function dirty_files(report) {
+  let dirty = [];
+  for (let analysis of [report.analyzer_report, report.yara_report]) {
+    if (analysis && analysis.files) {
+      for (let path in analysis.files) {
+        if (analysis.files[path].status === "DIRTY") {
+          dirty.push(path);
+        }
+      }
+    }
+  }
+  return dirty;
+}
+
 function throw_error(error_text) {
   console.error(error_text);
   set_error(error_text);
@@ -468,7 +501,7 @@ function update() {
         let val = obj[1];
         let key = val.path;
         let path = new Path(val.path, val.path_display, cur_path);
-        let file = new File(path, val.size, val.ftype, val.timestamp);
+        let file = new File(path, val.size, val.ftype, val.timestamp, val.symlink_target);
         fs.add_file(file);
 
         var cur_tr = document.createElement("tr");
@@ -506,7 +539,7 @@ function update() {
         let td = document.createElement("td");
         var i = document.createElement("i");
         i.classList.add("fas");
-        i.classList.add("fa-" + (file.isDir() ? "folder-open" : "file"));
+        i.classList.add("fa-" + (file.isDir() ? "folder-open" : file.isSymlink() ? "link" : "file"));
         i.innerText = "\u00a0";
         td.appendChild(i);
 
@@ -523,6 +556,10 @@ function update() {
         } else {
           var span = document.createElement("span");
           span.innerText = file.path_display.replace(cur_path.path_display, "").replace("/", "");
+          if (file.isSymlink()) {
+            span.innerText += " \u2192 " + file.symlink_target;
+            span.classList.add("text-muted");
+          }
           td.appendChild(span);
         }
 
@@ -706,7 +743,7 @@ function do_copy() {
           tbody.appendChild(nothing_tr);
           document.querySelector("#cancel-button").classList.remove("d-none");
           document.querySelector("#cancel-button").innerText = langDocument["return"];
-          for (let filtered_path of json.report.filtered_files) {
+          for (let filtered of json.report.filter_results) {
             // Display filtered elements
             has_error = true;
             let tr_err = document.createElement("tr");
@@ -723,36 +760,34 @@ function do_copy() {
             p_err.classList.add("text-danger");
             p_err.innerHTML = "<strong data-langkey=\"filterf\">" + langDocument["filterf"] + "</strong>";
             let span_err = document.createElement("span");
-            span_err.innerText = filtered_path;
+            span_err.innerText = filtered_text(filtered);
             p_err.appendChild(span_err);
             name_td_err.appendChild(p_err);
             tr_err.appendChild(name_td_err);
             tbody.appendChild(tr_err);
           }
-          for (let path in json.report.analyzer_report.files) {
-            if (json.report.analyzer_report.files[path].status === "DIRTY") {
-              // Display dirty elements
-              has_error = true;
-              let tr_err = document.createElement("tr");
-              let status_td_err = document.createElement("td");
-              status_td_err.innerHTML = "&nbsp;";
-              let format_status_icon_err = document.createElement("i");
-              format_status_icon_err.classList.add("fas");
-              format_status_icon_err.classList.add("fa-times");
-              format_status_icon_err.classList.add("text-danger");
-              status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
-              tr_err.appendChild(status_td_err);
-              let name_td_err = document.createElement("td");
-              let p_err = document.createElement("p");
-              p_err.classList.add("text-danger");
-              p_err.innerHTML = "<strong data-langkey=\"filterav\">" + langDocument["filterav"] + "</strong>";
-              let span_err = document.createElement("span");
-              span_err.innerText = path;
-              p_err.appendChild(span_err);
-              name_td_err.appendChild(p_err);
-              tr_err.appendChild(name_td_err);
-              tbody.appendChild(tr_err);
-            }
+          for (let path of dirty_files(json.report)) {
+            // Display dirty elements
+            has_error = true;
+            let tr_err = document.createElement("tr");
+            let status_td_err = document.createElement("td");
+            status_td_err.innerHTML = "&nbsp;";
+            let format_status_icon_err = document.createElement("i");
+            format_status_icon_err.classList.add("fas");
+            format_status_icon_err.classList.add("fa-times");
+            format_status_icon_err.classList.add("text-danger");
+            status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
+            tr_err.appendChild(status_td_err);
+            let name_td_err = document.createElement("td");
+            let p_err = document.createElement("p");
+            p_err.classList.add("text-danger");
+            p_err.innerHTML = "<strong data-langkey=\"filterav\">" + langDocument["filterav"] + "</strong>";
+            let span_err = document.createElement("span");
+            span_err.innerText = path;
+            p_err.appendChild(span_err);
+            name_td_err.appendChild(p_err);
+            tr_err.appendChild(name_td_err);
+            tbody.appendChild(tr_err);
           }
           set_state("WAIT_REMOVAL");
           break;
@@ -803,7 +838,7 @@ function do_copy() {
             tr_err.appendChild(name_td_err);
             tbody.appendChild(tr_err);
           }
-          for (let filtered_path of json.report.filtered_files) {
+          for (let filtered of json.report.filter_results) {
             // Display failed elements
             has_error = true;
             let tr_err = document.createElement("tr");
@@ -821,37 +856,35 @@ function do_copy() {
             p_err.classList.add("text-danger");
             p_err.innerHTML = "<strong data-langkey=\"filterf\">" + langDocument["filterf"] + "</strong>";
             let span_err = document.createElement("span");
-            span_err.innerText = filtered_path;
+            span_err.innerText = filtered_text(filtered);
             p_err.appendChild(span_err);
             name_td_err.appendChild(p_err);
             tr_err.appendChild(name_td_err);
             tbody.appendChild(tr_err);
           }
-          for (let path in json.report.analyzer_report.files) {
-            if (json.report.analyzer_report.files[path].status === "DIRTY") {
-               // Display dirty elements
-               has_error = true;
-               let tr_err = document.createElement("tr");
-               let status_td_err = document.createElement("td");
-               status_td_err.innerHTML = "&nbsp;";
-               let format_status_icon_err = document.createElement("i");
-               format_status_icon_err.classList.add("fas");
-               format_status_icon_err.classList.add("fa-times");
-               format_status_icon_err.classList.add("text-danger");
-               status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
-               tr_err.appendChild(status_td_err);
-
-               let name_td_err = document.createElement("td");
-               let p_err = document.createElement("p");
-               p_err.classList.add("text-danger");
-               p_err.innerHTML = "<strong data-langkey=\"filterav\">" + langDocument["filterav"] + "</strong>";
-               let span_err = document.createElement("span");
-               span_err.innerText = path;
-               p_err.appendChild(span_err);
-               name_td_err.appendChild(p_err);
-               tr_err.appendChild(name_td_err);
-               tbody.appendChild(tr_err);
-            }
+          for (let path of dirty_files(json.report)) {
+            // Display dirty elements
+            has_error = true;
+            let tr_err = document.createElement("tr");
+            let status_td_err = document.createElement("td");
+            status_td_err.innerHTML = "&nbsp;";
+            let format_status_icon_err = document.createElement("i");
+            format_status_icon_err.classList.add("fas");
+            format_status_icon_err.classList.add("fa-times");
+            format_status_icon_err.classList.add("text-danger");
+            status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
+            tr_err.appendChild(status_td_err);
+
+            let name_td_err = document.createElement("td");
+            let p_err = document.createElement("p");
+            p_err.classList.add("text-danger");
+            p_err.innerHTML = "<strong data-langkey=\"filterav\">" + langDocument["filterav"] + "</strong>";
+            let span_err = document.createElement("span");
+            span_err.innerText = path;
+            p_err.appendChild(span_err);
+            name_td_err.appendChild(p_err);
+            tr_err.appendChild(name_td_err);
+            tbody.appendChild(tr_err);
           }
           break;
         case "fatal_error":
@@ -897,6 +930,8 @@ function do_copy() {
   var fsfmt = document.querySelector("#fsfmt");
   var post_body = selected.toJSON();
   post_body.fsfmt = fsfmt.options[fsfmt.selectedIndex].value;
+  var parttable = document.querySelector("#parttable");
+  post_body.part_table = parttable.options[parttable.selectedIndex].value;
   var pin_input = document.querySelector("#pin-display").getAttribute("value");
   if (pin_input != "") {
     post_body.download_pin = pin_input;
@@ -1009,7 +1044,11 @@ function partition_choice() {
         cell = document.createElement('td');
         cell.classList.add("col-2");
         row.appendChild(cell);
-        if ( partition.ptype == 0 ) {
+        if ( ["EFI_SYSTEM", "MS_RESERVED", "MS_RECOVERY"].includes(partition.kind) ) {
+          cell.innerText = "[system]";
+          row.classList.add("text-muted");
+          row.style.pointerEvents = "none";
+        } else if ( partition.ptype == 0 ) {
           cell.innerText = "[unsupported]";
           row.classList.add("table-danger");
           row.style.pointerEvents = "none";
@@ -1446,8 +1485,10 @@ function do_wipe_key() {
 
   var fschoice = document.querySelector("#fsfmt");
   var fsfmt = fschoice.options[fschoice.selectedIndex].value;
+  var tablechoice = document.querySelector("#parttable");
+  var parttable = tablechoice.options[tablechoice.selectedIndex].value;
 
-  fetch(API + "/wipe" + "/" + devices.device_in.id + "/" + fsfmt + "/" + quick, {
+  fetch(API + "/wipe" + "/" + devices.device_in.id + "/" + fsfmt + "/" + quick + "?part_table=" + parttable, {
     method: "GET",
     headers: {
       Accept: "application/json",
@@ -1672,6 +1713,9 @@ function updateFsFmtDetails() {
     case 'fat32':
       updateElementLang(details, "warng4gb");
       break;
+    case 'ext4':
+      details.innerHTML = "";
+      break;
   }
 }
 
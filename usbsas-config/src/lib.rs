@@ -30,9 +30,21 @@ pub struct PostCopy {
 
 #[derive(Debug, Deserialize)]
 pub struct Filter {
+    /// Name reported for the files it filters
+    pub id: Option<String>,
+    pub description: Option<String>,
     pub contain: Option<Vec<String>>,
     pub start: Option<String>,
     pub end: Option<String>,
+    /// Regular expression matching the whole path
+    pub regex: Option<String>,
+    /// Shell-style pattern matching the whole path
+    pub glob: Option<String>,
+    pub case_sensitive: Option<bool>,
+    /// Types detected from the content of files
+    pub file_type: Option<Vec<String>>,
+    /// Whether the detected type isn't one expected for the extension
+    pub type_mismatch: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,25 +54,175 @@ pub struct PathFilter {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Analyzer {
-    pub url: String,
+    /// Remote analyzer server, unused if clamd_socket is set
+    pub url: Option<String>,
     pub krb_service_name: Option<String>,
+    /// Unix socket of a local clamd, files are then scanned by it directly
+    pub clamd_socket: Option<String>,
+    /// Larger files aren't scanned by clamd and aren't copied, it must not
+    /// exceed clamd's StreamMaxLength
+    pub clamd_max_file_size: Option<u64>,
     pub analyze_usb: bool,
     pub analyze_net: bool,
     pub analyze_cmd: bool,
 }
 
+/// Local scan of files with YARA rules
+#[derive(Clone, Debug, Deserialize)]
+pub struct Yara {
+    /// Directory of the rule files (".yar" or ".yara")
+    pub rules_dir: String,
+    /// Hex encoded ed25519 key, rule files must then be signed by it
+    pub public_key: Option<String>,
+    /// Larger files aren't scanned and aren't copied
+    pub max_file_size: Option<u64>,
+    /// Timeout of the scan of a file in seconds
+    pub timeout: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UsbPortAccesses {
     pub ports_src: Vec<Vec<u8>>,
     pub ports_dst: Vec<Vec<u8>>,
 }
 
+/// Limits of a transfer from a device, unlimited if unset
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Limits {
+    pub max_files: Option<u64>,
+    pub max_total_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+    /// Number of components of paths
+    pub max_depth: Option<u64>,
+    pub max_path_bytes: Option<u64>,
+    pub max_dir_entries: Option<u64>,
+}
+
+/// Inspection of archives found in transfers and limits bounding it
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Archives {
+    pub policy: Option<ArchivePolicy>,
+    /// Levels of nested archives inspected
+    pub max_depth: Option<u64>,
+    pub max_entries: Option<u64>,
+    /// Ratio between the decompressed and the compressed size of members
+    pub max_ratio: Option<u64>,
+    /// Decompressed bytes inspected per archive
+    pub max_size: Option<u64>,
+    /// Size of a nested archive, kept in memory to be inspected
+    pub max_nested_size: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Report {
     pub write_dest: bool,
     pub write_local: Option<String>,
 }
 
+/// What to do with symlinks found on the source device
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Don't copy them, they are listed as skipped in the report
+    #[default]
+    Skip,
+    /// Copy the content of their target if it is a file of the same partition
+    Follow,
+    /// Don't copy them but record them with their target in the report
+    Report,
+}
+
+/// What to do with NTFS alternate data streams found on the source device
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamPolicy {
+    /// Ignore them
+    Drop,
+    /// Don't copy them but list them in the report
+    #[default]
+    Report,
+    /// List them in the report and copy them as "<file>.<stream>.ads" files
+    Copy,
+}
+
+/// Names differing only by case on a case-insensitive destination file system
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Add a " (n)" suffix to the name and list it in the report
+    #[default]
+    Rename,
+    /// Don't copy the file, it is listed as skipped in the report
+    Skip,
+    /// Abort the transfer
+    Abort,
+}
+
+/// Files larger than 4 GiB when the destination file system is FAT32
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LargeFilePolicy {
+    /// Abort the transfer before copying, listing the files
+    Reject,
+    /// Don't copy them, they are listed as errors in the report
+    #[default]
+    Skip,
+    /// Split them in numbered parts with scripts to join them
+    Split,
+}
+
+/// Consistency check of the source file system before it is opened
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsCheckPolicy {
+    /// Don't check
+    #[default]
+    Off,
+    /// List anomalies found in the report
+    Report,
+    /// List anomalies found in the report and abort the transfer if any
+    Block,
+}
+
+/// Checks of names for characters and patterns misleading users (bidi and
+/// invisible characters, mixed scripts, padded extensions)
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NameCheckPolicy {
+    /// Don't check
+    #[default]
+    Off,
+    /// List unsafe names in the report
+    Report,
+    /// List unsafe names in the report and don't copy them
+    Block,
+}
+
+/// What to do with archives whose members are filtered or which are
+/// encrypted, corrupted or exceed the inspection limits
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchivePolicy {
+    /// Don't inspect archives
+    #[default]
+    Off,
+    /// List archive members and issues found in the report
+    Report,
+    /// List them in the report and don't copy the archive if it has issues
+    Block,
+}
+
+/// Whether files must match an allow filter to be copied
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    /// Copy files unless a filter matches
+    #[default]
+    Denylist,
+    /// Copy files matching an allow filter unless a filter matches
+    Allowlist,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub out_directory: String,
@@ -71,9 +233,20 @@ pub struct Config {
     pub networks: Option<Vec<Network>>,
     pub source_network: Option<Network>,
     pub filters: Option<Vec<Filter>>,
+    pub filter_mode: Option<FilterMode>,
+    pub allow_filters: Option<Vec<Filter>>,
+    pub name_checks: Option<NameCheckPolicy>,
     pub post_copy: Option<PostCopy>,
     pub analyzer: Option<Analyzer>,
+    pub yara: Option<Yara>,
     pub usb_port_accesses: Option<UsbPortAccesses>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub data_streams: Option<StreamPolicy>,
+    pub fs_check: Option<FsCheckPolicy>,
+    pub name_collisions: Option<CollisionPolicy>,
+    pub large_files: Option<LargeFilePolicy>,
+    pub limits: Option<Limits>,
+    pub archives: Option<Archives>,
 }
 
 pub fn conf_read(config_path: &str) -> io::Result<String> {
@@ -82,10 +255,22 @@ pub fn conf_read(config_path: &str) -> io::Result<String> {
 }
 
 pub fn conf_parse(conf_str: &str) -> io::Result<Config> {
-    toml::from_str(conf_str).map_err(|err| {
+    let config: Config = toml::from_str(conf_str).map_err(|err| {
         io::Error::new(
             io::ErrorKind::InvalidData,
             format!("Couldn't parse config: {err}"),
         )
-    })
+    })?;
+    // 0 would mean unlimited when sent to the processes listing directories
+    if let Some(Limits {
+        max_dir_entries: Some(0),
+        ..
+    }) = config.limits
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Couldn't parse config: max_dir_entries must be greater than 0",
+        ));
+    }
+    Ok(config)
 }
@@ -8,7 +8,10 @@ use std::{convert::TryFrom, io::prelude::*, str};
 use thiserror::Error;
 use usbsas_comm::{protoresponse, Comm};
 use usbsas_proto as proto;
-use usbsas_proto::{common::PartitionInfo, scsi::request::Msg};
+use usbsas_proto::{
+    common::{PartitionInfo, PartitionKind},
+    scsi::request::Msg,
+};
 #[cfg(not(feature = "mock"))]
 use {
     std::os::unix::io::AsRawFd,
@@ -54,9 +57,105 @@ protoresponse!(
     error = Error[ResponseError]
 );
 
-// Max we need to read for ext4 check (other fs need less) and iso9660
+// Max we need to read for ext4 check (other fs need less) and iso9660 / udf
 const MAX_LEN_PART_HEADER: u64 = 0x464;
 const MAX_LEN_ISO_HEADER: u64 = 0x8806;
+// Volume recognition sequence descriptors checked for UDF
+const MAX_VRS_DESCRIPTORS: usize = 8;
+
+// GPT partition type GUIDs
+const GPT_TYPES: &[(&str, PartitionKind, &str)] = &[
+    (
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        PartitionKind::BasicData,
+        "Microsoft basic data",
+    ),
+    (
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+        PartitionKind::LinuxFs,
+        "Linux filesystem",
+    ),
+    (
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+        PartitionKind::EfiSystem,
+        "EFI System",
+    ),
+    (
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE",
+        PartitionKind::MsReserved,
+        "Microsoft reserved",
+    ),
+    (
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC",
+        PartitionKind::MsRecovery,
+        "Windows recovery",
+    ),
+    (
+        "48465300-0000-11AA-AA11-00306543ECAC",
+        PartitionKind::AppleHfs,
+        "Apple HFS+",
+    ),
+    (
+        "7C3457EF-0000-11AA-AA11-00306543ECAC",
+        PartitionKind::AppleApfs,
+        "Apple APFS",
+    ),
+    (
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928",
+        PartitionKind::LinuxLvm,
+        "Linux LVM",
+    ),
+    (
+        "CA7D7CCB-63ED-4C53-861C-1742536059CC",
+        PartitionKind::Luks,
+        "LUKS",
+    ),
+];
+
+/// Format a GPT GUID (stored mixed-endian on disk) in its canonical form
+fn gpt_guid_to_string(guid: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        LittleEndian::read_u32(&guid[0..4]),
+        LittleEndian::read_u16(&guid[4..6]),
+        LittleEndian::read_u16(&guid[6..8]),
+        guid[8],
+        guid[9],
+        guid[10],
+        guid[11],
+        guid[12],
+        guid[13],
+        guid[14],
+        guid[15]
+    )
+}
+
+fn mbr_ptype(type_code: u8) -> u32 {
+    match type_code {
+        0x1 | // FAT12
+        0x4 | // FAT16 <32M
+        0x6 | // FAT16
+        0x7 | // NTFS / EXFAT
+        0xb | // W95 FAT32
+        0xc | // W95 FAT32 (LBA)
+        0xe | // W95 FAT16 (LBA)
+        0x83 | // Linux
+        0xaf  // Apple HFS/HFS+
+            => u32::from(type_code),
+        _ => {
+            warn!("Unsupported partition type: {}", type_code);
+            0
+        }
+    }
+}
+
+fn gpt_kind(type_guid: &str) -> (PartitionKind, &'static str) {
+    GPT_TYPES
+        .iter()
+        .find(|(guid, _, _)| *guid == type_guid)
+        .map(|(_, kind, descr)| (*kind, *descr))
+        .unwrap_or((PartitionKind::UnknownKind, "Unknown"))
+}
 
 enum State {
     Init(InitState),
@@ -152,6 +251,12 @@ impl InitState {
         comm.opendev(proto::scsi::ResponseOpenDevice {
             block_size: u64::from(usb_mass_storage.block_size),
             dev_size: usb_mass_storage.dev_size,
+            lun: u32::from(usb_mass_storage.lun),
+            luns: usb_mass_storage
+                .luns
+                .iter()
+                .map(|lun| u32::from(*lun))
+                .collect(),
         })?;
 
         Ok(State::DevOpened(DevOpenedState { usb_mass_storage }))
@@ -172,6 +277,25 @@ impl DevOpenedState {
                         })?;
                     }
                 },
+                Msg::OpenDevice(req) => match self.select_lun(req.lun) {
+                    Ok(_) => comm.opendev(proto::scsi::ResponseOpenDevice {
+                        block_size: u64::from(self.usb_mass_storage.block_size),
+                        dev_size: self.usb_mass_storage.dev_size,
+                        lun: u32::from(self.usb_mass_storage.lun),
+                        luns: self
+                            .usb_mass_storage
+                            .luns
+                            .iter()
+                            .map(|lun| u32::from(*lun))
+                            .collect(),
+                    })?,
+                    Err(err) => {
+                        error!("{}", err);
+                        comm.error(proto::scsi::ResponseError {
+                            err: format!("{err}"),
+                        })?;
+                    }
+                },
                 Msg::ReadSectors(req) => {
                     match self.usb_mass_storage.read_sectors(
                         req.offset,
@@ -191,10 +315,6 @@ impl DevOpenedState {
                     comm.end(proto::scsi::ResponseEnd {})?;
                     return Ok(State::End);
                 }
-                _ => {
-                    error!("Unexpected req");
-                    continue;
-                }
             }
         }
         Ok(State::PartitionsListed(PartitionsListedState {
@@ -202,6 +322,12 @@ impl DevOpenedState {
         }))
     }
 
+    fn select_lun(&mut self, lun: u32) -> Result<()> {
+        trace!("req select lun {}", lun);
+        self.usb_mass_storage.select_lun(u8::try_from(lun)?)?;
+        Ok(())
+    }
+
     fn partitions(&mut self, comm: &mut Comm<proto::scsi::Request>) -> Result<()> {
         trace!("req partitions");
         let mut partitions = vec![];
@@ -221,38 +347,53 @@ impl DevOpenedState {
                         return Err(Error::Partition("part len % block_size != 0".to_string()));
                     }
                     match &part.attributes {
-                        bootsector::Attributes::GPT { name, .. } => {
+                        bootsector::Attributes::GPT {
+                            type_uuid, name, ..
+                        } => {
+                            let type_guid = gpt_guid_to_string(type_uuid);
+                            let (kind, type_str) = gpt_kind(&type_guid);
                             partitions.push(PartitionInfo {
                                 ptype: 0,
                                 start: part.first_byte / block_size,
                                 size: part.len,
                                 name_str: name.clone(),
-                                type_str: "Unknown".into(),
+                                type_str: type_str.into(),
+                                kind: kind.into(),
+                                type_guid,
                             });
                         }
-                        bootsector::Attributes::MBR { type_code, .. } => {
-                            let ptype = match type_code {
-                                0x1 | // FAT12
-                                0x4 | // FAT16 <32M
-                                0x6 | // FAT16
-                                0x7 | // NTFS / EXFAT
-                                0xb | // W95 FAT32
-                                0xc | // W95 FAT32 (LBA)
-                                0xe | // W95 FAT16 (LBA)
-                                0xf | // W95 Ext'd (LBA)
-                                0x83  // Linux
-                                    => *type_code as u32,
-                                _ => {
-                                    warn!("Unsupported partition type: {}", type_code);
-                                    0
+                        bootsector::Attributes::MBR { type_code, .. }
+                            if usbsas_mbr::is_extended(*type_code) =>
+                        {
+                            // Walk the EBR chain and list logical partitions
+                            match usbsas_mbr::parse_extended_partitions(
+                                &mut self.usb_mass_storage,
+                                block_size,
+                                part.first_byte / block_size,
+                            ) {
+                                Ok(logical_parts) => {
+                                    for logical in logical_parts {
+                                        partitions.push(PartitionInfo {
+                                            ptype: mbr_ptype(logical.partition_type),
+                                            start: u64::from(logical.start_in_lba),
+                                            size: u64::from(logical.size_in_lba) * block_size,
+                                            name_str: "Unknown".into(),
+                                            type_str: "Unknown".into(),
+                                            ..Default::default()
+                                        });
+                                    }
                                 }
-                            };
+                                Err(err) => warn!("error reading extended partition: {}", err),
+                            }
+                        }
+                        bootsector::Attributes::MBR { type_code, .. } => {
                             partitions.push(PartitionInfo {
-                                ptype,
+                                ptype: mbr_ptype(*type_code),
                                 start: part.first_byte / block_size,
                                 size: part.len,
                                 name_str: "Unknown".into(),
                                 type_str: "Unknown".into(),
+                                ..Default::default()
                             });
                         }
                     }
@@ -272,6 +413,7 @@ impl DevOpenedState {
                 size: self.usb_mass_storage.dev_size,
                 name_str: "Unknown".into(),
                 type_str: "Unknown".into(),
+                ..Default::default()
             });
         }
 
@@ -282,6 +424,13 @@ impl DevOpenedState {
         }
         // Try to find name and also fs type if no bootsector was found
         for part in partitions.iter_mut() {
+            // Don't expose system partitions as sources, they're hidden by the client
+            if matches!(
+                part.kind(),
+                PartitionKind::EfiSystem | PartitionKind::MsReserved | PartitionKind::MsRecovery
+            ) {
+                continue;
+            }
             let data = self.usb_mass_storage.read_sectors(
                 part.start,
                 sectors_to_read,
@@ -342,6 +491,21 @@ impl DevOpenedState {
                     part.ptype = 0;
                 }
             }
+            // HFS+ / HFSX
+            else if data[0x400..0x402] == *b"H+" || data[0x400..0x402] == *b"HX" {
+                part.type_str = "HFS+".into();
+                if part.ptype == 0 {
+                    part.ptype = 0xaf;
+                }
+            }
+            // Encrypted volumes (unsupported)
+            else if let Ok("-FVE-FS-") = str::from_utf8(data[0x3..0xb].into()) {
+                part.type_str = "BitLocker".into();
+                part.kind = PartitionKind::Bitlocker.into();
+            } else if data[0x0..0x6] == *b"LUKS\xba\xbe" {
+                part.type_str = "LUKS".into();
+                part.kind = PartitionKind::Luks.into();
+            }
             // Trim 0 and leading / trailing whitespaces
             part.name_str = part.name_str.trim_end_matches(char::from(0)).trim().into();
             if part.name_str.is_empty() {
@@ -349,10 +513,11 @@ impl DevOpenedState {
             }
         }
 
-        // If we didn't find anything supported, last try with ISO9660 which requires different
-        // sectors to read
+        // If we didn't find anything supported, last try with UDF and ISO9660 which require
+        // different sectors to read
         if partitions.len() == 1 && partitions[0].ptype == 0 {
-            // Check for 'CD001' at 0x8001 and 0x8801
+            // Check for UDF 'NSR02' or 'NSR03' in the volume recognition sequence, then for
+            // 'CD001' at 0x8001 and 0x8801
             sectors_to_read = MAX_LEN_ISO_HEADER / block_size;
             if MAX_LEN_ISO_HEADER.rem_euclid(block_size) > 0 {
                 sectors_to_read += 1;
@@ -362,7 +527,18 @@ impl DevOpenedState {
                 sectors_to_read,
                 block_size as usize,
             )?;
-            if [0x43, 0x44, 0x30, 0x30, 0x31] == data[0x1..0x6]
+            // VRS descriptors are 2048 bytes long but start on a block boundary
+            let vrs_step = block_size.max(0x800) as usize;
+            let is_udf = (0..MAX_VRS_DESCRIPTORS)
+                .map(|i| i * vrs_step + 1)
+                .take_while(|off| off + 5 <= data.len())
+                .any(|off| &data[off..off + 5] == b"NSR02" || &data[off..off + 5] == b"NSR03");
+            // UDF bridge discs also have an ISO9660 descriptor, prefer UDF
+            if is_udf {
+                partitions[0].type_str = "UDF".into();
+                // same as ISO9660, there isn't any ptype for udf
+                partitions[0].ptype = 0xFF;
+            } else if [0x43, 0x44, 0x30, 0x30, 0x31] == data[0x1..0x6]
                 || [0x43, 0x44, 0x30, 0x30, 0x31] == data[0x801..0x806]
             {
                 partitions[0].type_str = "ISO9660".into();
@@ -1,26 +1,27 @@
 //! files2fs writes files in a new filesystem with partition table on disk (not
 //! on the destination USB device directly, that's fs2dev's job). Supported file
-//! systems are `FAT`, `exFAT` and `NTFS`. The size of the created file system
-//! is the size of the destination USB device. When writing the file system,
-//! files2fs will keep track of the (non empty) sectors actually written in a
-//! bit vector, fs2dev will use this bit vector to avoid writing the whole file
-//! system on the destination device.
+//! systems are `FAT`, `exFAT`, `NTFS` and `ext4`, supported partition tables
+//! are `MBR` and `GPT`. The size of the created file system is the size of the
+//! destination USB device. When writing the file system, files2fs will keep
+//! track of the (non empty) sectors actually written in a bit vector, fs2dev
+//! will use this bit vector to avoid writing the whole file system on the
+//! destination device.
 
 use fscommon::StreamSlice;
 use log::{debug, error, trace, warn};
 use std::{
     convert::TryFrom,
     fs::{self, File},
-    io::{Seek, SeekFrom, Write},
+    io::{Read, Seek, SeekFrom, Write},
     os::unix::io::AsRawFd,
 };
 use thiserror::Error;
 use usbsas_comm::{protoresponse, Comm};
-use usbsas_fsrw::{ff, ntfs, FSWrite};
-use usbsas_mbr::SECTOR_START;
+use usbsas_fsrw::{ext4fs, ff, ntfs, FSWrite};
+use usbsas_mbr::{gpt, SECTOR_START};
 use usbsas_proto as proto;
 use usbsas_proto::{
-    common::{FileType, OutFsType},
+    common::{FileType, OutFsType, PartitionTable, Timestamps},
     writefs::request::Msg,
 };
 use usbsas_utils::SECTOR_SIZE;
@@ -96,6 +97,8 @@ struct InitState {
 
 struct WaitFsInfosState {
     fs: File,
+    // Random bytes for GPT disk and partition GUIDs and ext4 UUID
+    guids: [u8; 48],
 }
 
 struct WaitNewFileState {
@@ -105,7 +108,7 @@ struct WaitNewFileState {
 struct WritingFileState {
     fs: Box<dyn FSWrite<StreamSlice<SparseFile<File>>>>,
     path: String,
-    timestamp: i64,
+    times: Timestamps,
 }
 
 struct ImgDiskState {
@@ -124,8 +127,10 @@ impl InitState {
             .read(true)
             .write(true)
             .open(self.fs_fname)?;
+        let mut guids = [0u8; 48];
+        File::open("/dev/urandom")?.read_exact(&mut guids)?;
         usbsas_sandbox::files2fs::seccomp(comm.input_fd(), comm.output_fd(), fs.as_raw_fd())?;
-        Ok(State::WaitFsInfos(WaitFsInfosState { fs }))
+        Ok(State::WaitFsInfos(WaitFsInfosState { fs, guids }))
     }
 }
 
@@ -134,15 +139,17 @@ impl WaitFsInfosState {
         trace!("wait fs infos");
         let req: proto::writefs::Request = comm.recv()?;
         let newstate = match req.msg.ok_or(Error::BadRequest)? {
-            Msg::SetFsInfos(fsinfos) => match self.mkfs(comm, fsinfos.dev_size, fsinfos.fstype) {
-                Ok(fs) => State::WaitNewFile(WaitNewFileState { fs }),
-                Err(err) => {
-                    comm.error(proto::writefs::ResponseError {
-                        err: format!("Error mkfs: {err}"),
-                    })?;
-                    State::WaitEnd(WaitEndState {})
+            Msg::SetFsInfos(fsinfos) => {
+                match self.mkfs(comm, fsinfos.dev_size, fsinfos.fstype, fsinfos.table) {
+                    Ok(fs) => State::WaitNewFile(WaitNewFileState { fs }),
+                    Err(err) => {
+                        comm.error(proto::writefs::ResponseError {
+                            err: format!("Error mkfs: {err}"),
+                        })?;
+                        State::WaitEnd(WaitEndState {})
+                    }
                 }
-            },
+            }
             Msg::ImgDisk(_) => return Ok(State::ImgDisk(ImgDiskState { fs: self.fs })),
             Msg::End(_) => {
                 comm.end(proto::writefs::ResponseEnd {})?;
@@ -164,76 +171,159 @@ impl WaitFsInfosState {
         comm: &mut Comm<proto::writefs::Request>,
         dev_size: u64,
         fstype: i32,
+        table: i32,
     ) -> Result<Box<dyn FSWrite<StreamSlice<SparseFile<File>>>>> {
         let out_fs_type =
             OutFsType::try_from(fstype).map_err(|err| Error::FSError(format!("{err}")))?;
+        let table =
+            PartitionTable::try_from(table).map_err(|err| Error::FSError(format!("{err}")))?;
 
-        log::debug!("mkfs dev_size: {}", dev_size);
-
-        let fs_size = dev_size - (SECTOR_START * SECTOR_SIZE);
-        if fs_size % SECTOR_SIZE != 0 {
-            return Err(Error::FSError("fs size not multiple of sector size".into()));
-        }
+        log::debug!("mkfs dev_size: {} table: {:?}", dev_size, table);
 
         if dev_size % SECTOR_SIZE != 0 {
             return Err(Error::FSError(
                 "dev size not multiple of sector size".into(),
             ));
         }
-        let sector_count = fs_size / SECTOR_SIZE;
-        let sector_count: u64 = if sector_count > 0xFFFF_FFFF {
-            return Err(Error::FSError("sector count too big".into()));
-        } else {
-            sector_count & 0xFFFF_FFFF
-        };
+        let dev_sectors = dev_size / SECTOR_SIZE;
 
-        let mut sparse_file =
-            SparseFile::new(self.fs, SECTOR_SIZE, (SECTOR_START + sector_count) as usize)?;
+        let mut sparse_file = SparseFile::new(self.fs, SECTOR_SIZE, usize::try_from(dev_sectors)?)?;
 
-        let fs: Box<dyn FSWrite<StreamSlice<SparseFile<File>>>> = match out_fs_type {
-            OutFsType::Fat | OutFsType::Exfat => {
-                // ff handles writing mbr but still wrap in StreamSlice so we have the same type as ntfs below
-                let file_slice =
-                    StreamSlice::new(sparse_file, 0, (SECTOR_START + sector_count) * SECTOR_SIZE)?;
+        let fs: Box<dyn FSWrite<StreamSlice<SparseFile<File>>>> = match table {
+            PartitionTable::Mbr => {
+                let sector_count = dev_sectors
+                    .checked_sub(SECTOR_START)
+                    .ok_or_else(|| Error::FSError("dev size too small".into()))?;
+                if sector_count > 0xFFFF_FFFF {
+                    return Err(Error::FSError("sector count too big".into()));
+                }
 
-                Box::new(ff::FatFsWriter::mkfs(
-                    file_slice,
-                    SECTOR_SIZE,
-                    sector_count,
-                    Some(out_fs_type),
-                )?)
+                // Clear a previous backup GPT at the end of the device, it
+                // would otherwise take precedence over the MBR for some tools
+                // (devices too small for a GPT can't have one)
+                if let Ok((_, last_usable)) = gpt::usable_lbas(SECTOR_SIZE, dev_sectors) {
+                    sparse_file.seek(SeekFrom::Start((last_usable + 1) * SECTOR_SIZE))?;
+                    sparse_file.write_all(&vec![
+                        0;
+                        ((dev_sectors - last_usable - 1) * SECTOR_SIZE)
+                            as usize
+                    ])?;
+                }
+
+                match out_fs_type {
+                    OutFsType::Fat | OutFsType::Exfat => {
+                        // ff handles writing mbr but still wrap in StreamSlice so we have the same type as ntfs below
+                        let file_slice = StreamSlice::new(
+                            sparse_file,
+                            0,
+                            (SECTOR_START + sector_count) * SECTOR_SIZE,
+                        )?;
+
+                        Box::new(ff::FatFsWriter::mkfs(
+                            file_slice,
+                            SECTOR_SIZE,
+                            sector_count,
+                            Some(out_fs_type),
+                        )?)
+                    }
+                    OutFsType::Ntfs | OutFsType::Ext4 => {
+                        // Write mbr before mkfs
+                        sparse_file.seek(SeekFrom::Start(446))?;
+                        let partition = usbsas_mbr::MbrPartitionEntry {
+                            boot_indicator: 0,
+                            start_head: 1,
+                            start_sector: 1,
+                            start_cylinder: 0,
+                            partition_type: if out_fs_type == OutFsType::Ext4 {
+                                0x83
+                            } else {
+                                0x7
+                            },
+                            end_head: 0xfe,
+                            end_sector: 0x3f,
+                            end_cylinder: 0x2,
+                            start_in_lba: u32::try_from(SECTOR_START)?,
+                            size_in_lba: u32::try_from(sector_count)?,
+                        };
+                        usbsas_mbr::write_partition(&mut sparse_file, &partition)?;
+                        sparse_file.seek(SeekFrom::Start(510))?;
+                        sparse_file.write_all(&[0x55, 0xAA])?;
+
+                        let file_slice = StreamSlice::new(
+                            sparse_file,
+                            SECTOR_START * SECTOR_SIZE,
+                            (SECTOR_START + sector_count) * SECTOR_SIZE,
+                        )?;
+
+                        if out_fs_type == OutFsType::Ext4 {
+                            mkfs_ext4(file_slice, sector_count, &self.guids)?
+                        } else {
+                            Box::new(ntfs::NTFS3G::mkfs(
+                                file_slice,
+                                SECTOR_SIZE,
+                                sector_count,
+                                None,
+                            )?)
+                        }
+                    }
+                }
             }
-            OutFsType::Ntfs => {
-                // Write mbr before mkfs
-                sparse_file.seek(SeekFrom::Start(446))?;
-                let partition = usbsas_mbr::MbrPartitionEntry {
-                    boot_indicator: 0,
-                    start_head: 1,
-                    start_sector: 1,
-                    start_cylinder: 0,
-                    partition_type: 0x7,
-                    end_head: 0xfe,
-                    end_sector: 0x3f,
-                    end_cylinder: 0x2,
-                    start_in_lba: u32::try_from(SECTOR_START)?,
-                    size_in_lba: u32::try_from(sector_count)?,
-                };
-                usbsas_mbr::write_partition(&mut sparse_file, &partition)?;
-                sparse_file.seek(SeekFrom::Start(510))?;
-                sparse_file.write_all(&[0x55, 0xAA])?;
+            PartitionTable::Gpt => {
+                let (_, last_usable) = gpt::usable_lbas(SECTOR_SIZE, dev_sectors)?;
+                let part_start = gpt::GPT_PART_ALIGN / SECTOR_SIZE;
+                if last_usable < part_start {
+                    return Err(Error::FSError("dev size too small".into()));
+                }
+                let mut sector_count = last_usable + 1 - part_start;
+                // ff can't address more sectors, leave the rest of the device unused
+                if matches!(out_fs_type, OutFsType::Fat | OutFsType::Exfat) {
+                    sector_count = sector_count.min(0xFFFF_FFFF);
+                }
+
+                let mut disk_guid = [0u8; 16];
+                let mut part_guid = [0u8; 16];
+                disk_guid.copy_from_slice(&self.guids[..16]);
+                part_guid.copy_from_slice(&self.guids[16..]);
+                gpt::write_gpt(
+                    &mut sparse_file,
+                    SECTOR_SIZE,
+                    dev_sectors,
+                    &gpt::guid_from_random(disk_guid),
+                    &[gpt::GptPartitionEntry {
+                        type_guid: if out_fs_type == OutFsType::Ext4 {
+                            gpt::GPT_TYPE_LINUX_FS
+                        } else {
+                            gpt::GPT_TYPE_BASIC_DATA
+                        },
+                        part_guid: gpt::guid_from_random(part_guid),
+                        first_lba: part_start,
+                        last_lba: part_start + sector_count - 1,
+                        attributes: 0,
+                        name: "usbsas".into(),
+                    }],
+                )?;
 
                 let file_slice = StreamSlice::new(
                     sparse_file,
-                    SECTOR_START * SECTOR_SIZE,
-                    (SECTOR_START + sector_count) * SECTOR_SIZE,
+                    part_start * SECTOR_SIZE,
+                    (part_start + sector_count) * SECTOR_SIZE,
                 )?;
 
-                Box::new(ntfs::NTFS3G::mkfs(
-                    file_slice,
-                    SECTOR_SIZE,
-                    sector_count,
-                    None,
-                )?)
+                match out_fs_type {
+                    OutFsType::Fat | OutFsType::Exfat => Box::new(ff::FatFsWriter::mkfs_sfd(
+                        file_slice,
+                        SECTOR_SIZE,
+                        sector_count,
+                        Some(out_fs_type),
+                    )?),
+                    OutFsType::Ntfs => Box::new(ntfs::NTFS3G::mkfs(
+                        file_slice,
+                        SECTOR_SIZE,
+                        sector_count,
+                        None,
+                    )?),
+                    OutFsType::Ext4 => mkfs_ext4(file_slice, sector_count, &self.guids)?,
+                }
             }
         };
 
@@ -242,12 +332,26 @@ impl WaitFsInfosState {
     }
 }
 
+fn mkfs_ext4(
+    file_slice: StreamSlice<SparseFile<File>>,
+    sector_count: u64,
+    guids: &[u8; 48],
+) -> Result<Box<dyn FSWrite<StreamSlice<SparseFile<File>>>>> {
+    let mut fs = ext4fs::Ext4Writer::mkfs(file_slice, SECTOR_SIZE, sector_count, None)?;
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&guids[32..]);
+    fs.set_uuid(uuid);
+    Ok(Box::new(fs))
+}
+
 impl WaitNewFileState {
     fn run(self, comm: &mut Comm<proto::writefs::Request>) -> Result<State> {
         trace!("wait new file state");
         let req: proto::writefs::Request = comm.recv()?;
         let newstate = match req.msg.ok_or(Error::BadRequest)? {
-            Msg::NewFile(msg) => self.newfile(comm, msg.path, msg.timestamp, msg.ftype)?,
+            Msg::NewFile(msg) => {
+                self.newfile(comm, msg.path, msg.times.unwrap_or_default(), msg.ftype)?
+            }
             Msg::Close(_) => {
                 let bitvec = self.fs.unmount_fs()?.into_inner().get_bitvec()?;
                 comm.close(proto::writefs::ResponseClose {})?;
@@ -277,7 +381,7 @@ impl WaitNewFileState {
         mut self,
         comm: &mut Comm<proto::writefs::Request>,
         path: String,
-        timestamp: i64,
+        times: Timestamps,
         ftype: i32,
     ) -> Result<State> {
         debug!("New file: \"{}\"", &path);
@@ -285,10 +389,10 @@ impl WaitNewFileState {
             Ok(FileType::Regular) => State::WritingFile(WritingFileState {
                 fs: self.fs,
                 path,
-                timestamp,
+                times,
             }),
             Ok(FileType::Directory) => {
-                match self.fs.newdir(&path, timestamp) {
+                match self.fs.newdir(&path, &times) {
                     Ok(_) => comm.newfile(proto::writefs::ResponseNewFile {})?,
                     Err(err) => {
                         warn!("{}", err);
@@ -327,7 +431,7 @@ impl WritingFileState {
 
     fn write_file(&mut self, comm: &mut Comm<proto::writefs::Request>) -> Result<()> {
         trace!("writing file state");
-        let mut file = self.fs.newfile(&self.path, self.timestamp)?;
+        let mut file = self.fs.newfile(&self.path, &self.times)?;
         comm.newfile(proto::writefs::ResponseNewFile {})?;
         loop {
             let req: proto::writefs::Request = comm.recv()?;
@@ -351,7 +455,7 @@ impl WritingFileState {
                 }
                 Msg::EndFile(_) => {
                     drop(file);
-                    self.fs.settimestamp(&self.path, self.timestamp)?;
+                    self.fs.settimestamp(&self.path, &self.times)?;
                     comm.endfile(proto::writefs::ResponseEndFile {})?;
                     break;
                 }
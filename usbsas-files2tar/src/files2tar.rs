@@ -69,10 +69,12 @@ impl WaitNewFileState {
             Msg::NewFile(req) => {
                 let fstype =
                     FileType::try_from(req.ftype).map_err(|err| Error::Error(format!("{err}")))?;
-                match self
-                    .archive
-                    .newfile(&req.path, fstype, req.size, req.timestamp)
-                {
+                match self.archive.newfile(
+                    &req.path,
+                    fstype,
+                    req.size,
+                    &req.times.unwrap_or_default(),
+                ) {
                     Ok(_) => {
                         comm.newfile(proto::writetar::ResponseNewFile {})?;
                         Ok(State::WritingFile(WritingFileState {
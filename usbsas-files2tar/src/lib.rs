@@ -7,7 +7,7 @@
 //!
 
 use thiserror::Error;
-use usbsas_proto::common::FileType;
+use usbsas_proto::common::{FileType, Timestamps};
 
 mod files2tar;
 mod tarwriter;
@@ -33,7 +33,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 pub(crate) trait ArchiveWriter {
     fn init(&mut self) -> Result<()>;
-    fn newfile(&mut self, path: &str, ftype: FileType, size: u64, timestamp: i64) -> Result<()>;
+    fn newfile(&mut self, path: &str, ftype: FileType, size: u64, times: &Timestamps)
+        -> Result<()>;
     fn writefile(&mut self, data: &[u8]) -> Result<()>;
     fn endfile(&mut self, len_written: usize) -> Result<()>;
     fn finish(self: Box<Self>, infos: &[u8]) -> Result<()>;
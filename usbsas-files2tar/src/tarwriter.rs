@@ -1,9 +1,20 @@
 use crate::ArchiveWriter;
 use crate::{Error, Result};
 use std::{io::Write, path::Path, time::SystemTime};
-use usbsas_proto::common::FileType;
+use usbsas_proto::common::{FileType, Timestamps};
 use usbsas_utils::{TAR_BLOCK_SIZE, TAR_DATA_DIR};
 
+// PAX times are decimal seconds, e.g. "1700000000.123456789"
+fn pax_time(nanos: i64) -> String {
+    let abs = nanos.unsigned_abs();
+    format!(
+        "{}{}.{:09}",
+        if nanos < 0 { "-" } else { "" },
+        abs / 1_000_000_000,
+        abs % 1_000_000_000
+    )
+}
+
 pub(crate) struct TarWriter<W: Write> {
     builder: tar::Builder<W>,
     data_dir: String,
@@ -33,7 +44,13 @@ impl<W: Write> ArchiveWriter for TarWriter<W> {
         Ok(())
     }
 
-    fn newfile(&mut self, path: &str, ftype: FileType, size: u64, timestamp: i64) -> Result<()> {
+    fn newfile(
+        &mut self,
+        path: &str,
+        ftype: FileType,
+        size: u64,
+        times: &Timestamps,
+    ) -> Result<()> {
         let mut header = tar::Header::new_ustar();
         match ftype {
             FileType::Regular => {
@@ -48,7 +65,23 @@ impl<W: Write> ArchiveWriter for TarWriter<W> {
             }
             _ => return Err(Error::Error("Bad file type".to_string())),
         }
-        header.set_mtime(timestamp as u64);
+        // ustar only has the modification time in seconds, the precise times
+        // are recorded in a PAX extended header
+        header.set_mtime(times.mtime_secs().max(0) as u64);
+        let pax_times: Vec<(&str, String)> = [
+            ("mtime", times.mtime),
+            ("atime", times.atime),
+            ("ctime", times.ctime),
+        ]
+        .into_iter()
+        .filter(|(key, time)| *key == "mtime" || *time != 0)
+        .map(|(key, time)| (key, pax_time(time)))
+        .collect();
+        self.builder.append_pax_extensions(
+            pax_times
+                .iter()
+                .map(|(key, value)| (*key, value.as_bytes())),
+        )?;
         let mut path_string: String = path.trim_start_matches('/').into();
         self.files.push(path_string.clone());
         path_string.insert_str(0, &self.data_dir);
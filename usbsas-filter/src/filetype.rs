@@ -0,0 +1,287 @@
+//! Identification of file types from their first bytes (magic numbers and
+//! container structure) and of mismatches with their extensions.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    Empty,
+    Text,
+    Script,
+    Pe,
+    Elf,
+    MachO,
+    JavaClass,
+    Lnk,
+    Pdf,
+    Rtf,
+    Ole,
+    Zip,
+    Jar,
+    Docx,
+    Xlsx,
+    Pptx,
+    Ooxml,
+    Odf,
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+    Tiff,
+    Gzip,
+    Bzip2,
+    Xz,
+    SevenZip,
+    Rar,
+    Tar,
+    Unknown,
+}
+
+const NAMES: &[(ContentType, &str)] = &[
+    (ContentType::Empty, "empty"),
+    (ContentType::Text, "text"),
+    (ContentType::Script, "script"),
+    (ContentType::Pe, "pe"),
+    (ContentType::Elf, "elf"),
+    (ContentType::MachO, "macho"),
+    (ContentType::JavaClass, "class"),
+    (ContentType::Lnk, "lnk"),
+    (ContentType::Pdf, "pdf"),
+    (ContentType::Rtf, "rtf"),
+    (ContentType::Ole, "ole"),
+    (ContentType::Zip, "zip"),
+    (ContentType::Jar, "jar"),
+    (ContentType::Docx, "docx"),
+    (ContentType::Xlsx, "xlsx"),
+    (ContentType::Pptx, "pptx"),
+    (ContentType::Ooxml, "ooxml"),
+    (ContentType::Odf, "odf"),
+    (ContentType::Png, "png"),
+    (ContentType::Jpeg, "jpeg"),
+    (ContentType::Gif, "gif"),
+    (ContentType::Bmp, "bmp"),
+    (ContentType::Tiff, "tiff"),
+    (ContentType::Gzip, "gzip"),
+    (ContentType::Bzip2, "bzip2"),
+    (ContentType::Xz, "xz"),
+    (ContentType::SevenZip, "7z"),
+    (ContentType::Rar, "rar"),
+    (ContentType::Tar, "tar"),
+    (ContentType::Unknown, "unknown"),
+];
+
+impl ContentType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        NAMES
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(name))
+            .map(|(t, _)| *t)
+    }
+
+    pub fn name(self) -> &'static str {
+        NAMES
+            .iter()
+            .find(|(t, _)| *t == self)
+            .map(|(_, n)| *n)
+            .unwrap_or("unknown")
+    }
+
+    /// Identify the type from the first bytes of a file
+    pub fn detect(header: &[u8]) -> Self {
+        let starts = |magic: &[u8]| header.starts_with(magic);
+        match header {
+            [] => ContentType::Empty,
+            _ if starts(b"MZ") && !is_text(header) => ContentType::Pe,
+            _ if starts(b"\x7fELF") => ContentType::Elf,
+            _ if starts(b"\xfe\xed\xfa\xce")
+                || starts(b"\xfe\xed\xfa\xcf")
+                || starts(b"\xce\xfa\xed\xfe")
+                || starts(b"\xcf\xfa\xed\xfe") =>
+            {
+                ContentType::MachO
+            }
+            // Universal Mach-O binaries and Java classes share their magic,
+            // the former have a few architectures, the latter a version >= 45
+            [0xca, 0xfe, 0xba, 0xbe, a, b, c, d, ..] => {
+                if u32::from_be_bytes([*a, *b, *c, *d]) < 45 {
+                    ContentType::MachO
+                } else {
+                    ContentType::JavaClass
+                }
+            }
+            _ if starts(b"#!") => ContentType::Script,
+            _ if starts(b"L\0\0\0\x01\x14\x02\0") => ContentType::Lnk,
+            _ if header[..header.len().min(1024)]
+                .windows(5)
+                .any(|w| w == b"%PDF-") =>
+            {
+                ContentType::Pdf
+            }
+            _ if starts(b"{\\rtf") => ContentType::Rtf,
+            _ if starts(b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1") => ContentType::Ole,
+            _ if starts(b"PK\x03\x04") => zip_type(header),
+            _ if starts(b"PK\x05\x06") => ContentType::Zip,
+            _ if starts(b"\x89PNG\r\n\x1a\n") => ContentType::Png,
+            _ if starts(b"\xff\xd8\xff") => ContentType::Jpeg,
+            _ if starts(b"GIF87a") || starts(b"GIF89a") => ContentType::Gif,
+            [b'B', b'M', _, _, _, _, 0, 0, 0, 0, ..] if header.len() >= 26 => ContentType::Bmp,
+            _ if starts(b"II*\0") || starts(b"MM\0*") => ContentType::Tiff,
+            _ if starts(b"\x1f\x8b") => ContentType::Gzip,
+            [b'B', b'Z', b'h', b'1'..=b'9', ..] => ContentType::Bzip2,
+            _ if starts(b"\xfd7zXZ\0") => ContentType::Xz,
+            _ if starts(b"7z\xbc\xaf\x27\x1c") => ContentType::SevenZip,
+            _ if starts(b"Rar!\x1a\x07") => ContentType::Rar,
+            _ if header.get(257..262) == Some(b"ustar") => ContentType::Tar,
+            _ if is_text(header) => ContentType::Text,
+            _ => ContentType::Unknown,
+        }
+    }
+
+    /// Identify the type of the file at `path` from its first bytes, text
+    /// files with the extension of a script language are scripts
+    pub fn detect_file(path: &str, header: &[u8]) -> Self {
+        match Self::detect(header) {
+            ContentType::Text
+                if extension(path).is_some_and(|ext| SCRIPT_EXTENSIONS.contains(&ext.as_str())) =>
+            {
+                ContentType::Script
+            }
+            content => content,
+        }
+    }
+
+    /// Whether the type isn't one expected for the extension of the path
+    pub fn mismatch(self, path: &str) -> bool {
+        if matches!(self, ContentType::Empty | ContentType::Unknown) {
+            return false;
+        }
+        extension(path)
+            .and_then(|ext| expected_types(&ext))
+            .is_some_and(|types| !types.contains(&self))
+    }
+}
+
+// Interpreted by a shell or the scripting hosts of Windows
+const SCRIPT_EXTENSIONS: &[&str] = &[
+    "sh", "bash", "py", "pl", "rb", "bat", "cmd", "ps1", "psm1", "vbs", "vbe", "js", "jse", "wsf",
+    "wsh", "hta",
+];
+
+/// Lowercase extension of the file name of the path
+fn extension(path: &str) -> Option<String> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => Some(ext.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Valid UTF-8 without NUL bytes, the header may end in the middle of a char
+fn is_text(header: &[u8]) -> bool {
+    if header.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(header) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    }
+}
+
+/// Tell archives, documents and packages apart from the names of the first
+/// entries of the zip
+fn zip_type(header: &[u8]) -> ContentType {
+    let u16_at = |off: usize| {
+        header
+            .get(off..off + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+    };
+    let u32_at = |off: usize| {
+        header
+            .get(off..off + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+
+    let mut names: Vec<&[u8]> = Vec::new();
+    let mut offset = 0;
+    while header.get(offset..offset + 4) == Some(b"PK\x03\x04") {
+        let (Some(flags), Some(data_size), Some(name_len), Some(extra_len)) = (
+            u16_at(offset + 6),
+            u32_at(offset + 18),
+            u16_at(offset + 26),
+            u16_at(offset + 28),
+        ) else {
+            break;
+        };
+        let name_start = offset + 30;
+        let Some(name) = header.get(name_start..name_start + name_len) else {
+            break;
+        };
+        let data_start = name_start + name_len + extra_len;
+        // ODF documents start with an uncompressed "mimetype" entry
+        if names.is_empty() && name == b"mimetype" {
+            if let Some(data) = header.get(data_start..data_start + data_size.min(64)) {
+                if data.starts_with(b"application/vnd.oasis.opendocument") {
+                    return ContentType::Odf;
+                }
+            }
+        }
+        names.push(name);
+        // Size of the data is after it
+        if flags & 0x8 != 0 {
+            break;
+        }
+        offset = data_start + data_size;
+    }
+
+    let has_prefix = |prefix: &[u8]| names.iter().any(|name| name.starts_with(prefix));
+    if names.contains(&&b"[Content_Types].xml"[..]) {
+        if has_prefix(b"word/") {
+            ContentType::Docx
+        } else if has_prefix(b"xl/") {
+            ContentType::Xlsx
+        } else if has_prefix(b"ppt/") {
+            ContentType::Pptx
+        } else {
+            ContentType::Ooxml
+        }
+    } else if names.contains(&&b"META-INF/MANIFEST.MF"[..]) {
+        ContentType::Jar
+    } else {
+        ContentType::Zip
+    }
+}
+
+fn expected_types(ext: &str) -> Option<&'static [ContentType]> {
+    use ContentType::*;
+    Some(match ext {
+        "exe" | "dll" | "sys" | "scr" | "cpl" | "ocx" | "efi" | "com" => &[Pe],
+        "so" | "ko" => &[Elf],
+        "dylib" => &[MachO],
+        "class" => &[JavaClass],
+        "lnk" => &[Lnk],
+        "sh" | "bash" | "py" | "pl" | "rb" | "bat" | "cmd" | "ps1" | "psm1" | "vbs" | "vbe"
+        | "js" | "jse" | "wsf" | "wsh" | "hta" => &[Script, Text],
+        "txt" | "csv" | "md" | "log" | "ini" | "cfg" | "conf" | "json" | "xml" | "html" | "htm"
+        | "svg" => &[Text],
+        "pdf" => &[Pdf],
+        "rtf" => &[Rtf],
+        "doc" | "dot" | "xls" | "xlt" | "ppt" | "pot" | "msg" | "msi" => &[Ole],
+        // The entries telling the document type may not be in the header
+        "docx" | "docm" | "dotx" | "dotm" => &[Docx, Ooxml, Zip],
+        "xlsx" | "xlsm" | "xltx" | "xltm" => &[Xlsx, Ooxml, Zip],
+        "pptx" | "pptm" | "potx" | "potm" => &[Pptx, Ooxml, Zip],
+        "odt" | "ods" | "odp" | "odg" => &[Odf, Zip],
+        "jar" | "war" | "apk" => &[Jar, Zip],
+        "zip" => &[Zip, Jar, Docx, Xlsx, Pptx, Ooxml, Odf],
+        "png" => &[Png],
+        "jpg" | "jpeg" | "jpe" => &[Jpeg],
+        "gif" => &[Gif],
+        "bmp" => &[Bmp],
+        "tif" | "tiff" => &[Tiff],
+        "gz" | "tgz" => &[Gzip],
+        "bz2" | "tbz2" => &[Bzip2],
+        "xz" | "txz" => &[Xz],
+        "7z" => &[SevenZip],
+        "rar" => &[Rar],
+        "tar" => &[Tar],
+        _ => return None,
+    })
+}
@@ -1,13 +1,18 @@
 //! usbsas's name filter process. filter can prevent the copy of certain files
-//! based on their names (for example ".DS_STORE", "AUTORUN.INF" etc.). Filters
-//! can be specified in the configuration file.
+//! based on their names (for example ".DS_STORE", "AUTORUN.INF" etc.) or on
+//! the type detected from their first bytes. Filters can be specified in the
+//! configuration file. Names misleading users can also be reported or blocked.
 
-use log::debug;
-#[cfg(test)]
-use serde::{Deserialize, Serialize};
+mod filetype;
+mod names;
+
+use fancy_regex::{Regex, RegexBuilder};
+use filetype::ContentType;
+use log::{debug, warn};
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
 use usbsas_comm::{protoresponse, Comm};
-use usbsas_config::{conf_parse, conf_read};
+use usbsas_config::{conf_parse, conf_read, Filter as FilterConfig, FilterMode, NameCheckPolicy};
 use usbsas_proto as proto;
 use usbsas_proto::{filter::request::Msg, filter::FilterResult};
 
@@ -26,6 +31,10 @@ pub enum Error {
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Size of compiled patterns and backtracking steps (bounding matching time)
+const PATTERN_SIZE_LIMIT: usize = 1 << 20;
+const PATTERN_BACKTRACK_LIMIT: usize = 100_000;
+
 protoresponse!(
     CommFilter,
     filter,
@@ -34,26 +43,92 @@ protoresponse!(
     end = End[ResponseEnd]
 );
 
-#[cfg_attr(test, derive(Serialize, Deserialize))]
 pub struct Rule {
+    id: String,
+    description: String,
     contain: Option<Vec<String>>,
     start: Option<String>,
     end: Option<String>,
+    regex: Option<Regex>,
+    glob: Option<Regex>,
+    case_sensitive: bool,
+    file_type: Option<Vec<ContentType>>,
+    type_mismatch: Option<bool>,
 }
 
 impl Rule {
-    fn into_lowercase(self) -> Self {
-        Rule {
-            contain: self
-                .contain
-                .map(|v| v.iter().map(|s| s.to_lowercase()).collect()),
-            start: self.start.map(|v| v.to_lowercase()),
-            end: self.end.map(|v| v.to_lowercase()),
-        }
+    fn new(filter: FilterConfig, index: usize) -> Result<Self> {
+        let case_sensitive = filter.case_sensitive.unwrap_or(false);
+        // Patterns and paths are compared in NFC form
+        let case = |s: String| {
+            let s: String = s.nfc().collect();
+            if case_sensitive {
+                s
+            } else {
+                s.to_lowercase()
+            }
+        };
+        Ok(Rule {
+            id: filter.id.unwrap_or_else(|| format!("#{}", index + 1)),
+            description: filter.description.unwrap_or_default(),
+            contain: filter.contain.map(|v| v.into_iter().map(case).collect()),
+            start: filter.start.map(case),
+            end: filter.end.map(case),
+            regex: filter
+                .regex
+                .map(|regex| build_pattern(&regex.nfc().collect::<String>(), case_sensitive))
+                .transpose()?,
+            glob: filter
+                .glob
+                .map(|glob| {
+                    build_pattern(
+                        &glob_to_regex(&glob.nfc().collect::<String>()),
+                        case_sensitive,
+                    )
+                })
+                .transpose()?,
+            case_sensitive,
+            file_type: filter
+                .file_type
+                .map(|types| {
+                    types
+                        .iter()
+                        .map(|name| {
+                            ContentType::from_name(name)
+                                .ok_or_else(|| Error::Error(format!("unknown file type {name}")))
+                        })
+                        .collect()
+                })
+                .transpose()?,
+            type_mismatch: filter.type_mismatch,
+        })
     }
 
-    fn match_(&self, input: &str) -> bool {
-        let input = input.to_lowercase();
+    /// `undecided` is the result when a pattern can't be decided, so that
+    /// deny rules match and allow rules don't.
+    fn match_(&self, input: &str, content: Option<ContentType>, undecided: bool) -> bool {
+        // Content components don't match directories
+        if self.file_type.is_some() || self.type_mismatch.is_some() {
+            let Some(content) = content else {
+                return false;
+            };
+            if let Some(ref types) = self.file_type {
+                if !types.contains(&content) {
+                    return false;
+                }
+            }
+            if let Some(mismatch) = self.type_mismatch {
+                if content.mismatch(input) != mismatch {
+                    return false;
+                }
+            }
+        }
+        let path: String = input.nfc().collect();
+        let input = if self.case_sensitive {
+            path.clone()
+        } else {
+            path.to_lowercase()
+        };
         if let Some(ref contain) = self.contain {
             for pattern in contain.iter() {
                 if !input.contains(pattern) {
@@ -71,29 +146,139 @@ impl Rule {
                 return false;
             }
         }
+        // Globs are written without the leading "/"
+        for (pattern, target) in [
+            (&self.regex, path.as_str()),
+            (&self.glob, path.trim_start_matches('/')),
+        ] {
+            if let Some(pattern) = pattern {
+                match pattern.is_match(target) {
+                    Ok(true) => (),
+                    Ok(false) => return false,
+                    Err(err) => {
+                        warn!("couldn't match {}: {}", path, err);
+                        if !undecided {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
         true
     }
 }
 
-#[cfg_attr(test, derive(Serialize, Deserialize))]
+/// Compile a pattern anchored to match the whole path
+fn build_pattern(pattern: &str, case_sensitive: bool) -> Result<Regex> {
+    // Inline flag since the builder's one doesn't apply to lookarounds
+    let flags = if case_sensitive { "" } else { "(?i)" };
+    RegexBuilder::new(&format!("{flags}^(?:{pattern})$"))
+        .backtrack_limit(PATTERN_BACKTRACK_LIMIT)
+        .delegate_size_limit(PATTERN_SIZE_LIMIT)
+        .delegate_dfa_size_limit(PATTERN_SIZE_LIMIT)
+        .build()
+        .map_err(|err| Error::Error(format!("bad filter pattern \"{pattern}\": {err}")))
+}
+
+/// Translate a shell-style pattern to a regular expression. "*" and "?" don't
+/// match "/", "**/" matches any number of directories and a trailing "/**"
+/// anything below.
+fn glob_to_regex(glob: &str) -> String {
+    let glob: Vec<char> = glob.trim_start_matches('/').chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+    while i < glob.len() {
+        match glob[i] {
+            '*' if glob.get(i + 1) == Some(&'*') => {
+                if glob.get(i + 2) == Some(&'/') {
+                    regex.push_str("(?:.*/)?");
+                    i += 1;
+                } else {
+                    regex.push_str(".*");
+                }
+                i += 1;
+            }
+            '/' if glob[i + 1..] == ['*', '*'] => {
+                regex.push_str("(?:/.*)?");
+                i += 2;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match glob[i + 1..].iter().skip(1).position(|c| *c == ']') {
+                Some(len) => {
+                    let class = &glob[i + 1..i + 2 + len];
+                    regex.push('[');
+                    let class = match class.first() {
+                        Some('!') => {
+                            regex.push('^');
+                            &class[1..]
+                        }
+                        _ => class,
+                    };
+                    for c in class {
+                        match c {
+                            '-' => regex.push('-'),
+                            c => regex.push_str(&fancy_regex::escape(&c.to_string())),
+                        }
+                    }
+                    regex.push(']');
+                    i += len + 2;
+                }
+                None => regex.push_str("\\["),
+            },
+            c => regex.push_str(&fancy_regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    regex
+}
+
 pub struct Rules {
     rules: Vec<Rule>,
+    /// Allow rules, only in allowlist mode
+    allow: Option<Vec<Rule>>,
 }
 
 impl Rules {
-    fn into_lowercase(self) -> Self {
-        Rules {
-            rules: self.rules.into_iter().map(|f| f.into_lowercase()).collect(),
-        }
+    fn new(filters: Vec<FilterConfig>, allow: Option<Vec<FilterConfig>>) -> Result<Self> {
+        let build = |filters: Vec<FilterConfig>| {
+            filters
+                .into_iter()
+                .enumerate()
+                .map(|(index, filter)| Rule::new(filter, index))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Rules {
+            rules: build(filters)?,
+            allow: allow.map(build).transpose()?,
+        })
     }
 
-    fn match_all(&self, input: &str) -> FilterResult {
-        for f in self.rules.iter() {
-            if f.match_(input) {
-                return FilterResult::PathFiltered;
+    fn matching_rule(&self, input: &str, content: Option<ContentType>) -> Option<&Rule> {
+        self.rules.iter().find(|f| f.match_(input, content, true))
+    }
+
+    fn match_allowed(&self, input: &str, content: Option<ContentType>) -> FilterResult {
+        match self.allow {
+            Some(ref allow) if !allow.iter().any(|f| f.match_(input, content, false)) => {
+                FilterResult::PathNotAllowed
             }
+            _ => FilterResult::PathOk,
+        }
+    }
+
+    fn match_denied(&self, input: &str, content: Option<ContentType>) -> FilterResult {
+        match self.matching_rule(input, content) {
+            Some(_) => FilterResult::PathFiltered,
+            None => FilterResult::PathOk,
+        }
+    }
+
+    fn match_all(&self, input: &str, content: Option<ContentType>) -> FilterResult {
+        match self.match_denied(input, content) {
+            FilterResult::PathOk => self.match_allowed(input, content),
+            result => result,
         }
-        FilterResult::PathOk
     }
 }
 
@@ -118,6 +303,7 @@ struct InitState {
 }
 struct RunningState {
     rules: Rules,
+    name_checks: NameCheckPolicy,
 }
 
 impl InitState {
@@ -127,19 +313,15 @@ impl InitState {
         usbsas_sandbox::filter::seccomp(comm.input_fd(), comm.output_fd())?;
 
         let config = conf_parse(&config_str)?;
-        let rules = config
-            .filters
-            .unwrap_or_default()
-            .into_iter()
-            .map(|f| Rule {
-                contain: f.contain,
-                start: f.start,
-                end: f.end,
-            })
-            .collect();
-
-        let rules = Rules { rules }.into_lowercase();
-        Ok(State::Running(RunningState { rules }))
+        let allow = match config.filter_mode.unwrap_or_default() {
+            FilterMode::Denylist => None,
+            FilterMode::Allowlist => Some(config.allow_filters.unwrap_or_default()),
+        };
+        let rules = Rules::new(config.filters.unwrap_or_default(), allow)?;
+        Ok(State::Running(RunningState {
+            rules,
+            name_checks: config.name_checks.unwrap_or_default(),
+        }))
     }
 }
 
@@ -148,7 +330,7 @@ impl RunningState {
         loop {
             let req: proto::filter::Request = comm.recv()?;
             match req.msg.ok_or(Error::BadRequest)? {
-                Msg::FilterPaths(req) => self.filterpaths(comm, req.path)?,
+                Msg::FilterPaths(req) => self.filterpaths(comm, req)?,
                 Msg::End(_) => {
                     comm.end(proto::filter::ResponseEnd {})?;
                     break;
@@ -161,14 +343,60 @@ impl RunningState {
     fn filterpaths(
         &self,
         comm: &mut Comm<proto::filter::Request>,
-        paths: Vec<String>,
+        req: proto::filter::RequestFilterPaths,
     ) -> Result<()> {
-        let results = paths
-            .iter()
-            .map(|p| self.rules.match_all(p) as i32)
-            .collect();
-        debug!("filter results {:?}", results);
-        comm.filterpaths(proto::filter::ResponseFilterPaths { results })?;
+        if !req.headers.is_empty() && req.headers.len() != req.path.len() {
+            return Err(Error::BadRequest);
+        }
+        let contents: Vec<Option<ContentType>> = if req.headers.is_empty() {
+            vec![None; req.path.len()]
+        } else {
+            req.path
+                .iter()
+                .zip(&req.headers)
+                .map(|(path, header)| Some(ContentType::detect_file(path, header)))
+                .collect()
+        };
+        let mut rep = proto::filter::ResponseFilterPaths::default();
+        for (p, content) in req.path.iter().zip(contents) {
+            // Allow rules are meant for files
+            let result = if req.directories {
+                self.rules.match_denied(p, content)
+            } else {
+                self.rules.match_all(p, content)
+            };
+            let issues = match self.name_checks {
+                NameCheckPolicy::Off => Vec::new(),
+                _ => names::check(&p.nfc().collect::<String>()),
+            };
+            let result = match result {
+                FilterResult::PathFiltered => result,
+                _ if self.name_checks == NameCheckPolicy::Block && !issues.is_empty() => {
+                    FilterResult::PathUnsafeName
+                }
+                _ => result,
+            };
+            let rule = match result {
+                FilterResult::PathFiltered => self.rules.matching_rule(p, content),
+                _ => None,
+            }
+            .map(|rule| proto::filter::FilterRule {
+                id: rule.id.clone(),
+                description: rule.description.clone(),
+            })
+            .unwrap_or_default();
+            rep.results.push(result as i32);
+            rep.rules.push(rule);
+            rep.types
+                .push(content.map(ContentType::name).unwrap_or_default().into());
+            rep.mismatches
+                .push(content.is_some_and(|content| content.mismatch(p)));
+            rep.name_issues.push(proto::filter::NameIssues {
+                issues: issues.into_iter().map(String::from).collect(),
+            });
+        }
+        debug!("filter results {:?}", rep.results);
+        comm.filterpaths(rep)?;
         Ok(())
     }
 }
@@ -200,9 +428,22 @@ impl Filter {
 
 #[cfg(test)]
 mod tests {
-    use crate::Rules;
+    use crate::{filetype::ContentType, names, Rules};
+    use serde::Deserialize;
+    use usbsas_config::Filter;
     use usbsas_proto::filter::FilterResult;
 
+    #[derive(Deserialize)]
+    struct Conf {
+        rules: Vec<Filter>,
+        allow: Option<Vec<Filter>>,
+    }
+
+    fn rules(conf: &str) -> Rules {
+        let conf: Conf = toml::from_str(conf).expect("can't parse toml");
+        Rules::new(conf.rules, conf.allow).expect("can't build rules")
+    }
+
     const CONF: &str = r#"
 [[rules]]
 contain = ["__MACOSX"]
@@ -225,19 +466,262 @@ end = ".lnk"
 
     #[test]
     fn test_filters_from_config() {
-        let rules: Rules = toml::from_str(CONF).expect("can't parse toml");
-        let rules = rules.into_lowercase();
-        assert_eq!(rules.match_all("good"), FilterResult::PathOk);
-        assert_eq!(rules.match_all("bad.lnk"), FilterResult::PathFiltered);
-        assert_eq!(rules.match_all("good.lnk.not_ending"), FilterResult::PathOk);
+        let rules = rules(CONF);
+        assert_eq!(rules.match_all("good", None), FilterResult::PathOk);
+        assert_eq!(rules.match_all("bad.lnk", None), FilterResult::PathFiltered);
+        assert_eq!(
+            rules.matching_rule("bad.lnk", None).map(|r| r.id.as_str()),
+            Some("#5")
+        );
+        assert_eq!(
+            rules.match_all("good.lnk.not_ending", None),
+            FilterResult::PathOk
+        );
+        assert_eq!(
+            rules.match_all("X frag1 frag2 Y", None),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(
+            rules.match_all("X frag3 frag4 Y", None),
+            FilterResult::PathOk
+        );
+        assert_eq!(rules.match_all(".bad", None), FilterResult::PathFiltered);
+        assert_eq!(
+            rules.match_all("not_starting.bad", None),
+            FilterResult::PathOk
+        );
+        assert_eq!(
+            rules.match_all(".__MACOSX", None),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(
+            rules.match_all(".DS_Store", None),
+            FilterResult::PathFiltered
+        );
+    }
+
+    const PATTERNS_CONF: &str = r#"
+[[rules]]
+id = "executables"
+regex = '/(?!tools/).*\.exe'
+
+[[rules]]
+glob = "**/.git/**"
+case_sensitive = true
+
+[[rules]]
+glob = "docs/*.[!t]?t"
+
+[[rules]]
+end = ".TMP"
+case_sensitive = true
+"#;
+
+    #[test]
+    fn test_patterns() {
+        let rules = rules(PATTERNS_CONF);
+        assert_eq!(rules.match_all("/a.exe", None), FilterResult::PathFiltered);
+        assert_eq!(
+            rules.matching_rule("/a.exe", None).map(|r| r.id.as_str()),
+            Some("executables")
+        );
+        assert_eq!(
+            rules.match_all("/dir/sub/A.EXE", None),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(rules.match_all("/tools/a.exe", None), FilterResult::PathOk);
+        assert_eq!(
+            rules.match_all("/dir/a.exe.txt", None),
+            FilterResult::PathOk
+        );
+        assert_eq!(rules.match_all("/.git", None), FilterResult::PathFiltered);
+        assert_eq!(
+            rules.match_all("/src/.git/config", None),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(
+            rules.match_all("/src/.GIT/config", None),
+            FilterResult::PathOk
+        );
+        assert_eq!(rules.match_all("/src/.github", None), FilterResult::PathOk);
+        assert_eq!(
+            rules.match_all("/docs/a.rst", None),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(rules.match_all("/docs/a.txt", None), FilterResult::PathOk);
+        assert_eq!(
+            rules.match_all("/docs/sub/a.rst", None),
+            FilterResult::PathOk
+        );
+        assert_eq!(rules.match_all("/a.TMP", None), FilterResult::PathFiltered);
+        assert_eq!(rules.match_all("/a.tmp", None), FilterResult::PathOk);
+    }
+
+    #[test]
+    fn test_bad_pattern() {
+        let conf: Conf = toml::from_str("[[rules]]\nregex = '(a'").expect("can't parse toml");
+        assert!(Rules::new(conf.rules, None).is_err());
+    }
+
+    #[test]
+    fn test_undecided_pattern() {
+        // The lookahead keeps it in the backtracking engine, which gives up
+        let pattern = "regex = '/((?=a)a|a)*b'";
+        let path = format!("/{}", "a".repeat(40));
+        let deny = rules(&format!("[[rules]]\n{pattern}"));
+        assert_eq!(deny.match_all(&path, None), FilterResult::PathFiltered);
+        let allow = rules(&format!("rules = []\n[[allow]]\n{pattern}"));
+        assert_eq!(allow.match_all(&path, None), FilterResult::PathNotAllowed);
+    }
+
+    const ALLOW_CONF: &str = r#"
+[[rules]]
+contain = ["secret"]
+
+[[allow]]
+end = ".pdf"
+
+[[allow]]
+glob = "images/*.png"
+"#;
+
+    #[test]
+    fn test_allowlist() {
+        let rules = rules(ALLOW_CONF);
+        assert_eq!(rules.match_all("/doc.PDF", None), FilterResult::PathOk);
+        assert_eq!(rules.match_all("/images/a.png", None), FilterResult::PathOk);
+        assert_eq!(
+            rules.match_all("/images/sub/a.png", None),
+            FilterResult::PathNotAllowed
+        );
+        assert_eq!(
+            rules.match_all("/run.exe", None),
+            FilterResult::PathNotAllowed
+        );
+        assert_eq!(
+            rules.match_all("/secret/doc.pdf", None),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(rules.match_denied("/images", None), FilterResult::PathOk);
+        assert_eq!(
+            rules.match_denied("/secret", None),
+            FilterResult::PathFiltered
+        );
+    }
+
+    const CONTENT_CONF: &str = r#"
+[[rules]]
+id = "executables"
+file_type = ["pe", "elf", "macho", "script"]
+
+[[rules]]
+id = "disguised"
+type_mismatch = true
+
+[[allow]]
+file_type = ["pdf", "docx", "png", "text"]
+"#;
+
+    #[test]
+    fn test_content() {
+        let rules = rules(CONTENT_CONF);
+        let detect = ContentType::detect;
+        let mut pe = b"MZ\x90\0\x03\0\0\0".to_vec();
+        pe.resize(512, 0);
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.resize(64, 0);
+        let mut docx = b"PK\x03\x04\x14\0\0\0\0\0".to_vec();
+        docx.resize(18, 0);
+        docx.extend_from_slice(&[3, 0, 0, 0, 3, 0, 0, 0, 19, 0, 0, 0]);
+        docx.extend_from_slice(b"[Content_Types].xml<a>");
+        docx.extend_from_slice(b"PK\x03\x04\x14\0\x08\0\0\0");
+        docx.resize(docx.len() + 16, 0);
+        docx.extend_from_slice(&[17, 0, 0, 0]);
+        docx.extend_from_slice(b"word/document.xml");
+
+        assert_eq!(detect(&pe), ContentType::Pe);
+        assert_eq!(detect(b"MZ is not a PE"), ContentType::Text);
+        assert_eq!(detect(b"\x7fELF\x02\x01\x01"), ContentType::Elf);
+        assert_eq!(detect(b"#!/bin/sh\n"), ContentType::Script);
+        assert_eq!(detect(b"%PDF-1.7\n"), ContentType::Pdf);
+        assert_eq!(detect(&png), ContentType::Png);
+        assert_eq!(detect(&docx), ContentType::Docx);
+        assert_eq!(detect(&docx[..60]), ContentType::Ooxml);
+        assert_eq!(detect(b"\xff\xfe\0\0\x01"), ContentType::Unknown);
+
+        assert_eq!(
+            rules.match_all("/report.pdf", Some(detect(&pe))),
+            FilterResult::PathFiltered
+        );
+        assert_eq!(
+            rules
+                .matching_rule("/a.png", Some(detect(b"%PDF-1.7")))
+                .map(|r| r.id.as_str()),
+            Some("disguised")
+        );
+        assert_eq!(
+            rules.match_all("/a.pdf", Some(detect(b"%PDF-1.7"))),
+            FilterResult::PathOk
+        );
+        assert_eq!(
+            rules.match_all("/a.docx", Some(detect(&docx))),
+            FilterResult::PathOk
+        );
+        assert_eq!(
+            rules.match_all("/notes", Some(detect(b"notes"))),
+            FilterResult::PathOk
+        );
+        assert_eq!(
+            rules.match_all("/a.bin", Some(detect(b"\xff\xfe\0\0\x01"))),
+            FilterResult::PathNotAllowed
+        );
+        assert_eq!(rules.match_denied("/dir.exe", None), FilterResult::PathOk);
+
+        // Windows scripts are text, told apart by their extension
+        let detect_file = ContentType::detect_file;
+        for (path, script) in [
+            ("/run.bat", &b"@echo off\r\ndel /q *\r\n"[..]),
+            ("/RUN.PS1", b"Invoke-WebRequest http://x -OutFile a.exe"),
+            ("/run.vbs", b"CreateObject(\"WScript.Shell\").Run \"cmd\""),
+        ] {
+            assert_eq!(detect(script), ContentType::Text);
+            assert_eq!(detect_file(path, script), ContentType::Script);
+            assert!(!ContentType::Script.mismatch(path));
+            assert_eq!(
+                rules
+                    .matching_rule(path, Some(detect_file(path, script)))
+                    .map(|r| r.id.as_str()),
+                Some("executables")
+            );
+        }
+        assert_eq!(detect_file("/notes.txt", b"@echo off"), ContentType::Text);
+    }
+
+    #[test]
+    fn test_names() {
+        assert!(names::check("/dir/report 2024.pdf").is_empty());
+        assert!(names::check("/Résumé – ПРОЕКТ/日本語のファイル.txt").is_empty());
+        assert_eq!(names::check("/invoice\u{202e}fdp.exe"), ["bidi_control"]);
+        assert_eq!(names::check("/a\u{200b}.txt"), ["invisible_char"]);
+        assert_eq!(names::check("/a\nb.txt"), ["control_char"]);
+        assert_eq!(names::check("/p\u{0430}ypal.pdf"), ["mixed_scripts"]);
+        assert_eq!(
+            names::check("/invoice.pdf          .exe"),
+            ["padded_extension"]
+        );
+        assert_eq!(names::check("/invoice.pdf___.exe"), ["padded_extension"]);
+        assert!(names::check("/invoice.pdf .exe").is_empty());
+
+        // Decomposed forms match rules written composed and the other way round
+        let rules =
+            rules("[[rules]]\nend = \"caf\u{e9}.txt\"\n[[rules]]\nglob = \"*/noe\u{0308}l/**\"");
+        assert_eq!(
+            rules.match_all("/cafe\u{301}.txt", None),
+            FilterResult::PathFiltered
+        );
         assert_eq!(
-            rules.match_all("X frag1 frag2 Y"),
+            rules.match_all("/a/no\u{eb}l/b", None),
             FilterResult::PathFiltered
         );
-        assert_eq!(rules.match_all("X frag3 frag4 Y"), FilterResult::PathOk);
-        assert_eq!(rules.match_all(".bad"), FilterResult::PathFiltered);
-        assert_eq!(rules.match_all("not_starting.bad"), FilterResult::PathOk);
-        assert_eq!(rules.match_all(".__MACOSX"), FilterResult::PathFiltered);
-        assert_eq!(rules.match_all(".DS_Store"), FilterResult::PathFiltered);
     }
 }
@@ -0,0 +1,63 @@
+//! Detection of names misleading users: bidi overrides, invisible and control
+//! characters, words mixing scripts (homoglyphs) and double extensions hidden
+//! by padding. Paths are checked once NFC normalized.
+
+use unicode_security::MixedScript;
+
+// Minimum padding between a hidden extension and the real one
+const MIN_PADDING: usize = 3;
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061c}' | '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00ad}'
+            | '\u{034f}'
+            | '\u{180e}'
+            | '\u{200b}'..='\u{200d}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{feff}'
+    )
+}
+
+/// "invoice.pdf          .exe"
+fn has_padded_extension(name: &str) -> bool {
+    let Some((stem, _)) = name.rsplit_once('.') else {
+        return false;
+    };
+    let unpadded = stem.trim_end_matches(|c: char| c.is_whitespace() || c == '_');
+    if stem.chars().count() - unpadded.chars().count() < MIN_PADDING {
+        return false;
+    }
+    unpadded.rsplit_once('.').is_some_and(|(base, ext)| {
+        !base.is_empty() && (1..=5).contains(&ext.len()) && ext.chars().all(char::is_alphanumeric)
+    })
+}
+
+/// Issues found in the (normalized) path, empty if it is safe
+pub fn check(path: &str) -> Vec<&'static str> {
+    let mut issues = Vec::new();
+    if path.chars().any(is_bidi_control) {
+        issues.push("bidi_control");
+    }
+    if path.chars().any(is_invisible) {
+        issues.push("invisible_char");
+    }
+    if path.chars().any(char::is_control) {
+        issues.push("control_char");
+    }
+    // Words, not whole names, as names can legitimately mix languages
+    if path
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| !word.is_single_script())
+    {
+        issues.push("mixed_scripts");
+    }
+    if path.split('/').any(has_padded_extension) {
+        issues.push("padded_extension");
+    }
+    issues
+}
@@ -226,6 +226,11 @@ impl CopyingState {
         let mut buffer = vec![0; BUFFER_MAX_WRITE_SIZE as usize];
 
         for (sector_start, sector_stop) in self.fs_bv {
+            // The bit vector spans the whole device (backup GPT included)
+            // but must not go beyond it
+            if sector_stop * SECTOR_SIZE > self.mass_storage.dev_size {
+                return Err(Error::Error("bit vector exceeds device size".into()));
+            }
             let sector_start_pos = sector_start * SECTOR_SIZE;
             self.fs.seek(SeekFrom::Start(sector_start_pos))?;
 
@@ -245,7 +250,7 @@ impl CopyingState {
                 .take(pad as usize)
                 .for_each(|b| *b = 0);
 
-            self.mass_storage.scsi_write_10(
+            self.mass_storage.scsi_write(
                 &mut buffer[..size as usize + pad as usize],
                 sector_start,
                 sector_count,
@@ -291,7 +296,7 @@ impl WipingState {
                 buffer.truncate(todo as usize);
             }
             self.mass_storage
-                .scsi_write_10(&mut buffer, sector_index, sector_count)?;
+                .scsi_write(&mut buffer, sector_index, sector_count)?;
             current_size += buffer.len() as u64;
             comm.copystatus(proto::fs2dev::ResponseCopyStatus {
                 current_size,
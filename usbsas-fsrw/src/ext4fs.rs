@@ -1,13 +1,51 @@
-use crate::FSRead;
-use crate::{Error, Result};
+use crate::{check_dir_entries, Error, Result};
+use crate::{FSRead, FSWrite, WriteSeek};
 use positioned_io2::ReadAt;
-use std::io::{Read, Seek, SeekFrom};
-use usbsas_proto::common::{FileInfo, FileType};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+use usbsas_proto::common::{FileInfo, FileType, OutFsType, Timestamps};
 
 pub struct Ext4<T> {
     vol: ext4::SuperBlock<T>,
 }
 
+fn ext4_file_type(file_type: ext4::FileType) -> FileType {
+    match file_type {
+        ext4::FileType::RegularFile => FileType::Regular,
+        ext4::FileType::Directory => FileType::Directory,
+        ext4::FileType::SymbolicLink => FileType::Symlink,
+        ext4::FileType::BlockDevice => FileType::BlockDevice,
+        ext4::FileType::CharacterDevice => FileType::CharDevice,
+        ext4::FileType::Fifo => FileType::Fifo,
+        ext4::FileType::Socket => FileType::Socket,
+    }
+}
+
+fn ext4_nanos(time: &ext4::Time) -> i64 {
+    Timestamps::nanos(time.epoch_secs, time.nanos.unwrap_or(0))
+}
+
+fn ext4_stat_timestamps(stat: &ext4::Stat) -> Timestamps {
+    Timestamps {
+        mtime: ext4_nanos(&stat.mtime),
+        ctime: ext4_nanos(&stat.ctime),
+        atime: ext4_nanos(&stat.atime),
+        crtime: stat.btime.as_ref().map(ext4_nanos).unwrap_or(0),
+    }
+}
+
+impl<T: ReadAt> Ext4<T> {
+    fn symlink_target(&self, inode: &ext4::Inode) -> Result<String> {
+        match self.vol.enhance(inode)? {
+            ext4::Enhanced::SymbolicLink(target) => Ok(target),
+            _ => Err(Error::FSError("not a symlink".into())),
+        }
+    }
+}
+
 impl<T: ReadAt> FSRead<T> for Ext4<T> {
     fn new(reader: T, _sector_size: u32) -> Result<Self> {
         let options = ext4::Options {
@@ -17,18 +55,17 @@ impl<T: ReadAt> FSRead<T> for Ext4<T> {
         Ok(Ext4 { vol })
     }
 
-    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)> {
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)> {
         let entry = self.vol.resolve_path(path)?;
         let inode = self.vol.load_inode(entry.inode)?;
-        let file_type = match entry.file_type {
-            ext4::FileType::RegularFile => FileType::Regular,
-            ext4::FileType::Directory => FileType::Directory,
-            _ => FileType::Other,
-        };
-        Ok((file_type, inode.stat.size, inode.stat.ctime.epoch_secs))
+        Ok((
+            ext4_file_type(entry.file_type),
+            inode.stat.size,
+            ext4_stat_timestamps(&inode.stat),
+        ))
     }
 
-    fn read_dir(&mut self, path: &str) -> Result<Vec<FileInfo>> {
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>> {
         let entry = self.vol.resolve_path(path)?;
         let inode = self.vol.load_inode(entry.inode)?;
         let mut files_info = vec![];
@@ -39,17 +76,22 @@ impl<T: ReadAt> FSRead<T> for Ext4<T> {
                     if &entry.name == "." || &entry.name == ".." || &entry.name == "lost+found" {
                         continue;
                     }
+                    check_dir_entries(path, files_info.len() + 1, max_entries)?;
                     let inode = self.vol.load_inode(entry.inode)?;
-                    let file_type = match entry.file_type {
-                        ext4::FileType::RegularFile => FileType::Regular,
-                        ext4::FileType::Directory => FileType::Directory,
-                        _ => FileType::Other,
+                    let file_type = ext4_file_type(entry.file_type);
+                    let symlink_target = if file_type == FileType::Symlink {
+                        self.symlink_target(&inode)?
+                    } else {
+                        String::new()
                     };
                     files_info.push(FileInfo {
                         path: format!("{}/{}", path, &entry.name),
                         ftype: file_type.into(),
                         size: inode.stat.size,
-                        timestamp: inode.stat.ctime.epoch_secs,
+                        timestamp: inode.stat.mtime.epoch_secs,
+                        symlink_target,
+                        streams: Vec::new(),
+                        times: Some(ext4_stat_timestamps(&inode.stat)),
                     });
                 }
             }
@@ -91,7 +133,955 @@ impl<T: ReadAt> FSRead<T> for Ext4<T> {
         }
     }
 
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let entry = self.vol.resolve_path(path)?;
+        let inode = self.vol.load_inode(entry.inode)?;
+        self.symlink_target(&inode)
+    }
+
     fn unmount_fs(self: Box<Self>) -> Result<T> {
         Ok(self.vol.into_inner())
     }
 }
+
+const EXT4_BLOCK_SIZE: u64 = 4096;
+const EXT4_BLOCKS_PER_GROUP: u64 = 8 * EXT4_BLOCK_SIZE;
+const EXT4_INODE_SIZE: u64 = 256;
+const EXT4_BYTES_PER_INODE: u64 = 16384;
+const EXT4_DESC_SIZE: u64 = 32;
+const EXT4_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_ROOT_INO: u32 = 2;
+const EXT4_LOST_FOUND_INO: u32 = 11;
+const EXT4_FIRST_INO: u32 = 11;
+const EXT4_MAX_EXTENT_LEN: u32 = 32768;
+const EXT4_EXTENT_MAGIC: u16 = 0xF30A;
+// Extents fitting in the inode, or leaves indexed by the inode (tree depth 1)
+const EXT4_INODE_EXTENTS: usize = 4;
+const EXT4_LEAF_EXTENTS: usize = (EXT4_BLOCK_SIZE as usize - 12) / 12;
+const EXT4_MAX_EXTENTS: usize = EXT4_INODE_EXTENTS * EXT4_LEAF_EXTENTS;
+const EXT4_S_IFREG: u16 = 0o100000;
+const EXT4_S_IFDIR: u16 = 0o040000;
+const EXT4_EXTENTS_FL: u32 = 0x80000;
+const EXT4_FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const EXT4_FEATURE_INCOMPAT_EXTENTS: u32 = 0x40;
+const EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const EXT4_FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const EXT4_FEATURE_RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const EXT4_FEATURE_RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const EXT4_FEATURE_RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+
+struct Ext4Extent {
+    block: u32,
+    len: u32,
+    start: u64,
+}
+
+struct Ext4Inode {
+    mode: u16,
+    parent: u32,
+    size: u64,
+    times: Timestamps,
+    extents: Vec<Ext4Extent>,
+    // Extent tree leaves, allocated when unmounting
+    leaves: Vec<u64>,
+}
+
+impl Ext4Inode {
+    fn new(mode: u16, parent: u32, times: &Timestamps) -> Self {
+        Ext4Inode {
+            mode,
+            parent,
+            size: 0,
+            times: times.or_mtime(),
+            extents: Vec::new(),
+            leaves: Vec::new(),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & EXT4_S_IFDIR != 0
+    }
+}
+
+// Seconds are stored on 32 bits, the extra field holds 2 epoch bits and the
+// nanoseconds
+fn ext4_timestamp(nanos: i64) -> (u32, u32) {
+    let secs = nanos
+        .div_euclid(1_000_000_000)
+        .clamp(-(1 << 31), (1 << 34) - (1 << 31) - 1);
+    let nsec = nanos.rem_euclid(1_000_000_000) as u32;
+    (
+        secs as u32,
+        ((secs - i64::from(secs as i32)) >> 32) as u32 & 0x3 | nsec << 2,
+    )
+}
+
+// crc16 used by the uninit_bg feature to checksum group descriptors
+pub(crate) fn ext4_crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+// Superblock backups are in groups 0, 1 and powers of 3, 5 and 7
+fn ext4_group_has_super(group: u64) -> bool {
+    if group <= 1 {
+        return true;
+    }
+    [3, 5, 7].iter().any(|base| {
+        let mut power = *base;
+        while power < group {
+            power *= base;
+        }
+        power == group
+    })
+}
+
+// Userland ext4 writer: no journal, files are made of extents and group
+// descriptors are checksummed (uninit_bg) so that unused parts of the inode
+// tables don't need to be zeroed
+pub struct Ext4Writer<T> {
+    writer: T,
+    blocks_count: u64,
+    groups: u64,
+    inodes_per_group: u32,
+    gdt_blocks: u64,
+    itable_blocks: u64,
+    uuid: [u8; 16],
+    block_bitmap: Vec<u8>,
+    inode_bitmap: Vec<u8>,
+    next_block: u64,
+    next_ino: u32,
+    inodes: BTreeMap<u32, Ext4Inode>,
+    paths: HashMap<String, u32>,
+    dirents: BTreeMap<u32, Vec<(String, u32)>>,
+}
+
+struct Ext4File<'a, T: Read + Write + Seek> {
+    fs: &'a mut Ext4Writer<T>,
+    ino: u32,
+    pos: u64,
+}
+
+impl<T: Read + Write + Seek> Write for Ext4File<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.fs.inode(self.ino).map_err(io::Error::other)?.size;
+        // Fill holes with zeros, ext4 supports sparse files but we don't
+        if self.pos > size {
+            let zeros = vec![0; usize::try_from(self.pos - size).map_err(io::Error::other)?];
+            self.fs
+                .write_data(self.ino, size, &zeros)
+                .map_err(io::Error::other)?;
+        }
+        self.fs
+            .write_data(self.ino, self.pos, buf)
+            .map_err(io::Error::other)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek> Seek for Ext4File<'_, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let size = self.fs.inode(self.ino).map_err(io::Error::other)?.size;
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::other("invalid seek"))?;
+        Ok(self.pos)
+    }
+}
+
+impl<T: Read + Write + Seek> Ext4Writer<T> {
+    /// File systems UUID, written when unmounting
+    pub fn set_uuid(&mut self, uuid: [u8; 16]) {
+        self.uuid = uuid;
+    }
+
+    fn group_overhead(&self, group: u64) -> u64 {
+        let sb_blocks = if ext4_group_has_super(group) {
+            1 + self.gdt_blocks
+        } else {
+            0
+        };
+        sb_blocks + 2 + self.itable_blocks
+    }
+
+    // Block bitmap, inode bitmap and inode table of a group
+    fn group_layout(&self, group: u64) -> (u64, u64, u64) {
+        let block_bitmap =
+            group * EXT4_BLOCKS_PER_GROUP + self.group_overhead(group) - 2 - self.itable_blocks;
+        (block_bitmap, block_bitmap + 1, block_bitmap + 2)
+    }
+
+    fn inode(&self, ino: u32) -> Result<&Ext4Inode> {
+        self.inodes
+            .get(&ino)
+            .ok_or_else(|| Error::FSError(format!("inode {ino} not found")))
+    }
+
+    fn inode_mut(&mut self, ino: u32) -> Result<&mut Ext4Inode> {
+        self.inodes
+            .get_mut(&ino)
+            .ok_or_else(|| Error::FSError(format!("inode {ino} not found")))
+    }
+
+    fn set_block_used(&mut self, block: u64, used: bool) {
+        let (byte, bit) = ((block / 8) as usize, block % 8);
+        if used {
+            self.block_bitmap[byte] |= 1 << bit;
+        } else {
+            self.block_bitmap[byte] &= !(1 << bit);
+            // Reused by the next allocations
+            self.next_block = self.next_block.min(block);
+        }
+    }
+
+    fn block_used(&self, block: u64) -> bool {
+        self.block_bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn alloc_block(&mut self) -> Result<u64> {
+        while self.next_block < self.blocks_count && self.block_used(self.next_block) {
+            self.next_block += 1;
+        }
+        if self.next_block >= self.blocks_count {
+            return Err(Error::FSError("no space left on device".into()));
+        }
+        self.set_block_used(self.next_block, true);
+        self.next_block += 1;
+        Ok(self.next_block - 1)
+    }
+
+    fn set_inode_used(&mut self, ino: u32, used: bool) {
+        let index = (ino - 1) as usize;
+        if used {
+            self.inode_bitmap[index / 8] |= 1 << (index % 8);
+        } else {
+            self.inode_bitmap[index / 8] &= !(1 << (index % 8));
+            self.next_ino = self.next_ino.min(ino);
+        }
+    }
+
+    fn inode_used(&self, ino: u32) -> bool {
+        let index = (ino - 1) as usize;
+        self.inode_bitmap[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn alloc_inode(&mut self, inode: Ext4Inode) -> Result<u32> {
+        let inodes_count = self.groups * u64::from(self.inodes_per_group);
+        while u64::from(self.next_ino) <= inodes_count && self.inode_used(self.next_ino) {
+            self.next_ino += 1;
+        }
+        if u64::from(self.next_ino) > inodes_count {
+            return Err(Error::FSError("no free inode left".into()));
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.set_inode_used(ino, true);
+        if inode.is_dir() {
+            self.dirents.insert(ino, Vec::new());
+        }
+        self.inodes.insert(ino, inode);
+        Ok(ino)
+    }
+
+    // Physical block of a file block, allocated if past the end of the file
+    fn map_block(&mut self, ino: u32, block: u64) -> Result<u64> {
+        let block = u32::try_from(block)?;
+        for extent in self.inode(ino)?.extents.iter().rev() {
+            if block >= extent.block && block < extent.block + extent.len {
+                return Ok(extent.start + u64::from(block - extent.block));
+            }
+        }
+        let start = self.alloc_block()?;
+        let inode = self.inode_mut(ino)?;
+        if let Some(extent) = inode.extents.last_mut().filter(|extent| {
+            extent.block + extent.len == block
+                && extent.start + u64::from(extent.len) == start
+                && extent.len < EXT4_MAX_EXTENT_LEN
+        }) {
+            extent.len += 1;
+        } else if inode.extents.len() < EXT4_MAX_EXTENTS {
+            inode.extents.push(Ext4Extent {
+                block,
+                len: 1,
+                start,
+            });
+        } else {
+            self.set_block_used(start, false);
+            return Err(Error::FSError("file too fragmented".into()));
+        }
+        Ok(start)
+    }
+
+    fn write_data(&mut self, ino: u32, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let first = pos / EXT4_BLOCK_SIZE;
+            let start = self.map_block(ino, first)?;
+            // Write contiguous blocks at once
+            let mut len =
+                ((EXT4_BLOCK_SIZE - pos % EXT4_BLOCK_SIZE) as usize).min(buf.len() - done);
+            let mut count = 1;
+            while done + len < buf.len() && self.map_block(ino, first + count)? == start + count {
+                len += (EXT4_BLOCK_SIZE as usize).min(buf.len() - done - len);
+                count += 1;
+            }
+            self.writer.seek(SeekFrom::Start(
+                start * EXT4_BLOCK_SIZE + pos % EXT4_BLOCK_SIZE,
+            ))?;
+            self.writer.write_all(&buf[done..done + len])?;
+            done += len;
+        }
+        let end = offset + buf.len() as u64;
+        let inode = self.inode_mut(ino)?;
+        if end > inode.size {
+            inode.size = end;
+            // Don't leave previous content of the device after the end of the file
+            if !end.is_multiple_of(EXT4_BLOCK_SIZE) {
+                self.writer
+                    .write_all(&vec![0; (EXT4_BLOCK_SIZE - end % EXT4_BLOCK_SIZE) as usize])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Parent directory inode and name of a new entry
+    fn lookup_parent(&self, path: &str) -> Result<(u32, String)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." || name.len() > 255 || name.contains('\0')
+        {
+            return Err(Error::FSError(format!("invalid file name '{name}'")));
+        }
+        if self.paths.contains_key(path) {
+            return Err(Error::FSError(format!("{path} already exists")));
+        }
+        match self.paths.get(parent) {
+            Some(ino) if self.inode(*ino)?.is_dir() => Ok((*ino, name.to_string())),
+            _ => Err(Error::FSError(format!("parent of {path} not found"))),
+        }
+    }
+
+    fn new_entry(&mut self, path: &str, mode: u16, times: &Timestamps) -> Result<u32> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let ino = self.alloc_inode(Ext4Inode::new(mode, parent, times))?;
+        self.paths
+            .insert(path.trim_end_matches('/').to_string(), ino);
+        if let Some(entries) = self.dirents.get_mut(&parent) {
+            entries.push((name, ino));
+        }
+        Ok(ino)
+    }
+
+    fn write_dirs(&mut self) -> Result<()> {
+        let dirs: Vec<u32> = self.dirents.keys().copied().collect();
+        for ino in dirs {
+            let mut entries = vec![
+                (".".to_string(), ino),
+                ("..".to_string(), self.inode(ino)?.parent),
+            ];
+            entries.append(
+                &mut self
+                    .dirents
+                    .get_mut(&ino)
+                    .map(std::mem::take)
+                    .unwrap_or_default(),
+            );
+            let mut data: Vec<u8> = Vec::new();
+            let mut block_start = 0;
+            let mut last = 0;
+            for (name, child) in entries.iter() {
+                let rec_len = (8 + name.len() + 3) & !3;
+                // Last entry of a block spans until its end
+                if data.len() + rec_len > block_start + EXT4_BLOCK_SIZE as usize {
+                    let end = block_start + EXT4_BLOCK_SIZE as usize;
+                    data[last + 4..last + 6].copy_from_slice(&((end - last) as u16).to_le_bytes());
+                    data.resize(end, 0);
+                    block_start = end;
+                }
+                let file_type: u8 = if self.inode(*child)?.is_dir() { 2 } else { 1 };
+                last = data.len();
+                data.extend_from_slice(&child.to_le_bytes());
+                data.extend_from_slice(&(rec_len as u16).to_le_bytes());
+                data.push(name.len() as u8);
+                data.push(file_type);
+                data.extend_from_slice(name.as_bytes());
+                data.resize(last + rec_len, 0);
+            }
+            let end = block_start + EXT4_BLOCK_SIZE as usize;
+            data[last + 4..last + 6].copy_from_slice(&((end - last) as u16).to_le_bytes());
+            data.resize(end, 0);
+            self.write_data(ino, 0, &data)?;
+        }
+        Ok(())
+    }
+
+    fn write_extent_leaves(&mut self) -> Result<()> {
+        let inos: Vec<u32> = self
+            .inodes
+            .iter()
+            .filter(|(_, inode)| inode.extents.len() > EXT4_INODE_EXTENTS)
+            .map(|(ino, _)| *ino)
+            .collect();
+        for ino in inos {
+            let leaves_count = self.inode(ino)?.extents.len().div_ceil(EXT4_LEAF_EXTENTS);
+            let mut leaves = Vec::with_capacity(leaves_count);
+            for _ in 0..leaves_count {
+                leaves.push(self.alloc_block()?);
+            }
+            let nodes: Vec<Vec<u8>> = self
+                .inode(ino)?
+                .extents
+                .chunks(EXT4_LEAF_EXTENTS)
+                .map(|extents| {
+                    let mut data = vec![0; EXT4_BLOCK_SIZE as usize];
+                    ext4_extent_node(&mut data, extents, &[], EXT4_LEAF_EXTENTS);
+                    data
+                })
+                .collect();
+            for (leaf, data) in leaves.iter().zip(nodes) {
+                self.writer.seek(SeekFrom::Start(leaf * EXT4_BLOCK_SIZE))?;
+                self.writer.write_all(&data)?;
+            }
+            self.inode_mut(ino)?.leaves = leaves;
+        }
+        Ok(())
+    }
+
+    fn inode_to_bytes(&self, inode: &Ext4Inode, links: u16) -> Vec<u8> {
+        let mut data = vec![0; EXT4_INODE_SIZE as usize];
+        let blocks: u64 = inode
+            .extents
+            .iter()
+            .map(|extent| u64::from(extent.len))
+            .sum::<u64>()
+            + inode.leaves.len() as u64;
+        let sectors = blocks * (EXT4_BLOCK_SIZE / 512);
+        let perms: u16 = if inode.is_dir() { 0o755 } else { 0o644 };
+        data[0x0..0x2].copy_from_slice(&(inode.mode | perms).to_le_bytes());
+        data[0x4..0x8].copy_from_slice(&(inode.size as u32).to_le_bytes());
+        let times = [
+            (0x8, 0x8C, inode.times.atime),
+            (0xC, 0x84, inode.times.ctime),
+            (0x10, 0x88, inode.times.mtime),
+            (0x90, 0x94, inode.times.crtime),
+        ];
+        for (offset, extra_offset, nanos) in times {
+            let (time, time_extra) = ext4_timestamp(nanos);
+            data[offset..offset + 4].copy_from_slice(&time.to_le_bytes());
+            data[extra_offset..extra_offset + 4].copy_from_slice(&time_extra.to_le_bytes());
+        }
+        data[0x1A..0x1C].copy_from_slice(&links.to_le_bytes());
+        data[0x1C..0x20].copy_from_slice(&(sectors as u32).to_le_bytes());
+        data[0x20..0x24].copy_from_slice(&EXT4_EXTENTS_FL.to_le_bytes());
+        if inode.leaves.is_empty() {
+            ext4_extent_node(
+                &mut data[0x28..0x64],
+                &inode.extents,
+                &[],
+                EXT4_INODE_EXTENTS,
+            );
+        } else {
+            let index: Vec<(u32, u64)> = inode
+                .extents
+                .chunks(EXT4_LEAF_EXTENTS)
+                .zip(inode.leaves.iter())
+                .map(|(extents, leaf)| (extents[0].block, *leaf))
+                .collect();
+            ext4_extent_node(&mut data[0x28..0x64], &[], &index, EXT4_INODE_EXTENTS);
+        }
+        data[0x6C..0x70].copy_from_slice(&((inode.size >> 32) as u32).to_le_bytes());
+        data[0x74..0x76].copy_from_slice(&((sectors >> 32) as u16).to_le_bytes());
+        data[0x80..0x82].copy_from_slice(&32u16.to_le_bytes());
+        data
+    }
+
+    // Write inode tables, returns the number of unused inodes of each group
+    fn write_inode_tables(&mut self) -> Result<Vec<u32>> {
+        let ipg = self.inodes_per_group;
+        let mut dir_links: HashMap<u32, u32> = HashMap::new();
+        for inode in self.inodes.values().filter(|inode| inode.is_dir()) {
+            *dir_links.entry(inode.parent).or_default() += 1;
+        }
+        let mut itable_unused = Vec::with_capacity(self.groups as usize);
+        for group in 0..self.groups {
+            let first = (group * u64::from(ipg) + 1) as u32;
+            let last = first + ipg - 1;
+            let used = match self.inodes.range(first..=last).next_back() {
+                Some((ino, _)) => ino - first + 1,
+                None => 0,
+            };
+            itable_unused.push(ipg - used);
+            if used == 0 {
+                continue;
+            }
+            let mut table =
+                vec![0; (used as u64 * EXT4_INODE_SIZE).next_multiple_of(EXT4_BLOCK_SIZE) as usize];
+            for (ino, inode) in self.inodes.range(first..=last) {
+                let links = if inode.is_dir() {
+                    // Root's parent is itself
+                    let subdirs =
+                        dir_links.get(ino).copied().unwrap_or(0) - u32::from(*ino == EXT4_ROOT_INO);
+                    // DIR_NLINK: 1 means too many subdirectories to count
+                    u16::try_from(2 + subdirs)
+                        .ok()
+                        .filter(|links| *links < 65000)
+                        .unwrap_or(1)
+                } else {
+                    1
+                };
+                let offset = ((ino - first) as u64 * EXT4_INODE_SIZE) as usize;
+                table[offset..offset + EXT4_INODE_SIZE as usize]
+                    .copy_from_slice(&self.inode_to_bytes(inode, links));
+            }
+            let (_, _, itable) = self.group_layout(group);
+            self.writer
+                .seek(SeekFrom::Start(itable * EXT4_BLOCK_SIZE))?;
+            self.writer.write_all(&table)?;
+        }
+        Ok(itable_unused)
+    }
+
+    // Write bitmaps and returns group descriptors with free blocks and inodes count
+    fn write_bitmaps(&mut self, itable_unused: &[u32]) -> Result<(Vec<u8>, u64, u64)> {
+        let ipg = self.inodes_per_group as usize;
+        let mut gdt = vec![0; (self.gdt_blocks * EXT4_BLOCK_SIZE) as usize];
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for group in 0..self.groups {
+            let (block_bitmap_blk, inode_bitmap_blk, itable_blk) = self.group_layout(group);
+            let g = group as usize;
+            let block_bitmap = self.block_bitmap
+                [g * EXT4_BLOCK_SIZE as usize..(g + 1) * EXT4_BLOCK_SIZE as usize]
+                .to_vec();
+            let mut inode_bitmap = vec![0xFF; EXT4_BLOCK_SIZE as usize];
+            inode_bitmap[..ipg / 8]
+                .copy_from_slice(&self.inode_bitmap[g * ipg / 8..(g + 1) * ipg / 8]);
+            let group_free_blocks: u32 = block_bitmap.iter().map(|byte| byte.count_zeros()).sum();
+            let group_free_inodes: u32 = inode_bitmap.iter().map(|byte| byte.count_zeros()).sum();
+            let first = (group as u32) * self.inodes_per_group + 1;
+            let dirs = self
+                .inodes
+                .range(first..first + self.inodes_per_group)
+                .filter(|(_, inode)| inode.is_dir())
+                .count() as u16;
+            self.writer
+                .seek(SeekFrom::Start(block_bitmap_blk * EXT4_BLOCK_SIZE))?;
+            self.writer.write_all(&block_bitmap)?;
+            self.writer.write_all(&inode_bitmap)?;
+
+            let desc = &mut gdt[g * EXT4_DESC_SIZE as usize..(g + 1) * EXT4_DESC_SIZE as usize];
+            desc[0x0..0x4].copy_from_slice(&(block_bitmap_blk as u32).to_le_bytes());
+            desc[0x4..0x8].copy_from_slice(&(inode_bitmap_blk as u32).to_le_bytes());
+            desc[0x8..0xC].copy_from_slice(&(itable_blk as u32).to_le_bytes());
+            desc[0xC..0xE].copy_from_slice(&(group_free_blocks as u16).to_le_bytes());
+            desc[0xE..0x10].copy_from_slice(&(group_free_inodes as u16).to_le_bytes());
+            desc[0x10..0x12].copy_from_slice(&dirs.to_le_bytes());
+            desc[0x1C..0x1E].copy_from_slice(&(itable_unused[g] as u16).to_le_bytes());
+            let mut crc = ext4_crc16(0xFFFF, &self.uuid);
+            crc = ext4_crc16(crc, &(group as u32).to_le_bytes());
+            crc = ext4_crc16(crc, &desc[..0x1E]);
+            desc[0x1E..0x20].copy_from_slice(&crc.to_le_bytes());
+            free_blocks += u64::from(group_free_blocks);
+            free_inodes += u64::from(group_free_inodes);
+        }
+        Ok((gdt, free_blocks, free_inodes))
+    }
+
+    fn superblock(&self, free_blocks: u64, free_inodes: u64, timestamp: u32) -> Vec<u8> {
+        let mut sb = vec![0; 1024];
+        let mut put = |offset: usize, value: u32, size: usize| {
+            sb[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        };
+        put(0x0, self.groups as u32 * self.inodes_per_group, 4);
+        put(0x4, self.blocks_count as u32, 4);
+        put(0xC, free_blocks as u32, 4);
+        put(0x10, free_inodes as u32, 4);
+        // log2(block size) - 10
+        put(0x18, 2, 4);
+        put(0x1C, 2, 4);
+        put(0x20, EXT4_BLOCKS_PER_GROUP as u32, 4);
+        put(0x24, EXT4_BLOCKS_PER_GROUP as u32, 4);
+        put(0x28, self.inodes_per_group, 4);
+        put(0x30, timestamp, 4);
+        put(0x36, 0xFFFF, 2);
+        put(0x38, 0xEF53, 2);
+        // Clean, continue on errors
+        put(0x3A, 1, 2);
+        put(0x3C, 1, 2);
+        put(0x40, timestamp, 4);
+        // Dynamic inode sizes
+        put(0x4C, 1, 4);
+        put(0x54, EXT4_FIRST_INO, 4);
+        put(0x58, EXT4_INODE_SIZE as u32, 2);
+        put(
+            0x60,
+            EXT4_FEATURE_INCOMPAT_FILETYPE | EXT4_FEATURE_INCOMPAT_EXTENTS,
+            4,
+        );
+        put(
+            0x64,
+            EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER
+                | EXT4_FEATURE_RO_COMPAT_LARGE_FILE
+                | EXT4_FEATURE_RO_COMPAT_HUGE_FILE
+                | EXT4_FEATURE_RO_COMPAT_GDT_CSUM
+                | EXT4_FEATURE_RO_COMPAT_DIR_NLINK
+                | EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE,
+            4,
+        );
+        put(0x108, timestamp, 4);
+        put(0x15C, 32, 2);
+        put(0x15E, 32, 2);
+        sb[0x68..0x78].copy_from_slice(&self.uuid);
+        sb
+    }
+}
+
+// Extent tree node with either extents (leaf) or indexes
+fn ext4_extent_node(data: &mut [u8], extents: &[Ext4Extent], index: &[(u32, u64)], max: usize) {
+    let (entries, depth) = if index.is_empty() {
+        (extents.len(), 0u16)
+    } else {
+        (index.len(), 1)
+    };
+    data[0x0..0x2].copy_from_slice(&EXT4_EXTENT_MAGIC.to_le_bytes());
+    data[0x2..0x4].copy_from_slice(&(entries as u16).to_le_bytes());
+    data[0x4..0x6].copy_from_slice(&(max as u16).to_le_bytes());
+    data[0x6..0x8].copy_from_slice(&depth.to_le_bytes());
+    for (i, extent) in extents.iter().enumerate() {
+        let entry = &mut data[12 + i * 12..24 + i * 12];
+        entry[0x0..0x4].copy_from_slice(&extent.block.to_le_bytes());
+        entry[0x4..0x6].copy_from_slice(&(extent.len as u16).to_le_bytes());
+        entry[0x6..0x8].copy_from_slice(&((extent.start >> 32) as u16).to_le_bytes());
+        entry[0x8..0xC].copy_from_slice(&(extent.start as u32).to_le_bytes());
+    }
+    for (i, (block, leaf)) in index.iter().enumerate() {
+        let entry = &mut data[12 + i * 12..24 + i * 12];
+        entry[0x0..0x4].copy_from_slice(&block.to_le_bytes());
+        entry[0x4..0x8].copy_from_slice(&(*leaf as u32).to_le_bytes());
+        entry[0x8..0xA].copy_from_slice(&((*leaf >> 32) as u16).to_le_bytes());
+    }
+}
+
+impl<T: Read + Write + Seek> FSWrite<T> for Ext4Writer<T> {
+    fn mkfs(
+        writer: T,
+        sector_size: u64,
+        sector_count: u64,
+        _fstype: Option<OutFsType>,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        // Without the 64bit feature, leave the rest of the device unused
+        let mut blocks_count = (sector_size * sector_count / EXT4_BLOCK_SIZE).min(0xFFFF_FFFF);
+        let mut groups = blocks_count.div_ceil(EXT4_BLOCKS_PER_GROUP);
+        let inodes_per_group = (blocks_count * EXT4_BLOCK_SIZE / EXT4_BYTES_PER_INODE)
+            .div_ceil(groups.max(1))
+            .next_multiple_of(EXT4_BLOCK_SIZE / EXT4_INODE_SIZE)
+            .clamp(EXT4_BLOCK_SIZE / EXT4_INODE_SIZE, 8 * EXT4_BLOCK_SIZE);
+        let mut fs = Ext4Writer {
+            writer,
+            blocks_count,
+            groups,
+            inodes_per_group: inodes_per_group as u32,
+            gdt_blocks: (groups * EXT4_DESC_SIZE).div_ceil(EXT4_BLOCK_SIZE),
+            itable_blocks: inodes_per_group * EXT4_INODE_SIZE / EXT4_BLOCK_SIZE,
+            uuid: [0; 16],
+            block_bitmap: Vec::new(),
+            inode_bitmap: Vec::new(),
+            next_block: 0,
+            next_ino: EXT4_FIRST_INO,
+            inodes: BTreeMap::new(),
+            paths: HashMap::new(),
+            dirents: BTreeMap::new(),
+        };
+        // Drop last group if too small to hold its metadata and some data
+        if groups > 0
+            && blocks_count - (groups - 1) * EXT4_BLOCKS_PER_GROUP
+                < fs.group_overhead(groups - 1) + 64
+        {
+            groups -= 1;
+            blocks_count = groups * EXT4_BLOCKS_PER_GROUP;
+        }
+        if groups == 0 {
+            return Err(Error::FSError("device too small for ext4".into()));
+        }
+        fs.groups = groups;
+        fs.blocks_count = blocks_count;
+
+        fs.block_bitmap = vec![0; (groups * EXT4_BLOCK_SIZE) as usize];
+        fs.inode_bitmap = vec![0; (groups * inodes_per_group / 8) as usize];
+        for group in 0..groups {
+            let first = group * EXT4_BLOCKS_PER_GROUP;
+            for block in first..first + fs.group_overhead(group) {
+                fs.set_block_used(block, true);
+            }
+        }
+        // Blocks past the end of the last group
+        for block in blocks_count..groups * EXT4_BLOCKS_PER_GROUP {
+            fs.set_block_used(block, true);
+        }
+        // Reserved inodes
+        for ino in 1..EXT4_FIRST_INO {
+            fs.set_inode_used(ino, true);
+        }
+        fs.inodes.insert(
+            EXT4_ROOT_INO,
+            Ext4Inode::new(EXT4_S_IFDIR, EXT4_ROOT_INO, &Timestamps::default()),
+        );
+        fs.dirents.insert(EXT4_ROOT_INO, Vec::new());
+        fs.paths.insert(String::new(), EXT4_ROOT_INO);
+        if fs.new_entry("/lost+found", EXT4_S_IFDIR, &Timestamps::default())? != EXT4_LOST_FOUND_INO
+        {
+            return Err(Error::FSError("couldn't create lost+found".into()));
+        }
+        Ok(fs)
+    }
+
+    fn newfile(&mut self, path: &str, times: &Timestamps) -> Result<Box<dyn WriteSeek + '_>> {
+        log::trace!("new file {}", path);
+        let ino = self.new_entry(path, EXT4_S_IFREG, times)?;
+        Ok(Box::new(Ext4File {
+            fs: self,
+            ino,
+            pos: 0,
+        }))
+    }
+
+    fn newdir(&mut self, path: &str, times: &Timestamps) -> Result<()> {
+        log::trace!("new dir {}", path);
+        self.new_entry(path, EXT4_S_IFDIR, times)?;
+        Ok(())
+    }
+
+    fn removefile(&mut self, path: &str) -> Result<()> {
+        log::trace!("remove file {}", path);
+        let path = path.trim_end_matches('/');
+        let ino = match self.paths.get(path) {
+            Some(ino) if *ino != EXT4_ROOT_INO => *ino,
+            _ => return Err(Error::FSError(format!("{path} not found"))),
+        };
+        if self
+            .dirents
+            .get(&ino)
+            .is_some_and(|entries| !entries.is_empty())
+        {
+            return Err(Error::FSError(format!("{path} is not empty")));
+        }
+        let inode = self
+            .inodes
+            .remove(&ino)
+            .ok_or_else(|| Error::FSError(format!("inode {ino} not found")))?;
+        for extent in inode.extents.iter() {
+            for block in extent.start..extent.start + u64::from(extent.len) {
+                self.set_block_used(block, false);
+            }
+        }
+        self.set_inode_used(ino, false);
+        self.paths.remove(path);
+        self.dirents.remove(&ino);
+        if let Some(entries) = self.dirents.get_mut(&inode.parent) {
+            entries.retain(|(_, child)| *child != ino);
+        }
+        Ok(())
+    }
+
+    fn settimestamp(&mut self, path: &str, times: &Timestamps) -> Result<()> {
+        let ino = *self
+            .paths
+            .get(path.trim_end_matches('/'))
+            .ok_or_else(|| Error::FSError(format!("{path} not found")))?;
+        self.inode_mut(ino)?.times = times.or_mtime();
+        Ok(())
+    }
+
+    fn unmount_fs(mut self: Box<Self>) -> Result<T> {
+        log::trace!("unmount fs");
+        // No clock in the sandbox, date the file system with its newest entry
+        let timestamp = self
+            .inodes
+            .values()
+            .map(|inode| inode.times.mtime)
+            .max()
+            .unwrap_or(0);
+        for ino in [EXT4_ROOT_INO, EXT4_LOST_FOUND_INO] {
+            self.inode_mut(ino)?.times = Timestamps {
+                mtime: timestamp,
+                ..Default::default()
+            }
+            .or_mtime();
+        }
+        self.write_dirs()?;
+        self.write_extent_leaves()?;
+        let itable_unused = self.write_inode_tables()?;
+        let (gdt, free_blocks, free_inodes) = self.write_bitmaps(&itable_unused)?;
+        let (time, _) = ext4_timestamp(timestamp);
+        for group in (0..self.groups).filter(|group| ext4_group_has_super(*group)) {
+            let mut sb = self.superblock(free_blocks, free_inodes, time);
+            sb[0x5A..0x5C].copy_from_slice(&(group as u16).to_le_bytes());
+            let mut block = vec![0; EXT4_BLOCK_SIZE as usize];
+            // The first superblock is after the boot sector, backups start their group
+            let offset = if group == 0 {
+                EXT4_SUPERBLOCK_OFFSET as usize
+            } else {
+                0
+            };
+            block[offset..offset + sb.len()].copy_from_slice(&sb);
+            self.writer.seek(SeekFrom::Start(
+                group * EXT4_BLOCKS_PER_GROUP * EXT4_BLOCK_SIZE,
+            ))?;
+            self.writer.write_all(&block)?;
+            self.writer.write_all(&gdt)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsck;
+    use std::io::Cursor;
+
+    const IMAGE_SIZE: usize = 32 * 1024 * 1024;
+
+    fn times(secs: i64) -> Timestamps {
+        Timestamps {
+            mtime: Timestamps::nanos(secs, 0),
+            ..Default::default()
+        }
+    }
+
+    fn write_file(fs: &mut Ext4Writer<Cursor<Vec<u8>>>, path: &str, data: &[u8]) {
+        let mut file = fs.newfile(path, &times(1_600_000_000)).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn big_file() -> Vec<u8> {
+        (0..300 * 1024).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn image() -> Vec<u8> {
+        let mut fs = Ext4Writer::mkfs(
+            Cursor::new(vec![0; IMAGE_SIZE]),
+            512,
+            (IMAGE_SIZE / 512) as u64,
+            Some(OutFsType::Ext4),
+        )
+        .unwrap();
+        fs.set_uuid([0x42; 16]);
+        fs.newdir("/dir", &times(1_500_000_000)).unwrap();
+        fs.newdir("/dir/sub", &times(1_500_000_000)).unwrap();
+        write_file(&mut fs, "/hello.txt", b"hello");
+        write_file(&mut fs, "/dir/big.bin", &big_file());
+        // Enough entries for the directory to span several blocks
+        for i in 0..150 {
+            write_file(
+                &mut fs,
+                &format!("/dir/sub/file_with_a_rather_long_name_{i:03}"),
+                &[i as u8; 10],
+            );
+        }
+        fs.removefile("/dir/sub/file_with_a_rather_long_name_000")
+            .unwrap();
+        fs.settimestamp("/dir", &times(1_700_000_000)).unwrap();
+        Box::new(fs).unmount_fs().unwrap().into_inner()
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = image();
+        assert_eq!(
+            fsck::check(&mut Cursor::new(&image), "Linux/Ext", image.len() as u64).unwrap(),
+            vec![]
+        );
+
+        let mut fs = Ext4::new(image, 512).unwrap();
+        let mut root: Vec<(String, i32)> = fs
+            .read_dir("", None)
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.path, file.ftype))
+            .collect();
+        root.sort();
+        assert_eq!(
+            root,
+            vec![
+                ("/dir".into(), FileType::Directory as i32),
+                ("/hello.txt".into(), FileType::Regular as i32),
+            ]
+        );
+        let (ftype, _, dir_times) = fs.get_attr("/dir").unwrap();
+        assert_eq!(ftype, FileType::Directory);
+        assert_eq!(dir_times.mtime_secs(), 1_700_000_000);
+
+        let big = big_file();
+        let mut buf = vec![0; big.len() + 10];
+        let len = buf.len() as u64;
+        assert_eq!(
+            fs.read_file("/dir/big.bin", &mut buf, 0, len).unwrap(),
+            big.len() as u64
+        );
+        assert_eq!(&buf[..big.len()], big.as_slice());
+        assert_eq!(fs.read_file("/hello.txt", &mut buf, 0, len).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        let sub = fs.read_dir("/dir/sub", None).unwrap();
+        assert_eq!(sub.len(), 149);
+        assert!(sub
+            .iter()
+            .all(|file| file.size == 10 && !file.path.ends_with("_000")));
+        assert_eq!(fs.read_dir("/dir/sub", Some(149)).unwrap().len(), 149);
+        assert!(fs.read_dir("/dir/sub", Some(148)).is_err());
+    }
+
+    #[test]
+    fn removed_file_space_reused() {
+        let mut fs = Ext4Writer::mkfs(
+            Cursor::new(vec![0; IMAGE_SIZE]),
+            512,
+            (IMAGE_SIZE / 512) as u64,
+            Some(OutFsType::Ext4),
+        )
+        .unwrap();
+        write_file(&mut fs, "/a.bin", &big_file());
+        let ino = fs.paths["/a.bin"];
+        let start = fs.inode(ino).unwrap().extents[0].start;
+        fs.removefile("/a.bin").unwrap();
+        write_file(&mut fs, "/b.bin", &big_file());
+        let b = fs.inode(fs.paths["/b.bin"]).unwrap();
+        assert_eq!(fs.paths["/b.bin"], ino);
+        assert_eq!(b.extents[0].start, start);
+
+        let image = Box::new(fs).unmount_fs().unwrap().into_inner();
+        assert_eq!(
+            fsck::check(&mut Cursor::new(&image), "Linux/Ext", image.len() as u64).unwrap(),
+            vec![]
+        );
+        let mut fs = Ext4::new(image, 512).unwrap();
+        let big = big_file();
+        let mut buf = vec![0; big.len()];
+        let len = buf.len() as u64;
+        assert_eq!(fs.read_file("/b.bin", &mut buf, 0, len).unwrap(), len);
+        assert_eq!(buf, big);
+    }
+}
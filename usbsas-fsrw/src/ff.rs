@@ -4,7 +4,19 @@ use std::{
     convert::TryFrom,
     io::{Read, Seek, Write},
 };
-use usbsas_proto::common::{FileInfo, FileType, OutFsType};
+use usbsas_proto::common::{FileInfo, FileType, OutFsType, Timestamps};
+
+// Only the modification time is available with ff
+fn fat_timestamps(timestamp: i64) -> Timestamps {
+    Timestamps {
+        mtime: Timestamps::nanos(timestamp, 0),
+        ..Default::default()
+    }
+}
+
+// FAT dates range from 1980 to 2107
+const FAT_MIN_TIMESTAMP: i64 = 315532800;
+const FAT_MAX_TIMESTAMP: i64 = 4354819198;
 
 pub struct FatFsReader<T> {
     fs: ff::FatFs<T>,
@@ -16,25 +28,34 @@ impl<T: Read + Seek> FSRead<T> for FatFsReader<T> {
         Ok(FatFsReader { fs })
     }
 
-    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)> {
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)> {
         log::trace!("get attr {}", path);
         let file_info = self
             .fs
             .get_attr(path)
             .map_err(|err| Error::FSError(format!("Couldn't get attr for {path}: {err}")))?;
         if file_info.is_dir() {
-            Ok((FileType::Directory, file_info.size, file_info.timestamp))
+            Ok((
+                FileType::Directory,
+                file_info.size,
+                fat_timestamps(file_info.timestamp),
+            ))
         } else {
-            Ok((FileType::Regular, file_info.size, file_info.timestamp))
+            Ok((
+                FileType::Regular,
+                file_info.size,
+                fat_timestamps(file_info.timestamp),
+            ))
         }
     }
 
-    fn read_dir(&mut self, path: &str) -> Result<Vec<FileInfo>> {
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>> {
         log::trace!("readdir {}", path);
-        Ok(self
+        let entries = self
             .fs
-            .read_dir(path)
-            .map_err(|err| Error::FSError(format!("Couldn't read dir {path}: {err}")))?
+            .read_dir(path, max_entries)
+            .map_err(|err| Error::FSError(format!("Couldn't read dir {path}: {err}")))?;
+        Ok(entries
             .iter()
             .map(|x| FileInfo {
                 path: format!("{}/{}", path.trim_end_matches('/'), x.name),
@@ -45,6 +66,9 @@ impl<T: Read + Seek> FSRead<T> for FatFsReader<T> {
                 } else {
                     FileType::Regular.into()
                 },
+                symlink_target: String::new(),
+                streams: Vec::new(),
+                times: Some(fat_timestamps(x.timestamp)),
             })
             .collect())
     }
@@ -72,16 +96,25 @@ pub struct FatFsWriter<T> {
     fs: ff::FatFs<T>,
 }
 
-impl<T: Read + Write + Seek> FSWrite<T> for FatFsWriter<T> {
-    fn mkfs(
+impl<T: Read + Write + Seek> FatFsWriter<T> {
+    /// Create a file system without partition table (`writer` is already a
+    /// partition)
+    pub fn mkfs_sfd(
         writer: T,
         sector_size: u64,
         sector_count: u64,
         fstype: Option<OutFsType>,
-    ) -> Result<Self>
-    where
-        Self: Sized,
-    {
+    ) -> Result<Self> {
+        Self::format(writer, sector_size, sector_count, fstype, ff::FM_SFD as u8)
+    }
+
+    fn format(
+        writer: T,
+        sector_size: u64,
+        sector_count: u64,
+        fstype: Option<OutFsType>,
+        flags: u8,
+    ) -> Result<Self> {
         let fstype = match fstype {
             Some(OutFsType::Exfat) => ff::FM_EXFAT as u8,
             Some(OutFsType::Fat) => ff::FM_FAT32 as u8,
@@ -92,24 +125,38 @@ impl<T: Read + Write + Seek> FSWrite<T> for FatFsWriter<T> {
                 writer,
                 u32::try_from(sector_size)?,
                 u32::try_from(sector_count)?,
-                fstype,
+                fstype | flags,
             )?,
         })
     }
+}
+
+impl<T: Read + Write + Seek> FSWrite<T> for FatFsWriter<T> {
+    fn mkfs(
+        writer: T,
+        sector_size: u64,
+        sector_count: u64,
+        fstype: Option<OutFsType>,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::format(writer, sector_size, sector_count, fstype, 0)
+    }
 
-    fn newfile(&mut self, path: &str, _timestamp: i64) -> Result<Box<dyn WriteSeek + '_>> {
+    fn newfile(&mut self, path: &str, _times: &Timestamps) -> Result<Box<dyn WriteSeek + '_>> {
         log::trace!("new file {}", path);
         Ok(Box::new(self.fs.new_file(path).map_err(|err| {
             Error::FSError(format!("Couldn't create file {path}: {err}"))
         })?))
     }
 
-    fn newdir(&mut self, path: &str, timestamp: i64) -> Result<()> {
+    fn newdir(&mut self, path: &str, times: &Timestamps) -> Result<()> {
         log::trace!("new dir: {}", path);
         self.fs
             .new_dir(path)
             .map_err(|err| Error::FSError(format!("Couldn't create dir {path}: {err}")))?;
-        self.settimestamp(path, timestamp)?;
+        self.settimestamp(path, times)?;
         Ok(())
     }
 
@@ -121,10 +168,15 @@ impl<T: Read + Write + Seek> FSWrite<T> for FatFsWriter<T> {
         Ok(())
     }
 
-    fn settimestamp(&mut self, path: &str, timestamp: i64) -> Result<()> {
+    fn settimestamp(&mut self, path: &str, times: &Timestamps) -> Result<()> {
         log::trace!("set timestamp");
         self.fs
-            .set_timestamp(path, timestamp)
+            .set_timestamp(
+                path,
+                times
+                    .mtime_secs()
+                    .clamp(FAT_MIN_TIMESTAMP, FAT_MAX_TIMESTAMP),
+            )
             .map_err(|err| Error::FSError(format!("Couldn't rm file {path}: {err}")))?;
         Ok(())
     }
@@ -0,0 +1,1499 @@
+//! Read-only consistency check of source file systems. It is done on the raw
+//! volume, before it is parsed, so that crafted structures are reported as
+//! anomalies instead of surfacing as parsing errors.
+
+use crate::ext4fs::ext4_crc16;
+use crate::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Seek, SeekFrom},
+};
+use usbsas_proto::common::{FsAnomaly, FsAnomalyKind};
+
+// The check stops after this many anomalies or entries
+const MAX_ANOMALIES: usize = 1000;
+const MAX_ENTRIES: usize = 1_000_000;
+// Maximum size of a single read
+const READ_CHUNK: usize = 1 << 20;
+
+const NTFS_ROOT_RECORD: u64 = 5;
+const NTFS_UPDATE_STRIDE: usize = 512;
+
+// Directories larger than this are only partly checked (FAT directories
+// can't be larger than 65536 entries)
+const FAT_MAX_DIR_SIZE: u64 = 65536 * 32;
+
+const EXT4_ROOT_INO: u32 = 2;
+const EXT4_MAX_DIR_BLOCKS: usize = 1 << 16;
+// Extent index and indirect blocks read for a directory
+const EXT4_MAX_TREE_BLOCKS: usize = 1 << 12;
+
+fn le_u16(buf: &[u8], off: usize) -> u16 {
+    buf.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .unwrap_or(0)
+}
+
+fn le_u32(buf: &[u8], off: usize) -> u32 {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .unwrap_or(0)
+}
+
+fn le_u64(buf: &[u8], off: usize) -> u64 {
+    u64::from(le_u32(buf, off)) | u64::from(le_u32(buf, off + 4)) << 32
+}
+
+fn utf16_name(buf: &[u8]) -> String {
+    let units: Vec<u16> = buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0 && *c != 0xFFFF)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+// crc32c as used by ext4 (no final inversion)
+fn ext4_crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Check the volume of type `fs_type` and `size` bytes (as detected in its
+/// partition info). File systems without checks return no anomalies.
+pub fn check<T: Read + Seek>(reader: &mut T, fs_type: &str, size: u64) -> Result<Vec<FsAnomaly>> {
+    let mut checker = Checker {
+        reader,
+        size,
+        anomalies: Vec::new(),
+        entries: 0,
+    };
+    let res = match fs_type {
+        "FAT" => checker.fat(),
+        "EXFAT" => checker.exfat(),
+        "NTFS" => checker.ntfs(),
+        "Linux/Ext" => checker.ext4(),
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        checker.anomaly(
+            FsAnomalyKind::BadStructure,
+            "",
+            format!("check aborted: {err}"),
+        );
+    }
+    checker.reader.seek(SeekFrom::Start(0))?;
+    Ok(checker.anomalies)
+}
+
+// FAT or exFAT allocation table, data clusters start at 2
+struct Fat {
+    next: Vec<u32>,
+    // End of chain markers are above or equal to `eoc`
+    eoc: u32,
+    bad: u32,
+    cluster_size: u64,
+    heap_offset: u64,
+    // Index (+1) in `paths` of the entry owning each cluster
+    owners: Vec<u32>,
+    paths: Vec<String>,
+}
+
+struct FatEntry {
+    name: String,
+    is_dir: bool,
+    start: u32,
+    size: u64,
+    // exFAT "NoFatChain" entries
+    contiguous: bool,
+    anomaly: Option<(FsAnomalyKind, String)>,
+}
+
+// Entries of a FAT12/16/32 directory, long names are used when present
+fn fat_dir_entries(data: &[u8], fat32: bool) -> Vec<FatEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Vec<u8> = Vec::new();
+    for entry in data.chunks_exact(32) {
+        match entry[0] {
+            0 => break,
+            0xE5 => {
+                long_name.clear();
+                continue;
+            }
+            _ => (),
+        }
+        let attr = entry[11];
+        if attr & 0x3F == 0x0F {
+            // Long name entries precede the short one, in reverse order
+            if entry[0] & 0x40 != 0 {
+                long_name.clear();
+            }
+            let mut part: Vec<u8> = [1..11, 14..26, 28..32]
+                .into_iter()
+                .flat_map(|range| entry[range].to_vec())
+                .collect();
+            part.append(&mut long_name);
+            long_name = part;
+            continue;
+        }
+        if attr & 0x08 != 0 || entry[0] == b'.' {
+            long_name.clear();
+            continue;
+        }
+        let name = if long_name.is_empty() {
+            let mut short = entry[..8].to_vec();
+            if short[0] == 0x05 {
+                short[0] = 0xE5;
+            }
+            let base = String::from_utf8_lossy(&short).trim_end().to_string();
+            let ext = String::from_utf8_lossy(&entry[8..11])
+                .trim_end()
+                .to_string();
+            if ext.is_empty() {
+                base
+            } else {
+                format!("{base}.{ext}")
+            }
+        } else {
+            utf16_name(&long_name)
+        };
+        long_name.clear();
+        let high = if fat32 { le_u16(entry, 20) } else { 0 };
+        entries.push(FatEntry {
+            name,
+            is_dir: attr & 0x10 != 0,
+            start: u32::from(high) << 16 | u32::from(le_u16(entry, 26)),
+            size: u64::from(le_u32(entry, 28)),
+            contiguous: false,
+            anomaly: None,
+        });
+    }
+    entries
+}
+
+// Entries of an exFAT directory: file entry sets, allocation bitmap and up-case table
+fn exfat_dir_entries(data: &[u8]) -> Vec<FatEntry> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(entry) = data.get(pos..pos + 32) {
+        match entry[0] {
+            0 => break,
+            0x81 | 0x82 => entries.push(FatEntry {
+                name: if entry[0] == 0x81 {
+                    "$Bitmap".into()
+                } else {
+                    "$UpCase".into()
+                },
+                is_dir: false,
+                start: le_u32(entry, 20),
+                size: le_u64(entry, 24),
+                contiguous: false,
+                anomaly: None,
+            }),
+            0x85 => {
+                let set_len = 32 * (usize::from(entry[1]) + 1);
+                let set = match data.get(pos..pos + set_len) {
+                    Some(set) if set_len >= 64 && set[32] == 0xC0 => set,
+                    _ => {
+                        entries.push(FatEntry {
+                            name: format!("<entry {}>", pos / 32),
+                            is_dir: false,
+                            start: 0,
+                            size: 0,
+                            contiguous: true,
+                            anomaly: Some((
+                                FsAnomalyKind::BadStructure,
+                                "invalid file entry set".into(),
+                            )),
+                        });
+                        pos += 32;
+                        continue;
+                    }
+                };
+                let checksum = set
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != 2 && *i != 3)
+                    .fold(0u16, |sum, (_, byte)| {
+                        sum.rotate_right(1).wrapping_add(u16::from(*byte))
+                    });
+                let stream = &set[32..64];
+                let name: Vec<u8> = set[64..]
+                    .chunks_exact(32)
+                    .filter(|name| name[0] == 0xC1)
+                    .flat_map(|name| name[2..].to_vec())
+                    .take(2 * usize::from(stream[3]))
+                    .collect();
+                entries.push(FatEntry {
+                    name: utf16_name(&name),
+                    is_dir: le_u16(entry, 4) & 0x10 != 0,
+                    start: le_u32(stream, 20),
+                    size: le_u64(stream, 24),
+                    contiguous: stream[1] & 0x2 != 0,
+                    anomaly: (checksum != le_u16(entry, 2)).then(|| {
+                        (
+                            FsAnomalyKind::BadChecksum,
+                            "file entry set checksum mismatch".into(),
+                        )
+                    }),
+                });
+                pos += set_len;
+                continue;
+            }
+            _ => (),
+        }
+        pos += 32;
+    }
+    entries
+}
+
+struct NtfsNode {
+    parent: u64,
+    name: String,
+    is_dir: bool,
+}
+
+// Apply the update sequence of an MFT record, false if a stride doesn't end with it
+fn ntfs_fixup(record: &mut [u8]) -> bool {
+    let usa_offset = usize::from(le_u16(record, 4));
+    let usa_count = usize::from(le_u16(record, 6));
+    if usa_count != record.len() / NTFS_UPDATE_STRIDE + 1
+        || usa_offset + 2 * usa_count > NTFS_UPDATE_STRIDE
+    {
+        return false;
+    }
+    let usn = [record[usa_offset], record[usa_offset + 1]];
+    for i in 1..usa_count {
+        let end = i * NTFS_UPDATE_STRIDE;
+        if record[end - 2..end] != usn {
+            return false;
+        }
+        record[end - 2] = record[usa_offset + 2 * i];
+        record[end - 1] = record[usa_offset + 2 * i + 1];
+    }
+    true
+}
+
+// (type, bytes) of the attributes of an MFT record, None if they overflow it
+fn ntfs_attributes(record: &[u8]) -> Option<Vec<(u32, &[u8])>> {
+    let mut attributes = Vec::new();
+    let mut pos = usize::from(le_u16(record, 0x14));
+    loop {
+        let attr_type = le_u32(record, pos);
+        if attr_type == 0xFFFF_FFFF {
+            return Some(attributes);
+        }
+        let len = le_u32(record, pos + 4) as usize;
+        if len < 24 || pos + len > record.len() {
+            return None;
+        }
+        attributes.push((attr_type, &record[pos..pos + len]));
+        pos += len;
+    }
+}
+
+// (lcn, clusters) data runs of a non resident attribute, lcn is None for sparse runs
+fn ntfs_runs(attr: &[u8]) -> Option<Vec<(Option<u64>, u64)>> {
+    let mut runs = Vec::new();
+    let mut pos = usize::from(le_u16(attr, 0x20));
+    let mut lcn: i64 = 0;
+    while let Some(&header) = attr.get(pos) {
+        if header == 0 {
+            break;
+        }
+        let (len_size, off_size) = (usize::from(header & 0xF), usize::from(header >> 4));
+        if len_size == 0 || len_size > 8 || off_size > 8 {
+            return None;
+        }
+        let bytes = attr.get(pos + 1..pos + 1 + len_size + off_size)?;
+        let mut len = [0; 8];
+        len[..len_size].copy_from_slice(&bytes[..len_size]);
+        let offset = match bytes[len_size..].last() {
+            Some(last) => {
+                let mut offset = if *last & 0x80 != 0 { [0xFF; 8] } else { [0; 8] };
+                offset[..off_size].copy_from_slice(&bytes[len_size..]);
+                Some(i64::from_le_bytes(offset))
+            }
+            None => None,
+        };
+        let len = u64::from_le_bytes(len);
+        match offset {
+            Some(offset) => {
+                lcn = lcn.checked_add(offset).filter(|lcn| *lcn >= 0)?;
+                runs.push((Some(lcn as u64), len));
+            }
+            None => runs.push((None, len)),
+        }
+        pos += 1 + len_size + off_size;
+    }
+    Some(runs)
+}
+
+struct Ext4Layout {
+    block_size: u64,
+    blocks: u64,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    inode_tables: Vec<u64>,
+    filetype: bool,
+}
+
+impl Ext4Layout {
+    // Byte offset of a block, None if it is out of the file system
+    fn offset(&self, block: u64) -> Option<u64> {
+        (block < self.blocks)
+            .then(|| block.checked_mul(self.block_size))
+            .flatten()
+    }
+}
+
+// Blocks of a directory being collected, and its extent index or indirect
+// blocks already read
+struct Ext4DirBlocks {
+    tree: HashSet<u64>,
+    blocks: Vec<u64>,
+    max: usize,
+}
+
+struct Checker<'a, T> {
+    reader: &'a mut T,
+    size: u64,
+    anomalies: Vec<FsAnomaly>,
+    entries: usize,
+}
+
+impl<T: Read + Seek> Checker<'_, T> {
+    // Sizes come from the volume, the buffer only grows with what was actually read
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let len = usize::try_from(len)?;
+        let mut buf = Vec::new();
+        self.reader.seek(SeekFrom::Start(offset))?;
+        while buf.len() < len {
+            let start = buf.len();
+            buf.resize(start + READ_CHUNK.min(len - start), 0);
+            self.reader.read_exact(&mut buf[start..])?;
+        }
+        Ok(buf)
+    }
+
+    fn anomaly(&mut self, kind: FsAnomalyKind, location: &str, detail: String) {
+        log::warn!("fs check: {}: {}", location, detail);
+        if self.anomalies.len() < MAX_ANOMALIES {
+            self.anomalies.push(FsAnomaly {
+                kind: kind.into(),
+                location: location.into(),
+                detail,
+            });
+        } else if self.anomalies.len() == MAX_ANOMALIES {
+            self.anomalies.push(FsAnomaly {
+                kind: FsAnomalyKind::Truncated.into(),
+                location: String::new(),
+                detail: "too many anomalies".into(),
+            });
+        }
+    }
+
+    // Count a visited entry, false when the check must stop
+    fn proceed(&mut self) -> bool {
+        self.entries += 1;
+        if self.entries == MAX_ENTRIES {
+            self.anomaly(FsAnomalyKind::Truncated, "", "too many entries".into());
+        }
+        self.entries < MAX_ENTRIES && self.anomalies.len() < MAX_ANOMALIES
+    }
+
+    // Claim the clusters of an entry, returns them if `keep` is set and their count
+    fn fat_chain(
+        &mut self,
+        fat: &mut Fat,
+        path: &str,
+        entry: &FatEntry,
+        keep: bool,
+    ) -> (Vec<u32>, u64) {
+        fat.paths.push(path.to_string());
+        let owner = fat.paths.len() as u32;
+        let contiguous = entry
+            .contiguous
+            .then(|| entry.size.div_ceil(fat.cluster_size));
+        let mut clusters = Vec::new();
+        let mut count = 0;
+        let mut cluster = entry.start;
+        loop {
+            if contiguous.is_some_and(|len| count >= len) {
+                break;
+            }
+            if cluster < 2 || cluster as usize >= fat.next.len() {
+                self.anomaly(
+                    FsAnomalyKind::BadStructure,
+                    path,
+                    format!("cluster {cluster} out of range"),
+                );
+                break;
+            }
+            match fat.owners[cluster as usize] {
+                0 => (),
+                other if other == owner => {
+                    self.anomaly(
+                        FsAnomalyKind::ChainLoop,
+                        path,
+                        format!("cluster {cluster} already in the chain"),
+                    );
+                    break;
+                }
+                other => {
+                    let detail = format!(
+                        "cluster {cluster} also used by {}",
+                        fat.paths[other as usize - 1]
+                    );
+                    self.anomaly(FsAnomalyKind::CrossLink, path, detail);
+                    break;
+                }
+            }
+            fat.owners[cluster as usize] = owner;
+            count += 1;
+            if keep {
+                clusters.push(cluster);
+            }
+            if contiguous.is_some() {
+                cluster += 1;
+                continue;
+            }
+            let next = fat.next[cluster as usize];
+            if next >= fat.eoc {
+                break;
+            } else if next == fat.bad || next == 0 {
+                self.anomaly(
+                    FsAnomalyKind::BadStructure,
+                    path,
+                    format!("chain of cluster {cluster} ends with a free or bad cluster"),
+                );
+                break;
+            }
+            cluster = next;
+        }
+        (clusters, count)
+    }
+
+    // Data of a directory, at most FAT_MAX_DIR_SIZE of it
+    fn read_clusters(&mut self, fat: &Fat, path: &str, clusters: &[u32]) -> Result<Vec<u8>> {
+        let max = usize::try_from(FAT_MAX_DIR_SIZE.div_ceil(fat.cluster_size))?;
+        if clusters.len() > max {
+            self.anomaly(
+                FsAnomalyKind::Truncated,
+                path,
+                format!(
+                    "{} clusters, only the first {max} are checked",
+                    clusters.len()
+                ),
+            );
+        }
+        let mut data = Vec::new();
+        for cluster in clusters.iter().take(max) {
+            data.extend(self.read_at(
+                fat.heap_offset + u64::from(cluster - 2) * fat.cluster_size,
+                fat.cluster_size,
+            )?);
+        }
+        Ok(data)
+    }
+
+    // Walk the directory tree, every entry claims its clusters. Directories
+    // are read when they are walked, pending ones only keep their clusters.
+    fn fat_tree(
+        &mut self,
+        fat: &mut Fat,
+        root: Vec<u8>,
+        root_start: u32,
+        parse: impl Fn(&[u8]) -> Vec<FatEntry>,
+    ) -> Result<()> {
+        let mut root = Some(root);
+        let mut dirs = vec![(String::new(), Vec::new())];
+        let mut dir_starts = HashSet::from([root_start]);
+        while let Some((dir_path, clusters)) = dirs.pop() {
+            let data = match root.take() {
+                Some(root) => root,
+                None => self.read_clusters(fat, &dir_path, &clusters)?,
+            };
+            for entry in parse(&data) {
+                if !self.proceed() {
+                    return Ok(());
+                }
+                let path = format!("{dir_path}/{}", entry.name);
+                if let Some((kind, detail)) = &entry.anomaly {
+                    self.anomaly(*kind, &path, detail.clone());
+                    continue;
+                }
+                if entry.is_dir {
+                    if !dir_starts.insert(entry.start) {
+                        self.anomaly(
+                            FsAnomalyKind::DirCycle,
+                            &path,
+                            format!("cluster {} is an already listed directory", entry.start),
+                        );
+                        continue;
+                    }
+                    let (clusters, _) = self.fat_chain(fat, &path, &entry, true);
+                    dirs.push((path, clusters));
+                } else if entry.start == 0 {
+                    if entry.size != 0 {
+                        self.anomaly(
+                            FsAnomalyKind::SizeMismatch,
+                            &path,
+                            format!("no cluster allocated for {} bytes", entry.size),
+                        );
+                    }
+                } else {
+                    let anomalies = self.anomalies.len();
+                    let (_, count) = self.fat_chain(fat, &path, &entry, false);
+                    let expected = entry.size.div_ceil(fat.cluster_size);
+                    if anomalies == self.anomalies.len() && count != expected {
+                        self.anomaly(
+                            FsAnomalyKind::SizeMismatch,
+                            &path,
+                            format!("{count} clusters allocated for {} bytes", entry.size),
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn fat(&mut self) -> Result<()> {
+        let boot = self.read_at(0, 512)?;
+        let sector_size = u64::from(le_u16(&boot, 11));
+        let cluster_sectors = u64::from(boot[13]);
+        let reserved = u64::from(le_u16(&boot, 14));
+        let fats = u64::from(boot[16]);
+        let root_entries = u64::from(le_u16(&boot, 17));
+        let total = match le_u16(&boot, 19) {
+            0 => u64::from(le_u32(&boot, 32)),
+            total => u64::from(total),
+        };
+        let fat_size = match le_u16(&boot, 22) {
+            0 => u64::from(le_u32(&boot, 36)),
+            size => u64::from(size),
+        };
+        let root_sectors = (root_entries * 32).div_ceil(sector_size.max(1));
+        let meta_sectors = reserved + fats * fat_size + root_sectors;
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !cluster_sectors.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_size == 0
+            || total <= meta_sectors
+        {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "boot sector",
+                "invalid BIOS parameter block".into(),
+            );
+            return Ok(());
+        }
+        let clusters = (total - meta_sectors) / cluster_sectors;
+        let (bits, eoc, bad) = if clusters < 4085 {
+            (12, 0xFF8, 0xFF7)
+        } else if clusters < 65525 {
+            (16, 0xFFF8, 0xFFF7)
+        } else {
+            (32, 0x0FFF_FFF8, 0x0FFF_FFF7)
+        };
+        let table = self.read_at(reserved * sector_size, fat_size * sector_size)?;
+        let entries = clusters + 2;
+        if (table.len() as u64) * 8 / bits < entries {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "FAT",
+                format!("too small for {clusters} clusters"),
+            );
+            return Ok(());
+        }
+        let next = (0..entries as usize)
+            .map(|cluster| match bits {
+                12 => {
+                    let value = le_u16(&table, cluster + cluster / 2);
+                    u32::from(if cluster & 1 == 1 {
+                        value >> 4
+                    } else {
+                        value & 0xFFF
+                    })
+                }
+                16 => u32::from(le_u16(&table, 2 * cluster)),
+                _ => le_u32(&table, 4 * cluster) & 0x0FFF_FFFF,
+            })
+            .collect();
+        let mut fat = Fat {
+            next,
+            eoc,
+            bad,
+            cluster_size: cluster_sectors * sector_size,
+            heap_offset: meta_sectors * sector_size,
+            owners: vec![0; entries as usize],
+            paths: Vec::new(),
+        };
+        let fat32 = bits == 32;
+        let (root, root_start) = if fat32 {
+            let root_start = le_u32(&boot, 44);
+            let root_entry = FatEntry {
+                name: String::new(),
+                is_dir: true,
+                start: root_start,
+                size: 0,
+                contiguous: false,
+                anomaly: None,
+            };
+            let (clusters, _) = self.fat_chain(&mut fat, "/", &root_entry, true);
+            (self.read_clusters(&fat, "/", &clusters)?, root_start)
+        } else {
+            (
+                self.read_at(
+                    (reserved + fats * fat_size) * sector_size,
+                    root_entries * 32,
+                )?,
+                0,
+            )
+        };
+        self.fat_tree(&mut fat, root, root_start, |data| {
+            fat_dir_entries(data, fat32)
+        })
+    }
+
+    fn exfat(&mut self) -> Result<()> {
+        let boot = self.read_at(0, 512)?;
+        let (sector_shift, cluster_shift) = (u32::from(boot[108]), u32::from(boot[109]));
+        if !(9..=12).contains(&sector_shift) || sector_shift + cluster_shift > 25 {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "boot sector",
+                "invalid sector or cluster size".into(),
+            );
+            return Ok(());
+        }
+        let sector_size = 1u64 << sector_shift;
+        // The main boot region checksum is repeated in its 12th sector
+        let region = self.read_at(0, 12 * sector_size)?;
+        let (sectors, checksums) = region.split_at(11 * sector_size as usize);
+        let checksum = sectors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !matches!(i, 106 | 107 | 112))
+            .fold(0u32, |sum, (_, byte)| {
+                sum.rotate_right(1).wrapping_add(u32::from(*byte))
+            });
+        if checksums
+            .chunks_exact(4)
+            .any(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) != checksum)
+        {
+            self.anomaly(
+                FsAnomalyKind::BadChecksum,
+                "boot region",
+                "checksum mismatch".into(),
+            );
+        }
+        let fat_offset = u64::from(le_u32(&boot, 80)) * sector_size;
+        let fat_length = u64::from(le_u32(&boot, 84)) * sector_size;
+        let entries = u64::from(le_u32(&boot, 92)) + 2;
+        if fat_length < entries * 4 {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "FAT",
+                format!("too small for {} clusters", entries - 2),
+            );
+            return Ok(());
+        }
+        let table = self.read_at(fat_offset, entries * 4)?;
+        let mut fat = Fat {
+            next: table
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+            eoc: 0xFFFF_FFF8,
+            bad: 0xFFFF_FFF7,
+            cluster_size: sector_size << cluster_shift,
+            heap_offset: u64::from(le_u32(&boot, 88)) * sector_size,
+            owners: vec![0; entries as usize],
+            paths: Vec::new(),
+        };
+        let root_entry = FatEntry {
+            name: String::new(),
+            is_dir: true,
+            start: le_u32(&boot, 96),
+            size: 0,
+            contiguous: false,
+            anomaly: None,
+        };
+        let (clusters, _) = self.fat_chain(&mut fat, "/", &root_entry, true);
+        let root = self.read_clusters(&fat, "/", &clusters)?;
+        self.fat_tree(&mut fat, root, root_entry.start, exfat_dir_entries)
+    }
+
+    fn ntfs(&mut self) -> Result<()> {
+        let boot = self.read_at(0, 512)?;
+        let sector_size = u64::from(le_u16(&boot, 11));
+        let cluster_size = match boot[13] {
+            shift @ 0x81..=0xFF => sector_size.checked_shl(256 - u32::from(shift)),
+            count => Some(sector_size * u64::from(count)),
+        }
+        .unwrap_or(0);
+        let record_size = match boot[0x40] as i8 {
+            clusters @ 1.. => cluster_size * clusters as u64,
+            shift => 1u64
+                .checked_shl(u32::from(shift.unsigned_abs()))
+                .unwrap_or(0),
+        };
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !cluster_size.is_power_of_two()
+            || cluster_size > 1 << 21
+            || !(1024..=4096).contains(&record_size)
+            || !record_size.is_power_of_two()
+        {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "boot sector",
+                "invalid BIOS parameter block".into(),
+            );
+            return Ok(());
+        }
+        let mft_offset = le_u64(&boot, 0x30).saturating_mul(cluster_size);
+        let mut record = self.read_at(mft_offset, record_size)?;
+        let runs = if &record[..4] == b"FILE" && ntfs_fixup(&mut record) {
+            ntfs_attributes(&record).and_then(|attributes| {
+                attributes
+                    .into_iter()
+                    .find(|(attr_type, attr)| *attr_type == 0x80 && attr[8] == 1 && attr[9] == 0)
+                    .and_then(|(_, attr)| Some((ntfs_runs(attr)?, le_u64(attr, 0x30))))
+            })
+        } else {
+            None
+        };
+        let (runs, mft_size) = match runs {
+            Some(runs) => runs,
+            None => {
+                self.anomaly(
+                    FsAnomalyKind::BadStructure,
+                    "MFT record 0",
+                    "unreadable $MFT data".into(),
+                );
+                return Ok(());
+            }
+        };
+
+        let records = mft_size / record_size;
+        let mut nodes: HashMap<u64, NtfsNode> = HashMap::new();
+        let mut index: u64 = 0;
+        'runs: for (lcn, len) in runs {
+            let run_records = len.saturating_mul(cluster_size) / record_size;
+            let lcn = match lcn {
+                Some(lcn) => lcn,
+                None => {
+                    self.anomaly(
+                        FsAnomalyKind::BadStructure,
+                        "$MFT",
+                        format!("sparse run at record {index}"),
+                    );
+                    index = index.saturating_add(run_records);
+                    continue;
+                }
+            };
+            let chunk_records = READ_CHUNK as u64 / record_size;
+            let mut done = 0;
+            while done < run_records {
+                let count = chunk_records
+                    .min(run_records - done)
+                    .min(records.saturating_sub(index));
+                if count == 0 {
+                    break 'runs;
+                }
+                let offset = lcn
+                    .saturating_mul(cluster_size)
+                    .saturating_add(done * record_size);
+                let mut data = self.read_at(offset, count * record_size)?;
+                for record in data.chunks_exact_mut(record_size as usize) {
+                    if !self.proceed() {
+                        return Ok(());
+                    }
+                    if let Some((record_index, node)) = self.ntfs_record(record, index) {
+                        nodes.insert(record_index, node);
+                    }
+                    index += 1;
+                }
+                done += count;
+            }
+        }
+        self.ntfs_tree(&nodes);
+        Ok(())
+    }
+
+    fn ntfs_record(&mut self, record: &mut [u8], index: u64) -> Option<(u64, NtfsNode)> {
+        let location = format!("MFT record {index}");
+        match &record[..4] {
+            b"FILE" => (),
+            b"BAAD" => {
+                self.anomaly(
+                    FsAnomalyKind::BadFixup,
+                    &location,
+                    "record marked bad".into(),
+                );
+                return None;
+            }
+            [0, 0, 0, 0] => return None,
+            _ => {
+                self.anomaly(
+                    FsAnomalyKind::BadStructure,
+                    &location,
+                    "invalid record signature".into(),
+                );
+                return None;
+            }
+        }
+        if !ntfs_fixup(record) {
+            self.anomaly(
+                FsAnomalyKind::BadFixup,
+                &location,
+                "update sequence mismatch".into(),
+            );
+            return None;
+        }
+        let flags = le_u16(record, 0x16);
+        // Skip unused and extension records
+        if flags & 0x1 == 0 || le_u64(record, 0x20) & 0xFFFF_FFFF_FFFF != 0 {
+            return None;
+        }
+        let attributes = match ntfs_attributes(record) {
+            Some(attributes) => attributes,
+            None => {
+                self.anomaly(
+                    FsAnomalyKind::BadStructure,
+                    &location,
+                    "invalid attributes".into(),
+                );
+                return None;
+            }
+        };
+        // Resident $FILE_NAME attributes, DOS names are only used as a fallback
+        let mut file_names: Vec<(u64, String, bool)> = attributes
+            .into_iter()
+            .filter(|(attr_type, attr)| *attr_type == 0x30 && attr[8] == 0)
+            .filter_map(|(_, attr)| {
+                let value = attr.get(usize::from(le_u16(attr, 0x14))..)?;
+                let name = value.get(0x42..0x42 + 2 * usize::from(*value.get(0x40)?))?;
+                Some((
+                    le_u64(value, 0) & 0xFFFF_FFFF_FFFF,
+                    utf16_name(name),
+                    value[0x41] == 2,
+                ))
+            })
+            .collect();
+        file_names.sort_by_key(|(_, _, dos)| *dos);
+        let (parent, name, _) = file_names.into_iter().next()?;
+        Some((
+            index,
+            NtfsNode {
+                parent,
+                name,
+                is_dir: flags & 0x2 != 0,
+            },
+        ))
+    }
+
+    // Every record must reach the root directory through its parents
+    fn ntfs_tree(&mut self, nodes: &HashMap<u64, NtfsNode>) {
+        let mut reachable = HashSet::from([NTFS_ROOT_RECORD]);
+        let mut unreachable = HashSet::new();
+        let mut records: Vec<u64> = nodes.keys().copied().collect();
+        records.sort_unstable();
+        for record in records {
+            let mut chain = HashSet::new();
+            let mut current = record;
+            let ok = loop {
+                if reachable.contains(&current) {
+                    break true;
+                }
+                if unreachable.contains(&current) {
+                    break false;
+                }
+                let node = &nodes[&current];
+                let location = format!("MFT record {current} ({})", node.name);
+                if !chain.insert(current) {
+                    self.anomaly(
+                        FsAnomalyKind::DirCycle,
+                        &location,
+                        "directory is its own ancestor".into(),
+                    );
+                    break false;
+                }
+                match nodes.get(&node.parent) {
+                    Some(parent) if parent.is_dir => current = node.parent,
+                    Some(_) => {
+                        self.anomaly(
+                            FsAnomalyKind::BadStructure,
+                            &location,
+                            format!("parent record {} isn't a directory", node.parent),
+                        );
+                        break false;
+                    }
+                    None => {
+                        self.anomaly(
+                            FsAnomalyKind::BadStructure,
+                            &location,
+                            format!("parent record {} isn't in use", node.parent),
+                        );
+                        break false;
+                    }
+                }
+            };
+            if ok {
+                reachable.extend(chain);
+            } else {
+                unreachable.extend(chain);
+            }
+        }
+    }
+
+    fn ext4(&mut self) -> Result<()> {
+        let sb = self.read_at(1024, 1024)?;
+        if le_u16(&sb, 0x38) != 0xEF53 {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "superblock",
+                "invalid magic".into(),
+            );
+            return Ok(());
+        }
+        let incompat = le_u32(&sb, 0x60);
+        let ro_compat = le_u32(&sb, 0x64);
+        let metadata_csum = ro_compat & 0x400 != 0;
+        if metadata_csum && ext4_crc32c(!0, &sb[..0x3FC]) != le_u32(&sb, 0x3FC) {
+            self.anomaly(
+                FsAnomalyKind::BadChecksum,
+                "superblock",
+                "checksum mismatch".into(),
+            );
+        }
+        let is_64bit = incompat & 0x80 != 0;
+        let desc_size = if is_64bit {
+            u64::from(le_u16(&sb, 0xFE))
+        } else {
+            32
+        };
+        let log_block_size = le_u32(&sb, 0x18);
+        let blocks = u64::from(le_u32(&sb, 0x4))
+            | if is_64bit {
+                u64::from(le_u32(&sb, 0x150)) << 32
+            } else {
+                0
+            };
+        let first_data_block = u64::from(le_u32(&sb, 0x14));
+        let blocks_per_group = u64::from(le_u32(&sb, 0x20));
+        let inodes_per_group = le_u32(&sb, 0x28);
+        let inode_size = if le_u32(&sb, 0x4C) == 0 {
+            128
+        } else {
+            u64::from(le_u16(&sb, 0x58))
+        };
+        if log_block_size > 6
+            || !(32..=1024).contains(&desc_size)
+            || !desc_size.is_power_of_two()
+            || blocks <= first_data_block
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < 128
+            || !inode_size.is_power_of_two()
+            || inode_size > 1024 << log_block_size
+        {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "superblock",
+                "invalid geometry".into(),
+            );
+            return Ok(());
+        }
+        let block_size = 1024 << log_block_size;
+        if blocks
+            .checked_mul(block_size)
+            .is_none_or(|fs_size| fs_size > self.size)
+        {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                "superblock",
+                format!("{blocks} blocks don't fit in {} bytes", self.size),
+            );
+            return Ok(());
+        }
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group);
+        let gdt_offset = (first_data_block + 1).checked_mul(block_size);
+        let gdt_len = groups.checked_mul(desc_size);
+        let (gdt_offset, gdt_len) = match gdt_offset.zip(gdt_len) {
+            Some(gdt) => gdt,
+            None => {
+                self.anomaly(
+                    FsAnomalyKind::BadStructure,
+                    "group descriptors",
+                    "out of range".into(),
+                );
+                return Ok(());
+            }
+        };
+        let gdt = self.read_at(gdt_offset, gdt_len)?;
+        let uuid = &sb[0x68..0x78];
+        let seed = if incompat & 0x2000 != 0 {
+            le_u32(&sb, 0x270)
+        } else {
+            ext4_crc32c(!0, uuid)
+        };
+        let mut inode_tables = Vec::new();
+        for (group, desc) in gdt.chunks_exact(desc_size as usize).enumerate() {
+            let group_le = (group as u32).to_le_bytes();
+            let checksum = if metadata_csum {
+                let mut crc = ext4_crc32c(seed, &group_le);
+                crc = ext4_crc32c(crc, &desc[..0x1E]);
+                crc = ext4_crc32c(crc, &[0, 0]);
+                crc = ext4_crc32c(crc, &desc[0x20..]);
+                Some(crc as u16)
+            } else if ro_compat & 0x10 != 0 {
+                let mut crc = ext4_crc16(0xFFFF, uuid);
+                crc = ext4_crc16(crc, &group_le);
+                crc = ext4_crc16(crc, &desc[..0x1E]);
+                if is_64bit {
+                    crc = ext4_crc16(crc, &desc[0x20..]);
+                }
+                Some(crc)
+            } else {
+                None
+            };
+            if checksum.is_some_and(|checksum| checksum != le_u16(desc, 0x1E)) {
+                self.anomaly(
+                    FsAnomalyKind::BadChecksum,
+                    &format!("group descriptor {group}"),
+                    "checksum mismatch".into(),
+                );
+            }
+            inode_tables.push(
+                u64::from(le_u32(desc, 0x8))
+                    | if is_64bit {
+                        u64::from(le_u32(desc, 0x28)) << 32
+                    } else {
+                        0
+                    },
+            );
+        }
+        let fs = Ext4Layout {
+            block_size,
+            blocks,
+            inodes: le_u32(&sb, 0x0),
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            filetype: incompat & 0x2 != 0,
+        };
+        self.ext4_tree(&fs)
+    }
+
+    fn ext4_inode(&mut self, fs: &Ext4Layout, ino: u32) -> Result<Option<Vec<u8>>> {
+        if ino == 0 || ino > fs.inodes {
+            return Ok(None);
+        }
+        let group = ((ino - 1) / fs.inodes_per_group) as usize;
+        let index = u64::from((ino - 1) % fs.inodes_per_group);
+        let offset = fs.inode_tables.get(group).and_then(|table| {
+            fs.offset(*table)?
+                .checked_add(index.checked_mul(fs.inode_size)?)
+        });
+        match offset {
+            Some(offset) => Ok(Some(self.read_at(offset, fs.inode_size)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Physical blocks of a directory, from its extent tree or block map
+    fn ext4_dir_blocks(&mut self, fs: &Ext4Layout, inode: &[u8], path: &str) -> Result<Vec<u64>> {
+        let size = u64::from(le_u32(inode, 0x4)) | u64::from(le_u32(inode, 0x6C)) << 32;
+        let max = usize::try_from(size.div_ceil(fs.block_size))?.min(EXT4_MAX_DIR_BLOCKS);
+        let flags = le_u32(inode, 0x20);
+        // Inline data directories have no blocks
+        if flags & 0x1000_0000 != 0 {
+            return Ok(Vec::new());
+        }
+        let mut dir = Ext4DirBlocks {
+            tree: HashSet::new(),
+            blocks: Vec::new(),
+            max,
+        };
+        if flags & 0x8_0000 != 0 {
+            self.ext4_extents(fs, &inode[0x28..0x64], None, path, &mut dir)?;
+        } else {
+            for i in 0..15 {
+                let block = u64::from(le_u32(inode, 0x28 + 4 * i));
+                let level = i.saturating_sub(11) as u32;
+                self.ext4_block_map(fs, block, level, path, &mut dir)?;
+            }
+        }
+        dir.blocks.truncate(max);
+        Ok(dir.blocks)
+    }
+
+    // Read an extent index or indirect block of a directory. Each one is
+    // counted as an entry and read once, at most EXT4_MAX_TREE_BLOCKS of them.
+    fn ext4_tree_block(
+        &mut self,
+        fs: &Ext4Layout,
+        block: u64,
+        path: &str,
+        dir: &mut Ext4DirBlocks,
+    ) -> Result<Option<Vec<u8>>> {
+        if dir.tree.len() >= EXT4_MAX_TREE_BLOCKS || !self.proceed() {
+            return Ok(None);
+        }
+        let Some(offset) = fs.offset(block) else {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                path,
+                format!("tree block {block} out of range"),
+            );
+            return Ok(None);
+        };
+        if !dir.tree.insert(block) {
+            self.anomaly(
+                FsAnomalyKind::ChainLoop,
+                path,
+                format!("tree block {block} already referenced"),
+            );
+            return Ok(None);
+        }
+        if dir.tree.len() == EXT4_MAX_TREE_BLOCKS {
+            self.anomaly(
+                FsAnomalyKind::Truncated,
+                path,
+                format!("more than {EXT4_MAX_TREE_BLOCKS} tree blocks"),
+            );
+        }
+        Ok(Some(self.read_at(offset, fs.block_size)?))
+    }
+
+    fn ext4_extents(
+        &mut self,
+        fs: &Ext4Layout,
+        node: &[u8],
+        expected_depth: Option<u16>,
+        path: &str,
+        dir: &mut Ext4DirBlocks,
+    ) -> Result<()> {
+        let entries = usize::from(le_u16(node, 2));
+        let depth = le_u16(node, 6);
+        if le_u16(node, 0) != 0xF30A
+            || depth > 5
+            || expected_depth.is_some_and(|expected| expected != depth)
+            || 12 * (entries + 1) > node.len()
+        {
+            self.anomaly(
+                FsAnomalyKind::BadStructure,
+                path,
+                "invalid extent tree".into(),
+            );
+            return Ok(());
+        }
+        for entry in node[12..12 * (entries + 1)].chunks_exact(12) {
+            if dir.blocks.len() >= dir.max {
+                break;
+            }
+            if depth == 0 {
+                let len = le_u16(entry, 4);
+                // Uninitialized extents read as zeroes
+                if len > 32768 {
+                    continue;
+                }
+                let start = u64::from(le_u16(entry, 6)) << 32 | u64::from(le_u32(entry, 8));
+                let remaining = dir.max - dir.blocks.len();
+                dir.blocks
+                    .extend((start..start.saturating_add(u64::from(len))).take(remaining));
+            } else {
+                let leaf = u64::from(le_u16(entry, 8)) << 32 | u64::from(le_u32(entry, 4));
+                let Some(child) = self.ext4_tree_block(fs, leaf, path, dir)? else {
+                    continue;
+                };
+                self.ext4_extents(fs, &child, Some(depth - 1), path, dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ext4_block_map(
+        &mut self,
+        fs: &Ext4Layout,
+        block: u64,
+        level: u32,
+        path: &str,
+        dir: &mut Ext4DirBlocks,
+    ) -> Result<()> {
+        if block == 0 || dir.blocks.len() >= dir.max {
+            return Ok(());
+        }
+        if level == 0 {
+            dir.blocks.push(block);
+            return Ok(());
+        }
+        let Some(data) = self.ext4_tree_block(fs, block, path, dir)? else {
+            return Ok(());
+        };
+        for pointer in data.chunks_exact(4) {
+            let pointer = u64::from(u32::from_le_bytes([
+                pointer[0], pointer[1], pointer[2], pointer[3],
+            ]));
+            self.ext4_block_map(fs, pointer, level - 1, path, dir)?;
+        }
+        Ok(())
+    }
+
+    // Walk directories from the root, each one must be listed once
+    fn ext4_tree(&mut self, fs: &Ext4Layout) -> Result<()> {
+        let mut visited = HashSet::from([EXT4_ROOT_INO]);
+        let mut dirs = vec![(String::new(), EXT4_ROOT_INO)];
+        while let Some((path, ino)) = dirs.pop() {
+            let inode = match self.ext4_inode(fs, ino)? {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let location = if path.is_empty() { "/" } else { &path };
+            for block in self.ext4_dir_blocks(fs, &inode, location)? {
+                let Some(offset) = fs.offset(block) else {
+                    self.anomaly(
+                        FsAnomalyKind::BadStructure,
+                        location,
+                        format!("directory block {block} out of range"),
+                    );
+                    continue;
+                };
+                let data = self.read_at(offset, fs.block_size)?;
+                let mut pos = 0;
+                while pos + 8 <= data.len() {
+                    let child = le_u32(&data, pos);
+                    let rec_len = usize::from(le_u16(&data, pos + 4));
+                    let name_len = usize::from(data[pos + 6]);
+                    if rec_len < 8 || pos + rec_len > data.len() || name_len + 8 > rec_len {
+                        self.anomaly(
+                            FsAnomalyKind::BadStructure,
+                            location,
+                            format!("invalid entry in directory block {block}"),
+                        );
+                        break;
+                    }
+                    let name = &data[pos + 8..pos + 8 + name_len];
+                    let file_type = data[pos + 7];
+                    pos += rec_len;
+                    if child == 0 || name == b"." || name == b".." {
+                        continue;
+                    }
+                    if !self.proceed() {
+                        return Ok(());
+                    }
+                    let child_path = format!("{path}/{}", String::from_utf8_lossy(name));
+                    if child > fs.inodes {
+                        self.anomaly(
+                            FsAnomalyKind::BadStructure,
+                            &child_path,
+                            format!("inode {child} out of range"),
+                        );
+                        continue;
+                    }
+                    let is_dir = if fs.filetype {
+                        file_type == 2
+                    } else {
+                        self.ext4_inode(fs, child)?
+                            .is_some_and(|inode| le_u16(&inode, 0) & 0xF000 == 0x4000)
+                    };
+                    if !is_dir {
+                        continue;
+                    }
+                    if !visited.insert(child) {
+                        self.anomaly(
+                            FsAnomalyKind::DirCycle,
+                            &child_path,
+                            format!("directory inode {child} is already listed"),
+                        );
+                        continue;
+                    }
+                    dirs.push((child_path, child));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ext4fs::Ext4Writer, FSWrite};
+    use std::io::Cursor;
+    use usbsas_proto::common::{OutFsType, Timestamps};
+
+    const EXT4_SIZE: usize = 4 * 1024 * 1024;
+
+    fn anomaly(kind: FsAnomalyKind, location: &str, detail: &str) -> FsAnomaly {
+        FsAnomaly {
+            kind: kind.into(),
+            location: location.into(),
+            detail: detail.into(),
+        }
+    }
+
+    fn set_le_u16(buf: &mut [u8], off: usize, value: u16) {
+        buf[off..off + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_le_u32(buf: &mut [u8], off: usize, value: u32) {
+        buf[off..off + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn ext4_image() -> Vec<u8> {
+        let mut fs = Ext4Writer::mkfs(
+            Cursor::new(vec![0; EXT4_SIZE]),
+            512,
+            (EXT4_SIZE / 512) as u64,
+            Some(OutFsType::Ext4),
+        )
+        .unwrap();
+        fs.newdir("/dir", &Timestamps::default()).unwrap();
+        Box::new(fs).unmount_fs().unwrap().into_inner()
+    }
+
+    fn check_image(image: &[u8], fs_type: &str) -> Vec<FsAnomaly> {
+        check(&mut Cursor::new(image), fs_type, image.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn ext4_blocks() {
+        let mut image = ext4_image();
+        assert_eq!(check_image(&image, "Linux/Ext"), vec![]);
+
+        let blocks = le_u32(&image, 1024 + 0x4);
+        let too_big = anomaly(
+            FsAnomalyKind::BadStructure,
+            "superblock",
+            &format!("{blocks} blocks don't fit in {} bytes", EXT4_SIZE / 2),
+        );
+        assert_eq!(
+            check_image(&image[..EXT4_SIZE / 2], "Linux/Ext"),
+            vec![too_big]
+        );
+
+        // 64 bit block count with a group per block, its descriptors would overflow
+        let sb = &mut image[1024..2048];
+        set_le_u32(sb, 0x60, le_u32(sb, 0x60) | 0x80);
+        set_le_u16(sb, 0xFE, 64);
+        set_le_u32(sb, 0x150, 0xFFFF_FFFF);
+        set_le_u32(sb, 0x20, 1);
+        let blocks = u64::from(blocks) | 0xFFFF_FFFF << 32;
+        assert_eq!(
+            check_image(&image, "Linux/Ext"),
+            vec![anomaly(
+                FsAnomalyKind::BadStructure,
+                "superblock",
+                &format!("{blocks} blocks don't fit in {EXT4_SIZE} bytes"),
+            )]
+        );
+    }
+
+    #[test]
+    fn ext4_dir_block_out_of_range() {
+        let mut image = ext4_image();
+        let sb = &image[1024..2048];
+        let block_size = 1024 << le_u32(sb, 0x18);
+        let gdt = (le_u32(sb, 0x14) as usize + 1) * block_size;
+        let inode_size = usize::from(le_u16(sb, 0x58));
+        // Root directory is the second inode of the first group
+        let root = le_u32(&image, gdt + 0x8) as usize * block_size + inode_size;
+        assert_ne!(le_u32(&image, root + 0x20) & 0x8_0000, 0);
+        // Physical start of its first extent
+        set_le_u16(&mut image, root + 0x28 + 12 + 6, 0xFFFF);
+        let start = 0xFFFF << 32 | u64::from(le_u32(&image, root + 0x28 + 12 + 8));
+        assert_eq!(
+            check_image(&image, "Linux/Ext"),
+            vec![anomaly(
+                FsAnomalyKind::BadStructure,
+                "/",
+                &format!("directory block {start} out of range"),
+            )]
+        );
+    }
+
+    #[test]
+    fn ext4_extent_index_reused() {
+        let mut image = ext4_image();
+        let sb = &image[1024..2048];
+        let block_size = 1024 << le_u32(sb, 0x18);
+        let last = le_u32(sb, 0x4) - 1;
+        let gdt = (le_u32(sb, 0x14) as usize + 1) * block_size;
+        let inode_size = usize::from(le_u16(sb, 0x58));
+        let root = le_u32(&image, gdt + 0x8) as usize * block_size + inode_size;
+        // Index node of 4 entries all pointing to the same empty leaf
+        let header = root + 0x28;
+        set_le_u16(&mut image, header + 2, 4);
+        set_le_u16(&mut image, header + 6, 1);
+        for entry in 0..4 {
+            let entry = header + 12 + 12 * entry;
+            set_le_u32(&mut image, entry, 0);
+            set_le_u32(&mut image, entry + 4, last);
+            set_le_u16(&mut image, entry + 8, 0);
+        }
+        let leaf = last as usize * block_size;
+        image[leaf..leaf + block_size].fill(0);
+        set_le_u16(&mut image, leaf, 0xF30A);
+        set_le_u16(&mut image, leaf + 4, 4);
+        let reused = anomaly(
+            FsAnomalyKind::ChainLoop,
+            "/",
+            &format!("tree block {last} already referenced"),
+        );
+        assert_eq!(
+            check_image(&image, "Linux/Ext"),
+            vec![reused.clone(), reused.clone(), reused]
+        );
+    }
+
+    #[test]
+    fn ntfs_sparse_mft() {
+        let mut image = vec![0; 8192];
+        // 512 bytes sectors and clusters, 1 KiB records, $MFT at cluster 8
+        set_le_u16(&mut image, 11, 512);
+        image[13] = 1;
+        image[0x30] = 8;
+        image[0x40] = 0xF6;
+        let record = &mut image[4096..5120];
+        record[..4].copy_from_slice(b"FILE");
+        set_le_u16(record, 4, 0x30);
+        set_le_u16(record, 6, 3);
+        set_le_u16(record, 0x30, 1);
+        set_le_u16(record, 510, 1);
+        set_le_u16(record, 1022, 1);
+        set_le_u16(record, 0x14, 0x38);
+        set_le_u16(record, 0x16, 1);
+        // Non resident $DATA of 4 records: a sparse run of 8 records, then the MFT
+        let attr = &mut record[0x38..0x80];
+        set_le_u32(attr, 0, 0x80);
+        set_le_u32(attr, 4, 0x48);
+        attr[8] = 1;
+        set_le_u16(attr, 0x20, 0x40);
+        set_le_u32(attr, 0x30, 4096);
+        attr[0x40..0x45].copy_from_slice(&[0x01, 16, 0x11, 8, 8]);
+        set_le_u32(record, 0x80, 0xFFFF_FFFF);
+        assert_eq!(
+            check_image(&image, "NTFS"),
+            vec![anomaly(
+                FsAnomalyKind::BadStructure,
+                "$MFT",
+                "sparse run at record 0"
+            )]
+        );
+    }
+}
@@ -0,0 +1,837 @@
+//! Read only HFS+ / HFSX implementation
+
+use crate::FSRead;
+use crate::{check_dir_entries, Error, Result};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+};
+use unicode_normalization::UnicodeNormalization;
+use usbsas_proto::common::{FileInfo, FileType, Timestamps};
+
+const VOLUME_HEADER_OFFSET: u64 = 1024;
+const VOLUME_HEADER_SIZE: usize = 512;
+const SIG_HFSPLUS: &[u8; 2] = b"H+";
+const SIG_HFSX: &[u8; 2] = b"HX";
+
+// Seconds between 1904-01-01 and 1970-01-01
+const HFS_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+const ROOT_FOLDER_ID: u32 = 2;
+const CATALOG_FILE_ID: u32 = 4;
+
+const NODE_KIND_LEAF: i8 = -1;
+const NODE_KIND_INDEX: i8 = 0;
+const NODE_DESCRIPTOR_SIZE: usize = 14;
+const MAX_TREE_DEPTH: usize = 16;
+
+const RECORD_FOLDER: i16 = 1;
+const RECORD_FILE: i16 = 2;
+
+const FORK_DATA: u8 = 0x00;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFLNK: u16 = 0o120000;
+const UF_COMPRESSED: u8 = 0x20;
+const MAX_SYMLINK_SIZE: u64 = 4096;
+
+// Metadata entries of the root folder that shouldn't be exposed
+const PRIVATE_DATA_DIR: &str = "\u{0}\u{0}\u{0}\u{0}HFS+ Private Data";
+const HIDDEN_ROOT_ENTRIES: &[&str] = &[
+    PRIVATE_DATA_DIR,
+    ".HFS+ Private Directory Data\r",
+    ".journal",
+    ".journal_info_block",
+];
+
+fn be_u16(buf: &[u8], off: usize) -> Result<u16> {
+    buf.get(off..off + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::FSError("hfs+: truncated record".into()))
+}
+
+fn be_u32(buf: &[u8], off: usize) -> Result<u32> {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::FSError("hfs+: truncated record".into()))
+}
+
+fn be_u64(buf: &[u8], off: usize) -> Result<u64> {
+    Ok((u64::from(be_u32(buf, off)?) << 32) | u64::from(be_u32(buf, off + 4)?))
+}
+
+// Extent key: fork type u8, pad u8, file id u32, start block u32
+fn extent_key(key: &[u8]) -> Result<(u32, u8, u64)> {
+    let fork_type = *key
+        .first()
+        .ok_or_else(|| Error::FSError("hfs+: truncated record".into()))?;
+    Ok((be_u32(key, 2)?, fork_type, u64::from(be_u32(key, 6)?)))
+}
+
+fn hfs_time(date: u32) -> i64 {
+    if date == 0 {
+        return 0;
+    }
+    Timestamps::nanos(i64::from(date) - HFS_EPOCH_OFFSET, 0)
+}
+
+// Create, content mod, attribute mod and access dates of file and folder records
+fn hfs_times(data: &[u8]) -> Result<Timestamps> {
+    Ok(Timestamps {
+        mtime: hfs_time(be_u32(data, 16)?),
+        ctime: hfs_time(be_u32(data, 20)?),
+        atime: hfs_time(be_u32(data, 24)?),
+        crtime: hfs_time(be_u32(data, 12)?),
+    })
+}
+
+#[derive(Clone, Debug, Default)]
+struct Fork {
+    logical_size: u64,
+    total_blocks: u64,
+    // (start block, block count)
+    extents: Vec<(u64, u64)>,
+}
+
+impl Fork {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        let mut extents = Vec::new();
+        for i in 0..8 {
+            let count = be_u32(buf, 20 + i * 8)?;
+            if count == 0 {
+                break;
+            }
+            extents.push((u64::from(be_u32(buf, 16 + i * 8)?), u64::from(count)));
+        }
+        Ok(Fork {
+            logical_size: be_u64(buf, 0)?,
+            total_blocks: u64::from(be_u32(buf, 12)?),
+            extents,
+        })
+    }
+
+    fn mapped_blocks(&self) -> u64 {
+        self.extents.iter().map(|(_, count)| count).sum()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct BTree {
+    fork: Fork,
+    node_size: usize,
+    root: u32,
+    total_nodes: u32,
+}
+
+#[derive(Clone, Debug)]
+struct CatalogEntry {
+    id: u32,
+    ftype: FileType,
+    times: Timestamps,
+    data: Fork,
+    // Hard link to an indirect node file
+    link: Option<u32>,
+}
+
+struct Node {
+    buf: Vec<u8>,
+    flink: u32,
+    kind: i8,
+}
+
+impl Node {
+    fn records(&self) -> Result<Vec<&[u8]>> {
+        let node_size = self.buf.len();
+        let num_records = usize::from(be_u16(&self.buf, 10)?);
+        if NODE_DESCRIPTOR_SIZE + 2 * (num_records + 1) > node_size {
+            return Err(Error::FSError("hfs+: bad record count".into()));
+        }
+        let mut records = Vec::with_capacity(num_records);
+        for i in 0..num_records {
+            let start = usize::from(be_u16(&self.buf, node_size - 2 * (i + 1))?);
+            let end = usize::from(be_u16(&self.buf, node_size - 2 * (i + 2))?);
+            if start < NODE_DESCRIPTOR_SIZE || end < start || end > node_size {
+                return Err(Error::FSError("hfs+: bad record offset".into()));
+            }
+            records.push(&self.buf[start..end]);
+        }
+        Ok(records)
+    }
+}
+
+// Return the key and the data of a record
+fn split_record(record: &[u8]) -> Result<(&[u8], &[u8])> {
+    let key_len = usize::from(be_u16(record, 0)?);
+    if 2 + key_len > record.len() {
+        return Err(Error::FSError("hfs+: bad key length".into()));
+    }
+    Ok((&record[2..2 + key_len], &record[2 + key_len..]))
+}
+
+// Decode an HFS+ name (UTF-16BE, decomposed) into a precomposed string
+fn catalog_name(key: &[u8]) -> Result<String> {
+    let len = usize::from(be_u16(key, 4)?);
+    let units = (0..len)
+        .map(|i| be_u16(key, 6 + 2 * i))
+        .collect::<Result<Vec<u16>>>()?;
+    Ok(String::from_utf16_lossy(&units).nfc().collect())
+}
+
+fn read_fork<T: Read + Seek>(
+    reader: &mut T,
+    block_size: u64,
+    fork: &Fork,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<usize> {
+    if offset >= fork.logical_size {
+        return Ok(0);
+    }
+    let to_read = (buf.len() as u64).min(fork.logical_size - offset) as usize;
+    let mut done = 0;
+    let mut extent_offset = 0;
+    for (start, count) in fork.extents.iter() {
+        let extent_size = count * block_size;
+        let pos = offset + done as u64;
+        if pos < extent_offset + extent_size {
+            let in_extent = pos - extent_offset;
+            let len = ((extent_size - in_extent) as usize).min(to_read - done);
+            reader.seek(SeekFrom::Start(start * block_size + in_extent))?;
+            reader.read_exact(&mut buf[done..done + len])?;
+            done += len;
+            if done == to_read {
+                break;
+            }
+        }
+        extent_offset += extent_size;
+    }
+    Ok(done)
+}
+
+pub struct HfsPlus<T: Read + Seek> {
+    reader: T,
+    block_size: u64,
+    catalog: BTree,
+    extents: BTree,
+    dir_cache: HashMap<u32, Vec<(String, CatalogEntry)>>,
+    // Path and complete data fork of the file being read, files are read in
+    // chunks
+    open_file: Option<(String, Fork)>,
+}
+
+impl<T: Read + Seek> HfsPlus<T> {
+    fn read_node(&mut self, tree: &BTree, index: u32) -> Result<Node> {
+        if index >= tree.total_nodes {
+            return Err(Error::FSError("hfs+: bad node index".into()));
+        }
+        let mut buf = vec![0; tree.node_size];
+        let offset = u64::from(index) * tree.node_size as u64;
+        if read_fork(
+            &mut self.reader,
+            self.block_size,
+            &tree.fork,
+            offset,
+            &mut buf,
+        )? != tree.node_size
+        {
+            return Err(Error::FSError("hfs+: short node read".into()));
+        }
+        Ok(Node {
+            flink: be_u32(&buf, 0)?,
+            kind: buf[8] as i8,
+            buf,
+        })
+    }
+
+    fn open_btree(&mut self, fork: Fork) -> Result<BTree> {
+        // Read the header node with a minimal node size first
+        let mut header = vec![0; 512];
+        read_fork(&mut self.reader, self.block_size, &fork, 0, &mut header)?;
+        let node_size = usize::from(be_u16(&header, NODE_DESCRIPTOR_SIZE + 18)?);
+        if !(512..=32768).contains(&node_size) || !node_size.is_power_of_two() {
+            return Err(Error::FSError("hfs+: bad b-tree node size".into()));
+        }
+        Ok(BTree {
+            fork,
+            node_size,
+            root: be_u32(&header, NODE_DESCRIPTOR_SIZE + 2)?,
+            total_nodes: be_u32(&header, NODE_DESCRIPTOR_SIZE + 22)?,
+        })
+    }
+
+    // Descend from the root to the leaf node that may contain the first record
+    // matching `is_before`, which tells if an index key sorts before (or is)
+    // the searched key.
+    fn find_leaf<F>(&mut self, tree: &BTree, is_before: F) -> Result<Option<Node>>
+    where
+        F: Fn(&[u8]) -> Result<bool>,
+    {
+        if tree.root == 0 {
+            return Ok(None);
+        }
+        let mut index = tree.root;
+        for _ in 0..MAX_TREE_DEPTH {
+            let node = self.read_node(tree, index)?;
+            match node.kind {
+                NODE_KIND_LEAF => return Ok(Some(node)),
+                NODE_KIND_INDEX => {
+                    let mut child = None;
+                    for record in node.records()? {
+                        let (key, data) = split_record(record)?;
+                        if child.is_none() || is_before(key)? {
+                            child = Some(be_u32(data, 0)?);
+                        } else {
+                            break;
+                        }
+                    }
+                    index = child.ok_or_else(|| Error::FSError("hfs+: empty index".into()))?;
+                }
+                _ => return Err(Error::FSError("hfs+: bad node kind".into())),
+            }
+        }
+        Err(Error::FSError("hfs+: b-tree too deep".into()))
+    }
+
+    // Walk leaf records from the leaf returned by find_leaf, `visit` returns
+    // false to stop
+    fn walk_leaves<F>(&mut self, tree: &BTree, mut node: Node, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        // Bound the walk in case of a loop in the leaves chain
+        for _ in 0..tree.total_nodes {
+            for record in node.records()? {
+                let (key, data) = split_record(record)?;
+                if !visit(key, data)? {
+                    return Ok(());
+                }
+            }
+            if node.flink == 0 {
+                return Ok(());
+            }
+            node = self.read_node(tree, node.flink)?;
+            if node.kind != NODE_KIND_LEAF {
+                return Err(Error::FSError("hfs+: bad leaf node".into()));
+            }
+        }
+        Err(Error::FSError("hfs+: loop in b-tree leaves".into()))
+    }
+
+    // Complete the fork extents with the extents overflow file
+    fn complete_fork(&mut self, file_id: u32, fork: &mut Fork) -> Result<()> {
+        if fork.mapped_blocks() >= fork.total_blocks {
+            return Ok(());
+        }
+        let tree = self.extents.clone();
+        let start_block = fork.mapped_blocks();
+        let leaf = match self.find_leaf(&tree, |key| {
+            Ok(extent_key(key)? <= (file_id, FORK_DATA, start_block))
+        })? {
+            Some(leaf) => leaf,
+            None => return Err(Error::FSError("hfs+: missing overflow extents".into())),
+        };
+        let mut overflow = Vec::new();
+        self.walk_leaves(&tree, leaf, |key, data| {
+            let (key_id, fork_type, key_start) = extent_key(key)?;
+            if (key_id, fork_type) < (file_id, FORK_DATA) {
+                return Ok(true);
+            }
+            if (key_id, fork_type) > (file_id, FORK_DATA) {
+                return Ok(false);
+            }
+            for i in 0..8 {
+                let count = be_u32(data, i * 8 + 4)?;
+                if count == 0 {
+                    break;
+                }
+                overflow.push((key_start, u64::from(be_u32(data, i * 8)?), u64::from(count)));
+            }
+            Ok(true)
+        })?;
+        overflow.sort_by_key(|(start, _, _)| *start);
+        for (_, block, count) in overflow {
+            if fork.mapped_blocks() >= fork.total_blocks {
+                break;
+            }
+            fork.extents.push((block, count));
+        }
+        if fork.mapped_blocks() < fork.total_blocks {
+            return Err(Error::FSError("hfs+: incomplete fork extents".into()));
+        }
+        Ok(())
+    }
+
+    fn list_folder(
+        &mut self,
+        path: &str,
+        folder_id: u32,
+        max_entries: Option<u64>,
+    ) -> Result<Vec<(String, CatalogEntry)>> {
+        let hidden =
+            |name: &str| folder_id == ROOT_FOLDER_ID && HIDDEN_ROOT_ENTRIES.contains(&name);
+        if let Some(entries) = self.dir_cache.get(&folder_id) {
+            let listed = entries.iter().filter(|(name, _)| !hidden(name)).count();
+            check_dir_entries(path, listed, max_entries)?;
+            return Ok(entries.clone());
+        }
+        let tree = self.catalog.clone();
+        // Thread record (empty name) sorts first for a given parent
+        let leaf = match self.find_leaf(&tree, |key| {
+            let parent = be_u32(key, 0)?;
+            Ok(parent < folder_id || (parent == folder_id && be_u16(key, 4)? == 0))
+        })? {
+            Some(leaf) => leaf,
+            None => return Ok(Vec::new()),
+        };
+        let mut entries = Vec::new();
+        let mut listed = 0;
+        self.walk_leaves(&tree, leaf, |key, data| {
+            let parent = be_u32(key, 0)?;
+            if parent < folder_id {
+                return Ok(true);
+            }
+            if parent > folder_id {
+                return Ok(false);
+            }
+            let name = catalog_name(key)?;
+            let record_type = be_u16(data, 0)? as i16;
+            if (record_type == RECORD_FOLDER || record_type == RECORD_FILE) && !hidden(&name) {
+                listed += 1;
+                check_dir_entries(path, listed, max_entries)?;
+            }
+            match record_type {
+                RECORD_FOLDER => entries.push((
+                    name,
+                    CatalogEntry {
+                        id: be_u32(data, 8)?,
+                        ftype: FileType::Directory,
+                        times: hfs_times(data)?,
+                        data: Fork::default(),
+                        link: None,
+                    },
+                )),
+                RECORD_FILE => {
+                    let mode = be_u16(data, 42)?;
+                    let owner_flags = *data
+                        .get(41)
+                        .ok_or_else(|| Error::FSError("hfs+: truncated record".into()))?;
+                    // fdType and fdCreator of the Finder info
+                    let finder_info = data
+                        .get(48..56)
+                        .ok_or_else(|| Error::FSError("hfs+: truncated record".into()))?;
+                    let link = if finder_info == b"hlnkhfs+" {
+                        Some(be_u32(data, 44)?)
+                    } else {
+                        None
+                    };
+                    let ftype = if mode & S_IFMT == S_IFLNK {
+                        FileType::Symlink
+                    } else if owner_flags & UF_COMPRESSED != 0 {
+                        // (decmpfs) compressed files aren't supported
+                        FileType::Other
+                    } else {
+                        FileType::Regular
+                    };
+                    entries.push((
+                        name,
+                        CatalogEntry {
+                            id: be_u32(data, 8)?,
+                            ftype,
+                            times: hfs_times(data)?,
+                            data: Fork::parse(
+                                data.get(88..168).ok_or_else(|| {
+                                    Error::FSError("hfs+: truncated record".into())
+                                })?,
+                            )?,
+                            link,
+                        },
+                    ))
+                }
+                // Thread records
+                _ => (),
+            }
+            Ok(true)
+        })?;
+        self.dir_cache.insert(folder_id, entries.clone());
+        Ok(entries)
+    }
+
+    // Hard links point to an "iNode<num>" file of the private data folder
+    fn resolve_link(&mut self, entry: CatalogEntry) -> Result<CatalogEntry> {
+        let num = match entry.link {
+            Some(num) => num,
+            None => return Ok(entry),
+        };
+        let private_id = self
+            .list_folder("/", ROOT_FOLDER_ID, None)?
+            .into_iter()
+            .find(|(name, _)| name == PRIVATE_DATA_DIR)
+            .map(|(_, entry)| entry.id)
+            .ok_or_else(|| Error::FSError("hfs+: missing private data folder".into()))?;
+        let inode_name = format!("iNode{num}");
+        let target = self
+            .list_folder(PRIVATE_DATA_DIR, private_id, None)?
+            .into_iter()
+            .find(|(name, _)| *name == inode_name)
+            .map(|(_, entry)| entry)
+            .ok_or_else(|| Error::FSError("hfs+: dangling hard link".into()))?;
+        Ok(CatalogEntry {
+            times: entry.times,
+            link: None,
+            ..target
+        })
+    }
+
+    // Symlink target is the (utf-8) content of the data fork
+    fn symlink_target(&mut self, mut entry: CatalogEntry) -> Result<String> {
+        if entry.data.logical_size > MAX_SYMLINK_SIZE {
+            return Err(Error::FSError("hfs+: symlink target too long".into()));
+        }
+        self.complete_fork(entry.id, &mut entry.data)?;
+        let mut buf = vec![0; entry.data.logical_size as usize];
+        let len = read_fork(&mut self.reader, self.block_size, &entry.data, 0, &mut buf)?;
+        buf.truncate(len);
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<CatalogEntry> {
+        let mut cur = CatalogEntry {
+            id: ROOT_FOLDER_ID,
+            ftype: FileType::Directory,
+            times: Timestamps::default(),
+            data: Fork::default(),
+            link: None,
+        };
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if cur.ftype != FileType::Directory {
+                return Err(Error::FSError(format!("hfs+: {path} not found")));
+            }
+            cur = self
+                .list_folder(path, cur.id, None)?
+                .into_iter()
+                .find(|(name, _)| name == component)
+                .map(|(_, entry)| entry)
+                .ok_or_else(|| Error::FSError(format!("hfs+: {path} not found")))?;
+        }
+        self.resolve_link(cur)
+    }
+}
+
+impl<T: Read + Seek> FSRead<T> for HfsPlus<T> {
+    fn new(mut reader: T, _sector_size: u32) -> Result<Self> {
+        let mut header = vec![0; VOLUME_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(VOLUME_HEADER_OFFSET))?;
+        reader.read_exact(&mut header)?;
+        if &header[0..2] != SIG_HFSPLUS && &header[0..2] != SIG_HFSX {
+            return Err(Error::FSError("hfs+: bad signature".into()));
+        }
+        let block_size = u64::from(be_u32(&header, 40)?);
+        if !(512..=0x1000_0000).contains(&block_size) || !block_size.is_power_of_two() {
+            return Err(Error::FSError("hfs+: bad block size".into()));
+        }
+        let extents_fork = Fork::parse(&header[192..272])?;
+        let mut catalog_fork = Fork::parse(&header[272..352])?;
+
+        let empty_tree = BTree {
+            fork: Fork::default(),
+            node_size: 512,
+            root: 0,
+            total_nodes: 0,
+        };
+        let mut fs = HfsPlus {
+            reader,
+            block_size,
+            catalog: empty_tree.clone(),
+            extents: empty_tree,
+            dir_cache: HashMap::new(),
+            open_file: None,
+        };
+        fs.extents = fs.open_btree(extents_fork)?;
+        fs.complete_fork(CATALOG_FILE_ID, &mut catalog_fork)?;
+        fs.catalog = fs.open_btree(catalog_fork)?;
+        Ok(fs)
+    }
+
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)> {
+        log::trace!("get_attr: '{}'", path);
+        let entry = self.lookup(path)?;
+        Ok((entry.ftype, entry.data.logical_size, entry.times))
+    }
+
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>> {
+        log::trace!("read_dir: '{}'", path);
+        let dir = self.lookup(path)?;
+        if dir.ftype != FileType::Directory {
+            return Err(Error::FSError("Cannot list a non dir entry".into()));
+        }
+        let mut files_info = Vec::new();
+        for (name, entry) in self.list_folder(path, dir.id, max_entries)? {
+            if dir.id == ROOT_FOLDER_ID && HIDDEN_ROOT_ENTRIES.contains(&name.as_str()) {
+                continue;
+            }
+            let file_path = format!("{}/{}", path.trim_end_matches('/'), name);
+            // Bad links are listed as other files instead of failing the listing
+            let mut entry = match self.resolve_link(entry.clone()) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::warn!("hfs+: {file_path}: {err}");
+                    CatalogEntry {
+                        ftype: FileType::Other,
+                        data: Fork::default(),
+                        ..entry
+                    }
+                }
+            };
+            let mut symlink_target = String::new();
+            if entry.ftype == FileType::Symlink {
+                match self.symlink_target(entry.clone()) {
+                    Ok(target) => symlink_target = target,
+                    Err(err) => {
+                        log::warn!("hfs+: {file_path}: {err}");
+                        entry.ftype = FileType::Other;
+                        entry.data = Fork::default();
+                    }
+                }
+            }
+            files_info.push(FileInfo {
+                path: file_path,
+                ftype: entry.ftype.into(),
+                size: entry.data.logical_size,
+                timestamp: entry.times.mtime_secs(),
+                symlink_target,
+                streams: Vec::new(),
+                times: Some(entry.times),
+            });
+        }
+        Ok(files_info)
+    }
+
+    fn read_file(
+        &mut self,
+        path: &str,
+        buf: &mut Vec<u8>,
+        offset: u64,
+        bytes_to_read: u64,
+    ) -> Result<u64> {
+        log::trace!("read_file: '{}'", path);
+        let (path, fork) = match self.open_file.take() {
+            Some((open_path, fork)) if open_path == path => (open_path, fork),
+            _ => {
+                let mut entry = self.lookup(path)?;
+                if entry.ftype != FileType::Regular {
+                    return Err(Error::FSError("Cannot read a non regular file".into()));
+                }
+                self.complete_fork(entry.id, &mut entry.data)?;
+                (path.to_string(), entry.data)
+            }
+        };
+        let len = usize::try_from(bytes_to_read)?.min(buf.len());
+        let read = read_fork(
+            &mut self.reader,
+            self.block_size,
+            &fork,
+            offset,
+            &mut buf[..len],
+        );
+        self.open_file = Some((path, fork));
+        Ok(read? as u64)
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let entry = self.lookup(path)?;
+        if entry.ftype != FileType::Symlink {
+            return Err(Error::FSError(format!("{path} is not a symlink")));
+        }
+        self.symlink_target(entry)
+    }
+
+    fn unmount_fs(self: Box<Self>) -> Result<T> {
+        Ok(self.reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn catalog_key(parent: u32, name: &str) -> Vec<u8> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let mut key = Vec::new();
+        key.extend_from_slice(&((6 + 2 * units.len()) as u16).to_be_bytes());
+        key.extend_from_slice(&parent.to_be_bytes());
+        key.extend_from_slice(&(units.len() as u16).to_be_bytes());
+        for unit in units {
+            key.extend_from_slice(&unit.to_be_bytes());
+        }
+        key
+    }
+
+    fn folder_record(parent: u32, name: &str, id: u32) -> Vec<u8> {
+        let mut data = vec![0; 88];
+        data[0..2].copy_from_slice(&(RECORD_FOLDER as u16).to_be_bytes());
+        data[8..12].copy_from_slice(&id.to_be_bytes());
+        [catalog_key(parent, name), data].concat()
+    }
+
+    fn file_record(
+        parent: u32,
+        name: &str,
+        id: u32,
+        link: Option<u32>,
+        fork: (u64, u32),
+    ) -> Vec<u8> {
+        let mut data = vec![0; 248];
+        data[0..2].copy_from_slice(&(RECORD_FILE as u16).to_be_bytes());
+        data[8..12].copy_from_slice(&id.to_be_bytes());
+        data[16..20].copy_from_slice(&(HFS_EPOCH_OFFSET as u32 + 1000).to_be_bytes());
+        data[42..44].copy_from_slice(&0o100644u16.to_be_bytes());
+        if let Some(num) = link {
+            data[44..48].copy_from_slice(&num.to_be_bytes());
+            data[48..56].copy_from_slice(b"hlnkhfs+");
+        }
+        let (size, block) = fork;
+        if size != 0 {
+            data[88..96].copy_from_slice(&size.to_be_bytes());
+            data[100..104].copy_from_slice(&1u32.to_be_bytes());
+            data[104..108].copy_from_slice(&block.to_be_bytes());
+            data[108..112].copy_from_slice(&1u32.to_be_bytes());
+        }
+        [catalog_key(parent, name), data].concat()
+    }
+
+    fn header_node(root: u32, total_nodes: u32) -> Vec<u8> {
+        let mut node = vec![0; BLOCK_SIZE];
+        node[8] = 1;
+        node[NODE_DESCRIPTOR_SIZE + 2..NODE_DESCRIPTOR_SIZE + 6]
+            .copy_from_slice(&root.to_be_bytes());
+        node[NODE_DESCRIPTOR_SIZE + 18..NODE_DESCRIPTOR_SIZE + 20]
+            .copy_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+        node[NODE_DESCRIPTOR_SIZE + 22..NODE_DESCRIPTOR_SIZE + 26]
+            .copy_from_slice(&total_nodes.to_be_bytes());
+        node
+    }
+
+    fn leaf_node(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut node = vec![0; BLOCK_SIZE];
+        node[8] = NODE_KIND_LEAF as u8;
+        node[10..12].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        let mut offset = NODE_DESCRIPTOR_SIZE;
+        for (i, record) in records.iter().enumerate() {
+            node[offset..offset + record.len()].copy_from_slice(record);
+            node[BLOCK_SIZE - 2 * (i + 1)..BLOCK_SIZE - 2 * i]
+                .copy_from_slice(&(offset as u16).to_be_bytes());
+            offset += record.len();
+        }
+        let free = BLOCK_SIZE - 2 * (records.len() + 1);
+        node[free..free + 2].copy_from_slice(&(offset as u16).to_be_bytes());
+        node
+    }
+
+    fn fork_data(size: u64, block: u32, count: u32) -> Vec<u8> {
+        let mut fork = vec![0; 80];
+        fork[0..8].copy_from_slice(&size.to_be_bytes());
+        fork[12..16].copy_from_slice(&count.to_be_bytes());
+        fork[16..20].copy_from_slice(&block.to_be_bytes());
+        fork[20..24].copy_from_slice(&count.to_be_bytes());
+        fork
+    }
+
+    // Block 0: volume header, 1: extents overflow tree, 2-3: catalog tree,
+    // 4: content of the hard linked file
+    fn image_with_hard_links() -> Vec<u8> {
+        let mut image = vec![0; 5 * BLOCK_SIZE];
+        let header = &mut image[VOLUME_HEADER_OFFSET as usize..];
+        header[0..2].copy_from_slice(SIG_HFSPLUS);
+        header[40..44].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+        header[192..272].copy_from_slice(&fork_data(BLOCK_SIZE as u64, 1, 1));
+        header[272..352].copy_from_slice(&fork_data(2 * BLOCK_SIZE as u64, 2, 2));
+
+        image[BLOCK_SIZE..2 * BLOCK_SIZE].copy_from_slice(&header_node(0, 1));
+        image[2 * BLOCK_SIZE..3 * BLOCK_SIZE].copy_from_slice(&header_node(1, 2));
+        let leaf = leaf_node(&[
+            folder_record(ROOT_FOLDER_ID, PRIVATE_DATA_DIR, 16),
+            file_record(ROOT_FOLDER_ID, "a", 17, Some(20), (0, 0)),
+            file_record(ROOT_FOLDER_ID, "b", 18, Some(20), (0, 0)),
+            file_record(16, "iNode20", 20, None, (5, 4)),
+        ]);
+        image[3 * BLOCK_SIZE..4 * BLOCK_SIZE].copy_from_slice(&leaf);
+        image[4 * BLOCK_SIZE..4 * BLOCK_SIZE + 5].copy_from_slice(b"hello");
+        image
+    }
+
+    #[test]
+    fn hard_links() {
+        let mut fs = HfsPlus::new(Cursor::new(image_with_hard_links()), 512).unwrap();
+        let files = fs.read_dir("/", None).unwrap();
+        let listed: Vec<(&str, i32, u64)> = files
+            .iter()
+            .map(|file| (file.path.as_str(), file.ftype, file.size))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("/a", FileType::Regular as i32, 5),
+                ("/b", FileType::Regular as i32, 5)
+            ]
+        );
+        for path in ["/a", "/b"] {
+            let mut buf = vec![0; 16];
+            assert_eq!(fs.read_file(path, &mut buf, 0, 16).unwrap(), 5);
+            assert_eq!(&buf[..5], b"hello");
+        }
+        assert_eq!(fs.get_attr("/a").unwrap().2.mtime_secs(), 1000);
+    }
+
+    #[test]
+    fn dir_entries_limit() {
+        let mut fs = HfsPlus::new(Cursor::new(image_with_hard_links()), 512).unwrap();
+        // The hidden private data folder isn't counted
+        assert!(fs.read_dir("/", Some(1)).is_err());
+        assert_eq!(fs.read_dir("/", Some(2)).unwrap().len(), 2);
+        // Neither when the folder is cached
+        assert!(fs.read_dir("/", Some(1)).is_err());
+    }
+
+    #[test]
+    fn bad_links() {
+        let mut image = image_with_hard_links();
+        let mut symlink = file_record(ROOT_FOLDER_ID, "d", 22, None, (MAX_SYMLINK_SIZE + 1, 4));
+        let mode = catalog_key(ROOT_FOLDER_ID, "d").len() + 42;
+        symlink[mode..mode + 2].copy_from_slice(&(S_IFLNK | 0o777).to_be_bytes());
+        let leaf = leaf_node(&[
+            folder_record(ROOT_FOLDER_ID, PRIVATE_DATA_DIR, 16),
+            file_record(ROOT_FOLDER_ID, "a", 17, Some(20), (0, 0)),
+            file_record(ROOT_FOLDER_ID, "c", 21, Some(30), (0, 0)),
+            symlink,
+            file_record(16, "iNode20", 20, None, (5, 4)),
+        ]);
+        image[3 * BLOCK_SIZE..4 * BLOCK_SIZE].copy_from_slice(&leaf);
+        let mut fs = HfsPlus::new(Cursor::new(image), 512).unwrap();
+        let files = fs.read_dir("/", None).unwrap();
+        let listed: Vec<(&str, i32, u64)> = files
+            .iter()
+            .map(|file| (file.path.as_str(), file.ftype, file.size))
+            .collect();
+        // The dangling hard link and the symlink with a too long target are
+        // listed as other files
+        assert_eq!(
+            listed,
+            vec![
+                ("/a", FileType::Regular as i32, 5),
+                ("/c", FileType::Other as i32, 0),
+                ("/d", FileType::Other as i32, 0)
+            ]
+        );
+        let mut buf = vec![0; 16];
+        assert!(fs.read_file("/c", &mut buf, 0, 16).is_err());
+        // Reads of the same file reuse its fork
+        assert_eq!(fs.read_file("/a", &mut buf, 0, 2).unwrap(), 2);
+        assert_eq!(fs.open_file.as_ref().unwrap().0, "/a");
+        assert_eq!(&buf[..2], b"he");
+        assert_eq!(fs.read_file("/a", &mut buf, 2, 16).unwrap(), 3);
+        assert_eq!(&buf[..3], b"llo");
+    }
+}
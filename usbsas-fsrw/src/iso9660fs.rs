@@ -1,83 +1,624 @@
+//! Read only ISO9660 implementation with Joliet and Rock Ridge extensions
+
 use crate::FSRead;
-use crate::{Error, Result};
-use std::io::{Read, Seek, SeekFrom};
-use usbsas_proto::common::{FileInfo, FileType};
+use crate::{check_dir_entries, Error, Result};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+};
+use time::{Date, Month, PrimitiveDateTime, Time};
+use usbsas_proto::common::{FileInfo, FileType, Timestamps};
+
+const SECTOR_SIZE: u64 = 2048;
+const VD_START: u64 = 16;
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+const MAX_CONTINUATIONS: usize = 16;
+// Directories bigger than this are considered corrupted
+const MAX_DIR_SIZE: u64 = 64 * 1024 * 1024;
+
+const VD_PRIMARY: u8 = 1;
+const VD_SUPPLEMENTARY: u8 = 2;
+const VD_TERMINATOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_ASSOCIATED: u8 = 0x04;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+const NM_CONTINUE: u8 = 0x01;
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+
+const TF_CREATION: u8 = 0x01;
+const TF_MODIFY: u8 = 0x02;
+const TF_ACCESS: u8 = 0x04;
+const TF_ATTRIBUTES: u8 = 0x08;
+const TF_LONG_FORM: u8 = 0x80;
+
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT: u8 = 0x02;
+const SL_PARENT: u8 = 0x04;
+const SL_ROOT: u8 = 0x08;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+fn le_u16(buf: &[u8], off: usize) -> Result<u16> {
+    buf.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::FSError("iso9660: truncated record".into()))
+}
+
+fn le_u32(buf: &[u8], off: usize) -> Result<u32> {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::FSError("iso9660: truncated record".into()))
+}
+
+fn unix_timestamp(
+    year: i32,
+    month: u8,
+    day: u8,
+    (hour, minute, second): (u8, u8, u8),
+    offset: i8,
+) -> i64 {
+    let date = match Month::try_from(month)
+        .ok()
+        .and_then(|month| Date::from_calendar_date(year, month, day).ok())
+    {
+        Some(date) => date,
+        None => return 0,
+    };
+    let time = Time::from_hms(hour, minute, second).unwrap_or(Time::MIDNIGHT);
+    // Offset from GMT in 15 minutes intervals
+    PrimitiveDateTime::new(date, time)
+        .assume_utc()
+        .unix_timestamp()
+        - i64::from(offset) * 15 * 60
+}
+
+// 7 bytes directory record date
+fn record_time(buf: &[u8]) -> i64 {
+    match buf {
+        [year, month, day, hour, minute, second, offset, ..] => unix_timestamp(
+            1900 + i32::from(*year),
+            *month,
+            *day,
+            (*hour, *minute, *second),
+            *offset as i8,
+        ),
+        _ => 0,
+    }
+}
+
+// 17 bytes volume descriptor date ("YYYYMMDDHHMMSScc" and offset)
+fn dec_time(buf: &[u8]) -> i64 {
+    if buf.len() < 17 {
+        return 0;
+    }
+    let num = |start: usize, len: usize| -> u32 {
+        std::str::from_utf8(&buf[start..start + len])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0)
+    };
+    unix_timestamp(
+        num(0, 4) as i32,
+        num(4, 2) as u8,
+        num(6, 2) as u8,
+        (num(8, 2) as u8, num(10, 2) as u8, num(12, 2) as u8),
+        buf[16] as i8,
+    )
+}
 
-use iso9660::{DirectoryEntry, ISO9660Reader, ISODirectory, ISOFile, ISO9660};
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Names {
+    Primary,
+    Joliet,
+    RockRidge(usize),
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    ftype: FileType,
+    size: u64,
+    times: Timestamps,
+    // (block, length) of each extent
+    extents: Vec<(u32, u64)>,
+    symlink_target: String,
+}
+
+struct Record {
+    name: Vec<u8>,
+    flags: u8,
+    node: Node,
+    system_use: Vec<u8>,
+}
+
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    times: Option<Timestamps>,
+    symlink: Option<String>,
+    child_link: Option<u32>,
+    relocated: bool,
+}
 
 pub struct Iso9660<T: Read + Seek> {
-    fs: ISO9660<T>,
+    reader: T,
+    block_size: u64,
+    names: Names,
+    root: Node,
+    cache: HashMap<String, Node>,
+    // First block of directories and the path they were found at: a directory
+    // found again (e.g. through a Rock Ridge child link) would make a loop
+    dirs: HashMap<u32, String>,
 }
 
-fn isobject_from_path<T: ISO9660Reader>(fs: &ISO9660<T>, path: &str) -> Result<DirectoryEntry<T>> {
-    let split_path = path
-        .trim_start_matches('/')
-        .trim_end_matches('/')
-        .split('/');
-    let mut cur_file = DirectoryEntry::Directory(fs.root.clone());
-    for cur_path in split_path {
-        // Already opened root dir
-        if cur_path.is_empty() {
-            continue;
-        }
-        cur_file = match cur_file {
-            DirectoryEntry::Directory(dir) => match dir.find(cur_path)? {
-                Some(new_file) => new_file,
-                None => return Err(Error::Error("couldn't find path".to_string())),
+impl<T: Read + Seek> Iso9660<T> {
+    fn read_at(&mut self, block: u64, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.reader
+            .seek(SeekFrom::Start(block * self.block_size + offset))?;
+        self.reader.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn parse_record(buf: &[u8]) -> Result<Record> {
+        let flags = *buf
+            .get(25)
+            .ok_or_else(|| Error::FSError("iso9660: truncated record".into()))?;
+        let name_len = usize::from(buf[32]);
+        let name = buf
+            .get(33..33 + name_len)
+            .ok_or_else(|| Error::FSError("iso9660: truncated record".into()))?
+            .to_vec();
+        // Padding byte if the name length is even
+        let su_start = 33 + name_len + (1 - name_len % 2);
+        let size = u64::from(le_u32(buf, 10)?);
+        Ok(Record {
+            name,
+            flags,
+            node: Node {
+                ftype: if flags & FLAG_DIRECTORY != 0 {
+                    FileType::Directory
+                } else {
+                    FileType::Regular
+                },
+                size,
+                times: Timestamps {
+                    mtime: Timestamps::nanos(record_time(&buf[18..25]), 0),
+                    ..Default::default()
+                },
+                // Extended attribute record precedes the data
+                extents: vec![(le_u32(buf, 2)?.saturating_add(u32::from(buf[1])), size)],
+                symlink_target: String::new(),
             },
-            _ => return Err(Error::Error("isobject_from_path".to_string())),
+            system_use: buf.get(su_start..).unwrap_or_default().to_vec(),
+        })
+    }
+
+    // Raw records of a directory, multi extent files are merged
+    fn dir_records(&mut self, dir: &Node) -> Result<Vec<Record>> {
+        if dir.size > MAX_DIR_SIZE {
+            return Err(Error::FSError("iso9660: directory too big".into()));
+        }
+        let mut data = vec![0; usize::try_from(dir.size)?];
+        self.read_node(dir, 0, &mut data)?;
+        let mut records: Vec<Record> = Vec::new();
+        let mut pos = 0;
+        let mut multi_extent = false;
+        while pos < data.len() {
+            let len = usize::from(data[pos]);
+            if len == 0 {
+                // Records don't cross sectors, skip to the next one
+                pos = (pos / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            let buf = data
+                .get(pos..pos + len)
+                .ok_or_else(|| Error::FSError("iso9660: truncated directory".into()))?;
+            pos += len;
+            if len < 34 {
+                return Err(Error::FSError("iso9660: bad directory record".into()));
+            }
+            let record = Self::parse_record(buf)?;
+            match records.last_mut() {
+                Some(last) if multi_extent && last.name == record.name => {
+                    last.node.size += record.node.size;
+                    last.node.extents.extend(record.node.extents);
+                }
+                _ => records.push(record),
+            }
+            multi_extent = buf[25] & FLAG_MULTI_EXTENT != 0;
         }
+        Ok(records)
     }
-    Ok(cur_file)
-}
 
-fn isodir_from_path<T: ISO9660Reader>(fs: &ISO9660<T>, path: &str) -> Result<ISODirectory<T>> {
-    match isobject_from_path(fs, path)? {
-        DirectoryEntry::Directory(dir) => Ok(dir),
-        _ => Err(Error::Error("isodir_from_path".to_string())),
+    // System use entries of a record, following continuation areas
+    fn susp_entries(&mut self, system_use: &[u8], skip: usize) -> Result<Vec<Vec<u8>>> {
+        let mut entries = Vec::new();
+        let mut area = system_use.get(skip..).unwrap_or_default().to_vec();
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut next = None;
+            let mut pos = 0;
+            while pos + 4 <= area.len() {
+                let len = usize::from(area[pos + 2]);
+                if len < 4 || pos + len > area.len() {
+                    break;
+                }
+                let entry = &area[pos..pos + len];
+                match &entry[..2] {
+                    b"ST" => break,
+                    b"CE" => {
+                        next = Some((le_u32(entry, 4)?, le_u32(entry, 12)?, le_u32(entry, 20)?))
+                    }
+                    _ => entries.push(entry.to_vec()),
+                }
+                pos += len;
+            }
+            match next {
+                Some((block, offset, len)) if u64::from(len) <= SECTOR_SIZE => {
+                    area = vec![0; usize::try_from(len)?];
+                    self.read_at(u64::from(block), u64::from(offset), &mut area)?;
+                }
+                Some(_) => return Err(Error::FSError("iso9660: bad continuation area".into())),
+                None => return Ok(entries),
+            }
+        }
+        Err(Error::FSError(
+            "iso9660: too many continuation areas".into(),
+        ))
+    }
+
+    fn rock_ridge(&mut self, record: &Record, skip: usize) -> Result<RockRidge> {
+        let mut rr = RockRidge::default();
+        let mut name = Vec::new();
+        let mut link: Vec<String> = Vec::new();
+        let mut link_continue = false;
+        for entry in self.susp_entries(&record.system_use, skip)? {
+            match &entry[..2] {
+                b"NM" if entry.len() > 4 && entry[4] & (NM_CURRENT | NM_PARENT) == 0 => {
+                    name.extend_from_slice(&entry[5..]);
+                    if entry[4] & NM_CONTINUE == 0 {
+                        rr.name = Some(String::from_utf8_lossy(&name).into_owned());
+                    }
+                }
+                b"PX" => rr.mode = Some(le_u32(&entry, 4)?),
+                b"TF" if entry.len() > 4 => {
+                    let flags = entry[4];
+                    let size = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+                    // Timestamps are recorded in the order of the flags bits
+                    let mut times = Timestamps::default();
+                    let mut pos = 5;
+                    for (flag, time) in [
+                        (TF_CREATION, &mut times.crtime),
+                        (TF_MODIFY, &mut times.mtime),
+                        (TF_ACCESS, &mut times.atime),
+                        (TF_ATTRIBUTES, &mut times.ctime),
+                    ] {
+                        if flags & flag == 0 {
+                            continue;
+                        }
+                        if let Some(ts) = entry.get(pos..pos + size) {
+                            *time = Timestamps::nanos(
+                                if size == 17 {
+                                    dec_time(ts)
+                                } else {
+                                    record_time(ts)
+                                },
+                                0,
+                            );
+                        }
+                        pos += size;
+                    }
+                    rr.times = Some(times);
+                }
+                // Symlink components, may be split in several SL entries
+                b"SL" if entry.len() > 4 => {
+                    let mut pos = 5;
+                    while pos + 2 <= entry.len() {
+                        let (flags, len) = (entry[pos], usize::from(entry[pos + 1]));
+                        let component = if flags & SL_ROOT != 0 {
+                            String::new()
+                        } else if flags & SL_CURRENT != 0 {
+                            ".".into()
+                        } else if flags & SL_PARENT != 0 {
+                            "..".into()
+                        } else {
+                            String::from_utf8_lossy(
+                                entry.get(pos + 2..pos + 2 + len).unwrap_or_default(),
+                            )
+                            .into_owned()
+                        };
+                        match link.last_mut() {
+                            Some(last) if link_continue => last.push_str(&component),
+                            _ => link.push(component),
+                        }
+                        link_continue = flags & SL_CONTINUE != 0;
+                        pos += 2 + len;
+                    }
+                }
+                b"CL" => rr.child_link = Some(le_u32(&entry, 4)?),
+                b"RE" => rr.relocated = true,
+                _ => (),
+            }
+        }
+        if !link.is_empty() {
+            rr.symlink = Some(if link == [""] {
+                "/".into()
+            } else {
+                link.join("/")
+            });
+        }
+        Ok(rr)
+    }
+
+    fn decode_name(&self, raw: &[u8]) -> String {
+        let name = match self.names {
+            Names::Joliet => {
+                let units: Vec<u16> = raw
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            _ => String::from_utf8_lossy(raw).into_owned(),
+        };
+        // Strip version number and trailing dot of names without extension
+        let name = match name.rsplit_once(';') {
+            Some((name, _)) => name,
+            None => &name,
+        };
+        name.strip_suffix('.').unwrap_or(name).to_string()
+    }
+
+    fn list_dir(
+        &mut self,
+        dir_path: &str,
+        dir: &Node,
+        max_entries: Option<u64>,
+    ) -> Result<Vec<(String, Node)>> {
+        let mut entries = Vec::new();
+        for record in self.dir_records(dir)? {
+            // "." and ".."
+            if record.name == [0] || record.name == [1] {
+                continue;
+            }
+            if record.flags & FLAG_ASSOCIATED != 0 {
+                continue;
+            }
+            let mut node = record.node.clone();
+            let mut name = self.decode_name(&record.name);
+            if let Names::RockRidge(skip) = self.names {
+                let rr = self.rock_ridge(&record, skip)?;
+                // Relocated directories are listed through their child link
+                if rr.relocated {
+                    continue;
+                }
+                if let Some(rr_name) = rr.name {
+                    name = rr_name;
+                }
+                if let Some(times) = rr.times {
+                    node.times = Timestamps {
+                        mtime: if times.mtime == 0 {
+                            node.times.mtime
+                        } else {
+                            times.mtime
+                        },
+                        ..times
+                    };
+                }
+                if let Some(mode) = rr.mode {
+                    node.ftype = match mode & S_IFMT {
+                        S_IFDIR => FileType::Directory,
+                        S_IFREG => FileType::Regular,
+                        S_IFLNK => FileType::Symlink,
+                        S_IFBLK => FileType::BlockDevice,
+                        S_IFCHR => FileType::CharDevice,
+                        S_IFIFO => FileType::Fifo,
+                        S_IFSOCK => FileType::Socket,
+                        _ => FileType::Other,
+                    };
+                }
+                if let Some(target) = rr.symlink {
+                    node.ftype = FileType::Symlink;
+                    node.size = 0;
+                    node.symlink_target = target;
+                }
+                if let Some(block) = rr.child_link {
+                    // Size of the relocated directory is in its "." record
+                    let mut buf = vec![0; 34];
+                    self.read_at(u64::from(block), 0, &mut buf)?;
+                    let size = u64::from(le_u32(&buf, 10)?);
+                    node = Node {
+                        ftype: FileType::Directory,
+                        size,
+                        times: node.times,
+                        extents: vec![(block, size)],
+                        symlink_target: String::new(),
+                    };
+                }
+            }
+            if name.is_empty() || name.contains('/') {
+                log::warn!("iso9660: skipping bad name {name:?}");
+                continue;
+            }
+            if node.ftype == FileType::Directory {
+                let path = format!("{dir_path}/{name}");
+                let block = node.extents.first().map_or(0, |(block, _)| *block);
+                match self.dirs.get(&block) {
+                    Some(other) if *other != path => {
+                        log::warn!("iso9660: skipping {path}, same directory as {other:?}");
+                        continue;
+                    }
+                    Some(_) => (),
+                    None => {
+                        self.dirs.insert(block, path);
+                    }
+                }
+            }
+            check_dir_entries(
+                if dir_path.is_empty() { "/" } else { dir_path },
+                entries.len() + 1,
+                max_entries,
+            )?;
+            entries.push((name, node));
+        }
+        Ok(entries)
     }
-}
 
-fn isofile_from_path<T: ISO9660Reader>(fs: &ISO9660<T>, path: &str) -> Result<ISOFile<T>> {
-    match isobject_from_path(fs, path)? {
-        DirectoryEntry::File(file) => Ok(file),
-        _ => Err(Error::Error("isofile_from_path".to_string())),
+    fn read_node(&mut self, node: &Node, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= node.size {
+            return Ok(0);
+        }
+        let to_read = (buf.len() as u64).min(node.size - offset) as usize;
+        let mut done = 0;
+        let mut extent_offset = 0;
+        for (block, len) in node.extents.clone() {
+            let pos = offset + done as u64;
+            if pos < extent_offset + len {
+                let in_extent = pos - extent_offset;
+                let chunk = ((len - in_extent) as usize).min(to_read - done);
+                self.read_at(u64::from(block), in_extent, &mut buf[done..done + chunk])?;
+                done += chunk;
+                if done == to_read {
+                    break;
+                }
+            }
+            extent_offset += len;
+        }
+        Ok(done)
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<Node> {
+        let path = path.trim_end_matches('/');
+        if let Some(node) = self.cache.get(path) {
+            return Ok(node.clone());
+        }
+        let mut cur = self.root.clone();
+        let mut cur_path = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if cur.ftype != FileType::Directory {
+                return Err(Error::FSError(format!("iso9660: {path} not found")));
+            }
+            for (name, node) in self.list_dir(&cur_path, &cur, None)? {
+                self.cache.insert(format!("{cur_path}/{name}"), node);
+            }
+            cur_path = format!("{cur_path}/{component}");
+            cur = self
+                .cache
+                .get(&cur_path)
+                .cloned()
+                .ok_or_else(|| Error::FSError(format!("iso9660: {path} not found")))?;
+        }
+        Ok(cur)
     }
 }
 
 impl<T: Read + Seek> FSRead<T> for Iso9660<T> {
-    fn new(reader: T, _sector_size: u32) -> Result<Self> {
-        let fs = ISO9660::new(reader)?;
-        Ok(Iso9660 { fs })
+    fn new(mut reader: T, _sector_size: u32) -> Result<Self> {
+        let mut primary = None;
+        let mut joliet = None;
+        for index in VD_START..VD_START + MAX_VOLUME_DESCRIPTORS {
+            let mut vd = vec![0; SECTOR_SIZE as usize];
+            reader.seek(SeekFrom::Start(index * SECTOR_SIZE))?;
+            reader.read_exact(&mut vd)?;
+            if &vd[1..6] != b"CD001" {
+                return Err(Error::FSError("iso9660: bad volume descriptor".into()));
+            }
+            match vd[0] {
+                VD_PRIMARY => primary = Some(vd),
+                // Joliet escape sequences for UCS-2 level 1, 2 and 3
+                VD_SUPPLEMENTARY
+                    if vd[88..90] == *b"%/" && matches!(vd[90], b'@' | b'C' | b'E') =>
+                {
+                    joliet = Some(vd)
+                }
+                VD_TERMINATOR => break,
+                _ => (),
+            }
+        }
+        let primary = primary
+            .ok_or_else(|| Error::FSError("iso9660: no primary volume descriptor".into()))?;
+        let block_size = u64::from(le_u16(&primary, 128)?);
+        if !block_size.is_power_of_two() || !(512..=SECTOR_SIZE).contains(&block_size) {
+            return Err(Error::FSError("iso9660: bad logical block size".into()));
+        }
+
+        let mut fs = Iso9660 {
+            reader,
+            block_size,
+            names: Names::Primary,
+            root: Self::parse_record(&primary[156..190])?.node,
+            cache: HashMap::new(),
+            dirs: HashMap::new(),
+        };
+
+        // Rock Ridge is announced by a SUSP "SP" entry in the root "." record
+        let root = fs.root.clone();
+        if let Some(dot) = fs.dir_records(&root)?.first() {
+            let su = &dot.system_use;
+            if su.len() >= 7 && &su[..2] == b"SP" && su[4..6] == [0xbe, 0xef] {
+                fs.names = Names::RockRidge(usize::from(su[6]));
+            }
+        }
+        // Otherwise prefer Joliet names
+        if fs.names == Names::Primary {
+            if let Some(joliet) = joliet {
+                fs.root = Self::parse_record(&joliet[156..190])?.node;
+                fs.names = Names::Joliet;
+            }
+        }
+        log::debug!("iso9660: using {:?} names", fs.names);
+        if let Some((block, _)) = fs.root.extents.first() {
+            fs.dirs.insert(*block, String::new());
+        }
+        Ok(fs)
     }
 
-    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)> {
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)> {
         log::trace!("get_attr: '{}'", path);
-        let (ftype, size, ts) = match isobject_from_path(&self.fs, path)? {
-            DirectoryEntry::Directory(dir) => (FileType::Directory, 0, dir.time()),
-            DirectoryEntry::File(file) => (FileType::Regular, file.size() as u64, file.time()),
+        let node = self.lookup(path)?;
+        let size = if node.ftype == FileType::Directory {
+            0
+        } else {
+            node.size
         };
-        Ok((ftype, size, ts.unix_timestamp()))
+        Ok((node.ftype, size, node.times))
     }
 
-    fn read_dir(&mut self, path: &str) -> Result<Vec<FileInfo>> {
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>> {
         log::trace!("read_dir: '{}'", path);
-        let dir = isodir_from_path(&self.fs, path)?;
-        let mut entries: Vec<FileInfo> = Vec::new();
-        for entry in dir.contents() {
-            let name_str = entry?.identifier().to_string();
-            if !(name_str == "." || name_str == "..") {
-                let full_name = format!("{path}/{name_str}");
-                let (ftype, size, timestamp) = self.get_attr(&full_name)?;
-                entries.push(FileInfo {
-                    path: full_name,
-                    size,
-                    ftype: ftype.into(),
-                    timestamp,
-                });
-            }
+        let dir = self.lookup(path)?;
+        if dir.ftype != FileType::Directory {
+            return Err(Error::FSError("Cannot list a non dir entry".into()));
         }
-        Ok(entries)
+        let dir_path = path.trim_end_matches('/');
+        let mut files_info = Vec::new();
+        for (name, node) in self.list_dir(dir_path, &dir, max_entries)? {
+            let path = format!("{dir_path}/{name}");
+            files_info.push(FileInfo {
+                path: path.clone(),
+                ftype: node.ftype.into(),
+                size: if node.ftype == FileType::Directory {
+                    0
+                } else {
+                    node.size
+                },
+                timestamp: node.times.mtime_secs(),
+                symlink_target: node.symlink_target.clone(),
+                streams: Vec::new(),
+                times: Some(node.times),
+            });
+            self.cache.insert(path, node);
+        }
+        Ok(files_info)
     }
 
     fn read_file(
@@ -88,30 +629,211 @@ impl<T: Read + Seek> FSRead<T> for Iso9660<T> {
         bytes_to_read: u64,
     ) -> Result<u64> {
         log::trace!("read_file: '{}'", path);
-        let file = isofile_from_path(&self.fs, path)?;
-        let mut file_reader = file.read();
-        file_reader.seek(SeekFrom::Start(offset))?;
-
-        // read() while buffer isn't full or EOF is reached.
-        // don't use read_exact() because it would ret an error and fuse always asks 4kb
-        let mut bytes_read = 0;
-        loop {
-            match file_reader.read(&mut buf[bytes_read..]) {
-                Ok(size) => {
-                    bytes_read += size;
-                    if bytes_read as u64 == bytes_to_read || size == 0 {
-                        return Ok(bytes_read as u64);
-                    }
-                }
-                Err(err) => {
-                    log::error!("read error: {}", err);
-                    return Err(err.into());
-                }
-            }
+        let node = self.lookup(path)?;
+        if node.ftype != FileType::Regular {
+            return Err(Error::FSError("Cannot read a non regular file".into()));
         }
+        let len = usize::try_from(bytes_to_read)?.min(buf.len());
+        Ok(self.read_node(&node, offset, &mut buf[..len])? as u64)
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let node = self.lookup(path)?;
+        if node.ftype != FileType::Symlink {
+            return Err(Error::FSError(format!("{path} is not a symlink")));
+        }
+        Ok(node.symlink_target)
     }
 
     fn unmount_fs(self: Box<Self>) -> Result<T> {
-        Ok(self.fs.try_into_inner()?)
+        Ok(self.reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PRIMARY_ROOT: usize = 20;
+    const JOLIET_ROOT: usize = 21;
+    const DATA: usize = 22;
+
+    fn record(name: &[u8], block: usize, size: usize, flags: u8, system_use: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; 33 + name.len() + (1 - name.len() % 2)];
+        record[2..6].copy_from_slice(&(block as u32).to_le_bytes());
+        record[6..10].copy_from_slice(&(block as u32).to_be_bytes());
+        record[10..14].copy_from_slice(&(size as u32).to_le_bytes());
+        record[14..18].copy_from_slice(&(size as u32).to_be_bytes());
+        record[25] = flags;
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+        record.extend_from_slice(system_use);
+        if record.len() % 2 == 1 {
+            record.push(0);
+        }
+        record[0] = record.len() as u8;
+        record
+    }
+
+    fn susp(signature: &[u8; 2], data: &[u8]) -> Vec<u8> {
+        [signature, &[4 + data.len() as u8, 1][..], data].concat()
+    }
+
+    fn write_dir(image: &mut [u8], block: usize, records: &[Vec<u8>]) {
+        let records = records.concat();
+        let start = block * SECTOR_SIZE as usize;
+        image[start..start + records.len()].copy_from_slice(&records);
+    }
+
+    fn volume_descriptor(image: &mut [u8], index: usize, kind: u8, root: &[u8]) {
+        let vd = &mut image[index * SECTOR_SIZE as usize..(index + 1) * SECTOR_SIZE as usize];
+        vd[0] = kind;
+        vd[1..6].copy_from_slice(b"CD001");
+        vd[6] = 1;
+        vd[128..130].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        vd[156..156 + root.len()].copy_from_slice(root);
+        if kind == VD_SUPPLEMENTARY {
+            vd[88..91].copy_from_slice(b"%/E");
+        }
+    }
+
+    // Primary and Joliet directory trees with a single file (and a symlink in
+    // the primary tree), system use areas of primary records are given
+    fn image(root_su: &[u8], file_su: &[u8], symlink_su: Option<&[u8]>) -> Vec<u8> {
+        let sector = SECTOR_SIZE as usize;
+        let mut image = vec![0; (DATA + 1) * sector];
+        let primary_root = record(&[0], PRIMARY_ROOT, sector, FLAG_DIRECTORY, &[]);
+        let joliet_root = record(&[0], JOLIET_ROOT, sector, FLAG_DIRECTORY, &[]);
+        volume_descriptor(&mut image, 16, VD_PRIMARY, &primary_root);
+        volume_descriptor(&mut image, 17, VD_SUPPLEMENTARY, &joliet_root);
+        volume_descriptor(&mut image, 18, VD_TERMINATOR, &[]);
+
+        let mut records = vec![
+            record(&[0], PRIMARY_ROOT, sector, FLAG_DIRECTORY, root_su),
+            record(&[1], PRIMARY_ROOT, sector, FLAG_DIRECTORY, &[]),
+            record(b"LONG_FIL.TXT;1", DATA, 5, 0, file_su),
+        ];
+        if let Some(symlink_su) = symlink_su {
+            records.push(record(b"LINK.;1", 0, 0, 0, symlink_su));
+        }
+        write_dir(&mut image, PRIMARY_ROOT, &records);
+
+        let joliet_name: Vec<u8> = "Long file name.txt;1"
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        write_dir(
+            &mut image,
+            JOLIET_ROOT,
+            &[
+                record(&[0], JOLIET_ROOT, sector, FLAG_DIRECTORY, &[]),
+                record(&[1], JOLIET_ROOT, sector, FLAG_DIRECTORY, &[]),
+                record(&joliet_name, DATA, 5, 0, &[]),
+            ],
+        );
+        image[DATA * sector..DATA * sector + 5].copy_from_slice(b"hello");
+        image
+    }
+
+    fn list(fs: &mut Iso9660<Cursor<Vec<u8>>>) -> Vec<(String, i32, String)> {
+        fs.read_dir("/", None)
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.path, file.ftype, file.symlink_target))
+            .collect()
+    }
+
+    #[test]
+    fn primary_names() {
+        let mut image = image(&[], &[], None);
+        // Without the Joliet descriptor
+        image[17 * SECTOR_SIZE as usize] = 0;
+        let mut fs = Iso9660::new(Cursor::new(image), 2048).unwrap();
+        assert_eq!(
+            list(&mut fs),
+            vec![("/LONG_FIL.TXT".into(), FileType::Regular as i32, "".into())]
+        );
+    }
+
+    #[test]
+    fn joliet_names() {
+        let mut fs = Iso9660::new(Cursor::new(image(&[], &[], None)), 2048).unwrap();
+        assert_eq!(
+            list(&mut fs),
+            vec![(
+                "/Long file name.txt".into(),
+                FileType::Regular as i32,
+                "".into()
+            )]
+        );
+        let mut buf = vec![0; 16];
+        assert_eq!(
+            fs.read_file("/Long file name.txt", &mut buf, 0, 16)
+                .unwrap(),
+            5
+        );
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn rock_ridge() {
+        let root_su = susp(b"SP", &[0xbe, 0xef, 0]);
+        let mut mode = vec![0; 32];
+        mode[0..4].copy_from_slice(&(S_IFREG | 0o644).to_le_bytes());
+        // 2020-01-02 03:04:05 GMT
+        let file_su = [
+            susp(b"NM", b"\0Long file name.txt"),
+            susp(b"PX", &mode),
+            susp(b"TF", &[TF_MODIFY, 120, 1, 2, 3, 4, 5, 0]),
+        ]
+        .concat();
+        let symlink_su = [
+            susp(b"NM", b"\0link"),
+            susp(b"SL", b"\0\0\x03dir\x01\x03tar\0\x03get"),
+        ]
+        .concat();
+        let mut fs = Iso9660::new(
+            Cursor::new(image(&root_su, &file_su, Some(&symlink_su))),
+            2048,
+        )
+        .unwrap();
+        assert_eq!(
+            list(&mut fs),
+            vec![
+                (
+                    "/Long file name.txt".into(),
+                    FileType::Regular as i32,
+                    "".into()
+                ),
+                (
+                    "/link".into(),
+                    FileType::Symlink as i32,
+                    "dir/target".into()
+                )
+            ]
+        );
+        let (ftype, size, times) = fs.get_attr("/Long file name.txt").unwrap();
+        assert_eq!((ftype, size), (FileType::Regular, 5));
+        assert_eq!(times.mtime_secs(), 1_577_934_245);
+        assert_eq!(fs.read_link("/link").unwrap(), "dir/target");
+    }
+
+    #[test]
+    fn rock_ridge_child_link_loop() {
+        let root_su = susp(b"SP", &[0xbe, 0xef, 0]);
+        let mut block = [0; 8];
+        block[..4].copy_from_slice(&(PRIMARY_ROOT as u32).to_le_bytes());
+        block[4..].copy_from_slice(&(PRIMARY_ROOT as u32).to_be_bytes());
+        // "/loop" is a child link to the root directory
+        let loop_su = [susp(b"NM", b"\0loop"), susp(b"CL", &block)].concat();
+        let mut fs = Iso9660::new(Cursor::new(image(&root_su, &[], Some(&loop_su))), 2048).unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                list(&mut fs),
+                vec![("/LONG_FIL.TXT".into(), FileType::Regular as i32, "".into())]
+            );
+        }
+        assert!(fs.read_dir("/loop", None).is_err());
     }
 }
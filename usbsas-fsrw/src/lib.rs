@@ -2,12 +2,15 @@
 
 use std::io::{Seek, Write};
 use thiserror::Error;
-use usbsas_proto::common::{FileInfo, FileType, OutFsType};
+use usbsas_proto::common::{FileInfo, FileType, OutFsType, StreamInfo, Timestamps};
 
 pub mod ext4fs;
 pub mod ff;
+pub mod fsck;
+pub mod hfsplus;
 pub mod iso9660fs;
 pub mod ntfs;
+pub mod udf;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -17,8 +20,6 @@ pub enum Error {
     Tryfromint(#[from] std::num::TryFromIntError),
     #[error("ntfs error: {0}")]
     Ntfs(#[from] ::ntfs::NtfsError),
-    #[error("iso error: {0}")]
-    Iso9660(#[from] iso9660::ISOError),
     #[error("{0}")]
     Error(String),
     #[error("{0}")]
@@ -38,12 +39,24 @@ pub(crate) type Result<T> = std::result::Result<T, Error>;
 pub trait WriteSeek: Write + Seek {}
 impl<T: Write + Seek> WriteSeek for T {}
 
+// Checked as entries are listed, so that big directories are rejected while
+// they are read
+fn check_dir_entries(path: &str, entries: usize, max_entries: Option<u64>) -> Result<()> {
+    match max_entries {
+        Some(max) if entries as u64 > max => {
+            Err(Error::FSError(format!("more than {max} entries in {path}")))
+        }
+        _ => Ok(()),
+    }
+}
+
 pub trait FSRead<T> {
     fn new(reader: T, sector_size: u32) -> Result<Self>
     where
         Self: Sized;
-    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)>;
-    fn read_dir(&mut self, path: &str) -> Result<Vec<FileInfo>>;
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)>;
+    // Fails if the directory has more than `max_entries` entries
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>>;
     fn read_file(
         &mut self,
         path: &str,
@@ -51,6 +64,27 @@ pub trait FSRead<T> {
         offset: u64,
         bytes_to_read: u64,
     ) -> Result<u64>;
+    // Named data streams, only NTFS has them
+    fn read_streams(&mut self, _path: &str) -> Result<Vec<StreamInfo>> {
+        Ok(Vec::new())
+    }
+    fn stream_size(&mut self, path: &str, stream: &str) -> Result<u64> {
+        Err(Error::FSError(format!("{path} has no stream {stream}")))
+    }
+    fn read_stream(
+        &mut self,
+        path: &str,
+        stream: &str,
+        _buf: &mut Vec<u8>,
+        _offset: u64,
+        _bytes_to_read: u64,
+    ) -> Result<u64> {
+        Err(Error::FSError(format!("{path} has no stream {stream}")))
+    }
+    // Only implemented by file systems supporting symlinks
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        Err(Error::FSError(format!("{path} is not a symlink")))
+    }
     fn unmount_fs(self: Box<Self>) -> Result<T>;
 }
 
@@ -65,10 +99,11 @@ pub trait FSWrite<T> {
     ) -> Result<Self>
     where
         Self: Sized;
-    fn newfile(&mut self, path: &str, timestamp: i64) -> Result<Box<dyn WriteSeek + '_>>;
-    fn newdir(&mut self, path: &str, timestamp: i64) -> Result<()>;
+    fn newfile(&mut self, path: &str, times: &Timestamps) -> Result<Box<dyn WriteSeek + '_>>;
+    fn newdir(&mut self, path: &str, times: &Timestamps) -> Result<()>;
     fn removefile(&mut self, path: &str) -> Result<()>;
     // Setting timestamp can be handled by this fn or directly when creating a file or a dir.
-    fn settimestamp(&mut self, path: &str, timestamp: i64) -> Result<()>;
+    // Unknown (0) times are replaced by the modification time.
+    fn settimestamp(&mut self, path: &str, times: &Timestamps) -> Result<()>;
     fn unmount_fs(self: Box<Self>) -> Result<T>;
 }
@@ -1,4 +1,4 @@
-use crate::{Error, Result};
+use crate::{check_dir_entries, Error, Result};
 use crate::{FSRead, FSWrite, WriteSeek};
 use ntfs::NtfsReadSeek;
 use std::{
@@ -6,7 +6,17 @@ use std::{
     convert::TryFrom,
     io::{Read, Seek, SeekFrom, Write},
 };
-use usbsas_proto::common::{FileInfo, FileType, OutFsType};
+use usbsas_proto::common::{FileInfo, FileType, OutFsType, StreamInfo, Timestamps};
+
+fn ntfs3g_times(times: &Timestamps) -> ntfs3g::NtfsTimes {
+    let times = times.or_mtime();
+    ntfs3g::NtfsTimes {
+        creation: times.crtime,
+        modification: times.mtime,
+        change: times.ctime,
+        access: times.atime,
+    }
+}
 
 pub struct NTFS3G<T> {
     volume: ntfs3g::Ntfs3g<T>,
@@ -31,20 +41,20 @@ impl<T: Read + Write + Seek> FSWrite<T> for NTFS3G<T> {
         })
     }
 
-    fn newfile(&mut self, path: &str, timestamp: i64) -> Result<Box<dyn WriteSeek + '_>> {
+    fn newfile(&mut self, path: &str, times: &Timestamps) -> Result<Box<dyn WriteSeek + '_>> {
         log::trace!("new file {}", path);
         let file: Box<dyn WriteSeek> = Box::new(
             self.volume
-                .new_file(path, timestamp)
+                .new_file(path, &ntfs3g_times(times))
                 .map_err(|err| Error::FSError(format!("Couldn't create file {path}: {err}")))?,
         );
         Ok(file)
     }
 
-    fn newdir(&mut self, path: &str, timestamp: i64) -> Result<()> {
+    fn newdir(&mut self, path: &str, times: &Timestamps) -> Result<()> {
         log::trace!("new dir {}", path);
         self.volume
-            .new_dir(path, timestamp)
+            .new_dir(path, &ntfs3g_times(times))
             .map_err(|err| Error::FSError(format!("Couldn't create dir {path}: {err}")))
     }
 
@@ -55,7 +65,7 @@ impl<T: Read + Write + Seek> FSWrite<T> for NTFS3G<T> {
             .map_err(|err| Error::FSError(format!("Couldn't remove file {path}: {err}")))
     }
 
-    fn settimestamp(&mut self, _path: &str, _timestamp: i64) -> Result<()> {
+    fn settimestamp(&mut self, _path: &str, _times: &Timestamps) -> Result<()> {
         // Timestamp is set when creating file
         Ok(())
     }
@@ -157,19 +167,159 @@ fn ntfs_name_from_file<T: Read + Seek>(file: &ntfs::NtfsFile, reader: &mut T) ->
     Ok(name?.name().to_string_lossy())
 }
 
+const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+const IO_REPARSE_TAG_LX_SYMLINK: u32 = 0xA000_001D;
+const MAX_REPARSE_DATA_SIZE: usize = 16 * 1024;
+
+// Target of a symlink or junction from its reparse data
+fn reparse_target(data: &[u8]) -> Option<String> {
+    let u16_at = |off: usize| -> Option<usize> {
+        data.get(off..off + 2)
+            .map(|b| usize::from(u16::from_le_bytes([b[0], b[1]])))
+    };
+    let tag = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let path_buffer = match tag {
+        IO_REPARSE_TAG_SYMLINK => 20,
+        IO_REPARSE_TAG_MOUNT_POINT => 16,
+        // WSL symlinks: version followed by an utf-8 target
+        IO_REPARSE_TAG_LX_SYMLINK => {
+            return Some(String::from_utf8_lossy(data.get(12..)?).into_owned())
+        }
+        _ => return None,
+    };
+    let utf16_at = |off: usize, len: usize| -> Option<String> {
+        let bytes = data.get(path_buffer + off..path_buffer + off + len)?;
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        Some(String::from_utf16_lossy(&units))
+    };
+    // Prefer the print name, fallback to the substitute name
+    let target = match utf16_at(u16_at(12)?, u16_at(14)?) {
+        Some(name) if !name.is_empty() => name,
+        _ => {
+            let name = utf16_at(u16_at(8)?, u16_at(10)?)?;
+            name.strip_prefix("\\??\\").unwrap_or(&name).to_string()
+        }
+    };
+    Some(target.replace('\\', "/"))
+}
+
+fn ntfs_symlink_target<T: Read + Seek>(
+    ntfs_file: &ntfs::NtfsFile,
+    reader: &mut T,
+) -> Result<Option<String>> {
+    if !ntfs_file
+        .info()?
+        .file_attributes()
+        .contains(ntfs::structured_values::NtfsFileAttributeFlags::REPARSE_POINT)
+    {
+        return Ok(None);
+    }
+    let mut attributes = ntfs_file.attributes();
+    while let Some(item) = attributes.next(reader) {
+        let item = item?;
+        let attribute = item.to_attribute()?;
+        if !matches!(attribute.ty()?, ntfs::NtfsAttributeType::ReparsePoint) {
+            continue;
+        }
+        let mut value = attribute.value(reader)?;
+        let mut data = vec![0; usize::try_from(value.len())?.min(MAX_REPARSE_DATA_SIZE)];
+        let mut bytes_read = 0;
+        while bytes_read < data.len() {
+            match value.read(reader, &mut data[bytes_read..])? {
+                0 => break,
+                size => bytes_read += size,
+            }
+        }
+        data.truncate(bytes_read);
+        return Ok(reparse_target(&data));
+    }
+    Ok(None)
+}
+
+fn ntfs_file_type<T: Read + Seek>(
+    ntfs_file: &ntfs::NtfsFile,
+    reader: &mut T,
+) -> Result<(FileType, String)> {
+    // Symlinks and junctions, other reparse points (dedup, cloud files...) are
+    // read as regular files
+    if let Some(target) = ntfs_symlink_target(ntfs_file, reader)? {
+        return Ok((FileType::Symlink, target));
+    }
+    if ntfs_file.is_directory() {
+        Ok((FileType::Directory, String::new()))
+    } else {
+        Ok((FileType::Regular, String::new()))
+    }
+}
+
+// NTFS times are 100ns intervals since 1601-01-01
+fn ntfs_timestamps(ntfs_file: &ntfs::NtfsFile) -> Result<Timestamps> {
+    let info = ntfs_file.info()?;
+    let nanos = |time: ntfs::NtfsTime| {
+        (time.nt_timestamp() as i64)
+            .saturating_sub(116444736000000000)
+            .saturating_mul(100)
+    };
+    Ok(Timestamps {
+        mtime: nanos(info.modification_time()),
+        ctime: nanos(info.mft_record_modification_time()),
+        atime: nanos(info.access_time()),
+        crtime: nanos(info.creation_time()),
+    })
+}
+
 fn ntfs_file_size<T: Read + Seek>(ntfs_file: &ntfs::NtfsFile, reader: &mut T) -> Result<u64> {
     if ntfs_file.is_directory() {
         Ok(0)
     } else {
-        // Empty string here means we ask for the unnamed $DATA attribute
-        let data_item = match ntfs_file.data(reader, "") {
-            Some(data_item) => data_item?,
-            None => return Err(Error::FSError("No ntfs data stream".into())),
-        };
-        Ok(data_item.to_attribute()?.value(reader)?.len())
+        ntfs_stream_size(ntfs_file, reader, "")
     }
 }
 
+// Empty stream name means the unnamed $DATA attribute
+fn ntfs_stream_size<T: Read + Seek>(
+    ntfs_file: &ntfs::NtfsFile,
+    reader: &mut T,
+    stream: &str,
+) -> Result<u64> {
+    let data_item = match ntfs_file.data(reader, stream) {
+        Some(data_item) => data_item?,
+        None => return Err(Error::FSError("No ntfs data stream".into())),
+    };
+    Ok(data_item.to_attribute()?.value(reader)?.len())
+}
+
+// Named $DATA attributes (alternate data streams)
+fn ntfs_streams<T: Read + Seek>(
+    ntfs_file: &ntfs::NtfsFile,
+    reader: &mut T,
+) -> Result<Vec<StreamInfo>> {
+    let mut names: Vec<String> = Vec::new();
+    let mut attributes = ntfs_file.attributes();
+    while let Some(item) = attributes.next(reader) {
+        let item = item?;
+        let attribute = item.to_attribute()?;
+        if !matches!(attribute.ty()?, ntfs::NtfsAttributeType::Data) {
+            continue;
+        }
+        let name = attribute.name()?.to_string_lossy();
+        // Non resident streams may be split in several attributes
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    let mut streams = Vec::with_capacity(names.len());
+    for name in names {
+        let size = ntfs_stream_size(ntfs_file, reader, &name)?;
+        streams.push(StreamInfo { name, size });
+    }
+    Ok(streams)
+}
+
 impl<T: Read + Seek> FSRead<T> for NTFS<T> {
     fn new(mut reader: T, _sector_size: u32) -> Result<Self> {
         let fs = ntfs::Ntfs::new(&mut reader)?;
@@ -184,23 +334,21 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
         })
     }
 
-    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)> {
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)> {
         log::trace!("get attr: {}", path);
         let ntfs_file =
             ntfs_file_from_path(&self.fs, &mut self.reader, path, &mut self.file_cache)?;
-        let file_type = if ntfs_file.is_directory() {
-            FileType::Directory
+        let ts = ntfs_timestamps(&ntfs_file)?;
+        let (file_type, _) = ntfs_file_type(&ntfs_file, &mut self.reader)?;
+        let size = if file_type == FileType::Symlink {
+            0
         } else {
-            FileType::Regular
+            ntfs_file_size(&ntfs_file, &mut self.reader)?
         };
-        let size = ntfs_file_size(&ntfs_file, &mut self.reader)?;
-        // Convert ntfs timestamp to unix timestamp (e.g. nano sec to sec and
-        // subtract ntfs "epoch" 01.01.1601 00:00:00
-        let ts = ntfs_file.info()?.creation_time().nt_timestamp() as i64 / 10000000 - 11644473600;
         Ok((file_type, size, ts))
     }
 
-    fn read_dir(&mut self, path: &str) -> Result<Vec<FileInfo>> {
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>> {
         log::trace!("readdir {}", path);
         let ntfs_dir = ntfs_file_from_path(&self.fs, &mut self.reader, path, &mut self.file_cache)?;
         let mut ntfs_entries: HashMap<u64, FileInfo> = HashMap::new();
@@ -222,20 +370,23 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
             if name_string == "." || name_string == ".." {
                 continue;
             }
-            // See get_attr() above
-            let ts =
-                ntfs_file.info()?.creation_time().nt_timestamp() as i64 / 10000000 - 11644473600;
+            check_dir_entries(path, ntfs_entries.len() + 1, max_entries)?;
+            let times = ntfs_timestamps(&ntfs_file)?;
+            let (file_type, symlink_target) = ntfs_file_type(&ntfs_file, &mut self.reader)?;
             ntfs_entries.insert(
                 ntfs_file.file_record_number(),
                 FileInfo {
                     path: format!("{path}/{name_string}"),
-                    size: ntfs_file_size(&ntfs_file, &mut self.reader)?,
-                    ftype: if ntfs_file.is_directory() {
-                        FileType::Directory.into()
+                    size: if file_type == FileType::Symlink {
+                        0
                     } else {
-                        FileType::Regular.into()
+                        ntfs_file_size(&ntfs_file, &mut self.reader)?
                     },
-                    timestamp: ts,
+                    ftype: file_type.into(),
+                    timestamp: times.mtime_secs(),
+                    symlink_target,
+                    streams: ntfs_streams(&ntfs_file, &mut self.reader)?,
+                    times: Some(times),
                 },
             );
         }
@@ -250,10 +401,34 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
         offset: u64,
         bytes_to_read: u64,
     ) -> Result<u64> {
+        self.read_stream(path, "", buf, offset, bytes_to_read)
+    }
+
+    fn read_streams(&mut self, path: &str) -> Result<Vec<StreamInfo>> {
         let ntfs_file =
             ntfs_file_from_path(&self.fs, &mut self.reader, path, &mut self.file_cache)?;
+        ntfs_streams(&ntfs_file, &mut self.reader)
+    }
 
-        let data_item = match ntfs_file.data(&mut self.reader, "") {
+    fn stream_size(&mut self, path: &str, stream: &str) -> Result<u64> {
+        let ntfs_file =
+            ntfs_file_from_path(&self.fs, &mut self.reader, path, &mut self.file_cache)?;
+        ntfs_stream_size(&ntfs_file, &mut self.reader, stream)
+    }
+
+    // Empty stream name reads the content of the file
+    fn read_stream(
+        &mut self,
+        path: &str,
+        stream: &str,
+        buf: &mut Vec<u8>,
+        offset: u64,
+        bytes_to_read: u64,
+    ) -> Result<u64> {
+        let ntfs_file =
+            ntfs_file_from_path(&self.fs, &mut self.reader, path, &mut self.file_cache)?;
+
+        let data_item = match ntfs_file.data(&mut self.reader, stream) {
             Some(data_item) => data_item?,
             None => {
                 return Err(Error::FSError(format!(
@@ -283,6 +458,13 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
         }
     }
 
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let ntfs_file =
+            ntfs_file_from_path(&self.fs, &mut self.reader, path, &mut self.file_cache)?;
+        ntfs_symlink_target(&ntfs_file, &mut self.reader)?
+            .ok_or_else(|| Error::FSError(format!("{path} is not a symlink")))
+    }
+
     fn unmount_fs(self: Box<Self>) -> Result<T> {
         drop(self.fs);
         Ok(self.reader)
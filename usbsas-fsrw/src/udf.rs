@@ -0,0 +1,807 @@
+//! Read only UDF (1.02 to 2.60) implementation
+
+use crate::FSRead;
+use crate::{check_dir_entries, Error, Result};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+};
+use time::{Date, Month, PrimitiveDateTime, Time};
+use usbsas_proto::common::{FileInfo, FileType, Timestamps};
+
+const AVDP_LOCATION: u64 = 256;
+const BLOCK_SIZES: &[u64] = &[512, 1024, 2048, 4096];
+const MAX_VDS_DESCRIPTORS: u64 = 256;
+const MAX_AD_CONTINUATIONS: usize = 64;
+// Directories bigger than this are considered corrupted
+const MAX_DIR_SIZE: u64 = 64 * 1024 * 1024;
+
+const TAG_AVDP: u16 = 2;
+const TAG_PD: u16 = 5;
+const TAG_LVD: u16 = 6;
+const TAG_TD: u16 = 8;
+const TAG_FSD: u16 = 256;
+const TAG_FID: u16 = 257;
+const TAG_AED: u16 = 258;
+const TAG_FE: u16 = 261;
+const TAG_EFE: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+const FILE_TYPE_REGULAR: u8 = 5;
+const FILE_TYPE_BLOCK_DEVICE: u8 = 6;
+const FILE_TYPE_CHAR_DEVICE: u8 = 7;
+const FILE_TYPE_FIFO: u8 = 9;
+const FILE_TYPE_SOCKET: u8 = 10;
+const FILE_TYPE_SYMLINK: u8 = 12;
+const MAX_SYMLINK_SIZE: u64 = 4096;
+
+const FID_DIRECTORY: u8 = 0x02;
+const FID_DELETED: u8 = 0x04;
+const FID_PARENT: u8 = 0x08;
+
+const AD_SHORT: u16 = 0;
+const AD_LONG: u16 = 1;
+const AD_EXTENDED: u16 = 2;
+const AD_EMBEDDED: u16 = 3;
+
+const EXTENT_RECORDED: u32 = 0;
+const EXTENT_NEXT: u32 = 3;
+
+fn le_u16(buf: &[u8], off: usize) -> Result<u16> {
+    buf.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::FSError("udf: truncated descriptor".into()))
+}
+
+fn le_u32(buf: &[u8], off: usize) -> Result<u32> {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::FSError("udf: truncated descriptor".into()))
+}
+
+fn le_u64(buf: &[u8], off: usize) -> Result<u64> {
+    Ok(u64::from(le_u32(buf, off)?) | (u64::from(le_u32(buf, off + 4)?) << 32))
+}
+
+fn slice(buf: &[u8], off: usize, len: usize) -> Result<&[u8]> {
+    buf.get(off..off + len)
+        .ok_or_else(|| Error::FSError("udf: truncated descriptor".into()))
+}
+
+// Check the descriptor tag checksum and return its identifier
+fn tag_id(buf: &[u8]) -> Result<u16> {
+    let tag = slice(buf, 0, 16)?;
+    let checksum = tag
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 4)
+        .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b));
+    if checksum != tag[4] {
+        return Err(Error::FSError("udf: bad tag checksum".into()));
+    }
+    le_u16(tag, 0)
+}
+
+// Decode an OSTA compressed unicode string
+fn dstring(buf: &[u8]) -> String {
+    match buf.first() {
+        Some(8) => buf[1..].iter().map(|c| char::from(*c)).collect(),
+        Some(16) => {
+            let units: Vec<u16> = buf[1..]
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::new(),
+    }
+}
+
+// ECMA-167 timestamp to nanoseconds since the unix epoch
+fn udf_time(buf: &[u8]) -> Result<i64> {
+    let type_tz = le_u16(buf, 0)?;
+    let year = le_u16(buf, 2)? as i16;
+    let (month, day, hour, minute, second) = (buf[4], buf[5], buf[6], buf[7], buf[8]);
+    // Centiseconds, hundreds of microseconds and microseconds
+    let micros = u32::from(buf[9]) * 10000 + u32::from(buf[10]) * 100 + u32::from(buf[11]);
+    let date = match Month::try_from(month)
+        .ok()
+        .and_then(|month| Date::from_calendar_date(i32::from(year), month, day).ok())
+    {
+        Some(date) => date,
+        None => return Ok(0),
+    };
+    let time = Time::from_hms(hour, minute, second).unwrap_or(Time::MIDNIGHT);
+    let mut timestamp = PrimitiveDateTime::new(date, time)
+        .assume_utc()
+        .unix_timestamp();
+    // Local time with a specified offset (in minutes)
+    if type_tz >> 12 == 1 {
+        let offset = ((type_tz & 0x0fff) << 4) as i16 >> 4;
+        if offset != -2047 {
+            timestamp -= i64::from(offset) * 60;
+        }
+    }
+    Ok(Timestamps::nanos(timestamp, micros.min(999_999) * 1000))
+}
+
+#[derive(Clone, Debug)]
+struct Extent {
+    len: u64,
+    // partition reference and logical block, None if not recorded (sparse)
+    loc: Option<(u16, u32)>,
+}
+
+#[derive(Clone, Debug)]
+enum Data {
+    Extents(Vec<Extent>),
+    Embedded(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    ftype: FileType,
+    size: u64,
+    times: Timestamps,
+    data: Data,
+}
+
+#[derive(Clone, Debug)]
+enum PartitionMap {
+    Physical { start: u64 },
+    // Metadata partition: blocks are read through the metadata file extents
+    Metadata { physical: u16, extents: Vec<Extent> },
+}
+
+pub struct Udf<T: Read + Seek> {
+    reader: T,
+    block_size: u64,
+    partition_maps: Vec<PartitionMap>,
+    root: Node,
+    cache: HashMap<String, Node>,
+}
+
+impl<T: Read + Seek> Udf<T> {
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; self.block_size as usize];
+        self.reader.seek(SeekFrom::Start(block * self.block_size))?;
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    // Translate a logical block of a partition into a physical block
+    fn physical_block(&self, part_ref: u16, lbn: u32) -> Result<u64> {
+        match self.partition_maps.get(usize::from(part_ref)) {
+            Some(PartitionMap::Physical { start }) => Ok(start + u64::from(lbn)),
+            Some(PartitionMap::Metadata { physical, extents }) => {
+                let mut offset = u64::from(lbn) * self.block_size;
+                for extent in extents {
+                    if offset < extent.len {
+                        let (_, ext_lbn) = extent
+                            .loc
+                            .ok_or_else(|| Error::FSError("udf: sparse metadata".into()))?;
+                        return self.physical_block(
+                            *physical,
+                            ext_lbn
+                                .checked_add(u32::try_from(offset / self.block_size)?)
+                                .ok_or_else(|| Error::FSError("udf: bad block".into()))?,
+                        );
+                    }
+                    offset -= extent.len;
+                }
+                Err(Error::FSError(
+                    "udf: block out of metadata partition".into(),
+                ))
+            }
+            None => Err(Error::FSError("udf: bad partition reference".into())),
+        }
+    }
+
+    fn read_lb(&mut self, part_ref: u16, lbn: u32) -> Result<Vec<u8>> {
+        let block = self.physical_block(part_ref, lbn)?;
+        self.read_block(block)
+    }
+
+    // Parse allocation descriptors, following continuation extents
+    fn parse_ads(&mut self, part_ref: u16, ad_type: u16, ads: &[u8]) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut ads = ads.to_vec();
+        let ad_size = match ad_type {
+            AD_SHORT => 8,
+            AD_LONG => 16,
+            AD_EXTENDED => 20,
+            _ => return Err(Error::FSError("udf: bad allocation type".into())),
+        };
+        for _ in 0..MAX_AD_CONTINUATIONS {
+            let mut next = None;
+            for ad in ads.chunks_exact(ad_size) {
+                let raw_len = le_u32(ad, 0)?;
+                let (len, kind) = (u64::from(raw_len & 0x3fff_ffff), raw_len >> 30);
+                if len == 0 {
+                    break;
+                }
+                let loc = match ad_type {
+                    AD_SHORT => (part_ref, le_u32(ad, 4)?),
+                    AD_LONG => (le_u16(ad, 8)?, le_u32(ad, 4)?),
+                    _ => (le_u16(ad, 16)?, le_u32(ad, 12)?),
+                };
+                match kind {
+                    EXTENT_NEXT => {
+                        next = Some(loc);
+                        break;
+                    }
+                    EXTENT_RECORDED => extents.push(Extent {
+                        len,
+                        loc: Some(loc),
+                    }),
+                    _ => extents.push(Extent { len, loc: None }),
+                }
+            }
+            match next {
+                Some((next_part, next_lbn)) => {
+                    let block = self.read_lb(next_part, next_lbn)?;
+                    if tag_id(&block)? != TAG_AED {
+                        return Err(Error::FSError("udf: bad allocation extent".into()));
+                    }
+                    let len = usize::try_from(le_u32(&block, 20)?)?;
+                    ads = slice(&block, 24, len)?.to_vec();
+                }
+                None => return Ok(extents),
+            }
+        }
+        Err(Error::FSError("udf: too many allocation extents".into()))
+    }
+
+    fn read_node(&mut self, part_ref: u16, lbn: u32) -> Result<Node> {
+        let block = self.read_lb(part_ref, lbn)?;
+        // Extended file entries have an additional creation time
+        let (atime_off, mtime_off, ctime_off, crtime_off, ea_len_off) = match tag_id(&block)? {
+            TAG_FE => (72, 84, 96, None, 168),
+            TAG_EFE => (80, 92, 116, Some(104), 208),
+            _ => return Err(Error::FSError("udf: bad file entry".into())),
+        };
+        let ftype = match block[16 + 11] {
+            FILE_TYPE_DIRECTORY => FileType::Directory,
+            FILE_TYPE_REGULAR => FileType::Regular,
+            FILE_TYPE_BLOCK_DEVICE => FileType::BlockDevice,
+            FILE_TYPE_CHAR_DEVICE => FileType::CharDevice,
+            FILE_TYPE_FIFO => FileType::Fifo,
+            FILE_TYPE_SOCKET => FileType::Socket,
+            FILE_TYPE_SYMLINK => FileType::Symlink,
+            _ => FileType::Other,
+        };
+        let ad_type = le_u16(&block, 16 + 18)? & 0x7;
+        let ea_len = usize::try_from(le_u32(&block, ea_len_off)?)?;
+        let ad_len = usize::try_from(le_u32(&block, ea_len_off + 4)?)?;
+        let ads = slice(&block, ea_len_off + 8 + ea_len, ad_len)?;
+        let size = le_u64(&block, 56)?;
+        let data = if ad_type == AD_EMBEDDED {
+            if size > ads.len() as u64 {
+                return Err(Error::FSError(
+                    "udf: file size larger than its embedded data".into(),
+                ));
+            }
+            Data::Embedded(ads.to_vec())
+        } else {
+            Data::Extents(self.parse_ads(part_ref, ad_type, ads)?)
+        };
+        Ok(Node {
+            ftype,
+            size,
+            times: Timestamps {
+                mtime: udf_time(slice(&block, mtime_off, 12)?)?,
+                ctime: udf_time(slice(&block, ctime_off, 12)?)?,
+                atime: udf_time(slice(&block, atime_off, 12)?)?,
+                crtime: match crtime_off {
+                    Some(off) => udf_time(slice(&block, off, 12)?)?,
+                    None => 0,
+                },
+            },
+            data,
+        })
+    }
+
+    fn read_data(&mut self, node: &Node, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= node.size {
+            return Ok(0);
+        }
+        let to_read = (buf.len() as u64).min(node.size - offset) as usize;
+        let extents = match &node.data {
+            Data::Embedded(data) => {
+                let start = usize::try_from(offset)?;
+                if start >= data.len() {
+                    return Ok(0);
+                }
+                let len = to_read.min(data.len() - start);
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                return Ok(len);
+            }
+            Data::Extents(extents) => extents,
+        };
+        let mut done = 0;
+        let mut extent_offset = 0;
+        for extent in extents {
+            let pos = offset + done as u64;
+            if pos < extent_offset + extent.len {
+                let in_extent = pos - extent_offset;
+                let len = ((extent.len - in_extent) as usize).min(to_read - done);
+                match extent.loc {
+                    Some((part_ref, lbn)) => {
+                        // Extents are contiguous in their partition but may not
+                        // be on disk (metadata partition), read block by block
+                        let mut read = 0;
+                        while read < len {
+                            let cur = in_extent + read as u64;
+                            let block = self.read_lb(
+                                part_ref,
+                                lbn.checked_add(u32::try_from(cur / self.block_size)?)
+                                    .ok_or_else(|| Error::FSError("udf: bad block".into()))?,
+                            )?;
+                            let block_off = (cur % self.block_size) as usize;
+                            let chunk = (block.len() - block_off).min(len - read);
+                            buf[done + read..done + read + chunk]
+                                .copy_from_slice(&block[block_off..block_off + chunk]);
+                            read += chunk;
+                        }
+                    }
+                    None => buf[done..done + len].fill(0),
+                }
+                done += len;
+                if done == to_read {
+                    break;
+                }
+            }
+            extent_offset += extent.len;
+        }
+        Ok(done)
+    }
+
+    // Symlink data is a list of path components
+    fn symlink_target(&mut self, node: &Node) -> Result<String> {
+        if node.size > MAX_SYMLINK_SIZE {
+            return Err(Error::FSError("udf: symlink target too long".into()));
+        }
+        let mut data = vec![0; node.size as usize];
+        let len = self.read_data(node, 0, &mut data)?;
+        let mut components = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= len {
+            let (kind, ident_len) = (data[pos], usize::from(data[pos + 1]));
+            let ident = slice(&data, pos + 4, ident_len)?;
+            components.push(match kind {
+                1 | 2 => String::new(),
+                3 => "..".into(),
+                4 => ".".into(),
+                5 => dstring(ident),
+                _ => return Err(Error::FSError("udf: bad symlink component".into())),
+            });
+            pos += 4 + ident_len;
+        }
+        Ok(match components.as_slice() {
+            [root] if root.is_empty() => "/".into(),
+            _ => components.join("/"),
+        })
+    }
+
+    fn list_dir(
+        &mut self,
+        path: &str,
+        dir: &Node,
+        max_entries: Option<u64>,
+    ) -> Result<Vec<(String, Node)>> {
+        if dir.size > MAX_DIR_SIZE {
+            return Err(Error::FSError("udf: directory too big".into()));
+        }
+        let mut data = vec![0; usize::try_from(dir.size)?];
+        let len = self.read_data(dir, 0, &mut data)?;
+        data.truncate(len);
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + 38 <= data.len() {
+            let fid = &data[pos..];
+            if tag_id(fid)? != TAG_FID {
+                return Err(Error::FSError("udf: bad file identifier".into()));
+            }
+            let characteristics = fid[18];
+            let name_len = usize::from(fid[19]);
+            let iu_len = usize::from(le_u16(fid, 36)?);
+            let name = dstring(slice(fid, 38 + iu_len, name_len)?);
+            let icb_lbn = le_u32(fid, 24)?;
+            let icb_part = le_u16(fid, 28)?;
+            pos += (38 + iu_len + name_len + 3) & !3;
+            if characteristics & (FID_DELETED | FID_PARENT) != 0 || name.is_empty() {
+                continue;
+            }
+            check_dir_entries(path, entries.len() + 1, max_entries)?;
+            let mut node = self.read_node(icb_part, icb_lbn)?;
+            if characteristics & FID_DIRECTORY != 0 {
+                node.ftype = FileType::Directory;
+            }
+            entries.push((name, node));
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<Node> {
+        let path = path.trim_end_matches('/');
+        if let Some(node) = self.cache.get(path) {
+            return Ok(node.clone());
+        }
+        let mut cur = self.root.clone();
+        let mut cur_path = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if cur.ftype != FileType::Directory {
+                return Err(Error::FSError(format!("udf: {path} not found")));
+            }
+            for (name, node) in self.list_dir(&cur_path, &cur, None)? {
+                self.cache.insert(format!("{cur_path}/{name}"), node);
+            }
+            cur_path = format!("{cur_path}/{component}");
+            cur = self
+                .cache
+                .get(&cur_path)
+                .cloned()
+                .ok_or_else(|| Error::FSError(format!("udf: {path} not found")))?;
+        }
+        Ok(cur)
+    }
+
+    fn find_avdp(reader: &mut T, sector_size: u32) -> Result<(u64, Vec<u8>)> {
+        // Try the device sector size first
+        let mut block_sizes = vec![u64::from(sector_size)];
+        block_sizes.extend(
+            BLOCK_SIZES
+                .iter()
+                .filter(|bs| **bs != u64::from(sector_size)),
+        );
+        for block_size in block_sizes {
+            if !BLOCK_SIZES.contains(&block_size) {
+                continue;
+            }
+            let mut buf = vec![0; block_size as usize];
+            reader.seek(SeekFrom::Start(AVDP_LOCATION * block_size))?;
+            if reader.read_exact(&mut buf).is_err() {
+                continue;
+            }
+            if let Ok(TAG_AVDP) = tag_id(&buf) {
+                if u64::from(le_u32(&buf, 12)?) == AVDP_LOCATION {
+                    return Ok((block_size, buf));
+                }
+            }
+        }
+        Err(Error::FSError(
+            "udf: anchor volume descriptor not found".into(),
+        ))
+    }
+}
+
+impl<T: Read + Seek> FSRead<T> for Udf<T> {
+    fn new(mut reader: T, sector_size: u32) -> Result<Self> {
+        let (block_size, avdp) = Self::find_avdp(&mut reader, sector_size)?;
+        let mut fs = Udf {
+            reader,
+            block_size,
+            partition_maps: Vec::new(),
+            root: Node {
+                ftype: FileType::Directory,
+                size: 0,
+                times: Timestamps::default(),
+                data: Data::Embedded(Vec::new()),
+            },
+            cache: HashMap::new(),
+        };
+
+        // Main volume descriptor sequence
+        let vds_len = u64::from(le_u32(&avdp, 16)?) / block_size;
+        let vds_loc = u64::from(le_u32(&avdp, 20)?);
+        let mut partitions = HashMap::new();
+        let mut lvd = None;
+        for i in 0..vds_len.min(MAX_VDS_DESCRIPTORS) {
+            let desc = fs.read_block(vds_loc + i)?;
+            match tag_id(&desc)? {
+                TAG_PD => {
+                    partitions.insert(le_u16(&desc, 22)?, u64::from(le_u32(&desc, 188)?));
+                }
+                TAG_LVD => lvd = Some(desc),
+                TAG_TD => break,
+                _ => (),
+            }
+        }
+        let lvd = lvd.ok_or_else(|| Error::FSError("udf: no logical volume".into()))?;
+        if u64::from(le_u32(&lvd, 212)?) != block_size {
+            return Err(Error::FSError("udf: unsupported logical block size".into()));
+        }
+
+        // Partition maps
+        let maps_count = le_u32(&lvd, 268)?;
+        let mut pos = 440;
+        let mut numbers = Vec::new();
+        let mut metadata_maps = Vec::new();
+        for index in 0..maps_count {
+            let (map_type, map_len) = match (lvd.get(pos), lvd.get(pos + 1)) {
+                (Some(map_type), Some(map_len)) if *map_len > 0 => {
+                    (*map_type, usize::from(*map_len))
+                }
+                _ => return Err(Error::FSError("udf: bad partition map".into())),
+            };
+            let map = slice(&lvd, pos, map_len)?;
+            let number = match map_type {
+                1 => le_u16(map, 4)?,
+                2 => le_u16(map, 38)?,
+                _ => return Err(Error::FSError("udf: bad partition map".into())),
+            };
+            let start = *partitions
+                .get(&number)
+                .ok_or_else(|| Error::FSError(format!("udf: missing partition {number}")))?;
+            if map_type == 2 {
+                let ident = slice(map, 5, 23)?;
+                if ident == b"*UDF Metadata Partition" {
+                    // Replaced below once all physical partitions are known
+                    metadata_maps.push((index as usize, number, le_u32(map, 40)?));
+                } else if ident != b"*UDF Sparable Partition" {
+                    // Sparing tables only remap defective packets of
+                    // rewritable media, sparable partitions are read as is
+                    return Err(Error::FSError(format!(
+                        "udf: unsupported partition map {}",
+                        String::from_utf8_lossy(ident).trim_end_matches('\0')
+                    )));
+                }
+            }
+            fs.partition_maps.push(PartitionMap::Physical { start });
+            numbers.push(number);
+            pos += map_len;
+        }
+
+        // Metadata partitions are stored in the physical partition with the
+        // same partition number
+        let metadata_indexes: Vec<usize> = metadata_maps.iter().map(|(i, _, _)| *i).collect();
+        for (index, number, file_lbn) in metadata_maps {
+            let physical = numbers
+                .iter()
+                .enumerate()
+                .position(|(i, num)| *num == number && !metadata_indexes.contains(&i))
+                .map(u16::try_from)
+                .transpose()?
+                .ok_or_else(|| Error::FSError(format!("udf: no physical partition {number}")))?;
+            let extents = match fs.read_node(physical, file_lbn)?.data {
+                Data::Extents(extents) => extents,
+                Data::Embedded(_) => {
+                    return Err(Error::FSError("udf: bad metadata file".into()));
+                }
+            };
+            fs.partition_maps[index] = PartitionMap::Metadata { physical, extents };
+        }
+
+        // File set descriptor and root directory
+        let fsd_lbn = le_u32(&lvd, 248 + 4)?;
+        let fsd_part = le_u16(&lvd, 248 + 8)?;
+        let fsd = fs.read_lb(fsd_part, fsd_lbn)?;
+        if tag_id(&fsd)? != TAG_FSD {
+            return Err(Error::FSError("udf: bad file set descriptor".into()));
+        }
+        fs.root = fs.read_node(le_u16(&fsd, 400 + 8)?, le_u32(&fsd, 400 + 4)?)?;
+        if fs.root.ftype != FileType::Directory {
+            return Err(Error::FSError("udf: bad root directory".into()));
+        }
+        Ok(fs)
+    }
+
+    fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, Timestamps)> {
+        log::trace!("get_attr: '{}'", path);
+        let node = self.lookup(path)?;
+        let size = if node.ftype == FileType::Directory {
+            0
+        } else {
+            node.size
+        };
+        Ok((node.ftype, size, node.times))
+    }
+
+    fn read_dir(&mut self, path: &str, max_entries: Option<u64>) -> Result<Vec<FileInfo>> {
+        log::trace!("read_dir: '{}'", path);
+        let dir = self.lookup(path)?;
+        if dir.ftype != FileType::Directory {
+            return Err(Error::FSError("Cannot list a non dir entry".into()));
+        }
+        let dir_path = path.trim_end_matches('/');
+        let mut files_info = Vec::new();
+        for (name, node) in self.list_dir(path, &dir, max_entries)? {
+            let path = format!("{dir_path}/{name}");
+            let symlink_target = if node.ftype == FileType::Symlink {
+                self.symlink_target(&node)?
+            } else {
+                String::new()
+            };
+            files_info.push(FileInfo {
+                path: path.clone(),
+                ftype: node.ftype.into(),
+                size: if node.ftype == FileType::Directory {
+                    0
+                } else {
+                    node.size
+                },
+                timestamp: node.times.mtime_secs(),
+                symlink_target,
+                streams: Vec::new(),
+                times: Some(node.times),
+            });
+            self.cache.insert(path, node);
+        }
+        Ok(files_info)
+    }
+
+    fn read_file(
+        &mut self,
+        path: &str,
+        buf: &mut Vec<u8>,
+        offset: u64,
+        bytes_to_read: u64,
+    ) -> Result<u64> {
+        log::trace!("read_file: '{}'", path);
+        let node = self.lookup(path)?;
+        if node.ftype != FileType::Regular {
+            return Err(Error::FSError("Cannot read a non regular file".into()));
+        }
+        let len = usize::try_from(bytes_to_read)?.min(buf.len());
+        Ok(self.read_data(&node, offset, &mut buf[..len])? as u64)
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let node = self.lookup(path)?;
+        if node.ftype != FileType::Symlink {
+            return Err(Error::FSError(format!("{path} is not a symlink")));
+        }
+        self.symlink_target(&node)
+    }
+
+    fn unmount_fs(self: Box<Self>) -> Result<T> {
+        Ok(self.reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: usize = 512;
+    const PARTITION_START: usize = 300;
+
+    fn set_tag(desc: &mut [u8], id: u16, location: u32) {
+        desc[0..2].copy_from_slice(&id.to_le_bytes());
+        desc[2..4].copy_from_slice(&3u16.to_le_bytes());
+        desc[12..16].copy_from_slice(&location.to_le_bytes());
+        desc[4] = desc[..16]
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 4)
+            .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b));
+    }
+
+    fn block(image: &mut [u8], block: usize) -> &mut [u8] {
+        &mut image[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]
+    }
+
+    // File entry with a short allocation descriptor or embedded data
+    fn file_entry(desc: &mut [u8], file_type: u8, size: u64, ad_type: u16, ads: &[u8]) {
+        desc[16 + 11] = file_type;
+        desc[16 + 18..16 + 20].copy_from_slice(&ad_type.to_le_bytes());
+        desc[56..64].copy_from_slice(&size.to_le_bytes());
+        desc[172..176].copy_from_slice(&(ads.len() as u32).to_le_bytes());
+        desc[176..176 + ads.len()].copy_from_slice(ads);
+        set_tag(desc, TAG_FE, 0);
+    }
+
+    fn fid(characteristics: u8, name: &str, lbn: u32, part: u16) -> Vec<u8> {
+        let name = if name.is_empty() {
+            Vec::new()
+        } else {
+            [&[8u8][..], name.as_bytes()].concat()
+        };
+        let mut fid = vec![0; (38 + name.len() + 3) & !3];
+        fid[18] = characteristics;
+        fid[19] = name.len() as u8;
+        fid[24..28].copy_from_slice(&lbn.to_le_bytes());
+        fid[28..30].copy_from_slice(&part.to_le_bytes());
+        fid[38..38 + name.len()].copy_from_slice(&name);
+        set_tag(&mut fid, TAG_FID, lbn);
+        fid
+    }
+
+    // UDF 2.50 volume with its file set in a metadata partition: the metadata
+    // file (physical lbn 0) maps the metadata partition on physical lbn 1-4
+    fn udf250_image() -> Vec<u8> {
+        let mut image = vec![0; (PARTITION_START + 5) * BLOCK_SIZE];
+
+        let avdp = block(&mut image, AVDP_LOCATION as usize);
+        avdp[16..20].copy_from_slice(&(3 * BLOCK_SIZE as u32).to_le_bytes());
+        avdp[20..24].copy_from_slice(&257u32.to_le_bytes());
+        set_tag(avdp, TAG_AVDP, AVDP_LOCATION as u32);
+
+        let pd = block(&mut image, 257);
+        pd[188..192].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+        set_tag(pd, TAG_PD, 257);
+
+        let lvd = block(&mut image, 258);
+        lvd[212..216].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        // File set descriptor at lbn 0 of the metadata partition
+        lvd[248..252].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        lvd[256..258].copy_from_slice(&1u16.to_le_bytes());
+        lvd[268..272].copy_from_slice(&2u32.to_le_bytes());
+        lvd[440] = 1;
+        lvd[441] = 6;
+        lvd[446] = 2;
+        lvd[447] = 64;
+        lvd[451..474].copy_from_slice(b"*UDF Metadata Partition");
+        set_tag(lvd, TAG_LVD, 258);
+
+        set_tag(block(&mut image, 259), TAG_TD, 259);
+
+        let mut metadata_ad = (4 * BLOCK_SIZE as u32).to_le_bytes().to_vec();
+        metadata_ad.extend_from_slice(&1u32.to_le_bytes());
+        file_entry(
+            block(&mut image, PARTITION_START),
+            250,
+            4 * BLOCK_SIZE as u64,
+            AD_SHORT,
+            &metadata_ad,
+        );
+
+        let fsd = block(&mut image, PARTITION_START + 1);
+        fsd[400..404].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        fsd[404..408].copy_from_slice(&1u32.to_le_bytes());
+        fsd[408..410].copy_from_slice(&1u16.to_le_bytes());
+        set_tag(fsd, TAG_FSD, 0);
+
+        let fids = [fid(FID_PARENT, "", 1, 1), fid(0, "hello.txt", 2, 1)].concat();
+        file_entry(
+            block(&mut image, PARTITION_START + 2),
+            FILE_TYPE_DIRECTORY,
+            fids.len() as u64,
+            AD_EMBEDDED,
+            &fids,
+        );
+        file_entry(
+            block(&mut image, PARTITION_START + 3),
+            FILE_TYPE_REGULAR,
+            9,
+            AD_EMBEDDED,
+            b"hello udf",
+        );
+        image
+    }
+
+    #[test]
+    fn metadata_partition() {
+        let mut fs = Udf::new(Cursor::new(udf250_image()), 512).unwrap();
+        let files = fs.read_dir("/", None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "/hello.txt");
+        assert_eq!(files[0].ftype, FileType::Regular as i32);
+        assert_eq!(files[0].size, 9);
+        let mut buf = vec![0; 16];
+        assert_eq!(fs.read_file("/hello.txt", &mut buf, 0, 16).unwrap(), 9);
+        assert_eq!(&buf[..9], b"hello udf");
+    }
+
+    #[test]
+    fn embedded_data_size() {
+        let mut image = udf250_image();
+        let entry = block(&mut image, PARTITION_START + 3);
+        entry[56..64].copy_from_slice(&100u64.to_le_bytes());
+        set_tag(entry, TAG_FE, 0);
+        let mut fs = Udf::new(Cursor::new(image), 512).unwrap();
+        assert!(fs.read_dir("/", None).is_err());
+        let mut buf = vec![0; 16];
+        assert!(fs.read_file("/hello.txt", &mut buf, 0, 16).is_err());
+
+        let node = Node {
+            ftype: FileType::Regular,
+            size: 100,
+            times: Timestamps::default(),
+            data: Data::Embedded(b"hello udf".to_vec()),
+        };
+        assert_eq!(fs.read_data(&node, 4, &mut buf).unwrap(), 5);
+        assert_eq!(fs.read_data(&node, 50, &mut buf).unwrap(), 0);
+    }
+}
@@ -43,17 +43,22 @@ const MSC_PROTOCOL_CLASS_BULK_ONLY: u8 = 0x50;
 // Mass storage struct used by dev2scsi
 pub struct MassStorage {
     scsiusb: RwLock<ScsiUsb<GlobalContext>>,
-    pub max_lba: u32,
+    pub max_lba: u64,
     pub block_size: u32,
     pub dev_size: u64,
     pub pos: u64,
+    pub lun: u8,
+    pub luns: Vec<u8>,
     _inner: Option<File>,
 }
 
 impl MassStorage {
     fn new(scsiusb: ScsiUsb<GlobalContext>, file: Option<File>) -> Result<Self> {
         let mut scsiusb = scsiusb;
-        let (max_lba, block_size, dev_size) = scsiusb.init_mass_storage()?;
+        let luns = scsiusb.init_mass_storage()?;
+        // Open the first ready lun by default
+        let lun = luns[0];
+        let (max_lba, block_size, dev_size) = scsiusb.select_lun(lun)?;
         // TODO: support more sector size
         assert!([0x200, 0x800, 0x1000].contains(&block_size));
         Ok(MassStorage {
@@ -62,10 +67,37 @@ impl MassStorage {
             block_size,
             dev_size,
             pos: 0,
+            lun,
+            luns,
             _inner: file,
         })
     }
 
+    pub fn select_lun(&mut self, lun: u8) -> Result<()> {
+        if !self.luns.contains(&lun) {
+            return Err(Error::Error(format!(
+                "lun {} not available (ready luns: {:?})",
+                lun, self.luns
+            )));
+        }
+        let (max_lba, block_size, dev_size) = self
+            .scsiusb
+            .write()
+            .map_err(|err| Error::Error(format!("write lock error: {}", err)))?
+            .select_lun(lun)?;
+        if ![0x200, 0x800, 0x1000].contains(&block_size) {
+            return Err(Error::Error(format!(
+                "unsupported block size: {block_size}"
+            )));
+        }
+        self.max_lba = max_lba;
+        self.block_size = block_size;
+        self.dev_size = dev_size;
+        self.lun = lun;
+        self.pos = 0;
+        Ok(())
+    }
+
     pub fn from_opened_file(file: File) -> Result<Self> {
         rusb::disable_device_discovery()?;
         assert!(rusb::supports_detach_kernel_driver());
@@ -119,12 +151,12 @@ impl MassStorage {
             .read_sectors(offset, count, block_size)?)
     }
 
-    pub fn scsi_write_10(&mut self, buffer: &mut [u8], offset: u64, count: u64) -> Result<u8> {
+    pub fn scsi_write(&mut self, buffer: &mut [u8], offset: u64, count: u64) -> Result<u8> {
         let ret = self
             .scsiusb
             .write()
             .map_err(|err| io::Error::new(ErrorKind::Other, format!("lock error: {err}")))?
-            .scsi_write_10(buffer, offset, count)?;
+            .scsi_write(buffer, offset, count)?;
         // Read last sector of what we've just written and verify it's ok.
         // XXX TODO FIXME Apparently, some devices requires reads between writes
         // to avoid overwriting cache of previous write call. Read call will
@@ -134,7 +166,7 @@ impl MassStorage {
         self.scsiusb
             .write()
             .map_err(|err| io::Error::new(ErrorKind::Other, format!("lock error: {err}")))?
-            .scsi_read_10(&mut buf_check, offset + count - 1, 1)?;
+            .scsi_read(&mut buf_check, offset + count - 1, 1)?;
         if buf_check != buffer[(buffer.len() - buf_check.len())..] {
             return Err(Error::Error("write check failed".into()));
         }
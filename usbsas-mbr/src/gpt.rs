@@ -0,0 +1,325 @@
+//! GUID partition table writer
+
+use crate::{MbrPartitionEntry, MBR_SIGNATURE};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+
+/// gpt header signature
+pub const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// gpt header revision 1.0
+pub const GPT_REVISION: u32 = 0x0001_0000;
+/// gpt header size in bytes
+pub const GPT_HEADER_SIZE: usize = 92;
+/// number of entries in the partition array
+pub const GPT_ENTRIES: u64 = 128;
+/// size of a partition entry in bytes
+pub const GPT_ENTRY_SIZE: u64 = 128;
+/// alignment of the first partition in bytes (1MiB)
+pub const GPT_PART_ALIGN: u64 = 0x10_0000;
+/// protective mbr partition type
+pub const GPT_PROTECTIVE_TYPE: u8 = 0xEE;
+
+/// build a GUID as stored on disk (first three fields little endian)
+pub const fn guid(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> [u8; 16] {
+    let d1 = d1.to_le_bytes();
+    let d2 = d2.to_le_bytes();
+    let d3 = d3.to_le_bytes();
+    [
+        d1[0], d1[1], d1[2], d1[3], d2[0], d2[1], d3[0], d3[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+        d4[5], d4[6], d4[7],
+    ]
+}
+
+/// Microsoft basic data partition type (FAT, exFAT, NTFS)
+pub const GPT_TYPE_BASIC_DATA: [u8; 16] = guid(
+    0xEBD0_A0A2,
+    0xB9E5,
+    0x4433,
+    [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+);
+
+/// Linux filesystem data partition type
+pub const GPT_TYPE_LINUX_FS: [u8; 16] = guid(
+    0x0FC6_3DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+
+/// turn random bytes into a version 4 GUID
+pub fn guid_from_random(mut bytes: [u8; 16]) -> [u8; 16] {
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes
+}
+
+/// gpt partition entry
+#[derive(Debug, Default)]
+pub struct GptPartitionEntry {
+    pub type_guid: [u8; 16],
+    pub part_guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartitionEntry {
+    pub fn to_bytes(&self) -> [u8; GPT_ENTRY_SIZE as usize] {
+        let mut buf = [0; GPT_ENTRY_SIZE as usize];
+        buf[0..16].copy_from_slice(&self.type_guid);
+        buf[16..32].copy_from_slice(&self.part_guid);
+        LittleEndian::write_u64(&mut buf[32..40], self.first_lba);
+        LittleEndian::write_u64(&mut buf[40..48], self.last_lba);
+        LittleEndian::write_u64(&mut buf[48..56], self.attributes);
+        // UTF-16LE name, 36 code units max
+        for (i, c) in self.name.encode_utf16().take(36).enumerate() {
+            LittleEndian::write_u16(&mut buf[56 + 2 * i..58 + 2 * i], c);
+        }
+        buf
+    }
+}
+
+/// standard crc32 (IEEE 802.3) used by gpt headers
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// number of sectors of the partition entries array
+pub fn entries_sectors(sector_size: u64) -> u64 {
+    (GPT_ENTRIES * GPT_ENTRY_SIZE).div_ceil(sector_size)
+}
+
+/// first and last usable lba of a disk of `sector_count` sectors
+pub fn usable_lbas(sector_size: u64, sector_count: u64) -> io::Result<(u64, u64)> {
+    let reserved = 1 + entries_sectors(sector_size);
+    // protective mbr + primary header/entries + backup entries/header
+    if sector_count <= 1 + 2 * reserved {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Disk too small"));
+    }
+    Ok((1 + reserved, sector_count - 1 - reserved))
+}
+
+fn header(
+    current_lba: u64,
+    backup_lba: u64,
+    usable: (u64, u64),
+    disk_guid: &[u8; 16],
+    entries_lba: u64,
+    entries_crc: u32,
+) -> [u8; GPT_HEADER_SIZE] {
+    let mut buf = [0; GPT_HEADER_SIZE];
+    buf[0..8].copy_from_slice(GPT_SIGNATURE);
+    LittleEndian::write_u32(&mut buf[8..12], GPT_REVISION);
+    LittleEndian::write_u32(&mut buf[12..16], GPT_HEADER_SIZE as u32);
+    LittleEndian::write_u64(&mut buf[24..32], current_lba);
+    LittleEndian::write_u64(&mut buf[32..40], backup_lba);
+    LittleEndian::write_u64(&mut buf[40..48], usable.0);
+    LittleEndian::write_u64(&mut buf[48..56], usable.1);
+    buf[56..72].copy_from_slice(disk_guid);
+    LittleEndian::write_u64(&mut buf[72..80], entries_lba);
+    LittleEndian::write_u32(&mut buf[80..84], GPT_ENTRIES as u32);
+    LittleEndian::write_u32(&mut buf[84..88], GPT_ENTRY_SIZE as u32);
+    LittleEndian::write_u32(&mut buf[88..92], entries_crc);
+    // header crc is computed with its own field zeroed
+    let crc = crc32(&buf);
+    LittleEndian::write_u32(&mut buf[16..20], crc);
+    buf
+}
+
+/// write a protective mbr, primary and backup gpt on a disk of
+/// `sector_count` sectors
+pub fn write_gpt<T>(
+    dev: &mut T,
+    sector_size: u64,
+    sector_count: u64,
+    disk_guid: &[u8; 16],
+    partitions: &[GptPartitionEntry],
+) -> io::Result<()>
+where
+    T: Write + Seek,
+{
+    if sector_size < crate::MBR_SIZE as u64 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Bad sector size"));
+    }
+    if partitions.len() as u64 > GPT_ENTRIES {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Too many partitions",
+        ));
+    }
+    let usable = usable_lbas(sector_size, sector_count)?;
+    let mut entries = vec![0; (entries_sectors(sector_size) * sector_size) as usize];
+    for (i, part) in partitions.iter().enumerate() {
+        if part.first_lba < usable.0 || part.last_lba > usable.1 || part.first_lba > part.last_lba {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Partition out of usable space",
+            ));
+        }
+        let off = i * GPT_ENTRY_SIZE as usize;
+        entries[off..off + GPT_ENTRY_SIZE as usize].copy_from_slice(&part.to_bytes());
+    }
+    let entries_crc = crc32(&entries[..(GPT_ENTRIES * GPT_ENTRY_SIZE) as usize]);
+    let last_lba = sector_count - 1;
+    let backup_entries_lba = usable.1 + 1;
+
+    // Protective mbr covering the whole disk
+    let mut mbr = vec![0; sector_size as usize];
+    let protective = MbrPartitionEntry {
+        boot_indicator: 0,
+        start_head: 0,
+        start_sector: 2,
+        start_cylinder: 0,
+        partition_type: GPT_PROTECTIVE_TYPE,
+        end_head: 0xff,
+        end_sector: 0xff,
+        end_cylinder: 0xff,
+        start_in_lba: 1,
+        size_in_lba: u32::try_from(last_lba).unwrap_or(u32::MAX),
+    };
+    mbr[446..462].copy_from_slice(&protective.to_bytes()?);
+    mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+    dev.seek(SeekFrom::Start(0))?;
+    dev.write_all(&mbr)?;
+
+    let mut sector = vec![0; sector_size as usize];
+
+    // Primary header and entries
+    sector[..GPT_HEADER_SIZE].copy_from_slice(&header(
+        1,
+        last_lba,
+        usable,
+        disk_guid,
+        2,
+        entries_crc,
+    ));
+    dev.seek(SeekFrom::Start(sector_size))?;
+    dev.write_all(&sector)?;
+    dev.write_all(&entries)?;
+
+    // Backup entries and header at the end of the disk
+    sector[..GPT_HEADER_SIZE].copy_from_slice(&header(
+        last_lba,
+        1,
+        usable,
+        disk_guid,
+        backup_entries_lba,
+        entries_crc,
+    ));
+    dev.seek(SeekFrom::Start(backup_entries_lba * sector_size))?;
+    dev.write_all(&entries)?;
+    dev.write_all(&sector)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_usable_lbas() {
+        assert_eq!(entries_sectors(512), 32);
+        assert_eq!(entries_sectors(4096), 4);
+        assert_eq!(usable_lbas(512, 4096).unwrap(), (34, 4062));
+        assert_eq!(usable_lbas(512, 68).unwrap(), (34, 34));
+        assert!(usable_lbas(512, 67).is_err());
+    }
+
+    // Check a header and return its crc of the partition entries
+    fn check_header(header: &[u8], current: u64, backup: u64, entries_lba: u64) -> u32 {
+        assert_eq!(&header[0..8], GPT_SIGNATURE);
+        assert_eq!(LittleEndian::read_u32(&header[8..12]), GPT_REVISION);
+        assert_eq!(
+            LittleEndian::read_u32(&header[12..16]),
+            GPT_HEADER_SIZE as u32
+        );
+        // header crc is computed with its own field zeroed
+        let mut zeroed = header[..GPT_HEADER_SIZE].to_vec();
+        zeroed[16..20].fill(0);
+        assert_eq!(crc32(&zeroed), LittleEndian::read_u32(&header[16..20]));
+        assert_eq!(LittleEndian::read_u64(&header[24..32]), current);
+        assert_eq!(LittleEndian::read_u64(&header[32..40]), backup);
+        assert_eq!(LittleEndian::read_u64(&header[40..48]), 34);
+        assert_eq!(LittleEndian::read_u64(&header[48..56]), 4062);
+        assert_eq!(&header[56..72], &[2; 16]);
+        assert_eq!(LittleEndian::read_u64(&header[72..80]), entries_lba);
+        assert_eq!(LittleEndian::read_u32(&header[80..84]), GPT_ENTRIES as u32);
+        assert_eq!(
+            LittleEndian::read_u32(&header[84..88]),
+            GPT_ENTRY_SIZE as u32
+        );
+        assert!(header[GPT_HEADER_SIZE..].iter().all(|&b| b == 0));
+        LittleEndian::read_u32(&header[88..92])
+    }
+
+    #[test]
+    fn test_write_gpt() {
+        let sector_count = 4096;
+        let mut dev = Cursor::new(vec![0; 4096 * 512]);
+        let part = GptPartitionEntry {
+            type_guid: GPT_TYPE_BASIC_DATA,
+            part_guid: guid_from_random([1; 16]),
+            first_lba: 2048,
+            last_lba: 4062,
+            attributes: 0,
+            name: "usbsas".into(),
+        };
+        write_gpt(&mut dev, 512, sector_count, &[2; 16], &[part]).unwrap();
+        let data = dev.into_inner();
+
+        // Protective mbr
+        assert_eq!(data[450], GPT_PROTECTIVE_TYPE);
+        assert_eq!(LittleEndian::read_u32(&data[454..458]), 1);
+        assert_eq!(LittleEndian::read_u32(&data[458..462]), 4095);
+        assert_eq!(&data[510..512], &MBR_SIGNATURE);
+
+        // Primary header at lba 1, its entries at lba 2
+        let entries_crc = check_header(&data[512..1024], 1, 4095, 2);
+        let entries = &data[1024..34 * 512];
+        assert_eq!(crc32(entries), entries_crc);
+        assert_eq!(&entries[0..16], &GPT_TYPE_BASIC_DATA);
+        assert_eq!(&entries[16..32], &guid_from_random([1; 16]));
+        assert_eq!(LittleEndian::read_u64(&entries[32..40]), 2048);
+        assert_eq!(LittleEndian::read_u64(&entries[40..48]), 4062);
+        assert_eq!(&entries[56..70], b"u\0s\0b\0s\0a\0s\0\0\0");
+        assert!(entries[GPT_ENTRY_SIZE as usize..].iter().all(|&b| b == 0));
+
+        // Backup entries just before the backup header at the last lba
+        assert_eq!(
+            check_header(&data[4095 * 512..], 4095, 1, 4063),
+            entries_crc
+        );
+        assert_eq!(&data[4063 * 512..4095 * 512], entries);
+        // Partition data is left untouched
+        assert!(data[34 * 512..4063 * 512].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_write_gpt_bad_partition() {
+        let mut dev = Cursor::new(vec![0; 4096 * 512]);
+        let part = GptPartitionEntry {
+            first_lba: 2048,
+            last_lba: 4063,
+            ..Default::default()
+        };
+        assert!(write_gpt(&mut dev, 512, 4096, &[2; 16], &[part]).is_err());
+    }
+}
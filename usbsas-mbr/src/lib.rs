@@ -1,8 +1,13 @@
-//! Master boot record parser
+//! Master boot record parser (and GUID partition table writer)
 
 use byteorder::{ByteOrder, LittleEndian};
 
-use std::io::{self, ErrorKind};
+pub mod gpt;
+
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+};
 
 /// mbr standard magic number
 /// # Value
@@ -17,6 +22,8 @@ pub const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
 /// ```
 pub const MBR_SIZE: usize = 512;
 pub const SECTOR_START: u64 = 0x3f;
+/// maximum number of logical partitions read from an extended partition
+pub const MAX_LOGICAL_PARTITIONS: usize = 128;
 
 /// mbr partition entry structure
 #[derive(Debug, Default)]
@@ -91,9 +98,125 @@ pub fn parse_partition_table(buffer: &[u8]) -> Result<Vec<MbrPartitionEntry>, io
     Ok(partition_table)
 }
 
+/// whether a partition type is an extended partition (container of logical
+/// partitions)
+pub fn is_extended(partition_type: u8) -> bool {
+    matches!(partition_type, 0x05 | 0x0f | 0x85)
+}
+
+/// follow the EBR chain of an extended partition starting at `ext_start` (in
+/// sectors) and return its logical partitions, with `start_in_lba` absolute
+pub fn parse_extended_partitions<T>(
+    dev: &mut T,
+    sector_size: u64,
+    ext_start: u64,
+) -> Result<Vec<MbrPartitionEntry>, io::Error>
+where
+    T: Read + Seek,
+{
+    let sector_size = usize::try_from(sector_size)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Bad sector size"))?;
+    if sector_size < MBR_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Bad sector size"));
+    }
+    let mut partitions = Vec::new();
+    let mut visited = HashSet::new();
+    let mut buffer = vec![0; sector_size];
+    let mut ebr_start = ext_start;
+    loop {
+        if !visited.insert(ebr_start) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Loop in EBR chain"));
+        }
+        if visited.len() > MAX_LOGICAL_PARTITIONS {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Too many logical partitions",
+            ));
+        }
+        let offset = ebr_start
+            .checked_mul(sector_size as u64)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Bad EBR offset"))?;
+        dev.seek(SeekFrom::Start(offset))?;
+        dev.read_exact(&mut buffer)?;
+        if buffer[510..512] != MBR_SIGNATURE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Bad ebr signature"));
+        }
+        // First entry is the logical partition, relative to this EBR
+        let mut entry = MbrPartitionEntry::from_bytes(&buffer[446..462]);
+        if entry.partition_type != 0x00 && entry.size_in_lba != 0 {
+            entry.start_in_lba = u64::from(entry.start_in_lba)
+                .checked_add(ebr_start)
+                .and_then(|start| u32::try_from(start).ok())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Bad partition start"))?;
+            partitions.push(entry);
+        }
+        // Second entry is the next EBR, relative to the extended partition
+        let next = MbrPartitionEntry::from_bytes(&buffer[462..478]);
+        if !is_extended(next.partition_type) || next.start_in_lba == 0 {
+            break;
+        }
+        ebr_start = ext_start + u64::from(next.start_in_lba);
+    }
+    Ok(partitions)
+}
+
 pub fn write_partition<T>(file: &mut T, partition: &MbrPartitionEntry) -> io::Result<()>
 where
     T: std::io::Seek + std::io::Write,
 {
     file.write_all(&partition.to_bytes()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn ebr(logical: (u8, u32, u32), next: u32) -> [u8; MBR_SIZE] {
+        let mut buf = [0; MBR_SIZE];
+        let (partition_type, start_in_lba, size_in_lba) = logical;
+        let entry = MbrPartitionEntry {
+            partition_type,
+            start_in_lba,
+            size_in_lba,
+            ..Default::default()
+        };
+        buf[446..462].copy_from_slice(&entry.to_bytes().unwrap());
+        if next != 0 {
+            let entry = MbrPartitionEntry {
+                partition_type: 0x05,
+                start_in_lba: next,
+                size_in_lba: 1,
+                ..Default::default()
+            };
+            buf[462..478].copy_from_slice(&entry.to_bytes().unwrap());
+        }
+        buf[510..512].copy_from_slice(&MBR_SIGNATURE);
+        buf
+    }
+
+    fn disk(ebrs: &[(u64, [u8; MBR_SIZE])]) -> Cursor<Vec<u8>> {
+        let mut data = vec![0; 64 * MBR_SIZE];
+        for (sector, ebr) in ebrs {
+            let off = *sector as usize * MBR_SIZE;
+            data[off..off + MBR_SIZE].copy_from_slice(ebr);
+        }
+        Cursor::new(data)
+    }
+
+    #[test]
+    fn test_ebr_chain() {
+        let mut dev = disk(&[(8, ebr((0x0c, 2, 4), 10)), (18, ebr((0x83, 2, 6), 0))]);
+        let parts = parse_extended_partitions(&mut dev, 512, 8).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!((parts[0].partition_type, parts[0].start_in_lba), (0x0c, 10));
+        assert_eq!((parts[1].partition_type, parts[1].start_in_lba), (0x83, 20));
+    }
+
+    #[test]
+    fn test_ebr_loop() {
+        // second EBR points to itself
+        let mut dev = disk(&[(8, ebr((0x0c, 2, 4), 10)), (18, ebr((0x83, 2, 4), 10))]);
+        assert!(parse_extended_partitions(&mut dev, 512, 8).is_err());
+    }
+}
@@ -9,6 +9,8 @@ pub struct MockMassStorage {
     pub block_size: u32,
     pub dev_size: u64,
     pub pos: u64,
+    pub lun: u8,
+    pub luns: Vec<u8>,
 }
 
 impl MockMassStorage {
@@ -19,9 +21,21 @@ impl MockMassStorage {
             block_size: 512,
             dev_size,
             pos: 0,
+            lun: 0,
+            luns: vec![0],
         })
     }
 
+    pub fn select_lun(&mut self, lun: u8) -> Result<(), io::Error> {
+        if lun != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("lun {lun} not available"),
+            ));
+        }
+        Ok(())
+    }
+
     pub fn read_sectors(
         &mut self,
         offset: u64,
@@ -35,12 +49,7 @@ impl MockMassStorage {
         Ok(buf)
     }
 
-    pub fn scsi_write_10(
-        &mut self,
-        buffer: &mut [u8],
-        offset: u64,
-        _: u64,
-    ) -> Result<u8, io::Error> {
+    pub fn scsi_write(&mut self, buffer: &mut [u8], offset: u64, _: u64) -> Result<u8, io::Error> {
         self.fakedev
             .seek(SeekFrom::Start(offset * self.block_size as u64))?;
         self.fakedev.write_all(buffer)?;
@@ -43,6 +43,8 @@ impl MockUsbDev {
                 serial: "plop".to_string(),
                 is_src: true,
                 is_dst: false,
+                lun: None,
+                max_lun: 0,
             });
         }
 
@@ -58,6 +60,8 @@ impl MockUsbDev {
                 serial: "plop".to_string(),
                 is_src: false,
                 is_dst: true,
+                lun: None,
+                max_lun: 0,
             });
         }
 
@@ -1,11 +1,16 @@
-use crate::{Error, HttpClient, Result};
-use log::{error, trace};
+use crate::{
+    clamd::{Clamd, Verdict},
+    Error, HttpClient, Result,
+};
+use log::{error, trace, warn};
 use reqwest::blocking::Body;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::File,
     io::{self, Read},
+    os::unix::io::AsRawFd,
     thread::sleep,
     time::Duration,
 };
@@ -13,6 +18,10 @@ use usbsas_comm::{protoresponse, Comm};
 use usbsas_config::{conf_parse, conf_read};
 use usbsas_proto as proto;
 use usbsas_proto::analyzer::request::Msg;
+use usbsas_utils::TAR_DATA_DIR;
+
+// clamd's default StreamMaxLength
+const DEFAULT_CLAMD_MAX_FILE_SIZE: u64 = 25 * 1024 * 1024;
 
 protoresponse!(
     CommAnalyzer,
@@ -58,6 +67,7 @@ impl Read for FileReaderProgress {
 enum State {
     Init(InitState),
     Running(RunningState),
+    Clamd(ClamdState),
     WaitEnd(WaitEndState),
     End,
 }
@@ -67,6 +77,7 @@ impl State {
         match self {
             State::Init(s) => s.run(comm),
             State::Running(s) => s.run(comm),
+            State::Clamd(s) => s.run(comm),
             State::WaitEnd(s) => s.run(comm),
             State::End => Err(Error::State),
         }
@@ -84,6 +95,12 @@ struct RunningState {
     http_client: HttpClient,
 }
 
+struct ClamdState {
+    file: Option<File>,
+    socket_path: String,
+    max_file_size: u64,
+}
+
 struct WaitEndState {}
 
 impl InitState {
@@ -105,19 +122,33 @@ impl InitState {
 
         // XXX seccomp
 
-        if let Some(conf) = config.analyzer {
-            Ok(State::Running(RunningState {
+        let Some(conf) = config.analyzer else {
+            warn!("No analyzer conf, parking");
+            return Ok(State::WaitEnd(WaitEndState {}));
+        };
+
+        if let Some(socket_path) = conf.clamd_socket {
+            // The tar is open, deny every other path than clamd's socket
+            // (connecting to it isn't restricted by landlock though, seccomp
+            // is entered once connected)
+            usbsas_sandbox::landlock(Some(&[&socket_path]), None)?;
+            return Ok(State::Clamd(ClamdState {
                 file: Some(file),
-                url: conf.url,
-                http_client: HttpClient::new(
-                    #[cfg(feature = "authkrb")]
-                    conf.krb_service_name,
-                )?,
-            }))
-        } else {
-            log::warn!("No analyzer conf, parking");
-            Ok(State::WaitEnd(WaitEndState {}))
+                socket_path,
+                max_file_size: conf
+                    .clamd_max_file_size
+                    .unwrap_or(DEFAULT_CLAMD_MAX_FILE_SIZE),
+            }));
         }
+
+        Ok(State::Running(RunningState {
+            file: Some(file),
+            url: conf.url.ok_or(Error::NoConf)?,
+            http_client: HttpClient::new(
+                #[cfg(feature = "authkrb")]
+                conf.krb_service_name,
+            )?,
+        }))
     }
 }
 
@@ -213,6 +244,108 @@ impl RunningState {
     }
 }
 
+impl ClamdState {
+    fn run(mut self, comm: &mut Comm<proto::analyzer::Request>) -> Result<State> {
+        loop {
+            let req: proto::analyzer::Request = comm.recv()?;
+            let res = match req.msg.ok_or(Error::BadRequest)? {
+                Msg::Analyze(req) => self.analyze(comm, &req.id),
+                Msg::End(_) => {
+                    comm.end(proto::analyzer::ResponseEnd {})?;
+                    break;
+                }
+            };
+            if let Err(err) = res {
+                error!("{}", err);
+                comm.error(proto::analyzer::ResponseError {
+                    err: format!("{err}"),
+                })?;
+            }
+        }
+        Ok(State::End)
+    }
+
+    /// Scan each file of the tar with clamd, the report has the format of
+    /// the remote analyzer's
+    fn analyze(&mut self, comm: &mut Comm<proto::analyzer::Request>, uid: &str) -> Result<()> {
+        trace!("req analyze (clamd)");
+        let file = self.file.take().ok_or(Error::BadRequest)?;
+        let total_size = file.metadata()?.len();
+
+        let mut clamd = Clamd::connect(&self.socket_path)?;
+        usbsas_sandbox::analyzer::seccomp_clamd(
+            comm.input_fd(),
+            comm.output_fd(),
+            file.as_raw_fd(),
+            clamd.as_raw_fd(),
+        )?;
+        let (clam_ver, db_ver, db_date) = clamd.version()?;
+
+        let data_dir = TAR_DATA_DIR.trim_end_matches('/').to_owned() + "/";
+        let mut files = BTreeMap::new();
+        let mut percent = 0;
+        let mut archive = tar::Archive::new(file);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            let path_name = entry.path()?.to_string_lossy().to_string();
+            let Some(name) = path_name.strip_prefix(&data_dir) else {
+                continue;
+            };
+            let size = entry.size();
+            let status = if size > self.max_file_size {
+                warn!("{} not scanned, larger than {}B", name, self.max_file_size);
+                json!({ "status": "ERROR", "error": "file too large" })
+            } else {
+                match clamd.instream(&mut entry)? {
+                    Verdict::Clean => json!({ "status": "CLEAN" }),
+                    Verdict::Infected(signature) => {
+                        warn!("{} infected: {}", name, signature);
+                        json!({ "status": "DIRTY", "signature": signature })
+                    }
+                    Verdict::Error(err) => {
+                        error!("couldn't scan {}: {}", name, err);
+                        json!({ "status": "ERROR", "error": err })
+                    }
+                }
+            };
+            files.insert(name.to_owned(), status);
+
+            // Only report each percent to keep the status of the client small
+            let current_size = entry.raw_file_position() + size;
+            if current_size * 100 / total_size > percent {
+                percent = current_size * 100 / total_size;
+                comm.uploadstatus(proto::analyzer::ResponseUploadStatus {
+                    current_size,
+                    total_size,
+                })?;
+            }
+        }
+        clamd.end()?;
+
+        let report = json!({
+            "id": uid,
+            "status": "scanned",
+            "version": 2,
+            "files": files,
+            "antivirus": {
+                "ClamAV": {
+                    "version": clam_ver,
+                    "database_version": db_ver,
+                    "database_timestamp": db_date,
+                }
+            }
+        });
+        trace!("clamd report: {}", &report);
+        comm.analyze(proto::analyzer::ResponseAnalyze {
+            report: serde_json::to_string(&report)?,
+        })?;
+        Ok(())
+    }
+}
+
 impl WaitEndState {
     fn run(self, comm: &mut Comm<proto::analyzer::Request>) -> Result<State> {
         trace!("wait end state");
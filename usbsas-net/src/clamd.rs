@@ -0,0 +1,286 @@
+//! Client of a local clamd. Files are scanned with `INSTREAM` commands in a
+//! single session (`IDSESSION`), so that the analyzer only needs the socket it
+//! opened before entering seccomp.
+
+use crate::{Error, Result};
+use std::{
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::UnixStream,
+    },
+};
+
+// Size of the chunks of files streamed to clamd
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Clean,
+    Infected(String),
+    Error(String),
+}
+
+pub(crate) struct Clamd {
+    socket: UnixStream,
+    // Replies in a session are prefixed with the number of the command
+    id: u64,
+    // clamd may end the session after an error, the sandbox doesn't allow
+    // connecting again so the next files aren't scanned
+    ended: bool,
+}
+
+impl Clamd {
+    pub(crate) fn connect(socket_path: &str) -> Result<Self> {
+        Self::new(UnixStream::connect(socket_path)?)
+    }
+
+    fn new(mut socket: UnixStream) -> Result<Self> {
+        socket.write_all(b"zIDSESSION\0")?;
+        Ok(Clamd {
+            socket,
+            id: 0,
+            ended: false,
+        })
+    }
+
+    fn command(&mut self, command: &[u8]) -> Result<()> {
+        self.id += 1;
+        self.socket.write_all(command)?;
+        Ok(())
+    }
+
+    fn reply(&mut self) -> Result<String> {
+        let mut reply = Vec::new();
+        let mut buf = [0; 512];
+        while reply.last() != Some(&0) {
+            let size = self.socket.read(&mut buf)?;
+            if size == 0 {
+                return Err(Error::Clamd("connection closed".into()));
+            }
+            reply.extend_from_slice(&buf[..size]);
+        }
+        reply.pop();
+        let reply = String::from_utf8_lossy(&reply);
+        let (id, reply) = reply
+            .split_once(": ")
+            .ok_or_else(|| Error::Clamd(format!("bad reply: {reply}")))?;
+        if id != self.id.to_string() {
+            return Err(Error::Clamd(format!("unexpected reply to {id}")));
+        }
+        Ok(reply.to_owned())
+    }
+
+    /// clamd, database version and database date
+    pub(crate) fn version(&mut self) -> Result<(String, String, String)> {
+        self.command(b"zVERSION\0")?;
+        let reply = self.reply()?;
+        let mut versions = reply.splitn(3, '/').map(str::to_owned);
+        Ok((
+            versions.next().unwrap_or_default(),
+            versions.next().unwrap_or_default(),
+            versions.next().unwrap_or_default(),
+        ))
+    }
+
+    /// Stream `data` to clamd, chunks are prefixed with their size and an
+    /// empty one ends the stream. Errors writing to clamd are returned in the
+    /// inner result: it stops reading once StreamMaxLength is reached but
+    /// still replies.
+    fn stream(&mut self, data: &mut impl Read) -> Result<io::Result<()>> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let size = data.read(&mut buf)?;
+            if let Err(err) = self.socket.write_all(&(size as u32).to_be_bytes()) {
+                return Ok(Err(err));
+            }
+            if size == 0 {
+                return Ok(Ok(()));
+            }
+            if let Err(err) = self.socket.write_all(&buf[..size]) {
+                return Ok(Err(err));
+            }
+        }
+    }
+
+    pub(crate) fn instream(&mut self, data: &mut impl Read) -> Result<Verdict> {
+        if self.ended {
+            return Ok(Verdict::Error("clamd ended the session".into()));
+        }
+        self.command(b"zINSTREAM\0")?;
+        let streamed = self.stream(data)?;
+        let reply = match self.reply() {
+            Ok(reply) => reply,
+            Err(err) => {
+                streamed?;
+                return Err(err);
+            }
+        };
+        if let Some(reply) = reply.strip_prefix("stream: ") {
+            if reply == "OK" {
+                return Ok(Verdict::Clean);
+            }
+            if let Some(signature) = reply.strip_suffix(" FOUND") {
+                return Ok(Verdict::Infected(signature.to_owned()));
+            }
+        }
+        // e.g. "INSTREAM size limit exceeded", this file is rejected and the
+        // session is considered ended
+        self.ended = true;
+        Ok(Verdict::Error(
+            reply.trim_end_matches(" ERROR").trim().to_owned(),
+        ))
+    }
+
+    pub(crate) fn end(mut self) -> Result<()> {
+        if !self.ended {
+            self.socket.write_all(b"zEND\0")?;
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Clamd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Client and the other end of its socket, playing clamd
+    fn session() -> (Clamd, UnixStream) {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let clamd = Clamd::new(client).unwrap();
+        let mut command = [0; 11];
+        server.read_exact(&mut command).unwrap();
+        assert_eq!(&command, b"zIDSESSION\0");
+        (clamd, server)
+    }
+
+    fn read_command(server: &mut UnixStream) -> Vec<u8> {
+        let mut command = Vec::new();
+        let mut byte = [0];
+        while command.last() != Some(&0) {
+            server.read_exact(&mut byte).unwrap();
+            command.push(byte[0]);
+        }
+        command
+    }
+
+    // Sizes of the chunks of a stream and its data
+    fn read_stream(server: &mut UnixStream) -> (Vec<u32>, Vec<u8>) {
+        let mut sizes = Vec::new();
+        let mut data = Vec::new();
+        loop {
+            let mut size = [0; 4];
+            server.read_exact(&mut size).unwrap();
+            let size = u32::from_be_bytes(size);
+            sizes.push(size);
+            if size == 0 {
+                return (sizes, data);
+            }
+            let mut chunk = vec![0; size as usize];
+            server.read_exact(&mut chunk).unwrap();
+            data.extend(chunk);
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        let (mut clamd, mut server) = session();
+        server
+            .write_all(b"1: ClamAV 1.4.3/27794/Sat Oct 17 07:35:14 2026\0")
+            .unwrap();
+        assert_eq!(
+            clamd.version().unwrap(),
+            (
+                "ClamAV 1.4.3".to_owned(),
+                "27794".to_owned(),
+                "Sat Oct 17 07:35:14 2026".to_owned()
+            )
+        );
+        assert_eq!(read_command(&mut server), b"zVERSION\0");
+    }
+
+    #[test]
+    fn test_replies() {
+        let (mut clamd, mut server) = session();
+        server.write_all(b"1: stream: OK\0").unwrap();
+        assert_eq!(clamd.instream(&mut &b"clean"[..]).unwrap(), Verdict::Clean);
+        server
+            .write_all(b"2: stream: Win.Test.EICAR_HDB-1 FOUND\0")
+            .unwrap();
+        assert_eq!(
+            clamd.instream(&mut &b"eicar"[..]).unwrap(),
+            Verdict::Infected("Win.Test.EICAR_HDB-1".to_owned())
+        );
+        server
+            .write_all(b"3: stream: Can't allocate memory ERROR\0")
+            .unwrap();
+        assert_eq!(
+            clamd.instream(&mut &b"data"[..]).unwrap(),
+            Verdict::Error("stream: Can't allocate memory".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_reply_id() {
+        let (mut clamd, mut server) = session();
+        server.write_all(b"2: stream: OK\0").unwrap();
+        assert!(matches!(
+            clamd.instream(&mut &b"data"[..]),
+            Err(Error::Clamd(err)) if err == "unexpected reply to 2"
+        ));
+        server.write_all(b"OK\0").unwrap();
+        assert!(matches!(
+            clamd.instream(&mut &b"data"[..]),
+            Err(Error::Clamd(err)) if err.starts_with("bad reply")
+        ));
+    }
+
+    #[test]
+    fn test_instream() {
+        let (mut clamd, mut server) = session();
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        server.write_all(b"1: stream: OK\0").unwrap();
+        assert_eq!(clamd.instream(&mut &data[..]).unwrap(), Verdict::Clean);
+        assert_eq!(read_command(&mut server), b"zINSTREAM\0");
+        let (sizes, streamed) = read_stream(&mut server);
+        assert_eq!(sizes, [CHUNK_SIZE as u32, 10, 0]);
+        assert_eq!(streamed, data);
+
+        server.write_all(b"2: stream: OK\0").unwrap();
+        assert_eq!(clamd.instream(&mut &b""[..]).unwrap(), Verdict::Clean);
+        assert_eq!(read_command(&mut server), b"zINSTREAM\0");
+        assert_eq!(read_stream(&mut server), (vec![0], vec![]));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let (mut clamd, mut server) = session();
+        // clamd replies and ends the session while the file is streamed
+        let server = std::thread::spawn(move || {
+            assert_eq!(read_command(&mut server), b"zINSTREAM\0");
+            let mut size = [0; 4];
+            server.read_exact(&mut size).unwrap();
+            server
+                .write_all(b"1: INSTREAM size limit exceeded. ERROR\0")
+                .unwrap();
+        });
+        let data = vec![0; 16 * CHUNK_SIZE];
+        assert_eq!(
+            clamd.instream(&mut &data[..]).unwrap(),
+            Verdict::Error("INSTREAM size limit exceeded.".to_owned())
+        );
+        server.join().unwrap();
+        // The next files aren't scanned
+        assert_eq!(
+            clamd.instream(&mut &b"data"[..]).unwrap(),
+            Verdict::Error("clamd ended the session".to_owned())
+        );
+        clamd.end().unwrap();
+    }
+}
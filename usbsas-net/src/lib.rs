@@ -1,6 +1,7 @@
 //! usbsas's uploader, downloader and analyzer processes.
 
 pub mod analyzer;
+mod clamd;
 pub mod downloader;
 pub mod uploader;
 
@@ -62,6 +63,8 @@ pub enum Error {
     State,
     #[error("{0}")]
     Upload(String),
+    #[error("clamd error: {0}")]
+    Clamd(String),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
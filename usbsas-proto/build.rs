@@ -1,6 +1,7 @@
 fn main() {
     let proto_files = [
         "proto/analyzer.proto3",
+        "proto/archive.proto3",
         "proto/identificator.proto3",
         "proto/cmdexec.proto3",
         "proto/common.proto3",
@@ -14,6 +15,7 @@ fn main() {
         "proto/usbsas.proto3",
         "proto/writefs.proto3",
         "proto/writetar.proto3",
+        "proto/yara.proto3",
     ];
 
     // Compile & gen protobuf code
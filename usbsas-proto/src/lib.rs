@@ -5,6 +5,10 @@ pub mod analyzer {
     include!(concat!(env!("OUT_DIR"), "/analyzer.rs"));
 }
 
+pub mod archive {
+    include!(concat!(env!("OUT_DIR"), "/archive.rs"));
+}
+
 pub mod identificator {
     include!(concat!(env!("OUT_DIR"), "/identificator.rs"));
 }
@@ -25,6 +29,41 @@ pub mod common {
             )
         }
     }
+
+    impl Timestamps {
+        /// Nanoseconds since the Unix epoch from seconds and nanoseconds
+        pub fn nanos(secs: i64, nanos: u32) -> i64 {
+            secs.saturating_mul(1_000_000_000)
+                .saturating_add(i64::from(nanos))
+        }
+
+        /// Same time (in seconds) for all fields
+        pub fn from_secs(secs: i64) -> Self {
+            let time = Self::nanos(secs, 0);
+            Timestamps {
+                mtime: time,
+                ctime: time,
+                atime: time,
+                crtime: time,
+            }
+        }
+
+        /// Modification time in seconds
+        pub fn mtime_secs(&self) -> i64 {
+            self.mtime.div_euclid(1_000_000_000)
+        }
+
+        /// Unknown times replaced by the modification time
+        pub fn or_mtime(&self) -> Self {
+            let or_mtime = |time: i64| if time == 0 { self.mtime } else { time };
+            Timestamps {
+                mtime: self.mtime,
+                ctime: or_mtime(self.ctime),
+                atime: or_mtime(self.atime),
+                crtime: or_mtime(self.crtime),
+            }
+        }
+    }
 }
 
 pub mod downloader {
@@ -66,3 +105,7 @@ pub mod writefs {
 pub mod writetar {
     include!(concat!(env!("OUT_DIR"), "/writetar.rs"));
 }
+
+pub mod yara {
+    include!(concat!(env!("OUT_DIR"), "/yara.rs"));
+}
@@ -0,0 +1,25 @@
+use crate::{seccomp, Result};
+use std::os::unix::io::RawFd;
+use syscallz::{Action, Cmp, Comparator, Syscall};
+
+/// Sandbox of the analyzer scanning files with a local clamd (the HTTP client
+/// of the remote analyzer isn't sandboxed)
+pub fn seccomp_clamd(
+    fd_read: RawFd,
+    fd_write: RawFd,
+    tar_fd: RawFd,
+    clamd_fd: RawFd,
+) -> Result<()> {
+    let mut ctx =
+        seccomp::new_context_with_common_rules(vec![fd_read, tar_fd], vec![fd_write, clamd_fd])?;
+
+    // Allow recv on the connected socket of clamd
+    ctx.set_rule_for_syscall(
+        Action::Allow,
+        Syscall::recvfrom,
+        &[Comparator::new(0, Cmp::Eq, clamd_fd as u64, None)],
+    )?;
+
+    ctx.load()?;
+    Ok(())
+}
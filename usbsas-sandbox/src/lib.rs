@@ -1,5 +1,7 @@
 //! Sandboxing helpers for usbsas processes.
 
+pub mod analyzer;
+pub mod archive;
 pub mod dev2scsi;
 pub mod files2fs;
 pub mod files2tar;
@@ -13,6 +15,7 @@ pub mod scsi2files;
 pub mod tar2files;
 pub mod usbdev;
 pub mod usbsas;
+pub mod yara;
 
 pub(crate) mod seccomp;
 
@@ -49,6 +52,7 @@ extern "C" {
     pub fn usbdevfs_get_capabilities() -> u64;
     pub fn usbdevfs_disconnect_claim() -> u64;
     pub fn usbdevfs_reset() -> u64;
+    pub fn usbdevfs_control() -> u64;
 }
 
 pub fn landlock(paths_ro: Option<&[&str]>, paths_rw: Option<&[&str]>) -> Result<()> {
@@ -83,6 +83,18 @@ pub fn seccomp_thread(udev_socket: RawFd, poll_fd: RawFd) -> Result<()> {
     ctx.allow_syscall(Syscall::getrandom)?;
     ctx.allow_syscall(Syscall::clock_nanosleep)?;
 
+    // Allow control transfers on plugged devices, to get their logical units
+    ctx.set_rule_for_syscall(
+        Action::Allow,
+        Syscall::ioctl,
+        &[Comparator::new(
+            1,
+            Cmp::Eq,
+            unsafe { crate::usbdevfs_control() },
+            None,
+        )],
+    )?;
+
     ctx.load()?;
     Ok(())
 }
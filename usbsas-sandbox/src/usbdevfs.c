@@ -33,3 +33,7 @@ uint64_t usbdevfs_disconnect_claim() {
 uint64_t usbdevfs_reset() {
     return USBDEVFS_RESET;
 }
+
+uint64_t usbdevfs_control() {
+    return USBDEVFS_CONTROL;
+}
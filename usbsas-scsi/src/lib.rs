@@ -17,6 +17,11 @@ const SCSI_TEST_UNIT_READY: u8 = 0x00;
 const SCSI_READ_CAPACITY_10: u8 = 0x25;
 const SCSI_READ_10: u8 = 0x28;
 const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_READ_16: u8 = 0x88;
+const SCSI_WRITE_16: u8 = 0x8A;
+const SCSI_SERVICE_ACTION_IN_16: u8 = 0x9E;
+const SCSI_SA_READ_CAPACITY_16: u8 = 0x10;
+const SCSI_READ_CAPACITY_16_LEN: usize = 32;
 const SCSI_INQUIRY: u8 = 0x12;
 const SCSI_REQUEST_SENSE: u8 = 0x3;
 const SCSI_MAX_READ_SECTORS: u64 = 0x800;
@@ -24,6 +29,45 @@ const ACK_TRYCOUNT: u8 = 3;
 const SCSI_DEV_TYPE_DIRECT_ACCESS_BLOCK_DEVICE: u8 = 0x0;
 const SCSI_DEV_TYPE_CD_DVD: u8 = 0x5;
 
+// READ(10) / WRITE(10) can only address 2^32 sectors and transfer 2^16
+// sectors at once, use the 16 bytes variants when needed.
+fn fits_10(offset: u64, count: u64) -> bool {
+    count <= u64::from(u16::MAX)
+        && offset
+            .checked_add(count)
+            .is_some_and(|end| end <= u64::from(u32::MAX) + 1)
+}
+
+// Command of READ(10) / WRITE(10)
+fn cdb_10(opcode: u8, offset: u64, count: u64) -> Result<[u8; 16], io::Error> {
+    let mut command_data: [u8; 16] = [0; 16];
+    command_data[0] = opcode;
+    BigEndian::write_u32(
+        &mut command_data[2..6],
+        u32::try_from(offset)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u32"))?,
+    );
+    BigEndian::write_u16(
+        &mut command_data[7..9],
+        u16::try_from(count)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u16"))?,
+    );
+    Ok(command_data)
+}
+
+// Command of READ(16) / WRITE(16)
+fn cdb_16(opcode: u8, offset: u64, count: u64) -> Result<[u8; 16], io::Error> {
+    let mut command_data: [u8; 16] = [0; 16];
+    command_data[0] = opcode;
+    BigEndian::write_u64(&mut command_data[2..10], offset);
+    BigEndian::write_u32(
+        &mut command_data[10..14],
+        u32::try_from(count)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u32"))?,
+    );
+    Ok(command_data)
+}
+
 //#[derive(Debug, Default)]
 pub struct ScsiUsb<T: UsbContext> {
     pub handle: DeviceHandle<T>,
@@ -226,27 +270,47 @@ impl<T: UsbContext> ScsiUsb<T> {
         self.bulk_transfer_read(command_data, buffer)
     }
 
+    fn scsi_read_capacity_16(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_SERVICE_ACTION_IN_16;
+        command_data[1] = SCSI_SA_READ_CAPACITY_16;
+        BigEndian::write_u32(&mut command_data[10..14], buffer.len() as u32);
+        self.bulk_transfer_read(command_data, buffer)
+    }
+
+    pub fn scsi_read(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        if fits_10(offset, count) {
+            self.scsi_read_10(buffer, offset, count)
+        } else {
+            self.scsi_read_16(buffer, offset, count)
+        }
+    }
+
+    pub fn scsi_write(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        if fits_10(offset, count) {
+            self.scsi_write_10(buffer, offset, count)
+        } else {
+            self.scsi_write_16(buffer, offset, count)
+        }
+    }
+
     pub fn scsi_read_10(
         &mut self,
         buffer: &mut [u8],
         offset: u64,
         count: u64,
     ) -> Result<u8, io::Error> {
-        let mut command_data: [u8; 16] = [0; 16];
-        command_data[0] = SCSI_READ_10;
-        BigEndian::write_u32(
-            &mut command_data[2..6],
-            u32::try_from(offset).map_err(|_| {
-                io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u32")
-            })?,
-        );
-        BigEndian::write_u16(
-            &mut command_data[7..9],
-            u16::try_from(count).map_err(|_| {
-                io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u16")
-            })?,
-        );
-        self.bulk_transfer_read(command_data, buffer)
+        self.bulk_transfer_read(cdb_10(SCSI_READ_10, offset, count)?, buffer)
     }
 
     pub fn scsi_write_10(
@@ -255,21 +319,25 @@ impl<T: UsbContext> ScsiUsb<T> {
         offset: u64,
         count: u64,
     ) -> Result<u8, io::Error> {
-        let mut command_data: [u8; 16] = [0; 16];
-        command_data[0] = SCSI_WRITE_10;
-        BigEndian::write_u32(
-            &mut command_data[2..6],
-            u32::try_from(offset).map_err(|_| {
-                io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u32")
-            })?,
-        );
-        BigEndian::write_u16(
-            &mut command_data[7..9],
-            u16::try_from(count).map_err(|_| {
-                io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u16")
-            })?,
-        );
-        self.bulk_transfer_write(command_data, buffer)
+        self.bulk_transfer_write(cdb_10(SCSI_WRITE_10, offset, count)?, buffer)
+    }
+
+    pub fn scsi_read_16(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        self.bulk_transfer_read(cdb_16(SCSI_READ_16, offset, count)?, buffer)
+    }
+
+    pub fn scsi_write_16(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        self.bulk_transfer_write(cdb_16(SCSI_WRITE_16, offset, count)?, buffer)
     }
 
     fn scsi_inquiry(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
@@ -335,7 +403,7 @@ impl<T: UsbContext> ScsiUsb<T> {
         while remaining_sectors != 0 {
             let sectors_to_read = std::cmp::min(remaining_sectors, SCSI_MAX_READ_SECTORS);
             let mut tmp = vec![0; block_size * sectors_to_read as usize];
-            self.scsi_read_10(&mut tmp, offset, sectors_to_read)?;
+            self.scsi_read(&mut tmp, offset, sectors_to_read)?;
             offset += sectors_to_read;
             buffer.append(&mut tmp);
             remaining_sectors -= sectors_to_read;
@@ -343,7 +411,9 @@ impl<T: UsbContext> ScsiUsb<T> {
         Ok(buffer)
     }
 
-    pub fn init_mass_storage(&mut self) -> Result<(u32, u32, u64), io::Error> {
+    /// Returns the logical units reporting a (ready) direct access block device
+    /// or CD/DVD
+    pub fn init_mass_storage(&mut self) -> Result<Vec<u8>, io::Error> {
         let max_lun = self.get_max_lun();
         debug!("init mass storage. Luns: {}", max_lun);
         // Store luns which Direct access device set
@@ -363,9 +433,9 @@ impl<T: UsbContext> ScsiUsb<T> {
 
         debug!("Direct access devices luns: {:?}", lun_dad);
 
-        /* For each lun, test if ready or not present, stop at first lun ready  */
+        /* For each lun, test if ready or not present */
 
-        let mut is_ok = false;
+        let mut lun_ready = vec![];
         'outer: for lun in lun_dad.iter() {
             debug!("Test lun {}", lun);
             self.lun = Some(*lun);
@@ -377,9 +447,9 @@ impl<T: UsbContext> ScsiUsb<T> {
                 match self.scsi_test_unit_ready(&mut buffer) {
                     Ok(0) => {
                         /* Everything is ok */
-                        is_ok = true;
                         log::debug!("Test unit ready");
-                        break 'outer;
+                        lun_ready.push(*lun);
+                        continue 'outer;
                     }
                     Ok(ret) => {
                         debug!("Test unit response {}", ret);
@@ -417,6 +487,11 @@ impl<T: UsbContext> ScsiUsb<T> {
                                                     thread::sleep(ten_millis);
                                                     continue;
                                                 }
+                                                0x3A => {
+                                                    /* Medium not present (empty card reader slot) */
+                                                    debug!("Medium not present");
+                                                    break;
+                                                }
                                                 _ => {
                                                     /* XXX TODO: All others code signal a fail? */
                                                     error!(
@@ -426,14 +501,12 @@ impl<T: UsbContext> ScsiUsb<T> {
                                                             &buffer[12..13]
                                                         )
                                                     );
-                                                    is_ok = false;
                                                     break;
                                                 }
                                             }
                                         }
                                         3..=5 => {
                                             error!("Medium error");
-                                            is_ok = false;
                                             break;
                                         }
                                         0x6 => {
@@ -459,7 +532,6 @@ impl<T: UsbContext> ScsiUsb<T> {
                             }
                             Err(_) => {
                                 error!("Error during request sense");
-                                is_ok = false;
                                 break;
                             }
                         }
@@ -468,17 +540,23 @@ impl<T: UsbContext> ScsiUsb<T> {
                         return Err(io::Error::new(ErrorKind::Other, "Test usb key fail"));
                     }
                 }
-                if is_ok {
-                    break;
-                }
             }
         }
 
-        if !is_ok {
+        debug!("Ready luns: {:?}", lun_ready);
+
+        if lun_ready.is_empty() {
             error!("No lun found!");
             return Err(io::Error::new(ErrorKind::Other, "Cannot find lun"));
         }
 
+        Ok(lun_ready)
+    }
+
+    /// Select the logical unit used for next commands and return its capacity
+    pub fn select_lun(&mut self, lun: u8) -> Result<(u64, u32, u64), io::Error> {
+        self.lun = Some(lun);
+
         let mut buffer: [u8; 8] = [0; 8];
         match self.scsi_read_capacity_10(&mut buffer) {
             Ok(_) => {}
@@ -489,10 +567,84 @@ impl<T: UsbContext> ScsiUsb<T> {
 
         assert!(buffer[4] == 0 && buffer[5] == 0);
 
-        let max_lba: u32 = BigEndian::read_u32(&buffer[0..4]);
-        let block_size: u32 = BigEndian::read_u32(&buffer[4..8]);
-        let dev_size: u64 = (u64::from(max_lba) + 1) * (u64::from(block_size));
+        let mut max_lba = u64::from(BigEndian::read_u32(&buffer[0..4]));
+        let mut block_size: u32 = BigEndian::read_u32(&buffer[4..8]);
+
+        // Devices larger than 2TiB report 0xFFFFFFFF, the real capacity must
+        // be read with READ CAPACITY(16)
+        if max_lba == u64::from(u32::MAX) {
+            debug!("max lba overflow, trying read capacity 16");
+            let mut buffer: [u8; SCSI_READ_CAPACITY_16_LEN] = [0; SCSI_READ_CAPACITY_16_LEN];
+            match self.scsi_read_capacity_16(&mut buffer) {
+                Ok(0) => {}
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        "Cannot read capacity (16)",
+                    ));
+                }
+            }
+            if buffer[8] != 0 || buffer[9] != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Bad block size (16)",
+                ));
+            }
+            max_lba = BigEndian::read_u64(&buffer[0..8]);
+            block_size = BigEndian::read_u32(&buffer[8..12]);
+        }
+
+        let dev_size: u64 = max_lba
+            .checked_add(1)
+            .and_then(|blocks| blocks.checked_mul(u64::from(block_size)))
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Device size overflow"))?;
 
         Ok((max_lba, block_size, dev_size))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits_10() {
+        let last_lba = u64::from(u32::MAX);
+        assert!(fits_10(0, 1));
+        assert!(fits_10(last_lba, 1));
+        assert!(fits_10(last_lba - 9, 10));
+        assert!(!fits_10(last_lba + 1, 1));
+        assert!(!fits_10(last_lba - 8, 10));
+        assert!(!fits_10(u64::MAX, 1));
+
+        assert!(fits_10(0, u64::from(u16::MAX)));
+        assert!(!fits_10(0, u64::from(u16::MAX) + 1));
+    }
+
+    #[test]
+    fn test_cdb_10() {
+        assert_eq!(
+            cdb_10(SCSI_READ_10, 0x1234_5678, 0xabcd).unwrap(),
+            [0x28, 0, 0x12, 0x34, 0x56, 0x78, 0, 0xab, 0xcd, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            cdb_10(SCSI_WRITE_10, u64::from(u32::MAX), 1).unwrap(),
+            [0x2a, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(cdb_10(SCSI_READ_10, u64::from(u32::MAX) + 1, 1).is_err());
+        assert!(cdb_10(SCSI_READ_10, 0, u64::from(u16::MAX) + 1).is_err());
+    }
+
+    #[test]
+    fn test_cdb_16() {
+        assert_eq!(
+            cdb_16(SCSI_READ_16, 0x0102_0304_0506_0708, 0x0001_0000).unwrap(),
+            [0x88, 0, 1, 2, 3, 4, 5, 6, 7, 8, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            cdb_16(SCSI_WRITE_16, u64::from(u32::MAX) + 1, 1).unwrap(),
+            [0x8a, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]
+        );
+        assert!(cdb_16(SCSI_READ_16, 0, u64::from(u32::MAX) + 1).is_err());
+    }
+}
@@ -9,11 +9,14 @@ use std::{
 };
 use thiserror::Error;
 use usbsas_comm::{protorequest, protoresponse, Comm};
-use usbsas_fsrw::{ext4fs, ff, iso9660fs, ntfs, FSRead};
+use usbsas_fsrw::{ext4fs, ff, fsck, hfsplus, iso9660fs, ntfs, udf, FSRead};
 use usbsas_mass_storage::MassStorageComm;
 use usbsas_process::{UsbsasChild, UsbsasChildSpawner};
 use usbsas_proto as proto;
-use usbsas_proto::{common::PartitionInfo, files::request::Msg};
+use usbsas_proto::{
+    common::{FileType, PartitionInfo},
+    files::request::Msg,
+};
 use usbsas_utils::READ_FILE_MAX_SIZE;
 
 #[derive(Error, Debug)]
@@ -134,7 +137,7 @@ impl ChildStartedState {
         let req: proto::files::Request = comm.recv()?;
         match req.msg.ok_or(Error::BadRequest)? {
             Msg::OpenDevice(req) => {
-                if let Err(err) = self.opendevice(comm, req.busnum, req.devnum) {
+                if let Err(err) = self.opendevice(comm, req.busnum, req.devnum, req.lun) {
                     error!("err open device: {}, waiting end", err);
                     comm.error(proto::files::ResponseError {
                         err: format!("{err}"),
@@ -163,24 +166,38 @@ impl ChildStartedState {
         comm: &mut Comm<proto::files::Request>,
         busnum: u32,
         devnum: u32,
+        lun: Option<u32>,
     ) -> Result<()> {
         trace!("req opendevice");
         let buf = (u64::from(devnum)) << 32 | u64::from(busnum);
         // unlock dev2scsi
         self.usb_mass.comm()?.write_all(&buf.to_le_bytes())?;
         let rep: proto::scsi::Response = self.usb_mass.comm()?.recv()?;
-        match rep.msg.ok_or(Error::BadRequest)? {
-            proto::scsi::response::Msg::OpenDevice(rep) => {
-                self.usb_mass.block_size = u32::try_from(rep.block_size)?;
-                self.usb_mass.dev_size = rep.dev_size;
-                comm.opendevice(proto::files::ResponseOpenDevice {
-                    block_size: rep.block_size,
-                    dev_size: rep.dev_size,
-                })?;
-            }
+        let mut rep = match rep.msg.ok_or(Error::BadRequest)? {
+            proto::scsi::response::Msg::OpenDevice(rep) => rep,
             proto::scsi::response::Msg::Error(rep) => return Err(Error::Error(rep.err)),
             _ => return Err(Error::BadRequest),
+        };
+        // dev2scsi opens the first ready lun, switch if another one was requested
+        if let Some(lun) = lun {
+            if lun != rep.lun {
+                rep = self
+                    .usb_mass
+                    .comm()?
+                    .opendev(proto::scsi::RequestOpenDevice {
+                        lun,
+                        ..Default::default()
+                    })?;
+            }
         }
+        self.usb_mass.block_size = u32::try_from(rep.block_size)?;
+        self.usb_mass.dev_size = rep.dev_size;
+        comm.opendevice(proto::files::ResponseOpenDevice {
+            block_size: rep.block_size,
+            dev_size: rep.dev_size,
+            lun: rep.lun,
+            luns: rep.luns,
+        })?;
         Ok(())
     }
 }
@@ -245,7 +262,7 @@ impl PartitionsListedState {
             Msg::OpenPartition(req) => {
                 // Keep comm in case of error so we can end dev2scsi properly
                 let comm_bk = self.usb_mass.comm.clone();
-                match self.open_partition(comm, req.index) {
+                match self.open_partition(comm, req.index, req.check) {
                     Ok(fs) => Ok(State::PartitionOpened(PartitionOpenedState { fs })),
                     Err(err) => {
                         comm.error(proto::files::ResponseError {
@@ -270,6 +287,7 @@ impl PartitionsListedState {
         mut self,
         comm: &mut Comm<proto::files::Request>,
         index: u32,
+        check: bool,
     ) -> Result<Box<dyn FSRead<MassStorageComm>>> {
         trace!("req open partition {}", index);
         let part_infos = if let Some(infos) = self.partitions_infos.get(index as usize) {
@@ -279,15 +297,22 @@ impl PartitionsListedState {
         };
         log::info!("Reading partition: {:?}", part_infos);
         self.usb_mass.partition_sector_start = part_infos.start;
+        let anomalies = if check {
+            fsck::check(&mut self.usb_mass, &part_infos.type_str, part_infos.size)?
+        } else {
+            Vec::new()
+        };
         let sector_size = self.usb_mass.block_size;
         let fs: Box<dyn FSRead<MassStorageComm>> = match part_infos.type_str.as_str() {
             "EXFAT" | "FAT" => Box::new(ff::FatFsReader::new(self.usb_mass, sector_size)?),
             "NTFS" => Box::new(ntfs::NTFS::new(self.usb_mass, sector_size)?),
             "Linux/Ext" => Box::new(ext4fs::Ext4::new(self.usb_mass, sector_size)?),
             "ISO9660" => Box::new(iso9660fs::Iso9660::new(self.usb_mass, sector_size)?),
+            "HFS+" => Box::new(hfsplus::HfsPlus::new(self.usb_mass, sector_size)?),
+            "UDF" => Box::new(udf::Udf::new(self.usb_mass, sector_size)?),
             _ => return Err(Error::Partition("Unsupported filesystem".into())),
         };
-        comm.openpartition(proto::files::ResponseOpenPartition {})?;
+        comm.openpartition(proto::files::ResponseOpenPartition { anomalies })?;
         Ok(fs)
     }
 }
@@ -297,9 +322,11 @@ impl PartitionOpenedState {
         loop {
             let req: proto::files::Request = comm.recv()?;
             let res = match req.msg.ok_or(Error::BadRequest)? {
-                Msg::GetAttr(req) => self.getattr(comm, req.path),
-                Msg::ReadDir(req) => self.readdir(comm, req.path),
-                Msg::ReadFile(req) => self.readfile(comm, req.path, req.offset, req.size),
+                Msg::GetAttr(req) => self.getattr(comm, req.path, req.stream),
+                Msg::ReadDir(req) => self.readdir(comm, req.path, req.max_entries),
+                Msg::ReadFile(req) => {
+                    self.readfile(comm, req.path, req.stream, req.offset, req.size)
+                }
                 Msg::End(_) => break,
                 _ => Err(Error::BadRequest),
             };
@@ -315,21 +342,53 @@ impl PartitionOpenedState {
         Ok(State::End)
     }
 
-    fn getattr(&mut self, comm: &mut Comm<proto::files::Request>, path: String) -> Result<()> {
-        trace!("req getattr {}", path);
-        let (ftype, size, timestamp) = self.fs.get_attr(&path)?;
+    fn getattr(
+        &mut self,
+        comm: &mut Comm<proto::files::Request>,
+        path: String,
+        stream: String,
+    ) -> Result<()> {
+        trace!("req getattr {} {}", path, stream);
+        let (ftype, size, times) = self.fs.get_attr(&path)?;
+        // Data streams are reported as regular files with the times of their file
+        if !stream.is_empty() {
+            comm.getattr(proto::files::ResponseGetAttr {
+                ftype: FileType::Regular.into(),
+                size: self.fs.stream_size(&path, &stream)?,
+                timestamp: times.mtime_secs(),
+                symlink_target: String::new(),
+                streams: Vec::new(),
+                times: Some(times),
+            })?;
+            return Ok(());
+        }
+        let symlink_target = if ftype == FileType::Symlink {
+            self.fs.read_link(&path)?
+        } else {
+            String::new()
+        };
         comm.getattr(proto::files::ResponseGetAttr {
             ftype: ftype.into(),
             size,
-            timestamp,
+            timestamp: times.mtime_secs(),
+            symlink_target,
+            streams: self.fs.read_streams(&path)?,
+            times: Some(times),
         })?;
         Ok(())
     }
 
-    fn readdir(&mut self, comm: &mut Comm<proto::files::Request>, path: String) -> Result<()> {
+    fn readdir(
+        &mut self,
+        comm: &mut Comm<proto::files::Request>,
+        path: String,
+        max_entries: u64,
+    ) -> Result<()> {
         trace!("req readdir {}", path);
         comm.readdir(proto::files::ResponseReadDir {
-            filesinfo: self.fs.read_dir(&path)?,
+            filesinfo: self
+                .fs
+                .read_dir(&path, (max_entries != 0).then_some(max_entries))?,
         })?;
         Ok(())
     }
@@ -338,6 +397,7 @@ impl PartitionOpenedState {
         &mut self,
         comm: &mut Comm<proto::files::Request>,
         path: String,
+        stream: String,
         offset: u64,
         size: u64,
     ) -> Result<()> {
@@ -345,7 +405,12 @@ impl PartitionOpenedState {
         if size > READ_FILE_MAX_SIZE {
             return Err(Error::Error("max read size exceeded".to_string()));
         }
-        self.fs.read_file(&path, &mut data, offset, size)?;
+        if stream.is_empty() {
+            self.fs.read_file(&path, &mut data, offset, size)?;
+        } else {
+            self.fs
+                .read_stream(&path, &stream, &mut data, offset, size)?;
+        }
         comm.readfile(proto::files::ResponseReadFile { data: *data })?;
         Ok(())
     }
@@ -21,7 +21,7 @@ use usbsas_comm::{protorequest, Comm};
 use usbsas_config::{conf_parse, conf_read, Config};
 use usbsas_process::UsbsasChildSpawner;
 use usbsas_proto as proto;
-use usbsas_proto::common::{OutFileType, OutFsType, UsbDevice};
+use usbsas_proto::common::{OutFileType, OutFsType, PartitionTable, UsbDevice};
 
 protorequest!(
     CommUsbsas,
@@ -104,6 +104,8 @@ pub struct Partition {
     ptype: u32,
     pub type_str: String,
     name_str: String,
+    kind: String,
+    type_guid: String,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -111,6 +113,7 @@ pub struct ReadDir {
     pub ftype: i32,
     size: u64,
     timestamp: i64,
+    symlink_target: String,
     pub path: String,
     pub path_display: String,
     path_parent: String,
@@ -126,6 +129,7 @@ pub struct USBDesc {
     description: String,
     is_src: bool,
     is_dst: bool,
+    lun: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -196,6 +200,7 @@ impl From<&TargetDevice> for DeviceDesc {
                     description: usb.description.to_owned(),
                     is_src: target.is_src,
                     is_dst: target.is_dst,
+                    lun: usb.lun,
                 };
 
                 let desc_json = Desc::Usb(net_json);
@@ -224,6 +229,9 @@ impl Fingerprinter for UsbDevice {
         hasher.update(&self.manufacturer);
         hasher.update(&self.description);
         hasher.update(&self.serial);
+        if let Some(lun) = self.lun {
+            hasher.update(lun.to_le_bytes());
+        }
         format!("{:x}", hasher.finalize())
     }
 }
@@ -273,6 +281,20 @@ pub(crate) struct CopyIn {
     pub(crate) selected: Vec<String>,
     pub(crate) fsfmt: String,
     pub(crate) download_pin: Option<String>,
+    pub(crate) part_table: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct WipeQuery {
+    pub(crate) part_table: Option<String>,
+}
+
+fn partition_table(part_table: Option<&str>) -> Result<PartitionTable, ServiceError> {
+    match part_table {
+        None | Some("mbr") => Ok(PartitionTable::Mbr),
+        Some("gpt") => Ok(PartitionTable::Gpt),
+        _ => Err(ServiceError::InternalServerError),
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -460,11 +482,33 @@ impl AppState {
             .usbdevices(proto::usbsas::RequestUsbDevices {})?
             .devices
         {
-            devices.push(TargetDevice {
-                device: Device::Usb(device.clone()),
-                is_src: device.is_src,
-                is_dst: device.is_dst,
-            });
+            // Devices with multiple logical units (card readers) are listed
+            // once per unit as source
+            if device.max_lun > 0 && device.is_src {
+                for lun in 0..=device.max_lun {
+                    let mut dev_lun = device.clone();
+                    dev_lun.lun = Some(lun);
+                    dev_lun.description = format!("{} (LUN {})", device.description, lun);
+                    devices.push(TargetDevice {
+                        device: Device::Usb(dev_lun),
+                        is_src: device.is_src,
+                        is_dst: false,
+                    });
+                }
+                if device.is_dst {
+                    devices.push(TargetDevice {
+                        device: Device::Usb(device.clone()),
+                        is_src: false,
+                        is_dst: device.is_dst,
+                    });
+                }
+            } else {
+                devices.push(TargetDevice {
+                    device: Device::Usb(device.clone()),
+                    is_src: device.is_src,
+                    is_dst: device.is_dst,
+                });
+            }
         }
         Ok(devices)
     }
@@ -621,6 +665,8 @@ impl AppState {
                     ptype: partition.ptype,
                     type_str: partition.type_str.to_string(),
                     name_str: partition.name_str.to_string(),
+                    kind: partition.kind().as_str_name().to_string(),
+                    type_guid: partition.type_guid.to_string(),
                 })
                 .collect()),
             Err(err) => {
@@ -667,6 +713,7 @@ impl AppState {
                 ftype: infos.ftype,
                 size: infos.size,
                 timestamp: infos.timestamp,
+                symlink_target: infos.symlink_target,
                 path: path_b64,
                 path_display: infos.path,
                 path_parent: parent_path_b64.clone(),
@@ -681,6 +728,7 @@ impl AppState {
         req_selected: Vec<String>,
         fsfmt: String,
         download_pin: Option<String>,
+        part_table: Option<String>,
         resp_stream: ResponseStream,
     ) -> Result<(), ServiceError> {
         use proto::usbsas::response::Msg;
@@ -722,12 +770,20 @@ impl AppState {
         let mut comm = self.comm.lock()?;
         resp_stream.report_progress("copy_start", progress)?;
 
-        let (analyze_usb, analyze_net, analyze_cmd) =
-            if let Some(conf) = &self.config.lock()?.analyzer {
-                (conf.analyze_usb, conf.analyze_net, conf.analyze_cmd)
+        let (analyze_usb, analyze_net, analyze_cmd) = {
+            let config = self.config.lock()?;
+            // Files are also scanned when yara is configured
+            let yara = config.yara.is_some();
+            if let Some(conf) = &config.analyzer {
+                (
+                    conf.analyze_usb || yara,
+                    conf.analyze_net || yara,
+                    conf.analyze_cmd || yara,
+                )
             } else {
-                (false, false, false)
-            };
+                (yara, yara, yara)
+            }
+        };
 
         let (destination, analyze) = match dest {
             Destination::Usb { busnum, devnum } => {
@@ -736,13 +792,16 @@ impl AppState {
                     "ntfs" => OutFsType::Ntfs,
                     "exfat" => OutFsType::Exfat,
                     "fat32" => OutFsType::Fat,
+                    "ext4" => OutFsType::Ext4,
                     _ => return Err(ServiceError::InternalServerError),
                 };
+                let table = partition_table(part_table.as_deref())?;
                 (
                     proto::usbsas::request_copy_start::Destination::Usb(proto::usbsas::DestUsb {
                         busnum: *busnum,
                         devnum: *devnum,
                         fstype: fstype.into(),
+                        table: table.into(),
                     }),
                     analyze_usb,
                 )
@@ -995,6 +1054,7 @@ impl AppState {
         device: UsbDevice,
         fsfmt: String,
         quick: bool,
+        part_table: Option<String>,
         resp_stream: ResponseStream,
     ) -> Result<(), ServiceError> {
         use proto::usbsas::response::Msg;
@@ -1006,8 +1066,10 @@ impl AppState {
             "ntfs" => OutFsType::Ntfs,
             "exfat" => OutFsType::Exfat,
             "fat32" => OutFsType::Fat,
+            "ext4" => OutFsType::Ext4,
             _ => return Err(ServiceError::InternalServerError),
         };
+        let table = partition_table(part_table.as_deref())?;
 
         let mut comm = self.comm.lock()?;
         comm.send(proto::usbsas::Request {
@@ -1017,6 +1079,7 @@ impl AppState {
                     devnum: device.devnum,
                     fstype: fstype.into(),
                     quick,
+                    table: table.into(),
                 },
             )),
         })?;
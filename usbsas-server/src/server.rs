@@ -1,5 +1,5 @@
 use crate::appstate::{
-    AppState, CopyIn, Desc, DeviceDesc, ReadDirQuery, ResponseStream, UsbsasInfos,
+    AppState, CopyIn, Desc, DeviceDesc, ReadDirQuery, ResponseStream, UsbsasInfos, WipeQuery,
 };
 use crate::error::ServiceError;
 use crate::srv_infos::get_server_infos;
@@ -107,6 +107,7 @@ async fn copy(
             files.selected.to_owned(),
             files.fsfmt.to_owned(),
             files.download_pin.to_owned(),
+            files.part_table.to_owned(),
             resp_stream_clone,
         ) {
             Ok(_) => {
@@ -123,15 +124,17 @@ async fn copy(
 #[get("/wipe/{fingertprint}/{fsfmt}/{quick}")]
 async fn wipe(
     params: web::Path<(String, String, bool)>,
+    query: web::Query<WipeQuery>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
     *data.status.write()? = "busy".into();
     let (fingerprint, fsfmt, quick) = params.into_inner();
+    let part_table = query.into_inner().part_table;
     let device = data.dev_from_fingerprint(fingerprint)?;
     let resp_stream = ResponseStream::new();
     let resp_stream_clone = resp_stream.clone();
     thread::spawn(move || {
-        let _ = data.wipe(device, fsfmt, quick, resp_stream_clone);
+        let _ = data.wipe(device, fsfmt, quick, part_table, resp_stream_clone);
     });
     Ok(HttpResponse::Ok().streaming(resp_stream))
 }
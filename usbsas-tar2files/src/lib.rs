@@ -16,7 +16,7 @@ use thiserror::Error;
 use usbsas_comm::{protoresponse, Comm};
 use usbsas_proto as proto;
 use usbsas_proto::{
-    common::{FileInfo, FileType},
+    common::{FileInfo, FileType, Timestamps},
     files::request::Msg,
 };
 use usbsas_utils::{READ_FILE_MAX_SIZE, TAR_DATA_DIR};
@@ -72,10 +72,45 @@ impl State {
 struct Attrs {
     ftype: FileType,
     size: u64,
-    timestamp: i64,
+    times: Timestamps,
     offset: u64,
 }
 
+// PAX times are decimal seconds, e.g. "1700000000.123456789"
+fn pax_time(value: &str) -> Option<i64> {
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let nanos: u32 = format!("{:0<9}", frac.get(..9).unwrap_or(frac))
+        .parse()
+        .ok()?;
+    // The fraction adds to the magnitude of negative times
+    let time = Timestamps::nanos(secs.parse::<i64>().ok()?.checked_abs()?, nanos);
+    Some(if secs.starts_with('-') { -time } else { time })
+}
+
+// Times from the PAX extended header, defaults to the ustar modification time
+fn entry_times<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Timestamps> {
+    let mut times = Timestamps {
+        mtime: Timestamps::nanos(i64::try_from(entry.header().mtime()?)?, 0),
+        ..Default::default()
+    };
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let time = match extension.value().ok().and_then(pax_time) {
+                Some(time) => time,
+                None => continue,
+            };
+            match extension.key() {
+                Ok("mtime") => times.mtime = time,
+                Ok("atime") => times.atime = time,
+                Ok("ctime") => times.ctime = time,
+                _ => (),
+            }
+        }
+    }
+    Ok(times)
+}
+
 struct InitState {
     tarpath: String,
 }
@@ -120,7 +155,7 @@ impl LoadMetadataState {
 
         // Read tar headers once
         for entry in archive.entries()? {
-            let entry = entry?;
+            let mut entry = entry?;
             let path_name = entry.path()?.to_path_buf().to_string_lossy().to_string();
             let ftype = match entry.header().entry_type() {
                 tar::EntryType::Directory => FileType::Directory,
@@ -133,7 +168,7 @@ impl LoadMetadataState {
                     Attrs {
                         ftype,
                         size: entry.header().size()?,
-                        timestamp: i64::try_from(entry.header().mtime()?)?,
+                        times: entry_times(&mut entry)?,
                         offset: entry.raw_file_position(),
                     },
                 );
@@ -143,7 +178,7 @@ impl LoadMetadataState {
                     Attrs {
                         ftype,
                         size: entry.header().size()?,
-                        timestamp: i64::try_from(entry.header().mtime()?)?,
+                        times: entry_times(&mut entry)?,
                         offset: entry.raw_file_position(),
                     },
                 );
@@ -166,8 +201,11 @@ impl MainLoopState {
             let req: proto::files::Request = comm.recv()?;
 
             let res = match req.msg.ok_or(Error::BadRequest)? {
-                Msg::GetAttr(req) => self.getattr(comm, &req.path),
-                Msg::ReadFile(req) => self.readfile(comm, &req.path, req.offset, req.size as usize),
+                // Tar archives have no data streams
+                Msg::GetAttr(req) if req.stream.is_empty() => self.getattr(comm, &req.path),
+                Msg::ReadFile(req) if req.stream.is_empty() => {
+                    self.readfile(comm, &req.path, req.offset, req.size as usize)
+                }
                 Msg::ReadDir(req) => self.readdir(comm, &req.path),
                 Msg::End(_) => {
                     comm.end(proto::files::ResponseEnd {})?;
@@ -204,7 +242,10 @@ impl MainLoopState {
         Ok(comm.getattr(proto::files::ResponseGetAttr {
             ftype: entry.ftype.into(),
             size: entry.size,
-            timestamp: entry.timestamp,
+            timestamp: entry.times.mtime_secs(),
+            symlink_target: String::new(),
+            streams: Vec::new(),
+            times: Some(entry.times),
         })?)
     }
 
@@ -250,7 +291,10 @@ impl MainLoopState {
                 path: entry.clone(),
                 ftype: attrs.ftype.into(),
                 size: attrs.size,
-                timestamp: attrs.timestamp,
+                timestamp: attrs.times.mtime_secs(),
+                symlink_target: String::new(),
+                streams: Vec::new(),
+                times: Some(attrs.times),
             })
             .collect::<Vec<FileInfo>>();
 
@@ -306,3 +350,18 @@ impl Tar2Files {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_times() {
+        assert_eq!(pax_time("1700000000"), Some(1_700_000_000_000_000_000));
+        assert_eq!(pax_time("1.5"), Some(1_500_000_000));
+        assert_eq!(pax_time("-1.5"), Some(-1_500_000_000));
+        assert_eq!(pax_time("-0.25"), Some(-250_000_000));
+        assert_eq!(pax_time("-9223372036854775808"), None);
+        assert_eq!(pax_time("plop"), None);
+    }
+}
@@ -9,7 +9,7 @@ use usbsas_process::{UsbsasChild, UsbsasChildSpawner};
 use usbsas_proto as proto;
 
 use fuse_mt::{
-    CallbackResult, DirectoryEntry, RequestInfo, ResultEmpty, ResultEntry, ResultOpen,
+    CallbackResult, DirectoryEntry, RequestInfo, ResultData, ResultEmpty, ResultEntry, ResultOpen,
     ResultReaddir, ResultSlice,
 };
 
@@ -43,22 +43,30 @@ protorequest!(
 
 const TTL: Duration = Duration::from_secs(1);
 
-fn system_time_from_timestamp(ts: i64) -> std::time::SystemTime {
-    let datetime =
-        time::OffsetDateTime::from_unix_timestamp(ts).unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+fn system_time_from_nanos(nanos: i64) -> std::time::SystemTime {
+    let datetime = time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(nanos))
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
     datetime.into()
 }
 
+fn fuse_file_type(ftype: i32) -> fuse_mt::FileType {
+    match usbsas_proto::common::FileType::try_from(ftype) {
+        Ok(usbsas_proto::common::FileType::Directory) => fuse_mt::FileType::Directory,
+        Ok(usbsas_proto::common::FileType::Symlink) => fuse_mt::FileType::Symlink,
+        _ => fuse_mt::FileType::RegularFile,
+    }
+}
+
 #[derive(Clone)]
 struct Entry {
     size: u64,
     ftype: fuse_mt::FileType,
-    ctime: i64,
+    times: proto::common::Timestamps,
 }
 
 impl From<&Entry> for fuse_mt::FileAttr {
     fn from(entry: &Entry) -> Self {
-        let time = system_time_from_timestamp(entry.ctime);
+        let times = entry.times.or_mtime();
         let perm = match entry.ftype {
             fuse_mt::FileType::Directory => 0o755,
             _ => 0o444,
@@ -66,10 +74,10 @@ impl From<&Entry> for fuse_mt::FileAttr {
         fuse_mt::FileAttr {
             size: entry.size,
             blocks: 0,
-            atime: time,
-            mtime: time,
-            ctime: time,
-            crtime: time,
+            atime: system_time_from_nanos(times.atime),
+            mtime: system_time_from_nanos(times.mtime),
+            ctime: system_time_from_nanos(times.ctime),
+            crtime: system_time_from_nanos(times.crtime),
             kind: entry.ftype,
             perm,
             nlink: 2,
@@ -92,7 +100,11 @@ impl UsbsasFS {
             UsbsasChildSpawner::new("usbsas-scsi2files").spawn::<proto::files::Request>()?;
         let _ = scsi2files
             .comm
-            .opendevice(proto::files::RequestOpenDevice { busnum, devnum })?;
+            .opendevice(proto::files::RequestOpenDevice {
+                busnum,
+                devnum,
+                lun: None,
+            })?;
         let parts = scsi2files
             .comm
             .partitions(proto::files::RequestPartitions {})?;
@@ -103,7 +115,10 @@ impl UsbsasFS {
         log::debug!("Opening partition {}", partnum);
         if let Err(err) = scsi2files
             .comm
-            .openpartition(proto::files::RequestOpenPartition { index: partnum })
+            .openpartition(proto::files::RequestOpenPartition {
+                index: partnum,
+                check: false,
+            })
         {
             return Err(Error::Partition(format!(
                 "Couldn't open part number {partnum} ({err})"
@@ -138,35 +153,53 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
             let entry = Entry {
                 size: 0,
                 ftype: fuse_mt::FileType::Directory,
-                ctime: 0,
+                times: proto::common::Timestamps::default(),
             };
             return Ok((TTL, fuse_mt::FileAttr::from(&entry)));
         }
 
         let mut scsi2files = self.scsi2files.write().unwrap();
 
-        let rep = match scsi2files
-            .comm
-            .getattr(proto::files::RequestGetAttr { path: path_str })
-        {
+        let rep = match scsi2files.comm.getattr(proto::files::RequestGetAttr {
+            path: path_str,
+            stream: String::new(),
+        }) {
             Ok(rep) => rep,
             Err(err) => {
                 log::error!("getattr err: {:?} {}", &path, err);
                 return Err(libc::ENOENT);
             }
         };
-        let ftype = match usbsas_proto::common::FileType::try_from(rep.ftype) {
-            Ok(usbsas_proto::common::FileType::Directory) => fuse_mt::FileType::Directory,
-            _ => fuse_mt::FileType::RegularFile,
-        };
         let entry = Entry {
             size: rep.size,
-            ftype,
-            ctime: rep.timestamp,
+            ftype: fuse_file_type(rep.ftype),
+            times: rep
+                .times
+                .unwrap_or_else(|| proto::common::Timestamps::from_secs(rep.timestamp)),
         };
         Ok((TTL, fuse_mt::FileAttr::from(&entry)))
     }
 
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        log::trace!("readlink: {:?}", path);
+        match self
+            .scsi2files
+            .write()
+            .unwrap()
+            .comm
+            .getattr(proto::files::RequestGetAttr {
+                path: path.to_string_lossy().to_string(),
+                stream: String::new(),
+            }) {
+            Ok(rep) if !rep.symlink_target.is_empty() => Ok(rep.symlink_target.into_bytes()),
+            Ok(_) => Err(libc::EINVAL),
+            Err(err) => {
+                log::error!("readlink err: {:?} {}", &path, err);
+                Err(libc::ENOENT)
+            }
+        }
+    }
+
     fn read(
         &self,
         _req: RequestInfo,
@@ -187,6 +220,7 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
                 path: path.to_string_lossy().to_string(),
                 size: size as u64,
                 offset,
+                stream: String::new(),
             }) {
             Ok(rep) => callback(Ok(&rep.data)),
             Err(err) => {
@@ -216,13 +250,10 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
             .comm
             .readdir(proto::files::RequestReadDir {
                 path: dir_str.to_string(),
+                max_entries: 0,
             })
             .unwrap();
         for attrs in rep.filesinfo {
-            let ftype = match usbsas_proto::common::FileType::try_from(attrs.ftype) {
-                Ok(usbsas_proto::common::FileType::Directory) => fuse_mt::FileType::Directory,
-                _ => fuse_mt::FileType::RegularFile,
-            };
             result_entries.push(DirectoryEntry {
                 name: attrs
                     .path
@@ -230,7 +261,7 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
                     .ok_or(libc::ENOENT)?
                     .trim_start_matches('/')
                     .into(),
-                kind: ftype,
+                kind: fuse_file_type(attrs.ftype),
             });
         }
         Ok(result_entries)
@@ -8,6 +8,7 @@ use mio::{Events, Interest, Poll, Token};
 use std::{
     collections::HashMap,
     ffi::OsStr,
+    fs::OpenOptions,
     os::unix::io::AsRawFd,
     sync::{Arc, Mutex},
     thread,
@@ -55,6 +56,57 @@ protoresponse!(
     end = End[ResponseEnd]
 );
 
+// Highest logical unit number of mass storage devices
+const MAX_LUN: u8 = 15;
+
+// struct usbdevfs_ctrltransfer
+#[repr(C)]
+struct CtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut libc::c_void,
+}
+
+/// Highest logical unit of a mass storage device, from a Get Max LUN request
+/// on its first interface. Devices with a single unit may not support it.
+fn max_lun(busnum: u32, devnum: u32) -> u8 {
+    let device_path = format!("/dev/bus/usb/{:03}/{:03}", busnum, devnum);
+    let file = match OpenOptions::new().read(true).write(true).open(&device_path) {
+        Ok(file) => file,
+        Err(err) => {
+            debug!("couldn't open {}: {}", device_path, err);
+            return 0;
+        }
+    };
+    let mut max_lun: u8 = 0;
+    let mut transfer = CtrlTransfer {
+        request_type: 0xA1,
+        request: 0xFE,
+        value: 0,
+        index: 0,
+        length: 1,
+        timeout: 1000,
+        data: &mut max_lun as *mut u8 as *mut libc::c_void,
+    };
+    // The transfer reads at most `length` bytes in `max_lun`
+    let ret = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            usbsas_sandbox::usbdevfs_control() as _,
+            &mut transfer,
+        )
+    };
+    if ret != 1 {
+        debug!("no max lun for {}-{}", busnum, devnum);
+        return 0;
+    }
+    max_lun.min(MAX_LUN)
+}
+
 /// Thread for getting plugged devices at startup and handling udev events
 fn handle_udev_events(
     current_devices: Arc<Mutex<CurrentDevices>>,
@@ -199,13 +251,15 @@ impl CurrentDevices {
             return Ok(());
         }
 
+        let devnum = device
+            .attribute_value("devnum")
+            .ok_or(Error::NoneValue)?
+            .to_string_lossy()
+            .parse::<u32>()?;
+
         let dev = UsbDevice {
             busnum: busnum.into(),
-            devnum: device
-                .attribute_value("devnum")
-                .ok_or(Error::NoneValue)?
-                .to_string_lossy()
-                .parse::<u32>()?,
+            devnum,
             vendorid: u32::from_str_radix(
                 &device
                     .attribute_value("idVendor")
@@ -237,10 +291,17 @@ impl CurrentDevices {
                 .to_string(),
             is_src,
             is_dst,
+            lun: None,
+            // Logical units are only opened separately on source devices
+            max_lun: if is_src {
+                u32::from(max_lun(busnum.into(), devnum))
+            } else {
+                0
+            },
         };
 
         info!(
-            "Device plugged {}-{} ({} - {} - {}) {:?}, src: {}, dst: {}]",
+            "Device plugged {}-{} ({} - {} - {}) {:?}, src: {}, dst: {}, max lun: {}]",
             dev.busnum,
             dev.devnum,
             dev.manufacturer,
@@ -248,7 +309,8 @@ impl CurrentDevices {
             dev.serial,
             dev_path.as_slice(),
             dev.is_src,
-            dev.is_dst
+            dev.is_dst,
+            dev.max_lun
         );
 
         self.devices
@@ -308,7 +370,8 @@ impl InitState {
                 "/sys/devices",
                 "/run/udev",
             ]),
-            None,
+            // Get Max LUN requests on plugged devices
+            Some(&["/dev/bus/usb"]),
         )?;
 
         let current_devices = Arc::new(Mutex::new(CurrentDevices::new(None)));
@@ -6,21 +6,49 @@
 use log::{debug, error, info, trace, warn};
 use serde_json::json;
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     convert::TryFrom,
     env,
     fs::File,
 };
 use thiserror::Error;
 use usbsas_comm::{protorequest, protoresponse, Comm};
-use usbsas_config::{conf_parse, conf_read};
+use usbsas_config::{
+    conf_parse, conf_read, ArchivePolicy, CollisionPolicy, FsCheckPolicy, LargeFilePolicy,
+    NameCheckPolicy, StreamPolicy, SymlinkPolicy,
+};
 use usbsas_process::{UsbsasChild, UsbsasChildSpawner};
 use usbsas_proto as proto;
 use usbsas_proto::{
     common::*,
     usbsas::{request::Msg, request_copy_start::Destination, request_copy_start::Source},
 };
-use usbsas_utils::{self, clap::UsbsasClap, READ_FILE_MAX_SIZE, TAR_DATA_DIR};
+use usbsas_utils::{self, clap::UsbsasClap, FILE_HEADER_SIZE, READ_FILE_MAX_SIZE, TAR_DATA_DIR};
+
+// Max number of chained symlinks followed
+const MAX_SYMLINK_DEPTH: usize = 8;
+// Number of paths (and file headers) per filter request
+const FILTER_BATCH_SIZE: usize = 256;
+// Detected types of the files inspected as archives, OOXML and ODF documents
+// are zip archives
+const ARCHIVE_TYPES: &[&str] = &[
+    "zip", "jar", "docx", "xlsx", "pptx", "ooxml", "odf", "gzip", "tar", "7z",
+];
+// Max length of a name component on destination file systems
+const MAX_NAME_LEN: usize = 255;
+const FAT_MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+// Size of the parts of split files, they are named "<file>.001", ...
+const SPLIT_PART_SIZE: u64 = 1 << 31;
+// Length of the longest name added to split files (".join.bat")
+const SPLIT_SUFFIX_LEN: usize = 9;
+// Longer extensions are truncated with the rest of the name
+const MAX_EXT_LEN: usize = 16;
+const WINDOWS_FORBIDDEN_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "CONIN$",
+    "CONOUT$",
+];
 
 #[derive(Error, Debug)]
 enum Error {
@@ -48,6 +76,14 @@ enum Error {
     Wipe(String),
     #[error("{0}")]
     WriteFs(String),
+    #[error("name collision: {0}")]
+    NameCollision(String),
+    #[error("files larger than 4 GiB can't be written on FAT32, use exFAT or NTFS instead: {0}")]
+    LargeFiles(String),
+    #[error("transfer limit exceeded: {0}")]
+    Limit(String),
+    #[error("archive error: {0}")]
+    Archive(String),
     #[error("serde_json: {0}")]
     Json(#[from] serde_json::Error),
     #[error("Bad Request")]
@@ -92,6 +128,12 @@ protorequest!(
     end = End[RequestEnd, ResponseEnd]
 );
 
+protorequest!(
+    CommArchive,
+    archive,
+    end = End[RequestEnd, ResponseEnd]
+);
+
 protorequest!(
     CommIdentificator,
     identificator,
@@ -183,6 +225,16 @@ protorequest!(
     end = End[RequestEnd, ResponseEnd]
 );
 
+protorequest!(
+    CommYara,
+    yara,
+    newfile = NewFile[RequestNewFile, ResponseNewFile],
+    writefile = WriteFile[RequestWriteFile, ResponseWriteFile],
+    endfile = EndFile[RequestEndFile, ResponseEndFile],
+    report = Report[RequestReport, ResponseReport],
+    end = End[RequestEnd, ResponseEnd]
+);
+
 #[derive(Clone, Debug)]
 pub struct UsbMS {
     pub dev: UsbDevice,
@@ -200,7 +252,8 @@ enum State {
     Init(InitState),
     DevOpened(DevOpenedState),
     PartitionOpened(PartitionOpenedState),
-    CopyFiles(CopyFilesState),
+    // Boxed, it holds the lists of files reported
+    CopyFiles(Box<CopyFilesState>),
     Analyze(AnalyzeState),
     DownloadTar(DownloadTarState),
     WriteCleanTar(WriteCleanTarState),
@@ -219,7 +272,7 @@ impl State {
             State::Init(s) => s.run(comm, children),
             State::DevOpened(s) => s.run(comm, children),
             State::PartitionOpened(s) => s.run(comm, children),
-            State::CopyFiles(s) => s.run(comm, children),
+            State::CopyFiles(s) => (*s).run(comm, children),
             State::Analyze(s) => s.run(comm, children),
             State::DownloadTar(s) => s.run(comm, children),
             State::WriteCleanTar(s) => s.run(comm, children),
@@ -291,6 +344,7 @@ impl InitState {
                         devnum: req.devnum as u64,
                         quick: req.quick,
                         fstype: req.fstype,
+                        table: req.table,
                     }))
                 }
                 Msg::ImgDisk(req) => {
@@ -400,10 +454,13 @@ impl InitState {
             .opendevice(proto::files::RequestOpenDevice {
                 busnum: dev_req.busnum,
                 devnum: dev_req.devnum,
+                lun: dev_req.lun,
             })?;
         comm.opendevice(proto::usbsas::ResponseOpenDevice {
             sector_size: device.block_size,
             dev_size: device.dev_size,
+            lun: device.lun,
+            luns: device.luns,
         })?;
         Ok(UsbMS {
             dev: UsbDevice {
@@ -416,6 +473,8 @@ impl InitState {
                 description: dev_req.description,
                 is_src: dev_req.is_src,
                 is_dst: dev_req.is_dst,
+                lun: Some(device.lun),
+                max_lun: dev_req.max_lun,
             },
             sector_size: u32::try_from(device.block_size)?,
             dev_size: device.dev_size,
@@ -441,11 +500,12 @@ impl DevOpenedState {
                 Msg::Id(_) => children.id(comm, &mut self.id),
                 Msg::Partitions(_) => self.partitions(comm, children),
                 Msg::OpenPartition(req) => match self.open_partition(comm, children, req.index) {
-                    Ok(_) => {
+                    Ok(fs_anomalies) => {
                         return Ok(State::PartitionOpened(PartitionOpenedState {
                             device: self.device,
                             id: self.id,
                             config: self.config,
+                            fs_anomalies,
                         }))
                     }
                     Err(err) => {
@@ -490,14 +550,20 @@ impl DevOpenedState {
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
         index: u32,
-    ) -> Result<()> {
+    ) -> Result<Vec<FsAnomaly>> {
         trace!("req open partition");
-        children
+        let anomalies = children
             .scsi2files
             .comm
-            .openpartition(proto::files::RequestOpenPartition { index })?;
-        comm.openpartition(proto::usbsas::ResponseOpenPartition {})?;
-        Ok(())
+            .openpartition(proto::files::RequestOpenPartition {
+                index,
+                check: self.config.fs_check != FsCheckPolicy::Off,
+            })?
+            .anomalies;
+        comm.openpartition(proto::usbsas::ResponseOpenPartition {
+            anomalies: anomalies.clone(),
+        })?;
+        Ok(anomalies)
     }
 }
 
@@ -505,6 +571,7 @@ struct PartitionOpenedState {
     device: UsbMS,
     id: Option<String>,
     config: Config,
+    fs_anomalies: Vec<FsAnomaly>,
 }
 
 impl PartitionOpenedState {
@@ -522,13 +589,22 @@ impl PartitionOpenedState {
                 Msg::CopyStart(req) => match req.source.ok_or(Error::BadRequest)? {
                     Source::SrcUsb(_) => {
                         if let Some(id) = self.id {
-                            return Ok(State::CopyFiles(CopyFilesState {
+                            return Ok(State::CopyFiles(Box::new(CopyFilesState {
                                 device: self.device,
                                 id,
                                 selected: req.selected,
                                 destination: req.destination.ok_or(Error::BadRequest)?,
                                 config: self.config,
-                            }));
+                                src_paths: HashMap::new(),
+                                skipped: Vec::new(),
+                                symlinks: Vec::new(),
+                                streams: Vec::new(),
+                                renamed: Vec::new(),
+                                file_types: Vec::new(),
+                                unsafe_names: Vec::new(),
+                                archives: Vec::new(),
+                                fs_anomalies: self.fs_anomalies,
+                            })));
                         } else {
                             error!("empty id");
                             Err(Error::BadRequest)
@@ -562,11 +638,16 @@ impl PartitionOpenedState {
         let attrs = children
             .scsi2files
             .comm
-            .getattr(proto::files::RequestGetAttr { path })?;
+            .getattr(proto::files::RequestGetAttr {
+                path,
+                stream: String::new(),
+            })?;
         comm.getattr(proto::usbsas::ResponseGetAttr {
             ftype: attrs.ftype,
             size: attrs.size,
             timestamp: attrs.timestamp,
+            symlink_target: attrs.symlink_target,
+            times: attrs.times,
         })?;
         Ok(())
     }
@@ -582,7 +663,10 @@ impl PartitionOpenedState {
             filesinfo: children
                 .scsi2files
                 .comm
-                .readdir(proto::files::RequestReadDir { path })?
+                .readdir(proto::files::RequestReadDir {
+                    path,
+                    max_entries: 0,
+                })?
                 .filesinfo,
         })?;
         Ok(())
@@ -595,6 +679,17 @@ struct CopyFilesState {
     id: String,
     selected: Vec<String>,
     config: Config,
+    // Entries read from another path and stream (followed symlinks, data
+    // streams)
+    src_paths: HashMap<String, (String, String)>,
+    skipped: Vec<serde_json::Value>,
+    symlinks: Vec<serde_json::Value>,
+    streams: Vec<serde_json::Value>,
+    renamed: Vec<serde_json::Value>,
+    file_types: Vec<serde_json::Value>,
+    unsafe_names: Vec<serde_json::Value>,
+    archives: Vec<serde_json::Value>,
+    fs_anomalies: Vec<FsAnomaly>,
 }
 
 impl CopyFilesState {
@@ -611,6 +706,31 @@ impl CopyFilesState {
         );
 
         let mut report = init_report()?;
+        report["user"] = serde_json::Value::String(self.id.clone());
+        report["source"] = json!({
+            "vendorid": self.device.dev.vendorid,
+            "productid": self.device.dev.productid,
+            "manufacturer": self.device.dev.manufacturer,
+            "serial": self.device.dev.serial,
+            "description": self.device.dev.description,
+            "lun": self.device.dev.lun
+        });
+        if self.config.fs_check != FsCheckPolicy::Off {
+            report["fs_anomalies"] = self.fs_anomalies.iter().map(anomaly_to_json).collect();
+        }
+
+        // Abort before any file is read if the source file system is blocked
+        if self.config.fs_check == FsCheckPolicy::Block && !self.fs_anomalies.is_empty() {
+            comm.nothingtocopy(proto::usbsas::ResponseNothingToCopy {
+                report: serde_json::to_vec(&report)?,
+            })?;
+            warn!(
+                "Aborting copy, {} source file system anomalies found",
+                self.fs_anomalies.len()
+            );
+            return Ok(State::WaitEnd(WaitEndState {}));
+        }
+
         let mut errors = vec![];
         let mut all_directories = vec![];
         let mut all_files = vec![];
@@ -621,26 +741,41 @@ impl CopyFilesState {
             &mut all_files,
             &mut all_directories,
         )?;
-        let mut filtered: Vec<String> = Vec::new();
+        let mut filtered = Vec::new();
 
-        let all_files_filtered = self.filter_files(children, all_files, &mut filtered)?;
+        let mut all_files_filtered =
+            self.filter_files(children, all_files, false, &mut filtered)?;
+        if self.config.archives != ArchivePolicy::Off {
+            all_files_filtered =
+                self.inspect_archives(children, all_files_filtered, &mut filtered)?;
+        }
         let all_directories_filtered =
-            self.filter_files(children, all_directories, &mut filtered)?;
+            self.filter_files(children, all_directories, true, &mut filtered)?;
 
         let mut all_entries_filtered = vec![];
         all_entries_filtered.append(&mut all_directories_filtered.clone());
         all_entries_filtered.append(&mut all_files_filtered.clone());
 
         report["file_names"] = all_files_filtered.clone().into();
-        report["filtered_files"] = filtered.clone().into();
-        report["user"] = serde_json::Value::String(self.id.clone());
-        report["source"] = json!({
-            "vendorid": self.device.dev.vendorid,
-            "productid": self.device.dev.productid,
-            "manufacturer": self.device.dev.manufacturer,
-            "serial": self.device.dev.serial,
-            "description": self.device.dev.description
-        });
+        report["filtered_files"] = filtered.iter().map(|f| f["path"].clone()).collect();
+        report["filter_results"] = filtered.into();
+        report["file_types"] = self.file_types.clone().into();
+        if self.config.name_checks != NameCheckPolicy::Off {
+            report["unsafe_names"] = self.unsafe_names.clone().into();
+        }
+        if self.config.archives != ArchivePolicy::Off {
+            report["archives"] = self.archives.clone().into();
+        }
+        report["skipped_files"] = self.skipped.clone().into();
+        if self.config.symlinks == SymlinkPolicy::Report {
+            report["symlinks"] = self.symlinks.clone().into();
+        }
+        if self.config.data_streams != StreamPolicy::Drop {
+            report["data_streams"] = self.streams.clone().into();
+        }
+        if self.config.name_collisions == CollisionPolicy::Rename {
+            report["renamed_files"] = self.renamed.clone().into();
+        }
 
         if let Destination::Usb(dest) = &self.destination {
             if let Some(out_dev) = children
@@ -669,9 +804,21 @@ impl CopyFilesState {
             warn!("Aborting copy, no files survived filter");
             return Ok(State::WaitEnd(WaitEndState {}));
         }
+        if self.config.large_files == LargeFilePolicy::Reject
+            && is_fat_destination(&self.destination)
+        {
+            let large = large_files(
+                &mut children.scsi2files.comm,
+                &all_files_filtered,
+                &self.src_paths,
+            );
+            if !large.is_empty() {
+                return Err(Error::LargeFiles(large.join(", ")));
+            }
+        }
         let max_file_size = match children.check_dst_size(comm, &self.destination, total_files_size)
         {
-            Ok(max_size) => max_size,
+            Ok(max_size) => max_size.filter(|_| self.config.large_files == LargeFilePolicy::Skip),
             Err(Error::NotEnoughSpace) => return Ok(State::WaitEnd(WaitEndState {})),
             Err(err) => return Err(err),
         };
@@ -684,16 +831,10 @@ impl CopyFilesState {
             &all_entries_filtered,
             &mut errors,
             max_file_size,
-            &report,
+            &mut report,
         )?;
 
-        let analyze = match self.destination {
-            Destination::Usb(_) => self.config.analyze_usb,
-            Destination::Net(_) => self.config.analyze_net,
-            Destination::Cmd(_) => self.config.analyze_cmd,
-        };
-
-        if analyze {
+        if self.config.analyze(&self.destination) || self.config.yara {
             Ok(State::Analyze(AnalyzeState {
                 directories: all_directories_filtered,
                 files: all_files_filtered,
@@ -744,6 +885,7 @@ impl CopyFilesState {
         let mut total_size: u64 = 0;
         let mut todo = VecDeque::from(self.selected.to_vec());
         let mut all_entries = HashSet::new();
+        let mut sidecars = Vec::new();
         while let Some(entry) = todo.pop_front() {
             // First add parent(s) of file if not selected
             let mut parts = entry.trim_start_matches('/').split('/');
@@ -762,6 +904,7 @@ impl CopyFilesState {
                 .comm
                 .getattr(proto::files::RequestGetAttr {
                     path: entry.clone(),
+                    stream: String::new(),
                 }) {
                 Ok(rep) => rep,
                 Err(_) => {
@@ -772,70 +915,666 @@ impl CopyFilesState {
             match FileType::try_from(rep.ftype) {
                 Ok(FileType::Regular) => {
                     if all_entries.insert(entry.clone()) {
-                        files.push(entry);
+                        total_size += self.data_streams(&entry, &rep.streams, &mut sidecars);
                         total_size += rep.size;
+                        self.check_limits(&entry, rep.size, files.len() + 1, total_size)?;
+                        files.push(entry);
                     }
                 }
                 Ok(FileType::Directory) => {
+                    if !all_entries.contains(&entry) {
+                        total_size += self.data_streams(&entry, &rep.streams, &mut sidecars);
+                    }
                     let mut todo_dir = VecDeque::from(vec![entry]);
                     while let Some(dir) = todo_dir.pop_front() {
+                        self.check_limits(&dir, 0, files.len(), total_size)?;
                         if all_entries.insert(dir.clone()) {
                             directories.push(dir.clone());
                         }
-                        let rep = children
-                            .scsi2files
-                            .comm
-                            .readdir(proto::files::RequestReadDir { path: dir })?;
+                        let rep =
+                            children
+                                .scsi2files
+                                .comm
+                                .readdir(proto::files::RequestReadDir {
+                                    path: dir.clone(),
+                                    max_entries: self.config.limits.max_dir_entries.unwrap_or(0),
+                                })?;
                         for file in rep.filesinfo.iter() {
                             match FileType::try_from(file.ftype) {
                                 Ok(FileType::Regular) => {
                                     if all_entries.insert(file.path.clone()) {
-                                        files.push(file.path.clone());
+                                        total_size += self.data_streams(
+                                            &file.path,
+                                            &file.streams,
+                                            &mut sidecars,
+                                        );
                                         total_size += file.size;
+                                        self.check_limits(
+                                            &file.path,
+                                            file.size,
+                                            files.len() + 1,
+                                            total_size,
+                                        )?;
+                                        files.push(file.path.clone());
                                     }
                                 }
                                 Ok(FileType::Directory) => {
+                                    if !all_entries.contains(&file.path) {
+                                        total_size += self.data_streams(
+                                            &file.path,
+                                            &file.streams,
+                                            &mut sidecars,
+                                        );
+                                    }
                                     todo_dir.push_back(file.path.clone());
                                 }
-                                _ => errors.push(file.path.clone()),
+                                Ok(FileType::Other) | Err(_) => errors.push(file.path.clone()),
+                                Ok(ftype) => {
+                                    if all_entries.insert(file.path.clone()) {
+                                        if let Some(size) = self.special_file(
+                                            children,
+                                            &file.path,
+                                            ftype,
+                                            &file.symlink_target,
+                                        ) {
+                                            total_size += size;
+                                            self.check_limits(
+                                                &file.path,
+                                                size,
+                                                files.len() + 1,
+                                                total_size,
+                                            )?;
+                                            files.push(file.path.clone());
+                                        }
+                                    }
+                                }
                             }
                         }
                     }
                 }
-                _ => errors.push(entry),
+                Ok(FileType::Other) | Err(_) => errors.push(entry),
+                Ok(ftype) => {
+                    if all_entries.insert(entry.clone()) {
+                        if let Some(size) =
+                            self.special_file(children, &entry, ftype, &rep.symlink_target)
+                        {
+                            total_size += size;
+                            self.check_limits(&entry, size, files.len() + 1, total_size)?;
+                            files.push(entry);
+                        }
+                    }
+                }
             }
         }
+        // Data streams are copied unless a file has the name of their sidecar
+        for (sidecar, source, size) in sidecars {
+            if all_entries.insert(sidecar.clone()) {
+                self.check_limits(&sidecar, size, files.len() + 1, total_size)?;
+                self.src_paths.insert(sidecar.clone(), source);
+                files.push(sidecar);
+            } else {
+                warn!("{} already exists, data stream not copied", sidecar);
+                total_size -= size;
+            }
+        }
+        self.name_collisions(files, directories)?;
         Ok(total_size)
     }
 
+    /// Enforce the limits of the transfer on an entry of the files list
+    fn check_limits(
+        &self,
+        path: &str,
+        size: u64,
+        files_count: usize,
+        total_size: u64,
+    ) -> Result<()> {
+        let limits = &self.config.limits;
+        if let Some(max) = limits.max_path_bytes {
+            if path.len() as u64 > max {
+                return Err(Error::Limit(format!(
+                    "path longer than {max} bytes: {path}"
+                )));
+            }
+        }
+        if let Some(max) = limits.max_depth {
+            if path.trim_start_matches('/').split('/').count() as u64 > max {
+                return Err(Error::Limit(format!("path deeper than {max}: {path}")));
+            }
+        }
+        if let Some(max) = limits.max_file_size {
+            if size > max {
+                return Err(Error::Limit(format!(
+                    "file larger than {max} bytes: {path}"
+                )));
+            }
+        }
+        if let Some(max) = limits.max_files {
+            if files_count as u64 > max {
+                return Err(Error::Limit(format!("more than {max} files")));
+            }
+        }
+        if let Some(max) = limits.max_total_size {
+            if total_size > max {
+                return Err(Error::Limit(format!("more than {max} bytes")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the name collisions policy if the destination file system is
+    /// case-insensitive. Entries of renamed or skipped directories follow them.
+    fn name_collisions(
+        &mut self,
+        files: &mut Vec<String>,
+        directories: &mut Vec<String>,
+    ) -> Result<()> {
+        let fstype = match &self.destination {
+            Destination::Usb(usb) => match OutFsType::try_from(usb.fstype) {
+                Ok(fstype @ (OutFsType::Fat | OutFsType::Exfat | OutFsType::Ntfs)) => fstype,
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let entries = entries_by_depth(std::mem::take(directories), std::mem::take(files));
+        // Destination path of directories, None if skipped
+        let mut dirs: HashMap<String, Option<String>> = HashMap::new();
+        // Upper case destination paths and the entries they belong to
+        let mut taken: HashMap<String, String> = HashMap::new();
+        for (path, is_dir) in entries {
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+            let parent = match dirs.get(parent) {
+                Some(Some(parent)) => parent.clone(),
+                Some(None) => {
+                    if is_dir {
+                        dirs.insert(path.clone(), None);
+                    }
+                    continue;
+                }
+                None => parent.to_string(),
+            };
+            let mut dest = format!("{parent}/{name}");
+            if let Some(other) = taken.get(&dest.to_uppercase()) {
+                match self.config.name_collisions {
+                    CollisionPolicy::Abort => {
+                        return Err(Error::NameCollision(format!("{path} and {other}")));
+                    }
+                    CollisionPolicy::Skip => {
+                        warn!("skipping {}: name collision with {}", path, other);
+                        self.skipped.push(json!({
+                            "path": path,
+                            "reason": format!("name collision with {other}")
+                        }));
+                        if is_dir {
+                            dirs.insert(path, None);
+                        }
+                        continue;
+                    }
+                    CollisionPolicy::Rename => {
+                        let mut index = 1;
+                        while taken.contains_key(&dest.to_uppercase()) {
+                            dest = format!(
+                                "{parent}/{}",
+                                dest_name(name, Some(index), is_dir, fstype, MAX_NAME_LEN)
+                            );
+                            index += 1;
+                        }
+                        warn!("renaming {} to {}: name collision", path, dest);
+                        self.renamed.push(json!({"path": path, "renamed": dest}));
+                    }
+                }
+            }
+            taken.insert(dest.to_uppercase(), path.clone());
+            if is_dir {
+                dirs.insert(path.clone(), Some(dest.clone()));
+                directories.push(dest.clone());
+            } else {
+                files.push(dest.clone());
+            }
+            if dest != path {
+                let source = self
+                    .src_paths
+                    .remove(&path)
+                    .unwrap_or((path, String::new()));
+                self.src_paths.insert(dest, source);
+            }
+        }
+        Ok(())
+    }
+
+    /// Path and stream the content of an entry is read from
+    fn source<'a>(&'a self, path: &'a str) -> (&'a str, &'a str) {
+        self.src_paths
+            .get(path)
+            .map_or((path, ""), |(path, stream)| (path, stream))
+    }
+
+    /// Apply the data streams policy, returns the size of the streams to copy.
+    /// Streams to copy are added to `sidecars` (with their source and size).
+    fn data_streams(
+        &mut self,
+        path: &str,
+        streams: &[StreamInfo],
+        sidecars: &mut Vec<(String, (String, String), u64)>,
+    ) -> u64 {
+        let mut size = 0;
+        if self.config.data_streams == StreamPolicy::Drop {
+            return size;
+        }
+        for stream in streams {
+            warn!(
+                "{} has a data stream: {} ({}B)",
+                path, stream.name, stream.size
+            );
+            self.streams.push(json!({
+                "path": path,
+                "name": stream.name,
+                "size": stream.size
+            }));
+            if self.config.data_streams == StreamPolicy::Copy {
+                sidecars.push((
+                    stream_sidecar(path, &stream.name),
+                    (path.to_string(), stream.name.clone()),
+                    stream.size,
+                ));
+                size += stream.size;
+            }
+        }
+        size
+    }
+
+    /// Apply the symlink policy, other special files are always skipped.
+    /// Returns the size of the file to copy if the symlink is followed.
+    fn special_file(
+        &mut self,
+        children: &mut Children,
+        path: &str,
+        ftype: FileType,
+        target: &str,
+    ) -> Option<u64> {
+        let reason = match (ftype, self.config.symlinks) {
+            (FileType::Symlink, SymlinkPolicy::Report) => {
+                self.symlinks.push(json!({"path": path, "target": target}));
+                return None;
+            }
+            (FileType::Symlink, SymlinkPolicy::Follow) => {
+                match Self::resolve_symlink(children, path, target) {
+                    Ok((resolved, size)) => {
+                        debug!("following symlink {} -> {}", path, resolved);
+                        self.src_paths
+                            .insert(path.to_string(), (resolved, String::new()));
+                        return Some(size);
+                    }
+                    Err(reason) => reason.to_string(),
+                }
+            }
+            (FileType::Symlink, SymlinkPolicy::Skip) => "symlink".to_string(),
+            (ftype, _) => ftype.as_str_name().to_lowercase(),
+        };
+        warn!("skipping {}: {}", path, reason);
+        self.skipped.push(json!({"path": path, "reason": reason}));
+        None
+    }
+
+    /// Resolve a symlink (and chained ones) to a regular file of the partition
+    fn resolve_symlink(
+        children: &mut Children,
+        path: &str,
+        target: &str,
+    ) -> std::result::Result<(String, u64), &'static str> {
+        let mut path = path.to_string();
+        let mut target = target.to_string();
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            // Absolute targets point to the host file system
+            if target.starts_with('/') || has_drive_letter(&target) {
+                return Err("symlink target outside of the partition");
+            }
+            let mut resolved: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+            // Remove link name
+            let _ = resolved.pop();
+            for component in target.split('/') {
+                match component {
+                    "" | "." => (),
+                    ".." => {
+                        if resolved.pop().is_none() {
+                            return Err("symlink target outside of the partition");
+                        }
+                    }
+                    component => resolved.push(component),
+                }
+            }
+            let resolved = format!("/{}", resolved.join("/"));
+            let rep = children
+                .scsi2files
+                .comm
+                .getattr(proto::files::RequestGetAttr {
+                    path: resolved.clone(),
+                    stream: String::new(),
+                })
+                .map_err(|_| {
+                    // Names may contain ':', "file:stream" targets designate
+                    // NTFS data streams
+                    if target.contains(':') {
+                        "symlink to a data stream"
+                    } else {
+                        "dangling symlink"
+                    }
+                })?;
+            match FileType::try_from(rep.ftype) {
+                Ok(FileType::Regular) => return Ok((resolved, rep.size)),
+                Ok(FileType::Symlink) => {
+                    path = resolved;
+                    target = rep.symlink_target;
+                }
+                Ok(FileType::Directory) => return Err("symlink to a directory"),
+                _ => return Err("symlink to a special file"),
+            }
+        }
+        Err("too many levels of symlinks")
+    }
+
     fn filter_files(
         &mut self,
         children: &mut Children,
         files: Vec<String>,
-        filtered: &mut Vec<String>,
+        directories: bool,
+        filtered: &mut Vec<serde_json::Value>,
     ) -> Result<Vec<String>> {
         trace!("filter files");
         let mut filtered_files: Vec<String> = Vec::new();
-        let files_count = files.len();
-        let rep = children
-            .filter
-            .comm
-            .filterpaths(proto::filter::RequestFilterPaths {
-                path: files.to_vec(),
-            })?;
-        if rep.results.len() != files_count {
-            return Err(Error::Filter);
-        }
-        for (i, f) in files.iter().enumerate().take(files_count) {
-            if rep.results[i] == proto::filter::FilterResult::PathOk as i32 {
-                filtered_files.push(f.clone());
+        for batch in files.chunks(FILTER_BATCH_SIZE) {
+            // Files are also filtered on their content, those whose header
+            // can't be read are rejected
+            let mut headers = Vec::new();
+            let batch: Vec<String> = if directories {
+                batch.to_vec()
             } else {
-                filtered.push(f.clone());
+                let mut readable = Vec::new();
+                for path in batch {
+                    match self.file_header(children, path) {
+                        Ok(header) => {
+                            headers.push(header);
+                            readable.push(path.clone());
+                        }
+                        Err(err) => {
+                            warn!("couldn't read header of {}: {}", path, err);
+                            filtered.push(json!({
+                                "path": path,
+                                "result": proto::filter::FilterResult::PathError.as_str_name(),
+                                "reason": format!("couldn't read its header: {err}"),
+                            }));
+                        }
+                    }
+                }
+                readable
+            };
+            if batch.is_empty() {
+                continue;
+            }
+            let rep = children
+                .filter
+                .comm
+                .filterpaths(proto::filter::RequestFilterPaths {
+                    path: batch.clone(),
+                    directories,
+                    headers,
+                })?;
+            if rep.results.len() != batch.len()
+                || rep.rules.len() != batch.len()
+                || rep.types.len() != batch.len()
+                || rep.mismatches.len() != batch.len()
+                || rep.name_issues.len() != batch.len()
+            {
+                return Err(Error::Filter);
+            }
+            for (i, f) in batch.iter().enumerate() {
+                if !rep.types[i].is_empty() {
+                    self.file_types.push(json!({
+                        "path": f,
+                        "type": rep.types[i],
+                        "mismatch": rep.mismatches[i],
+                    }));
+                }
+                let issues = &rep.name_issues[i].issues;
+                if !issues.is_empty() {
+                    warn!("unsafe name {:?}: {}", f, issues.join(", "));
+                    self.unsafe_names.push(json!({
+                        "path": f,
+                        "issues": issues,
+                    }));
+                }
+                let rule = &rep.rules[i];
+                let result = proto::filter::FilterResult::try_from(rep.results[i])
+                    .unwrap_or(proto::filter::FilterResult::PathError);
+                let Some(reason) = filter_reason(result, rule, issues) else {
+                    filtered_files.push(f.clone());
+                    continue;
+                };
+                info!("{}: {}", f, reason);
+                let mut entry = json!({
+                    "path": f,
+                    "result": result.as_str_name(),
+                    "reason": reason,
+                });
+                if !rule.id.is_empty() {
+                    entry["rule"] = rule.id.clone().into();
+                    entry["description"] = rule.description.clone().into();
+                }
+                filtered.push(entry);
             }
         }
         Ok(filtered_files)
     }
 
+    /// Inspect the archives among files, their members are listed in the
+    /// report and filtered. Archives to block are removed from the files.
+    fn inspect_archives(
+        &mut self,
+        children: &mut Children,
+        files: Vec<String>,
+        filtered: &mut Vec<serde_json::Value>,
+    ) -> Result<Vec<String>> {
+        trace!("inspect archives");
+        let archives: HashSet<String> = self
+            .file_types
+            .iter()
+            .filter(|entry| ARCHIVE_TYPES.contains(&entry["type"].as_str().unwrap_or_default()))
+            .filter_map(|entry| entry["path"].as_str().map(String::from))
+            .collect();
+        let mut kept_files = Vec::new();
+        for path in files {
+            if !archives.contains(&path) {
+                kept_files.push(path);
+                continue;
+            }
+            let reasons = self.inspect_archive(children, &path)?;
+            if self.config.archives == ArchivePolicy::Block && !reasons.is_empty() {
+                let reason = format!("archive: {}", reasons.join(", "));
+                info!("{}: {}", path, reason);
+                filtered.push(json!({
+                    "path": path,
+                    "result": proto::filter::FilterResult::PathFiltered.as_str_name(),
+                    "reason": reason,
+                }));
+            } else {
+                kept_files.push(path);
+            }
+        }
+        Ok(kept_files)
+    }
+
+    /// Report the members of an archive and return why it should be blocked:
+    /// its issues and its filtered members
+    fn inspect_archive(&mut self, children: &mut Children, path: &str) -> Result<Vec<String>> {
+        let rep = match self.read_archive(children, path) {
+            Ok(rep) => rep,
+            Err(err) => {
+                warn!("couldn't inspect archive {}: {}", path, err);
+                proto::archive::ResponseInspect {
+                    issues: vec![proto::archive::ArchiveIssue {
+                        kind: "error".into(),
+                        path: String::new(),
+                    }],
+                    ..Default::default()
+                }
+            }
+        };
+        // Paths in the archive are relative to it
+        let member_path = |member: &str| match member {
+            "" => path.to_string(),
+            member => format!("{path}/{member}"),
+        };
+        let mut reasons: Vec<String> = rep
+            .issues
+            .iter()
+            .map(|issue| format!("{} ({})", issue.kind, member_path(&issue.path)))
+            .collect();
+        let mut filtered_members = Vec::new();
+        for batch in rep.members.chunks(FILTER_BATCH_SIZE) {
+            let paths: Vec<String> = batch.iter().map(|m| member_path(&m.path)).collect();
+            let filter_rep =
+                children
+                    .filter
+                    .comm
+                    .filterpaths(proto::filter::RequestFilterPaths {
+                        path: paths.clone(),
+                        directories: false,
+                        headers: batch.iter().map(|m| m.header.clone()).collect(),
+                    })?;
+            if filter_rep.results.len() != batch.len()
+                || filter_rep.rules.len() != batch.len()
+                || filter_rep.name_issues.len() != batch.len()
+            {
+                return Err(Error::Filter);
+            }
+            for (i, member) in paths.iter().enumerate() {
+                let result = proto::filter::FilterResult::try_from(filter_rep.results[i])
+                    .unwrap_or(proto::filter::FilterResult::PathError);
+                if let Some(reason) = filter_reason(
+                    result,
+                    &filter_rep.rules[i],
+                    &filter_rep.name_issues[i].issues,
+                ) {
+                    reasons.push(format!("{member}: {reason}"));
+                    filtered_members.push(json!({
+                        "path": member,
+                        "reason": reason,
+                    }));
+                }
+            }
+        }
+        if !reasons.is_empty() {
+            warn!("archive {}: {}", path, reasons.join(", "));
+        }
+        self.archives.push(json!({
+            "path": path,
+            "format": rep.format,
+            "members": rep
+                .members
+                .iter()
+                .map(|m| json!({
+                    "path": m.path,
+                    "size": m.size,
+                    "compressed_size": m.compressed_size,
+                }))
+                .collect::<Vec<_>>(),
+            "issues": rep
+                .issues
+                .iter()
+                .map(|i| json!({ "kind": i.kind, "path": i.path }))
+                .collect::<Vec<_>>(),
+            "filtered_members": filtered_members,
+        }));
+        Ok(reasons)
+    }
+
+    /// Have the archive process inspect a file, serving the data it reads
+    fn read_archive(
+        &self,
+        children: &mut Children,
+        path: &str,
+    ) -> Result<proto::archive::ResponseInspect> {
+        let (src_path, stream) = self.source(path);
+        let size = children
+            .scsi2files
+            .comm
+            .getattr(proto::files::RequestGetAttr {
+                path: src_path.into(),
+                stream: stream.into(),
+            })?
+            .size;
+        children.archive.comm.send(proto::archive::Request {
+            msg: Some(proto::archive::request::Msg::Inspect(
+                proto::archive::RequestInspect {
+                    path: path.into(),
+                    size,
+                },
+            )),
+        })?;
+        loop {
+            let rep: proto::archive::Response = children.archive.comm.recv()?;
+            match rep.msg.ok_or(Error::BadRequest)? {
+                proto::archive::response::Msg::ReadData(req) => {
+                    // Data of the wrong size makes the inspection fail
+                    let data = if req.size > READ_FILE_MAX_SIZE {
+                        Vec::new()
+                    } else {
+                        match children
+                            .scsi2files
+                            .comm
+                            .readfile(proto::files::RequestReadFile {
+                                path: src_path.into(),
+                                offset: req.offset,
+                                size: req.size,
+                                stream: stream.into(),
+                            }) {
+                            Ok(rep) => rep.data,
+                            Err(err) => {
+                                warn!("couldn't read {}: {}", path, err);
+                                Vec::new()
+                            }
+                        }
+                    };
+                    children.archive.comm.send(proto::archive::Request {
+                        msg: Some(proto::archive::request::Msg::Data(
+                            proto::archive::RequestData { data },
+                        )),
+                    })?;
+                }
+                proto::archive::response::Msg::Inspect(rep) => return Ok(rep),
+                proto::archive::response::Msg::Error(err) => return Err(Error::Archive(err.err)),
+                proto::archive::response::Msg::End(_) => return Err(Error::BadRequest),
+            }
+        }
+    }
+
+    /// First bytes of a file, empty if it can't be read
+    fn file_header(&self, children: &mut Children, path: &str) -> Result<Vec<u8>> {
+        let (src_path, stream) = self.source(path);
+        let size = children
+            .scsi2files
+            .comm
+            .getattr(proto::files::RequestGetAttr {
+                path: src_path.into(),
+                stream: stream.into(),
+            })?
+            .size
+            .min(FILE_HEADER_SIZE);
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(children
+            .scsi2files
+            .comm
+            .readfile(proto::files::RequestReadFile {
+                path: src_path.into(),
+                offset: 0,
+                size,
+                stream: stream.into(),
+            })?
+            .data)
+    }
+
     fn tar_src_files(
         &self,
         comm: &mut Comm<proto::usbsas::Request>,
@@ -843,15 +1582,20 @@ impl CopyFilesState {
         entries_filtered: &[String],
         errors: &mut Vec<String>,
         max_file_size: Option<u64>,
-        report: &serde_json::Value,
+        report: &mut serde_json::Value,
     ) -> Result<()> {
         trace!("tar src files");
+        let mut file_times = Vec::new();
         for path in entries_filtered {
-            if let Err(err) = self.file_to_tar(comm, children, path, max_file_size) {
-                error!("Couldn't copy file {}: {}", &path, err);
-                errors.push(path.clone());
+            match self.file_to_tar(comm, children, path, max_file_size) {
+                Ok(times) => file_times.push(times_to_json(path, &times)),
+                Err(err) => {
+                    error!("Couldn't copy file {}: {}", &path, err);
+                    errors.push(path.clone());
+                }
             };
         }
+        report["file_times"] = file_times.into();
         children
             .files2tar
             .comm
@@ -868,11 +1612,16 @@ impl CopyFilesState {
         children: &mut Children,
         path: &str,
         max_file_size: Option<u64>,
-    ) -> Result<()> {
+    ) -> Result<Timestamps> {
+        // Content of followed symlinks and data streams is read from their source
+        let (src_path, stream) = self.source(path);
         let mut attrs = children
             .scsi2files
             .comm
-            .getattr(proto::files::RequestGetAttr { path: path.into() })?;
+            .getattr(proto::files::RequestGetAttr {
+                path: src_path.into(),
+                stream: stream.into(),
+            })?;
 
         if let Some(max_size) = max_file_size {
             if attrs.size > max_size {
@@ -896,8 +1645,17 @@ impl CopyFilesState {
                 path: path.to_string(),
                 size: attrs.size,
                 ftype: attrs.ftype,
-                timestamp: attrs.timestamp,
+                times: attrs.times,
+            })?;
+
+        // Regular files are also streamed to the yara process to be scanned
+        let scan = self.config.yara && attrs.ftype == FileType::Regular as i32;
+        if scan {
+            children.yara.comm.newfile(proto::yara::RequestNewFile {
+                path: path.to_string(),
+                size: attrs.size,
             })?;
+        }
 
         let mut offset: u64 = 0;
         while attrs.size > 0 {
@@ -910,10 +1668,21 @@ impl CopyFilesState {
                 .scsi2files
                 .comm
                 .readfile(proto::files::RequestReadFile {
-                    path: path.to_string(),
+                    path: src_path.to_string(),
                     offset,
                     size: size_todo,
+                    stream: stream.to_string(),
                 })?;
+            if scan {
+                children
+                    .yara
+                    .comm
+                    .writefile(proto::yara::RequestWriteFile {
+                        path: path.to_string(),
+                        offset,
+                        data: rep.data.clone(),
+                    })?;
+            }
             children
                 .files2tar
                 .comm
@@ -935,8 +1704,13 @@ impl CopyFilesState {
             .endfile(proto::writetar::RequestEndFile {
                 path: path.to_string(),
             })?;
+        if scan {
+            children.yara.comm.endfile(proto::yara::RequestEndFile {
+                path: path.to_string(),
+            })?;
+        }
 
-        Ok(())
+        Ok(attrs.times.unwrap_or_default())
     }
 }
 
@@ -970,7 +1744,7 @@ impl DownloadTarState {
             .size;
         let max_file_size = match children.check_dst_size(comm, &self.destination, total_files_size)
         {
-            Ok(max_size) => max_size,
+            Ok(max_size) => max_size.filter(|_| self.config.large_files == LargeFilePolicy::Skip),
             Err(Error::NotEnoughSpace) => return Ok(State::WaitEnd(WaitEndState {})),
             Err(err) => return Err(err),
         };
@@ -985,14 +1759,38 @@ impl DownloadTarState {
             &mut all_directories,
             max_file_size,
         )?;
+        // The content of the archive is only known once downloaded
+        if self.config.large_files == LargeFilePolicy::Reject
+            && is_fat_destination(&self.destination)
+        {
+            let large = large_files(&mut children.tar2files.comm, &all_files, &HashMap::new());
+            if !large.is_empty() {
+                return Err(Error::LargeFiles(large.join(", ")));
+            }
+        }
 
         let mut report = init_report()?;
         report["source"] = "network".into();
         report["file_names"] = all_files.clone().into();
+        report["file_times"] = all_files
+            .iter()
+            .filter_map(|path| {
+                let attrs = children
+                    .tar2files
+                    .comm
+                    .getattr(proto::files::RequestGetAttr {
+                        path: path.clone(),
+                        stream: String::new(),
+                    })
+                    .ok()?;
+                Some(times_to_json(path, &attrs.times.unwrap_or_default()))
+            })
+            .collect();
 
         self.config.analyze_usb = false;
         self.config.analyze_net = false;
         self.config.analyze_cmd = false;
+        self.config.yara = false;
         self.config.write_report_dest = false;
         match self.destination {
             Destination::Usb(usb) => Ok(State::WriteFs(WriteFsState {
@@ -1089,6 +1887,7 @@ impl DownloadTarState {
                 .comm
                 .getattr(proto::files::RequestGetAttr {
                     path: entry.clone(),
+                    stream: String::new(),
                 }) {
                 Ok(rep) => rep,
                 Err(_) => {
@@ -1123,7 +1922,10 @@ impl DownloadTarState {
                     let rep = children
                         .tar2files
                         .comm
-                        .readdir(proto::files::RequestReadDir { path: entry })?;
+                        .readdir(proto::files::RequestReadDir {
+                            path: entry,
+                            max_entries: 0,
+                        })?;
                     for file in rep.filesinfo.iter() {
                         todo.push_back(file.path.clone());
                     }
@@ -1154,8 +1956,13 @@ impl AnalyzeState {
         children: &mut Children,
     ) -> Result<State> {
         let mut dirty: Vec<String> = Vec::new();
-        let analyze_report = self.analyze_files(comm, children, &mut dirty)?;
-        self.report["analyzer_report"] = analyze_report;
+        if self.config.analyze(&self.destination) {
+            self.report["analyzer_report"] = self.analyze_files(comm, children, &mut dirty)?;
+        }
+        if self.config.yara {
+            self.report["yara_report"] = self.yara_files(children, &mut dirty)?;
+        }
+        comm.analyzedone(proto::usbsas::ResponseAnalyzeDone {})?;
 
         children.tar2files.unlock_with(&[1])?;
 
@@ -1215,50 +2022,7 @@ impl AnalyzeState {
                 Msg::Analyze(res) => {
                     let report_json: serde_json::Value = serde_json::from_str(&res.report)?;
                     log::trace!("analyzer report: {:?}", report_json);
-                    let files_status = report_json["files"].as_object().ok_or(Error::Analyze(
-                        "Couldn't get files from analyzer report".into(),
-                    ))?;
-
-                    match &report_json["version"].as_u64() {
-                        Some(2) => self.files.retain(|x| {
-                            if let Some(status) = files_status.get(x.trim_start_matches('/')) {
-                                match status["status"].as_str() {
-                                    Some("CLEAN") => true,
-                                    Some("DIRTY") => {
-                                        dirty.push(x.to_string());
-                                        false
-                                    }
-                                    _ => {
-                                        self.errors.push(x.to_string());
-                                        false
-                                    }
-                                }
-                            } else {
-                                false
-                            }
-                        }),
-                        _ => self.files.retain(|x| {
-                            if let Some(status) = files_status
-                                .get(&format!("{TAR_DATA_DIR}/{}", x.trim_start_matches('/')))
-                            {
-                                match status.as_str() {
-                                    Some("CLEAN") => true,
-                                    Some("DIRTY") => {
-                                        dirty.push(x.to_string());
-                                        false
-                                    }
-                                    _ => {
-                                        self.errors.push(x.to_string());
-                                        false
-                                    }
-                                }
-                            } else {
-                                false
-                            }
-                        }),
-                    }
-
-                    comm.analyzedone(proto::usbsas::ResponseAnalyzeDone {})?;
+                    self.apply_verdicts(&report_json, dirty)?;
                     return Ok(report_json);
                 }
                 Msg::UploadStatus(status) => {
@@ -1276,6 +2040,73 @@ impl AnalyzeState {
             }
         }
     }
+
+    /// Keep the files reported clean, in the formats of both versions of the
+    /// remote analyzer
+    fn apply_verdicts(
+        &mut self,
+        report_json: &serde_json::Value,
+        dirty: &mut Vec<String>,
+    ) -> Result<()> {
+        let files_status = report_json["files"].as_object().ok_or(Error::Analyze(
+            "Couldn't get files from analyzer report".into(),
+        ))?;
+
+        match &report_json["version"].as_u64() {
+            Some(2) => self.files.retain(|x| {
+                if let Some(status) = files_status.get(x.trim_start_matches('/')) {
+                    match status["status"].as_str() {
+                        Some("CLEAN") => true,
+                        Some("DIRTY") => {
+                            dirty.push(x.to_string());
+                            false
+                        }
+                        _ => {
+                            self.errors.push(x.to_string());
+                            false
+                        }
+                    }
+                } else {
+                    false
+                }
+            }),
+            _ => self.files.retain(|x| {
+                if let Some(status) =
+                    files_status.get(&format!("{TAR_DATA_DIR}/{}", x.trim_start_matches('/')))
+                {
+                    match status.as_str() {
+                        Some("CLEAN") => true,
+                        Some("DIRTY") => {
+                            dirty.push(x.to_string());
+                            false
+                        }
+                        _ => {
+                            self.errors.push(x.to_string());
+                            false
+                        }
+                    }
+                } else {
+                    false
+                }
+            }),
+        }
+        Ok(())
+    }
+
+    fn yara_files(
+        &mut self,
+        children: &mut Children,
+        dirty: &mut Vec<String>,
+    ) -> Result<serde_json::Value> {
+        trace!("yara report");
+        let rep = children.yara.comm.report(proto::yara::RequestReport {
+            id: self.id.to_string(),
+        })?;
+        let report_json: serde_json::Value = serde_json::from_str(&rep.report)?;
+        log::trace!("yara report: {:?}", report_json);
+        self.apply_verdicts(&report_json, dirty)?;
+        Ok(report_json)
+    }
 }
 
 struct WriteCleanTarState {
@@ -1329,7 +2160,10 @@ impl WriteCleanTarState {
         let mut attrs = children
             .tar2files
             .comm
-            .getattr(proto::files::RequestGetAttr { path: path.into() })?;
+            .getattr(proto::files::RequestGetAttr {
+                path: path.into(),
+                stream: String::new(),
+            })?;
 
         children
             .files2cleantar
@@ -1338,7 +2172,7 @@ impl WriteCleanTarState {
                 path: path.to_string(),
                 size: attrs.size,
                 ftype: attrs.ftype,
-                timestamp: attrs.timestamp,
+                times: attrs.times,
             })?;
 
         let mut offset: u64 = 0;
@@ -1355,6 +2189,7 @@ impl WriteCleanTarState {
                     path: path.to_string(),
                     offset,
                     size: size_todo,
+                    stream: String::new(),
                 })?;
             children
                 .files2cleantar
@@ -1398,24 +2233,29 @@ impl WriteFsState {
         children: &mut Children,
     ) -> Result<State> {
         self.init_fs(children)?;
+        let split = self.split_files(children);
+        let dest_paths = self.sanitize_paths(&split)?;
 
         trace!("copy usb");
 
         // Create directory tree
         for dir in &self.directories {
-            let timestamp = children
+            let times = children
                 .tar2files
                 .comm
-                .getattr(proto::files::RequestGetAttr { path: dir.clone() })?
-                .timestamp;
+                .getattr(proto::files::RequestGetAttr {
+                    path: dir.clone(),
+                    stream: String::new(),
+                })?
+                .times;
             children
                 .files2fs
                 .comm
                 .newfile(proto::writefs::RequestNewFile {
-                    path: dir.to_string(),
+                    path: dest_paths[dir].clone(),
                     size: 0,
                     ftype: FileType::Directory.into(),
-                    timestamp,
+                    times,
                 })?;
         }
 
@@ -1424,8 +2264,10 @@ impl WriteFsState {
             let attrs = match children
                 .tar2files
                 .comm
-                .getattr(proto::files::RequestGetAttr { path: path.clone() })
-            {
+                .getattr(proto::files::RequestGetAttr {
+                    path: path.clone(),
+                    stream: String::new(),
+                }) {
                 Ok(rep) => rep,
                 Err(err) => {
                     error!("{}", err);
@@ -1434,14 +2276,16 @@ impl WriteFsState {
                 }
             };
 
-            match self.write_file(
-                comm,
-                children,
-                path,
-                attrs.size,
-                attrs.ftype,
-                attrs.timestamp,
-            ) {
+            let res = match split.get(path) {
+                Some(parts) => {
+                    self.write_split_file(comm, children, path, &dest_paths[path], attrs, *parts)
+                }
+                None => {
+                    let size = attrs.size;
+                    self.write_file(comm, children, path, &dest_paths[path], &attrs, 0..size)
+                }
+            };
+            match res {
                 Ok(_) => (),
                 Err(err) => {
                     warn!("didn't copy file {}: {}", path, err);
@@ -1500,29 +2344,175 @@ impl WriteFsState {
             .setfsinfos(proto::writefs::RequestSetFsInfos {
                 dev_size,
                 fstype: self.usb.fstype,
+                table: self.usb.table,
             })?;
         Ok(())
     }
 
+    /// Files to split in parts and their number of parts, they are listed in
+    /// the report
+    fn split_files(&mut self, children: &mut Children) -> HashMap<String, u64> {
+        if self.config.large_files != LargeFilePolicy::Split
+            || self.usb.fstype != OutFsType::Fat as i32
+        {
+            return HashMap::new();
+        }
+        let mut split = Vec::new();
+        for path in &self.files {
+            if let Ok(attrs) = children
+                .tar2files
+                .comm
+                .getattr(proto::files::RequestGetAttr {
+                    path: path.clone(),
+                    stream: String::new(),
+                })
+            {
+                if attrs.size > FAT_MAX_FILE_SIZE {
+                    split.push((path.clone(), attrs.size.div_ceil(SPLIT_PART_SIZE)));
+                }
+            }
+        }
+        self.report["split_files"] = split
+            .iter()
+            .map(|(path, parts)| json!({"path": path, "parts": parts}))
+            .collect();
+        split.into_iter().collect()
+    }
+
+    /// Map paths of the archive to names valid on the destination file system
+    /// (names of the parts of split files included). Collisions between the
+    /// rewritten names follow the name collisions policy, skipped entries and
+    /// the content of skipped directories aren't written. Rewritten and
+    /// skipped names are listed in the report.
+    fn sanitize_paths(&mut self, split: &HashMap<String, u64>) -> Result<HashMap<String, String>> {
+        let fstype = OutFsType::try_from(self.usb.fstype).map_err(|_| Error::BadRequest)?;
+        let mut dest_paths: HashMap<String, String> = HashMap::new();
+        // Destination names (upper case if the file system is case-insensitive)
+        // and the entries they belong to
+        let mut taken: HashMap<String, String> = HashMap::new();
+        let mut skipped_dirs = HashSet::new();
+        let mut sanitized = Vec::new();
+        let mut skipped = Vec::new();
+        let key = |path: &str| match fstype {
+            OutFsType::Ext4 => path.to_string(),
+            _ => path.to_uppercase(),
+        };
+        for (path, is_dir) in entries_by_depth(self.directories.clone(), self.files.clone()) {
+            let (parent, src_name) = path.rsplit_once('/').unwrap_or(("", &path));
+            if skipped_dirs.contains(parent) {
+                if is_dir {
+                    skipped_dirs.insert(path.clone());
+                }
+                continue;
+            }
+            let parent = dest_paths.get(parent).map_or(parent, String::as_str);
+            let parts = split.get(&path).copied().unwrap_or(0);
+            let max_len = if parts > 0 {
+                MAX_NAME_LEN - SPLIT_SUFFIX_LEN
+            } else {
+                MAX_NAME_LEN
+            };
+            let name = sanitize_name(src_name, is_dir, fstype, max_len);
+            let mut dest = format!("{parent}/{name}");
+            let mut index = 1;
+            let dest = loop {
+                let names: Vec<String> = std::iter::once(dest.clone())
+                    .chain(split_file_names(&dest, parts))
+                    .map(|name| key(&name))
+                    .collect();
+                let Some(other) = names.iter().find_map(|name| taken.get(name)) else {
+                    taken.extend(names.into_iter().map(|name| (name, path.clone())));
+                    break Some(dest);
+                };
+                match self.config.name_collisions {
+                    CollisionPolicy::Abort => {
+                        return Err(Error::NameCollision(format!("{path} and {other}")));
+                    }
+                    CollisionPolicy::Skip => {
+                        warn!("skipping {}: name collision with {}", path, other);
+                        skipped.push(json!({
+                            "path": path,
+                            "reason": format!("name collision with {other}")
+                        }));
+                        break None;
+                    }
+                    CollisionPolicy::Rename => {
+                        dest = format!(
+                            "{parent}/{}",
+                            dest_name(&name, Some(index), is_dir, fstype, max_len)
+                        );
+                        index += 1;
+                    }
+                }
+            };
+            let Some(dest) = dest else {
+                if is_dir {
+                    skipped_dirs.insert(path);
+                }
+                continue;
+            };
+            if dest != format!("{parent}/{src_name}") {
+                warn!("writing {} as {}", path, dest);
+                sanitized.push(json!({"path": path, "sanitized": dest}));
+            }
+            dest_paths.insert(path, dest);
+        }
+        self.files.retain(|path| dest_paths.contains_key(path));
+        self.directories
+            .retain(|path| dest_paths.contains_key(path));
+        self.report["sanitized_files"] = sanitized.into();
+        if !skipped.is_empty() {
+            let mut skipped_files = self.report["skipped_files"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            skipped_files.extend(skipped);
+            self.report["skipped_files"] = skipped_files.into();
+        }
+        Ok(dest_paths)
+    }
+
+    /// Write parts of a file too large for the destination file system and
+    /// the scripts joining them
+    fn write_split_file(
+        &self,
+        comm: &mut Comm<proto::usbsas::Request>,
+        children: &mut Children,
+        path: &str,
+        dest_path: &str,
+        attrs: proto::files::ResponseGetAttr,
+        parts: u64,
+    ) -> Result<()> {
+        let names = split_file_names(dest_path, parts);
+        let (part_paths, scripts) = names.split_at(parts as usize);
+        for (part, range) in part_paths.iter().zip(split_ranges(attrs.size)) {
+            self.write_file(comm, children, path, part, &attrs, range)?;
+        }
+        let (join_sh, join_bat) = join_scripts(dest_path, part_paths);
+        self.write_data(children, &scripts[0], join_sh.into_bytes(), attrs.times)?;
+        self.write_data(children, &scripts[1], join_bat.into_bytes(), attrs.times)
+    }
+
+    /// Write `range` of a file of the archive to `dest_path`
     fn write_file(
         &self,
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
         path: &str,
-        size: u64,
-        ftype: i32,
-        timestamp: i64,
+        dest_path: &str,
+        attrs: &proto::files::ResponseGetAttr,
+        range: std::ops::Range<u64>,
     ) -> Result<()> {
         children
             .files2fs
             .comm
             .newfile(proto::writefs::RequestNewFile {
-                path: path.to_string(),
-                size,
-                ftype,
-                timestamp,
+                path: dest_path.to_string(),
+                size: range.end - range.start,
+                ftype: attrs.ftype,
+                times: attrs.times,
             })?;
-        let mut size = size;
+        let mut size = range.end - range.start;
         let mut offset: u64 = 0;
         while size > 0 {
             let size_todo = if size < READ_FILE_MAX_SIZE {
@@ -1535,14 +2525,15 @@ impl WriteFsState {
                 .comm
                 .readfile(proto::files::RequestReadFile {
                     path: path.to_string(),
-                    offset,
+                    offset: range.start + offset,
                     size: size_todo,
+                    stream: String::new(),
                 })?;
             children
                 .files2fs
                 .comm
                 .writefile(proto::writefs::RequestWriteFile {
-                    path: path.to_string(),
+                    path: dest_path.to_string(),
                     offset,
                     data: rep.data,
                 })?;
@@ -1556,7 +2547,7 @@ impl WriteFsState {
             .files2fs
             .comm
             .endfile(proto::writefs::RequestEndFile {
-                path: path.to_string(),
+                path: dest_path.to_string(),
             })?;
         Ok(())
     }
@@ -1566,28 +2557,41 @@ impl WriteFsState {
 
         let report_data = serde_json::to_vec_pretty(&self.report)?;
         let report_name = format!("/usbsas-report-{}.json", self.report["timestamp"]);
+        let times = Timestamps::from_secs(self.report["timestamp"].as_f64().unwrap_or(0.0) as i64);
+        self.write_data(children, &report_name, report_data, Some(times))
+    }
 
+    /// Write a regular file generated by usbsas
+    fn write_data(
+        &self,
+        children: &mut Children,
+        path: &str,
+        data: Vec<u8>,
+        times: Option<Timestamps>,
+    ) -> Result<()> {
         children
             .files2fs
             .comm
             .newfile(proto::writefs::RequestNewFile {
-                path: report_name.clone(),
-                size: report_data.len() as u64,
+                path: path.to_string(),
+                size: data.len() as u64,
                 ftype: FileType::Regular.into(),
-                timestamp: self.report["timestamp"].as_f64().unwrap_or(0.0) as i64,
+                times,
             })?;
         children
             .files2fs
             .comm
             .writefile(proto::writefs::RequestWriteFile {
-                path: report_name.clone(),
+                path: path.to_string(),
                 offset: 0,
-                data: report_data,
+                data,
             })?;
         children
             .files2fs
             .comm
-            .endfile(proto::writefs::RequestEndFile { path: report_name })?;
+            .endfile(proto::writefs::RequestEndFile {
+                path: path.to_string(),
+            })?;
         Ok(())
     }
 
@@ -1706,6 +2710,7 @@ struct WipeState {
     devnum: u64,
     quick: bool,
     fstype: i32,
+    table: i32,
 }
 
 impl WipeState {
@@ -1762,6 +2767,7 @@ impl WipeState {
             .setfsinfos(proto::writefs::RequestSetFsInfos {
                 dev_size,
                 fstype: self.fstype,
+                table: self.table,
             })?;
         children
             .files2fs
@@ -1958,8 +2964,227 @@ fn init_report() -> Result<serde_json::Value> {
     Ok(report)
 }
 
+/// Times of a copied file for the report, as "YYYY-MM-DD HH:MM:SS.nnnnnnnnn"
+/// (UTC), null if unknown
+fn times_to_json(path: &str, times: &Timestamps) -> serde_json::Value {
+    let datetime =
+        |nanos: i64| match time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(nanos)) {
+            Ok(time) if nanos != 0 => format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09}",
+                time.year(),
+                time.month() as u8,
+                time.day(),
+                time.hour(),
+                time.minute(),
+                time.second(),
+                time.nanosecond()
+            )
+            .into(),
+            _ => serde_json::Value::Null,
+        };
+    json!({
+        "path": path,
+        "mtime": datetime(times.mtime),
+        "ctime": datetime(times.ctime),
+        "atime": datetime(times.atime),
+        "crtime": datetime(times.crtime),
+    })
+}
+
+/// Parents first, directories before files of the same depth
+fn entries_by_depth(directories: Vec<String>, files: Vec<String>) -> Vec<(String, bool)> {
+    let mut entries: Vec<(String, bool)> = directories
+        .into_iter()
+        .map(|dir| (dir, true))
+        .chain(files.into_iter().map(|file| (file, false)))
+        .collect();
+    entries.sort_by_key(|(path, _)| path.matches('/').count());
+    entries
+}
+
+/// Length of a name on the destination file system (UTF-16 units or bytes)
+fn name_len(name: &str, fstype: OutFsType) -> usize {
+    match fstype {
+        OutFsType::Ext4 => name.len(),
+        _ => name.encode_utf16().count(),
+    }
+}
+
+/// "name (n).ext" ("name (n)" for directories) if `index` is set. The stem is
+/// truncated if the name is too long for the destination file system.
+fn dest_name(
+    name: &str,
+    index: Option<u32>,
+    is_dir: bool,
+    fstype: OutFsType,
+    max_len: usize,
+) -> String {
+    let (mut stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !is_dir && !stem.is_empty() && ext.chars().count() <= MAX_EXT_LEN => {
+            (stem.to_string(), format!(".{ext}"))
+        }
+        _ => (name.to_string(), String::new()),
+    };
+    let suffix = index.map(|index| format!(" ({index})")).unwrap_or_default();
+    while stem.chars().count() > 1 && name_len(&format!("{stem}{suffix}{ext}"), fstype) > max_len {
+        stem.pop();
+    }
+    format!("{stem}{suffix}{ext}")
+}
+
+/// Rewrite a name so it is valid on the destination file system. FAT, exFAT
+/// and NTFS follow the Windows naming rules.
+fn sanitize_name(name: &str, is_dir: bool, fstype: OutFsType, max_len: usize) -> String {
+    if fstype == OutFsType::Ext4 {
+        return dest_name(name, None, is_dir, fstype, max_len);
+    }
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c < ' ' || WINDOWS_FORBIDDEN_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let mut name = dest_name(&name, None, is_dir, fstype, max_len);
+    // No trailing dots or spaces
+    let kept = name.trim_end_matches(['.', ' ']).len();
+    let trailing = name.len() - kept;
+    name.truncate(kept);
+    name.push_str(&"_".repeat(trailing));
+    // Reserved device names, with or without extension
+    let stem_len = name.find('.').unwrap_or(name.len());
+    if WINDOWS_RESERVED_NAMES.contains(&name[..stem_len].trim_end().to_uppercase().as_str()) {
+        name.insert(stem_len, '_');
+    }
+    name
+}
+
+/// Ranges of the parts of a split file
+fn split_ranges(size: u64) -> Vec<std::ops::Range<u64>> {
+    (0..size.div_ceil(SPLIT_PART_SIZE))
+        .map(|index| index * SPLIT_PART_SIZE..((index + 1) * SPLIT_PART_SIZE).min(size))
+        .collect()
+}
+
+/// Shell and batch scripts concatenating the parts of a split file, they are
+/// written next to them
+fn join_scripts(dest_path: &str, part_paths: &[String]) -> (String, String) {
+    let basename = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+    let name = basename(dest_path);
+    let part_names: Vec<String> = part_paths.iter().map(|part| basename(part)).collect();
+    let sh_quote = |name: &str| format!("'{}'", name.replace('\'', "'\\''"));
+    let join_sh = format!(
+        "#!/bin/sh\n# Rebuild {0} from its parts\n\
+         cd \"$(dirname \"$0\")\" && cat {1} > {0}\n",
+        sh_quote(&name),
+        part_names
+            .iter()
+            .map(|part| sh_quote(part))
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+    let bat_quote = |name: &str| format!("\"{}\"", name.replace('%', "%%"));
+    let join_bat = format!(
+        "@echo off\r\nrem Rebuild {0} from its parts\r\n\
+         cd /d \"%~dp0\"\r\ncopy /b {1} {0}\r\n",
+        bat_quote(&name),
+        part_names
+            .iter()
+            .map(|part| bat_quote(part))
+            .collect::<Vec<_>>()
+            .join("+"),
+    );
+    (join_sh, join_bat)
+}
+
+/// "<file>.<stream>.ads", the name of the file a data stream is copied to.
+/// The stream name is sanitized like names written on Windows file systems.
+fn stream_sidecar(path: &str, stream: &str) -> String {
+    format!(
+        "{path}.{}.ads",
+        sanitize_name(stream, false, OutFsType::Ntfs, MAX_NAME_LEN)
+    )
+}
+
+/// Windows absolute paths ("C:/dir", or "C:dir" relative to the current
+/// directory of drive C)
+fn has_drive_letter(target: &str) -> bool {
+    matches!(target.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic())
+}
+
+/// Parts of a split file followed by its join scripts
+fn split_file_names(path: &str, parts: u64) -> Vec<String> {
+    if parts == 0 {
+        return Vec::new();
+    }
+    (1..=parts)
+        .map(|index| format!("{path}.{index:03}"))
+        .chain([format!("{path}.join.sh"), format!("{path}.join.bat")])
+        .collect()
+}
+
+fn is_fat_destination(destination: &Destination) -> bool {
+    matches!(destination, Destination::Usb(usb) if usb.fstype == OutFsType::Fat as i32)
+}
+
+/// Files too large for a FAT32 destination, `src_paths` maps files to the
+/// path and stream they are read from
+fn large_files(
+    files_comm: &mut Comm<proto::files::Request>,
+    files: &[String],
+    src_paths: &HashMap<String, (String, String)>,
+) -> Vec<String> {
+    files
+        .iter()
+        .filter(|path| {
+            let (src_path, stream) = src_paths
+                .get(*path)
+                .map_or((path.as_str(), ""), |(path, stream)| (path, stream));
+            files_comm
+                .getattr(proto::files::RequestGetAttr {
+                    path: src_path.into(),
+                    stream: stream.into(),
+                })
+                .is_ok_and(|attrs| attrs.size > FAT_MAX_FILE_SIZE)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Why the filter didn't let a path through, none if it did
+fn filter_reason(
+    result: proto::filter::FilterResult,
+    rule: &proto::filter::FilterRule,
+    issues: &[String],
+) -> Option<String> {
+    Some(match result {
+        proto::filter::FilterResult::PathOk => return None,
+        proto::filter::FilterResult::PathFiltered => format!("filtered by rule {}", rule.id),
+        proto::filter::FilterResult::PathNotAllowed => "didn't match an allow filter".to_string(),
+        proto::filter::FilterResult::PathUnsafeName => {
+            format!("unsafe name ({})", issues.join(", "))
+        }
+        proto::filter::FilterResult::PathError => "filter error".to_string(),
+    })
+}
+
+fn anomaly_to_json(anomaly: &FsAnomaly) -> serde_json::Value {
+    json!({
+        "kind": FsAnomalyKind::try_from(anomaly.kind)
+            .map(|kind| kind.as_str_name().to_lowercase())
+            .unwrap_or_else(|_| "unknown".to_string()),
+        "location": anomaly.location,
+        "detail": anomaly.detail,
+    })
+}
+
 struct Children {
     analyzer: UsbsasChild<proto::analyzer::Request>,
+    archive: UsbsasChild<proto::archive::Request>,
+    yara: UsbsasChild<proto::yara::Request>,
     identificator: UsbsasChild<proto::identificator::Request>,
     cmdexec: UsbsasChild<proto::cmdexec::Request>,
     downloader: UsbsasChild<proto::downloader::Request>,
@@ -2047,7 +3272,7 @@ impl Children {
                     return Err(Error::NotEnoughSpace);
                 }
                 match OutFsType::try_from(usb.fstype) {
-                    Ok(OutFsType::Fat) => Ok(Some(0xFFFF_FFFF)),
+                    Ok(OutFsType::Fat) => Ok(Some(FAT_MAX_FILE_SIZE)),
                     _ => Ok(None),
                 }
             }
@@ -2086,6 +3311,12 @@ impl Children {
         if let Err(err) = self.filter.comm.end(proto::filter::RequestEnd {}) {
             error!("Couldn't end filter: {}", err);
         };
+        if let Err(err) = self.archive.comm.end(proto::archive::RequestEnd {}) {
+            error!("Couldn't end archive: {}", err);
+        };
+        if let Err(err) = self.yara.comm.end(proto::yara::RequestEnd {}) {
+            error!("Couldn't end yara: {}", err);
+        };
         self.fs2dev.unlock_with(&(0_u64).to_ne_bytes()).ok();
         if let Err(err) = self.fs2dev.comm.end(proto::fs2dev::RequestEnd {}) {
             error!("Couldn't end fs2dev: {}", err);
@@ -2140,6 +3371,14 @@ impl Children {
         if let Err(err) = self.filter.wait() {
             error!("Waiting filter failed: {}", err);
         };
+        trace!("waiting archive");
+        if let Err(err) = self.archive.wait() {
+            error!("Waiting archive failed: {}", err);
+        };
+        trace!("waiting yara");
+        if let Err(err) = self.yara.wait() {
+            error!("Waiting yara failed: {}", err);
+        };
         trace!("waiting fs2dev");
         if let Err(err) = self.fs2dev.wait() {
             error!("Waiting fs2dev failed: {}", err);
@@ -2251,6 +3490,18 @@ impl Usbsas {
         pipes_read.push(filter.comm.input_fd());
         pipes_write.push(filter.comm.output_fd());
 
+        let archive = UsbsasChildSpawner::new("usbsas-archive")
+            .args(&["-c", config_path])
+            .spawn::<proto::archive::Request>()?;
+        pipes_read.push(archive.comm.input_fd());
+        pipes_write.push(archive.comm.output_fd());
+
+        let yara = UsbsasChildSpawner::new("usbsas-yara")
+            .args(&["-c", config_path])
+            .spawn::<proto::yara::Request>()?;
+        pipes_read.push(yara.comm.input_fd());
+        pipes_write.push(yara.comm.output_fd());
+
         let fs2dev = UsbsasChildSpawner::new("usbsas-fs2dev")
             .arg(&out_files.fs_path)
             .wait_on_startup()
@@ -2284,6 +3535,8 @@ impl Usbsas {
 
         let children = Children {
             analyzer,
+            archive,
+            yara,
             identificator,
             cmdexec,
             downloader,
@@ -2324,14 +3577,35 @@ impl Usbsas {
     }
 }
 
+#[derive(Default)]
 struct Config {
     analyze_usb: bool,
     analyze_net: bool,
     analyze_cmd: bool,
+    yara: bool,
     write_report_dest: bool,
     dst_networks: Option<Vec<usbsas_config::Network>>,
     src_network: Option<usbsas_config::Network>,
     command: Option<usbsas_config::Command>,
+    symlinks: SymlinkPolicy,
+    data_streams: StreamPolicy,
+    fs_check: FsCheckPolicy,
+    name_collisions: CollisionPolicy,
+    large_files: LargeFilePolicy,
+    name_checks: NameCheckPolicy,
+    limits: usbsas_config::Limits,
+    archives: ArchivePolicy,
+}
+
+impl Config {
+    /// Whether files copied to the destination are analyzed remotely
+    fn analyze(&self, destination: &Destination) -> bool {
+        match destination {
+            Destination::Usb(_) => self.analyze_usb,
+            Destination::Net(_) => self.analyze_net,
+            Destination::Cmd(_) => self.analyze_cmd,
+        }
+    }
 }
 
 struct OutFiles {
@@ -2362,10 +3636,22 @@ fn main() -> Result<()> {
         analyze_usb: false,
         analyze_net: false,
         analyze_cmd: false,
+        yara: config.yara.is_some(),
         write_report_dest: false,
         dst_networks: config.networks,
         src_network: config.source_network,
         command: config.command,
+        symlinks: config.symlinks.unwrap_or_default(),
+        data_streams: config.data_streams.unwrap_or_default(),
+        fs_check: config.fs_check.unwrap_or_default(),
+        name_collisions: config.name_collisions.unwrap_or_default(),
+        large_files: config.large_files.unwrap_or_default(),
+        name_checks: config.name_checks.unwrap_or_default(),
+        limits: config.limits.unwrap_or_default(),
+        archives: config
+            .archives
+            .and_then(|archives| archives.policy)
+            .unwrap_or_default(),
     };
     if let Some(analyzer_conf) = config.analyzer {
         conf.analyze_usb = analyzer_conf.analyze_usb;
@@ -2410,3 +3696,523 @@ fn main() -> Result<()> {
         .main_loop()
         .map(|_| log::debug!("exit"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    fn copy_state(config: Config) -> CopyFilesState {
+        CopyFilesState {
+            destination: Destination::Cmd(proto::usbsas::DestCmd {}),
+            device: UsbMS {
+                dev: UsbDevice::default(),
+                sector_size: 512,
+                dev_size: 0,
+            },
+            id: "id".into(),
+            selected: Vec::new(),
+            config,
+            src_paths: HashMap::new(),
+            skipped: Vec::new(),
+            symlinks: Vec::new(),
+            streams: Vec::new(),
+            renamed: Vec::new(),
+            file_types: Vec::new(),
+            unsafe_names: Vec::new(),
+            archives: Vec::new(),
+            fs_anomalies: Vec::new(),
+        }
+    }
+
+    fn streams() -> Vec<StreamInfo> {
+        vec![
+            StreamInfo {
+                name: "Zone.Identifier".into(),
+                size: 26,
+            },
+            StreamInfo {
+                name: "a/b?".into(),
+                size: 4,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_stream_sidecar() {
+        assert_eq!(
+            stream_sidecar("/dir/file.txt", "Zone.Identifier"),
+            "/dir/file.txt.Zone.Identifier.ads"
+        );
+        assert_eq!(stream_sidecar("/file", "../x"), "/file..._x.ads");
+        assert_eq!(stream_sidecar("/file", "a\\b\x01"), "/file.a_b_.ads");
+        assert_eq!(stream_sidecar("/file", "CON"), "/file.CON_.ads");
+    }
+
+    #[test]
+    fn test_data_streams() {
+        for (policy, listed, copied) in [
+            (StreamPolicy::Drop, 0, 0),
+            (StreamPolicy::Report, 2, 0),
+            (StreamPolicy::Copy, 2, 2),
+        ] {
+            let mut state = copy_state(Config {
+                data_streams: policy,
+                ..Default::default()
+            });
+            let mut sidecars = Vec::new();
+            let size = state.data_streams("/dir/file", &streams(), &mut sidecars);
+            assert_eq!(state.streams.len(), listed);
+            assert_eq!(sidecars.len(), copied);
+            assert_eq!(size, if copied > 0 { 30 } else { 0 });
+        }
+        let mut state = copy_state(Config {
+            data_streams: StreamPolicy::Copy,
+            ..Default::default()
+        });
+        let mut sidecars = Vec::new();
+        state.data_streams("/dir/file", &streams(), &mut sidecars);
+        assert_eq!(
+            state.streams[1],
+            json!({"path": "/dir/file", "name": "a/b?", "size": 4})
+        );
+        // Streams are read by name, not from a "file:stream" path
+        assert_eq!(
+            sidecars,
+            vec![
+                (
+                    "/dir/file.Zone.Identifier.ads".to_string(),
+                    ("/dir/file".to_string(), "Zone.Identifier".to_string()),
+                    26
+                ),
+                (
+                    "/dir/file.a_b_.ads".to_string(),
+                    ("/dir/file".to_string(), "a/b?".to_string()),
+                    4
+                ),
+            ]
+        );
+    }
+
+    fn write_fs_state(
+        fstype: OutFsType,
+        policy: CollisionPolicy,
+        directories: &[&str],
+        files: &[&str],
+    ) -> WriteFsState {
+        WriteFsState {
+            directories: directories.iter().map(|dir| dir.to_string()).collect(),
+            errors: Vec::new(),
+            files: files.iter().map(|file| file.to_string()).collect(),
+            usb: proto::usbsas::DestUsb {
+                fstype: fstype.into(),
+                ..Default::default()
+            },
+            report: json!({}),
+            config: Config {
+                name_collisions: policy,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_dest_name() {
+        assert_eq!(
+            dest_name("a.txt", None, false, OutFsType::Fat, 255),
+            "a.txt"
+        );
+        assert_eq!(
+            dest_name("a.txt", Some(2), false, OutFsType::Fat, 255),
+            "a (2).txt"
+        );
+        assert_eq!(
+            dest_name("a.txt", Some(2), true, OutFsType::Fat, 255),
+            "a.txt (2)"
+        );
+        assert_eq!(
+            dest_name(".bashrc", Some(1), false, OutFsType::Ext4, 255),
+            ".bashrc (1)"
+        );
+        // The stem is truncated, the extension is kept
+        assert_eq!(
+            dest_name("abcdef.txt", Some(1), false, OutFsType::Fat, 10),
+            "ab (1).txt"
+        );
+        // UTF-16 units on Windows file systems, bytes on ext4
+        let name = "é".repeat(200);
+        assert_eq!(dest_name(&name, None, false, OutFsType::Ntfs, 255), name);
+        assert_eq!(
+            dest_name(&name, None, false, OutFsType::Ext4, 255),
+            "é".repeat(127)
+        );
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(
+            sanitize_name("a:b?.txt", false, OutFsType::Fat, 255),
+            "a_b_.txt"
+        );
+        assert_eq!(
+            sanitize_name("a\x01\\b", false, OutFsType::Exfat, 255),
+            "a__b"
+        );
+        assert_eq!(
+            sanitize_name("a:b?.txt", false, OutFsType::Ext4, 255),
+            "a:b?.txt"
+        );
+        // Trailing dots and spaces are replaced
+        assert_eq!(
+            sanitize_name("dir. .", true, OutFsType::Ntfs, 255),
+            "dir___"
+        );
+        assert_eq!(sanitize_name("file.", false, OutFsType::Ntfs, 255), "file_");
+        assert_eq!(sanitize_name("file.", false, OutFsType::Ext4, 255), "file.");
+        let name = format!("{}:", "a".repeat(300));
+        assert_eq!(
+            sanitize_name(&name, false, OutFsType::Fat, 255),
+            "a".repeat(255)
+        );
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        for name in [
+            "CON",
+            "con",
+            "Nul.txt",
+            "com1.tar.gz",
+            "LPT9",
+            "CONIN$",
+            "conout$.log",
+        ] {
+            let sanitized = sanitize_name(name, false, OutFsType::Fat, 255);
+            let stem_len = name.find('.').unwrap_or(name.len());
+            assert_eq!(
+                sanitized,
+                format!("{}_{}", &name[..stem_len], &name[stem_len..])
+            );
+            assert_eq!(sanitize_name(name, false, OutFsType::Ext4, 255), name);
+        }
+        for name in ["CONSOLE", "COM10", "LPT", "xCON", "CON_"] {
+            assert_eq!(sanitize_name(name, false, OutFsType::Ntfs, 255), name);
+        }
+    }
+
+    #[test]
+    fn test_sanitize_paths() {
+        let dirs = ["/d:", "/d?"];
+        let files = ["/d:/a", "/d?/b", "/a:b", "/A?B", "/c"];
+
+        let mut state = write_fs_state(OutFsType::Fat, CollisionPolicy::Rename, &dirs, &files);
+        let dest_paths = state.sanitize_paths(&HashMap::new()).unwrap();
+        assert_eq!(dest_paths["/d:"], "/d_");
+        assert_eq!(dest_paths["/d?"], "/d_ (1)");
+        assert_eq!(dest_paths["/d?/b"], "/d_ (1)/b");
+        assert_eq!(dest_paths["/a:b"], "/a_b");
+        assert_eq!(dest_paths["/A?B"], "/A_B (1)");
+        assert_eq!(dest_paths["/c"], "/c");
+        assert_eq!(state.report["sanitized_files"].as_array().unwrap().len(), 4);
+        assert_eq!(state.files.len(), 5);
+
+        let mut state = write_fs_state(OutFsType::Fat, CollisionPolicy::Skip, &dirs, &files);
+        let dest_paths = state.sanitize_paths(&HashMap::new()).unwrap();
+        assert_eq!(state.directories, vec!["/d:"]);
+        assert_eq!(state.files, vec!["/d:/a", "/a:b", "/c"]);
+        assert_eq!(dest_paths.len(), 4);
+        assert_eq!(
+            state.report["skipped_files"],
+            json!([
+                {"path": "/d?", "reason": "name collision with /d:"},
+                {"path": "/A?B", "reason": "name collision with /a:b"},
+            ])
+        );
+
+        let mut state = write_fs_state(OutFsType::Fat, CollisionPolicy::Abort, &dirs, &files);
+        assert!(matches!(
+            state.sanitize_paths(&HashMap::new()),
+            Err(Error::NameCollision(_))
+        ));
+
+        // Names are kept on ext4
+        let mut state = write_fs_state(OutFsType::Ext4, CollisionPolicy::Abort, &dirs, &files);
+        let dest_paths = state.sanitize_paths(&HashMap::new()).unwrap();
+        assert!(dest_paths.iter().all(|(path, dest)| path == dest));
+    }
+
+    #[test]
+    fn test_sanitize_split_paths() {
+        // Parts of split files can collide with other files
+        let files = ["/big", "/big.001", "/other"];
+        let split = HashMap::from([("/big".to_string(), 3)]);
+        let mut state = write_fs_state(OutFsType::Fat, CollisionPolicy::Rename, &[], &files);
+        let dest_paths = state.sanitize_paths(&split).unwrap();
+        assert_eq!(dest_paths["/big"], "/big");
+        assert_eq!(dest_paths["/big.001"], "/big (1).001");
+
+        let mut state = write_fs_state(OutFsType::Fat, CollisionPolicy::Skip, &[], &files);
+        state.sanitize_paths(&split).unwrap();
+        assert_eq!(state.files, vec!["/big", "/other"]);
+    }
+
+    fn collisions_state(fstype: OutFsType, policy: CollisionPolicy) -> CopyFilesState {
+        let mut state = copy_state(Config {
+            name_collisions: policy,
+            ..Default::default()
+        });
+        state.destination = Destination::Usb(proto::usbsas::DestUsb {
+            fstype: fstype.into(),
+            ..Default::default()
+        });
+        state
+    }
+
+    fn collisions_entries() -> (Vec<String>, Vec<String>) {
+        (
+            vec!["/Dir".into(), "/DIR".into(), "/dir".into()],
+            vec![
+                "/Dir/a".into(),
+                "/DIR/a".into(),
+                "/DIR/b".into(),
+                "/Readme.txt".into(),
+                "/README.TXT".into(),
+                "/readme.txt".into(),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_name_collisions_rename() {
+        let mut state = collisions_state(OutFsType::Fat, CollisionPolicy::Rename);
+        state
+            .src_paths
+            .insert("/README.TXT".into(), ("/link".into(), String::new()));
+        let (mut directories, mut files) = collisions_entries();
+        state.name_collisions(&mut files, &mut directories).unwrap();
+        assert_eq!(directories, vec!["/Dir", "/DIR (1)", "/dir (2)"]);
+        assert_eq!(
+            files,
+            vec![
+                "/Readme.txt",
+                "/README (1).TXT",
+                "/readme (2).txt",
+                "/Dir/a",
+                "/DIR (1)/a",
+                "/DIR (1)/b"
+            ]
+        );
+        assert_eq!(state.renamed.len(), 4);
+        assert_eq!(
+            state.renamed[0],
+            json!({"path": "/DIR", "renamed": "/DIR (1)"})
+        );
+        // Renamed files are still read from their source
+        assert_eq!(state.source("/README (1).TXT"), ("/link", ""));
+        assert_eq!(state.source("/DIR (1)/a"), ("/DIR/a", ""));
+        assert!(state.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_name_collisions_skip() {
+        let mut state = collisions_state(OutFsType::Exfat, CollisionPolicy::Skip);
+        let (mut directories, mut files) = collisions_entries();
+        state.name_collisions(&mut files, &mut directories).unwrap();
+        assert_eq!(directories, vec!["/Dir"]);
+        assert_eq!(files, vec!["/Readme.txt", "/Dir/a"]);
+        // Entries of skipped directories aren't listed
+        assert_eq!(
+            state
+                .skipped
+                .iter()
+                .map(|skipped| skipped["path"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["/DIR", "/dir", "/README.TXT", "/readme.txt"]
+        );
+        assert_eq!(state.skipped[0]["reason"], "name collision with /Dir");
+        assert!(state.renamed.is_empty());
+    }
+
+    #[test]
+    fn test_name_collisions_abort() {
+        let mut state = collisions_state(OutFsType::Ntfs, CollisionPolicy::Abort);
+        let (mut directories, mut files) = collisions_entries();
+        assert!(matches!(
+            state.name_collisions(&mut files, &mut directories),
+            Err(Error::NameCollision(_))
+        ));
+        // No collision without names only differing by case
+        let mut directories = vec!["/Dir".to_string()];
+        let mut files = vec!["/Dir/a".to_string(), "/Dir/A.txt".to_string()];
+        state.name_collisions(&mut files, &mut directories).unwrap();
+        assert_eq!(files, vec!["/Dir/a", "/Dir/A.txt"]);
+    }
+
+    #[test]
+    fn test_name_collisions_case_sensitive() {
+        // ext4 and non USB destinations are case-sensitive
+        for mut state in [
+            collisions_state(OutFsType::Ext4, CollisionPolicy::Abort),
+            copy_state(Config {
+                name_collisions: CollisionPolicy::Abort,
+                ..Default::default()
+            }),
+        ] {
+            let (mut directories, mut files) = collisions_entries();
+            state.name_collisions(&mut files, &mut directories).unwrap();
+            assert_eq!((directories, files), collisions_entries());
+        }
+    }
+
+    #[test]
+    fn test_split_file_names() {
+        assert!(split_file_names("/dir/big.iso", 0).is_empty());
+        assert_eq!(
+            split_file_names("/dir/big.iso", 3),
+            vec![
+                "/dir/big.iso.001",
+                "/dir/big.iso.002",
+                "/dir/big.iso.003",
+                "/dir/big.iso.join.sh",
+                "/dir/big.iso.join.bat"
+            ]
+        );
+        assert_eq!(".join.bat".len(), SPLIT_SUFFIX_LEN);
+    }
+
+    #[test]
+    fn test_split_ranges() {
+        const GIB: u64 = 1 << 30;
+        assert_eq!(
+            split_ranges(FAT_MAX_FILE_SIZE + 1),
+            vec![0..2 * GIB, 2 * GIB..4 * GIB]
+        );
+        assert_eq!(
+            split_ranges(4 * GIB + 1),
+            vec![0..2 * GIB, 2 * GIB..4 * GIB, 4 * GIB..4 * GIB + 1]
+        );
+        assert_eq!(
+            split_ranges(6 * GIB),
+            vec![0..2 * GIB, 2 * GIB..4 * GIB, 4 * GIB..6 * GIB]
+        );
+        // As many ranges as parts named
+        for size in [FAT_MAX_FILE_SIZE + 1, 6 * GIB - 1, 6 * GIB, 6 * GIB + 1] {
+            assert_eq!(
+                split_ranges(size).len() as u64,
+                size.div_ceil(SPLIT_PART_SIZE)
+            );
+        }
+    }
+
+    #[test]
+    fn test_join_scripts() {
+        let names = split_file_names("/dir/it's 100%.iso", 2);
+        let (join_sh, join_bat) = join_scripts("/dir/it's 100%.iso", &names[..2]);
+        assert_eq!(
+            join_sh,
+            "#!/bin/sh\n# Rebuild 'it'\\''s 100%.iso' from its parts\n\
+             cd \"$(dirname \"$0\")\" && cat 'it'\\''s 100%.iso.001' 'it'\\''s 100%.iso.002' \
+             > 'it'\\''s 100%.iso'\n"
+        );
+        assert_eq!(
+            join_bat,
+            "@echo off\r\nrem Rebuild \"it's 100%%.iso\" from its parts\r\n\
+             cd /d \"%~dp0\"\r\ncopy /b \"it's 100%%.iso.001\"+\"it's 100%%.iso.002\" \
+             \"it's 100%%.iso\"\r\n"
+        );
+    }
+
+    #[test]
+    fn test_large_files() {
+        let (client_read, server_write) = UnixStream::pair().unwrap();
+        let (server_read, client_write) = UnixStream::pair().unwrap();
+        let mut client: Comm<proto::files::Request> =
+            Comm::from_fd(client_read.into(), client_write.into());
+        let mut server: Comm<proto::files::Request> =
+            Comm::from_fd(server_read.into(), server_write.into());
+        // Serve the sizes of the source files
+        let sizes = HashMap::from([
+            (("/small", ""), 10),
+            (("/max", ""), FAT_MAX_FILE_SIZE),
+            (("/big", ""), FAT_MAX_FILE_SIZE + 1),
+            (("/target", ""), FAT_MAX_FILE_SIZE + 1),
+            (("/small", "ads"), FAT_MAX_FILE_SIZE + 1),
+        ]);
+        let server = std::thread::spawn(move || loop {
+            let req: proto::files::Request = server.recv().unwrap();
+            match req.msg {
+                Some(proto::files::request::Msg::GetAttr(req)) => {
+                    let msg = match sizes.get(&(req.path.as_str(), req.stream.as_str())) {
+                        Some(size) => {
+                            proto::files::response::Msg::GetAttr(proto::files::ResponseGetAttr {
+                                size: *size,
+                                ..Default::default()
+                            })
+                        }
+                        None => proto::files::response::Msg::Error(proto::files::ResponseError {
+                            err: "not found".into(),
+                        }),
+                    };
+                    server
+                        .send(proto::files::Response { msg: Some(msg) })
+                        .unwrap();
+                }
+                _ => return,
+            }
+        });
+        let files: Vec<String> = [
+            "/small",
+            "/max",
+            "/big",
+            "/link",
+            "/small.ads.ads",
+            "/missing",
+        ]
+        .iter()
+        .map(|file| file.to_string())
+        .collect();
+        let src_paths = HashMap::from([
+            ("/link".to_string(), ("/target".to_string(), String::new())),
+            (
+                "/small.ads.ads".to_string(),
+                ("/small".to_string(), "ads".to_string()),
+            ),
+        ]);
+        assert_eq!(
+            large_files(&mut client, &files, &src_paths),
+            vec!["/big", "/link", "/small.ads.ads"]
+        );
+        client.end(proto::files::RequestEnd {}).unwrap_err();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_times_to_json() {
+        let times = Timestamps {
+            mtime: 1_577_934_245_123_456_789,
+            ctime: -1,
+            atime: 0,
+            crtime: Timestamps::nanos(1_577_934_245, 0),
+        };
+        assert_eq!(
+            times_to_json("/dir/file", &times),
+            json!({
+                "path": "/dir/file",
+                "mtime": "2020-01-02 03:04:05.123456789",
+                "ctime": "1969-12-31 23:59:59.999999999",
+                "atime": null,
+                "crtime": "2020-01-02 03:04:05.000000000",
+            })
+        );
+    }
+
+    #[test]
+    fn test_has_drive_letter() {
+        for target in ["C:/Windows", "c:", "z:file", "C:"] {
+            assert!(has_drive_letter(target));
+        }
+        for target in ["ab:c", "dir/C:", "1:2", ":", "file.txt:stream", "é:"] {
+            assert!(!has_drive_letter(target));
+        }
+    }
+}
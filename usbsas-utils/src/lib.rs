@@ -5,6 +5,7 @@ use std::env;
 pub mod clap;
 pub mod log;
 
+pub const FILE_HEADER_SIZE: u64 = 8192;
 pub const INPUT_PIPE_FD_VAR: &str = "INPUT_PIPE_FD";
 pub const OUTPUT_PIPE_FD_VAR: &str = "OUTPUT_PIPE_FD";
 pub const READ_FILE_MAX_SIZE: u64 = 1024 * 1024 * 10;
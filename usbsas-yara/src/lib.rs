@@ -0,0 +1,502 @@
+//! usbsas's local analyzer process. Files are scanned with YARA rules from a
+//! local directory as usbsas copies them from the source device, verdicts are
+//! then reported in the format of the remote analyzer. Rule files can be
+//! required to be signed, for kiosks receiving rule updates offline.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::{error, info, trace, warn};
+use serde_json::json;
+use std::{collections::BTreeMap, fs, path::Path};
+use thiserror::Error;
+use usbsas_comm::{protoresponse, Comm};
+use usbsas_config::{conf_parse, conf_read};
+use usbsas_proto as proto;
+use usbsas_proto::yara::request::Msg;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("sandbox: {0}")]
+    Sandbox(#[from] usbsas_sandbox::Error),
+    #[error("yara error: {0}")]
+    Yara(String),
+    #[error("rules: {0}")]
+    Rules(String),
+    #[error("json error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Bad Request")]
+    BadRequest,
+    #[error("State error")]
+    State,
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 128 * 1024 * 1024;
+const DEFAULT_TIMEOUT: u64 = 60;
+const RULES_EXTENSIONS: &[&str] = &["yar", "yara"];
+// Detached signatures of rule files are "<file>.sig"
+const SIGNATURE_SUFFIX: &str = ".sig";
+
+protoresponse!(
+    CommYara,
+    yara,
+    newfile = NewFile[ResponseNewFile],
+    writefile = WriteFile[ResponseWriteFile],
+    endfile = EndFile[ResponseEndFile],
+    report = Report[ResponseReport],
+    error = Error[ResponseError],
+    end = End[ResponseEnd]
+);
+
+/// File being received
+struct CurrentFile {
+    path: String,
+    offset: u64,
+    data: Vec<u8>,
+    too_large: bool,
+}
+
+impl CurrentFile {
+    fn new(path: String, size: u64, max_size: u64) -> Self {
+        let too_large = size > max_size;
+        CurrentFile {
+            path,
+            offset: 0,
+            data: if too_large {
+                Vec::new()
+            } else {
+                Vec::with_capacity(size as usize)
+            },
+            too_large,
+        }
+    }
+
+    /// Data must be written in order, it is dropped once the file is larger
+    /// than `max_size`
+    fn write(&mut self, offset: u64, data: &[u8], max_size: u64) -> Result<()> {
+        if offset != self.offset {
+            return Err(Error::BadRequest);
+        }
+        self.offset += data.len() as u64;
+        if self.offset > max_size {
+            self.too_large = true;
+            self.data = Vec::new();
+        }
+        if !self.too_large {
+            self.data.extend_from_slice(data);
+        }
+        Ok(())
+    }
+}
+
+enum State {
+    Init(InitState),
+    Running(RunningState),
+    WaitEnd(WaitEndState),
+    End,
+}
+
+impl State {
+    fn run(self, comm: &mut Comm<proto::yara::Request>) -> Result<Self> {
+        match self {
+            State::Init(s) => s.run(comm),
+            State::Running(s) => s.run(comm),
+            State::WaitEnd(s) => s.run(comm),
+            State::End => Err(Error::State),
+        }
+    }
+}
+
+struct InitState {
+    config_path: String,
+}
+
+struct RunningState {
+    rules: yara::Rules,
+    rule_files: Vec<String>,
+    max_file_size: u64,
+    timeout: i32,
+    current: Option<CurrentFile>,
+    files: BTreeMap<String, serde_json::Value>,
+}
+
+struct WaitEndState {}
+
+impl InitState {
+    fn run(self, comm: &mut Comm<proto::yara::Request>) -> Result<State> {
+        let config = conf_parse(&conf_read(&self.config_path)?)?;
+
+        let Some(conf) = config.yara else {
+            usbsas_sandbox::yara::seccomp(comm.input_fd(), comm.output_fd())?;
+            warn!("No yara conf, parking");
+            return Ok(State::WaitEnd(WaitEndState {}));
+        };
+        let public_key = conf
+            .public_key
+            .as_deref()
+            .map(parse_public_key)
+            .transpose()?;
+        let (rules, rule_files) = compile_rules(&conf.rules_dir, public_key.as_ref())?;
+        info!("rules: {}", rule_files.join(", "));
+
+        usbsas_sandbox::yara::seccomp(comm.input_fd(), comm.output_fd())?;
+
+        Ok(State::Running(RunningState {
+            rules,
+            rule_files,
+            max_file_size: conf.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            timeout: i32::try_from(conf.timeout.unwrap_or(DEFAULT_TIMEOUT)).unwrap_or(i32::MAX),
+            current: None,
+            files: BTreeMap::new(),
+        }))
+    }
+}
+
+impl RunningState {
+    fn run(mut self, comm: &mut Comm<proto::yara::Request>) -> Result<State> {
+        loop {
+            let req: proto::yara::Request = comm.recv()?;
+            let res = match req.msg.ok_or(Error::BadRequest)? {
+                Msg::NewFile(req) => self.newfile(comm, req),
+                Msg::WriteFile(req) => self.writefile(comm, req),
+                Msg::EndFile(req) => self.endfile(comm, req),
+                Msg::Report(req) => self.report(comm, req),
+                Msg::End(_) => {
+                    comm.end(proto::yara::ResponseEnd {})?;
+                    break;
+                }
+            };
+            if let Err(err) = res {
+                error!("{}", err);
+                comm.error(proto::yara::ResponseError {
+                    err: format!("{err}"),
+                })?;
+            }
+        }
+        Ok(State::End)
+    }
+
+    fn newfile(
+        &mut self,
+        comm: &mut Comm<proto::yara::Request>,
+        req: proto::yara::RequestNewFile,
+    ) -> Result<()> {
+        trace!("new file {}", req.path);
+        self.current = Some(CurrentFile::new(req.path, req.size, self.max_file_size));
+        comm.newfile(proto::yara::ResponseNewFile {})?;
+        Ok(())
+    }
+
+    fn writefile(
+        &mut self,
+        comm: &mut Comm<proto::yara::Request>,
+        req: proto::yara::RequestWriteFile,
+    ) -> Result<()> {
+        self.current
+            .as_mut()
+            .filter(|file| file.path == req.path)
+            .ok_or(Error::BadRequest)?
+            .write(req.offset, &req.data, self.max_file_size)?;
+        comm.writefile(proto::yara::ResponseWriteFile {})?;
+        Ok(())
+    }
+
+    fn endfile(
+        &mut self,
+        comm: &mut Comm<proto::yara::Request>,
+        req: proto::yara::RequestEndFile,
+    ) -> Result<()> {
+        let file = self
+            .current
+            .take()
+            .filter(|file| file.path == req.path)
+            .ok_or(Error::BadRequest)?;
+        let verdict = if file.too_large {
+            warn!(
+                "{} not scanned, larger than {}B",
+                file.path, self.max_file_size
+            );
+            json!({ "status": "ERROR", "error": "file too large" })
+        } else {
+            match self.rules.scan_mem(&file.data, self.timeout) {
+                Ok(matches) if matches.is_empty() => json!({ "status": "CLEAN" }),
+                Ok(matches) => {
+                    let matches: Vec<String> = matches
+                        .iter()
+                        .map(|rule| format!("{}:{}", rule.namespace, rule.identifier))
+                        .collect();
+                    warn!("{} matches {}", file.path, matches.join(", "));
+                    json!({ "status": "DIRTY", "matches": matches })
+                }
+                Err(err) => {
+                    error!("couldn't scan {}: {}", file.path, err);
+                    json!({ "status": "ERROR", "error": format!("{err}") })
+                }
+            }
+        };
+        self.files
+            .insert(file.path.trim_start_matches('/').to_string(), verdict);
+        comm.endfile(proto::yara::ResponseEndFile {})?;
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        comm: &mut Comm<proto::yara::Request>,
+        req: proto::yara::RequestReport,
+    ) -> Result<()> {
+        trace!("req report");
+        let report = json!({
+            "id": req.id,
+            "status": "scanned",
+            "version": 2,
+            "files": self.files,
+            "antivirus": {
+                "YARA": {
+                    "rules": self.rule_files,
+                }
+            }
+        });
+        comm.report(proto::yara::ResponseReport {
+            report: serde_json::to_string(&report)?,
+        })?;
+        Ok(())
+    }
+}
+
+impl WaitEndState {
+    fn run(self, comm: &mut Comm<proto::yara::Request>) -> Result<State> {
+        trace!("wait end state");
+        loop {
+            let req: proto::yara::Request = comm.recv()?;
+            match req.msg.ok_or(Error::BadRequest)? {
+                Msg::End(_) => {
+                    comm.end(proto::yara::ResponseEnd {})?;
+                    break;
+                }
+                _ => {
+                    error!("bad request");
+                    comm.error(proto::yara::ResponseError {
+                        err: "bad req, waiting end".into(),
+                    })?;
+                }
+            }
+        }
+        Ok(State::End)
+    }
+}
+
+/// 32 bytes, hex encoded
+fn parse_public_key(hex: &str) -> Result<VerifyingKey> {
+    let hex = hex.trim();
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect();
+    let bytes: [u8; 32] = bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::Rules("public key must be 32 hex encoded bytes".into()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|err| Error::Rules(format!("public key: {err}")))
+}
+
+fn verify_signature(key: &VerifyingKey, path: &Path, source: &[u8]) -> Result<()> {
+    let mut sig_path = path.as_os_str().to_owned();
+    sig_path.push(SIGNATURE_SUFFIX);
+    let signature = fs::read(&sig_path)
+        .map_err(|err| Error::Rules(format!("{}: no signature: {err}", path.display())))?;
+    Signature::from_slice(&signature)
+        .and_then(|signature| key.verify_strict(source, &signature))
+        .map_err(|_| Error::Rules(format!("{}: bad signature", path.display())))
+}
+
+/// Compile the rule files of the directory, each in the namespace of its
+/// name. Their signatures are checked if a key is given.
+fn compile_rules(dir: &str, key: Option<&VerifyingKey>) -> Result<(yara::Rules, Vec<String>)> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        path.is_file()
+            && path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| RULES_EXTENSIONS.contains(&ext))
+    });
+    if paths.is_empty() {
+        return Err(Error::Rules(format!("no rule files in {dir}")));
+    }
+    paths.sort();
+
+    let mut compiler = yara::Compiler::new().map_err(|err| Error::Yara(format!("{err}")))?;
+    let mut names = Vec::new();
+    for path in paths {
+        let source = fs::read(&path)?;
+        if let Some(key) = key {
+            verify_signature(key, &path, &source)?;
+        }
+        let source = String::from_utf8(source)
+            .map_err(|_| Error::Rules(format!("{}: invalid UTF-8", path.display())))?;
+        let namespace = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        compiler = compiler
+            .add_rules_str_with_namespace(&source, &namespace)
+            .map_err(|err| Error::Rules(format!("{}: {err}", path.display())))?;
+        names.push(
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        );
+    }
+    let rules = compiler
+        .compile_rules()
+        .map_err(|err| Error::Yara(format!("{err}")))?;
+    Ok((rules, names))
+}
+
+pub struct YaraScanner {
+    comm: Comm<proto::yara::Request>,
+    state: State,
+}
+
+impl YaraScanner {
+    pub fn new(comm: Comm<proto::yara::Request>, config_path: String) -> Result<Self> {
+        Ok(YaraScanner {
+            comm,
+            state: State::Init(InitState { config_path }),
+        })
+    }
+
+    pub fn main_loop(self) -> Result<()> {
+        let (mut comm, mut state) = (self.comm, self.state);
+        loop {
+            state = match state.run(&mut comm) {
+                Ok(State::End) => break,
+                Ok(state) => state,
+                Err(err) => {
+                    error!("state run error: {}, waiting end", err);
+                    comm.error(proto::yara::ResponseError {
+                        err: format!("run error: {err}"),
+                    })?;
+                    State::WaitEnd(WaitEndState {})
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const RULE: &str = "rule test { condition: true }";
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn public_key() {
+        let key = signing_key().verifying_key();
+        let encoded = hex(key.as_bytes());
+        assert_eq!(parse_public_key(&encoded).unwrap(), key);
+        assert_eq!(parse_public_key(&format!(" {encoded}\n")).unwrap(), key);
+        assert_eq!(parse_public_key(&encoded.to_uppercase()).unwrap(), key);
+        for bad in [
+            "",
+            &encoded[..62],
+            &encoded[..63],
+            &format!("{encoded}00"),
+            &format!("zz{}", &encoded[2..]),
+        ] {
+            assert!(parse_public_key(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn signatures() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.yar");
+        let sig_path = dir.path().join("rules.yar.sig");
+        let key = signing_key();
+        let source = RULE.as_bytes();
+        fs::write(&path, source).unwrap();
+
+        let err = verify_signature(&key.verifying_key(), &path, source).unwrap_err();
+        assert!(format!("{err}").contains("no signature"), "{err}");
+
+        fs::write(&sig_path, key.sign(source).to_bytes()).unwrap();
+        verify_signature(&key.verifying_key(), &path, source).unwrap();
+        // Modified rules, signature of another key or truncated
+        assert!(verify_signature(&key.verifying_key(), &path, b"rule other").is_err());
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert!(verify_signature(&other.verifying_key(), &path, source).is_err());
+        fs::write(&sig_path, &key.sign(source).to_bytes()[..63]).unwrap();
+        assert!(verify_signature(&key.verifying_key(), &path, source).is_err());
+    }
+
+    #[test]
+    fn rule_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_str = dir.path().to_str().unwrap();
+        assert!(compile_rules(dir_str, None).is_err());
+
+        for name in ["b.yara", "a.yar", "notes.txt", "c.yar.sig"] {
+            fs::write(dir.path().join(name), RULE).unwrap();
+        }
+        fs::create_dir(dir.path().join("d.yar")).unwrap();
+        let (_, names) = compile_rules(dir_str, None).unwrap();
+        assert_eq!(names, vec!["a.yar", "b.yara"]);
+
+        // Every rule file must be signed
+        let key = signing_key();
+        fs::write(
+            dir.path().join("a.yar.sig"),
+            key.sign(RULE.as_bytes()).to_bytes(),
+        )
+        .unwrap();
+        assert!(compile_rules(dir_str, Some(&key.verifying_key())).is_err());
+        fs::write(
+            dir.path().join("b.yara.sig"),
+            key.sign(RULE.as_bytes()).to_bytes(),
+        )
+        .unwrap();
+        let (_, names) = compile_rules(dir_str, Some(&key.verifying_key())).unwrap();
+        assert_eq!(names, vec!["a.yar", "b.yara"]);
+    }
+
+    #[test]
+    fn write_file() {
+        let mut file = CurrentFile::new("/a".into(), 6, 8);
+        file.write(0, b"abc", 8).unwrap();
+        assert!(file.write(0, b"abc", 8).is_err());
+        assert!(file.write(4, b"abc", 8).is_err());
+        file.write(3, b"def", 8).unwrap();
+        assert_eq!(file.data, b"abcdef");
+        assert!(!file.too_large);
+
+        // Larger than announced
+        file.write(6, b"ghi", 8).unwrap();
+        assert!(file.too_large);
+        assert!(file.data.is_empty());
+        file.write(9, b"j", 8).unwrap();
+        assert!(file.too_large && file.data.is_empty());
+        assert_eq!(file.offset, 10);
+
+        let mut file = CurrentFile::new("/b".into(), 9, 8);
+        assert!(file.too_large);
+        file.write(0, b"abc", 8).unwrap();
+        assert!(file.data.is_empty());
+    }
+}